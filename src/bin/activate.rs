@@ -16,6 +16,7 @@ use tokio::time::timeout;
 use std::time::Duration;
 
 use std::env;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use notify::{recommended_watcher, RecommendedWatcher, RecursiveMode, Watcher};
@@ -44,6 +45,9 @@ enum SubCommand {
     Activate(ActivateOpts),
     Wait(WaitOpts),
     Revoke(RevokeOpts),
+    ListGenerations(ListGenerationsOpts),
+    DiffClosures(DiffClosuresOpts),
+    CurrentProfile(CurrentProfileOpts),
 }
 
 /// Activate a profile
@@ -119,6 +123,24 @@ struct RevokeOpts {
     /// The profile name
     #[arg(long, requires = "profile_user")]
     profile_name: Option<String>,
+
+    /// Switch to this generation instead of the previous one
+    #[arg(long)]
+    to_generation: Option<u32>,
+}
+
+/// List the generations of a profile
+#[derive(Parser, Debug)]
+struct ListGenerationsOpts {
+    /// The profile path to list generations of
+    #[arg(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[arg(long, requires = "profile_name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[arg(long, requires = "profile_user")]
+    profile_name: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -465,8 +487,191 @@ pub async fn activate(
     Ok(())
 }
 
-async fn revoke(profile_path: String) -> Result<(), DeactivateError> {
-    deactivate(profile_path.as_str()).await?;
+#[derive(Error, Debug)]
+pub enum SwitchGenerationError {
+    #[error("Failed to execute the command for switching generation: {0}")]
+    SwitchGen(std::io::Error),
+    #[error("The command for switching generation resulted in a bad exit code: {0:?}")]
+    SwitchGenExit(Option<i32>),
+    #[error("Failed to run command for re-activating the generation: {0}")]
+    Reactivate(std::io::Error),
+    #[error("Command for re-activating the generation resulted in a bad exit code: {0:?}")]
+    ReactivateExit(Option<i32>),
+}
+
+pub async fn switch_generation(profile_path: &str, generation: u32) -> Result<(), SwitchGenerationError> {
+    warn!("Switching to generation {}", generation);
+
+    let nix_env_switch_generation_exit_status = Command::new("nix-env")
+        .arg("-p")
+        .arg(profile_path)
+        .arg("--switch-generation")
+        .arg(generation.to_string())
+        .status()
+        .await
+        .map_err(SwitchGenerationError::SwitchGen)?;
+
+    match nix_env_switch_generation_exit_status.code() {
+        Some(0) => (),
+        a => return Err(SwitchGenerationError::SwitchGenExit(a)),
+    };
+
+    info!("Attempting to re-activate generation {}", generation);
+
+    let re_activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
+        .env("PROFILE", profile_path)
+        .current_dir(profile_path)
+        .status()
+        .await
+        .map_err(SwitchGenerationError::Reactivate)?;
+
+    match re_activate_exit_status.code() {
+        Some(0) => (),
+        a => return Err(SwitchGenerationError::ReactivateExit(a)),
+    };
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RevokeError {
+    #[error("{0}")]
+    Deactivate(#[from] DeactivateError),
+    #[error("{0}")]
+    SwitchGeneration(#[from] SwitchGenerationError),
+}
+
+async fn revoke(profile_path: String, to_generation: Option<u32>) -> Result<(), RevokeError> {
+    match to_generation {
+        Some(generation) => switch_generation(profile_path.as_str(), generation).await?,
+        None => deactivate(profile_path.as_str()).await?,
+    }
+    Ok(())
+}
+
+/// Show the package changes between a profile and a new closure
+#[derive(Parser, Debug)]
+struct DiffClosuresOpts {
+    /// The closure that is about to be activated
+    closure: String,
+
+    /// The profile path to compare against
+    #[arg(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[arg(long, requires = "profile_name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[arg(long, requires = "profile_user")]
+    profile_name: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum DiffClosuresError {
+    #[error("Failed to run nix store diff-closures: {0}")]
+    DiffClosures(std::io::Error),
+    #[error("nix store diff-closures resulted in a bad exit code: {0:?}")]
+    DiffClosuresExit(Option<i32>),
+    #[error("Failed to write closure differences to stdout: {0}")]
+    Stdout(std::io::Error),
+}
+
+async fn diff_closures(profile_path: String, closure: String) -> Result<(), DiffClosuresError> {
+    if !Path::new(&profile_path).exists() {
+        info!("Profile {} does not exist yet, everything in the closure is new", profile_path);
+        return Ok(());
+    }
+
+    let nix_diff_closures_out = Command::new("nix")
+        .arg("--extra-experimental-features")
+        .arg("nix-command")
+        .arg("store")
+        .arg("diff-closures")
+        .arg(&profile_path)
+        .arg(&closure)
+        .output()
+        .await
+        .map_err(DiffClosuresError::DiffClosures)?;
+
+    match nix_diff_closures_out.status.code() {
+        Some(0) => (),
+        a => return Err(DiffClosuresError::DiffClosuresExit(a)),
+    };
+
+    std::io::stdout()
+        .write_all(&nix_diff_closures_out.stdout)
+        .map_err(DiffClosuresError::Stdout)?;
+
+    Ok(())
+}
+
+/// Print the store path a profile currently points to
+#[derive(Parser, Debug)]
+struct CurrentProfileOpts {
+    /// The profile path to resolve
+    #[arg(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[arg(long, requires = "profile_name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[arg(long, requires = "profile_user")]
+    profile_name: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum CurrentProfileError {
+    #[error("Failed to resolve the profile symlink: {0}")]
+    Resolve(std::io::Error),
+    #[error("Failed to write the current profile to stdout: {0}")]
+    Stdout(std::io::Error),
+}
+
+async fn current_profile(profile_path: String) -> Result<(), CurrentProfileError> {
+    if !Path::new(&profile_path).exists() {
+        debug!("Profile {} does not exist yet", profile_path);
+        return Ok(());
+    }
+
+    let current = tokio::fs::canonicalize(&profile_path)
+        .await
+        .map_err(CurrentProfileError::Resolve)?;
+
+    writeln!(std::io::stdout(), "{}", current.display()).map_err(CurrentProfileError::Stdout)?;
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum ListGenerationsError {
+    #[error("Failed to run command for listing generations: {0}")]
+    ListGen(std::io::Error),
+    #[error("Command for listing generations resulted in a bad exit code: {0:?}")]
+    ListGenExit(Option<i32>),
+    #[error("Failed to write generation list to stdout: {0}")]
+    Stdout(std::io::Error),
+}
+
+async fn list_generations(profile_path: String) -> Result<(), ListGenerationsError> {
+    debug!("Listing generations");
+
+    let nix_env_list_generations_out = Command::new("nix-env")
+        .arg("-p")
+        .arg(&profile_path)
+        .arg("--list-generations")
+        .output()
+        .await
+        .map_err(ListGenerationsError::ListGen)?;
+
+    match nix_env_list_generations_out.status.code() {
+        Some(0) => (),
+        a => return Err(ListGenerationsError::ListGenExit(a)),
+    };
+
+    std::io::stdout()
+        .write_all(&nix_env_list_generations_out.stdout)
+        .map_err(ListGenerationsError::Stdout)?;
+
     Ok(())
 }
 
@@ -540,11 +745,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     deploy::init_logger(
         opts.debug_logs,
         opts.log_dir.as_deref(),
+        false,
         &match opts.subcmd {
             SubCommand::Activate(_) => deploy::LoggerType::Activate,
             SubCommand::Wait(_) => deploy::LoggerType::Wait,
             SubCommand::Revoke(_) => deploy::LoggerType::Revoke,
+            SubCommand::ListGenerations(_) => deploy::LoggerType::ListGenerations,
+            SubCommand::DiffClosures(_) => deploy::LoggerType::DiffClosures,
+            SubCommand::CurrentProfile(_) => deploy::LoggerType::CurrentProfile,
         },
+        deploy::LogFormat::Human,
+        // Runs over SSH without a terminal, the output still ends up on the deploying user's one.
+        // deploy strips the colors again where its own output isn't colored
+        deploy::ColorChoice::Always,
+        None,
     )?;
 
     let r = match opts.subcmd {
@@ -565,14 +779,51 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
-        SubCommand::Wait(wait_opts) => wait(wait_opts.temp_path, wait_opts.closure, wait_opts.activation_timeout)
-            .await
-            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        SubCommand::Wait(wait_opts) => {
+            match wait(wait_opts.temp_path, wait_opts.closure, wait_opts.activation_timeout).await {
+                // Lets deploy-rs tell a timeout apart from the waiter itself failing
+                Err(WaitError::Waiting(DangerZoneError::TimesUp)) => {
+                    error!("Timed out waiting for the activation to finish");
+                    std::process::exit(deploy::WAIT_TIMED_OUT_EXIT_CODE)
+                }
+                r => r.map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+            }
+        }
+
+        SubCommand::Revoke(revoke_opts) => revoke(
+            get_profile_path(
+                revoke_opts.profile_path,
+                revoke_opts.profile_user,
+                revoke_opts.profile_name,
+            )?,
+            revoke_opts.to_generation,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::ListGenerations(list_generations_opts) => list_generations(get_profile_path(
+            list_generations_opts.profile_path,
+            list_generations_opts.profile_user,
+            list_generations_opts.profile_name,
+        )?)
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::DiffClosures(diff_closures_opts) => diff_closures(
+            get_profile_path(
+                diff_closures_opts.profile_path,
+                diff_closures_opts.profile_user,
+                diff_closures_opts.profile_name,
+            )?,
+            diff_closures_opts.closure,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
-        SubCommand::Revoke(revoke_opts) => revoke(get_profile_path(
-            revoke_opts.profile_path,
-            revoke_opts.profile_user,
-            revoke_opts.profile_name,
+        SubCommand::CurrentProfile(current_profile_opts) => current_profile(get_profile_path(
+            current_profile_opts.profile_path,
+            current_profile_opts.profile_user,
+            current_profile_opts.profile_name,
         )?)
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
@@ -3,23 +3,30 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use std::collections::HashMap;
-use std::io::{stdin, stdout, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{stdin, stdout, IsTerminal, Read, Write};
 use std::str::Utf8Error;
 
-use clap::{ArgMatches, Parser, FromArgMatches};
+use clap::{ArgMatches, CommandFactory, Parser, FromArgMatches};
 
 use crate as deploy;
 
+use self::deploy::command::{CommandRunner, CommandSpec};
+use self::deploy::notify::{DeployReport, ProfileStatus};
 use self::deploy::{DeployFlake, ParseFlakeError};
 use futures_util::stream::{StreamExt, TryStreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use log::{debug, error, info, warn};
-use serde::Serialize;
-use std::path::PathBuf;
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::fs::try_exists;
 use tokio::process::Command;
+use zeroize::Zeroizing;
 
 /// Simple Rust rewrite of a simple Nix Flake deployment tool
 #[derive(Parser, Debug, Clone)]
@@ -32,94 +39,712 @@ pub struct Opts {
     /// A list of flakes to deploy alternatively
     #[arg(long, group = "deploy", num_args = 1..)]
     targets: Option<Vec<String>>,
+    /// Read additional flakes to deploy from this file (`-` for stdin), one per line
+    #[arg(long)]
+    targets_file: Option<PathBuf>,
+    /// Deploy from this deployment data (the JSON of the `deploy` output of the flake) instead of evaluating the targets, which still select the nodes and profiles. Skips the checks
+    #[arg(long)]
+    from_data: Option<PathBuf>,
     /// Treat targets as files instead of flakes
     #[clap(short, long)]
     file: Option<String>,
     /// Check signatures when using `nix copy`
-    #[arg(short, long)]
-    checksigs: bool,
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    checksigs: Option<bool>,
     /// Use the interactive prompt before deployment
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    interactive: Option<bool>,
+    /// Prompt before activating each node instead of once before the deployment, to skip nodes or abort the rest
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    interactive_per_node: Option<bool>,
+    /// Answer every prompt with yes, only printing what is going to be deployed
     #[arg(short, long)]
-    interactive: bool,
+    yes: bool,
     /// Extra arguments to be passed to nix build
     extra_build_args: Vec<String>,
+    /// Override a flake input for checking, evaluating and building, can be given multiple times
+    #[arg(long, num_args = 2, value_names = ["INPUT", "FLAKE_REF"], action = clap::ArgAction::Append)]
+    override_input: Vec<String>,
 
     /// Print debug logs to output
-    #[arg(short, long)]
-    debug_logs: bool,
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    debug_logs: Option<bool>,
     /// Directory to print logs to (including the background activation process)
     #[arg(long)]
     log_dir: Option<String>,
+    /// Format of the log output
+    #[arg(long, value_enum, default_value_t)]
+    log_format: deploy::LogFormat,
+    /// When to color the log output
+    #[arg(long, value_enum, default_value_t)]
+    color: deploy::ColorChoice,
+    /// Show a progress bar per node, ignored unless stdout is a terminal and logs are human-readable
+    #[arg(long)]
+    progress: bool,
+    /// Write the logs of each node to a subdirectory of `--log-dir` named after the node
+    #[arg(long, requires = "log_dir")]
+    per_node_logs: bool,
+    /// Log the output of the builds, copies and activations line by line, prefixed with the node and profile it belongs to
+    #[arg(long)]
+    prefix_output: bool,
 
     /// Keep the build outputs of each built profile
-    #[arg(short, long)]
-    keep_result: bool,
-    /// Location to keep outputs from built profiles in
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    keep_result: Option<bool>,
+    /// Location to keep outputs from built profiles in, either a directory or a link containing the `{node}` and `{profile}` placeholders
     #[arg(short, long)]
     result_path: Option<String>,
 
     /// Skip the automatic pre-build checks
-    #[arg(short, long)]
-    skip_checks: bool,
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    skip_checks: Option<bool>,
+    /// Skip the pre-build checks of flakes that already passed them with the same source and lock file (flakes only)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    skip_checks_if_unchanged: Option<bool>,
+    /// Skip the pre-build checks of this flake only, given as its path or as a target in it, can be given multiple times
+    #[arg(long, action = clap::ArgAction::Append)]
+    skip_checks_for: Vec<String>,
+    /// Only run the checks of these systems, e.g. `x86_64-linux,aarch64-linux`, instead of the whole `nix flake check` (or the current system without flakes)
+    #[arg(long, value_delimiter = ',', value_parser = parse_system)]
+    check_systems: Vec<String>,
+    /// Cache the evaluation of flakes in this directory, reusing it as long as the flake and its inputs don't change (flakes only)
+    #[arg(long)]
+    eval_cache: Option<PathBuf>,
+    /// Evaluate flakes with `--pure-eval`, so the deployment data can't depend on the environment
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pure_eval: Option<bool>,
 
-    /// Build on remote host
+    /// Build on remote host, for the nodes and profiles that don't set `remoteBuild` themselves
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    remote_build: Option<bool>,
+    /// Copy profiles to this binary cache instead of the nodes, and let the nodes substitute them from it. The nodes need to have the cache configured as a substituter
+    #[arg(long)]
+    via_cache: Option<String>,
+    /// Build with these distributed builders (passed to nix as `--builders`), ignored for profiles that are built on the node
     #[arg(long)]
-    remote_build: bool,
+    builders: Option<String>,
+    /// Show the build progress with nix-output-monitor, if `nom` is installed
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    nom: Option<bool>,
+    /// Print the full logs of builds (passed to `nix build` as `-L`), including those built on the node
+    #[arg(short = 'L', long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    print_build_logs: Option<bool>,
+    /// System of the nodes (e.g. `aarch64-linux`) to evaluate non-flake deployments and build profiles for, if it differs from the local one
+    #[arg(long)]
+    target_system: Option<String>,
 
     /// Override the SSH user with the given value
-    #[arg(long)]
+    #[arg(long, env = "DEPLOY_SSH_USER")]
     ssh_user: Option<String>,
     /// Override the profile user with the given value
-    #[arg(long)]
+    #[arg(long, env = "DEPLOY_PROFILE_USER")]
     profile_user: Option<String>,
-    /// Override the SSH options used
-    #[arg(long, allow_hyphen_values = true)]
+    /// Override the SSH options used, split into arguments like a shell would (e.g. `--ssh-opts "-o 'ProxyCommand=ssh -W %h:%p bastion'"`)
+    #[arg(long, allow_hyphen_values = true, env = "DEPLOY_SSH_OPTS")]
     ssh_opts: Option<String>,
+    /// Pass the `--ssh-opts` before the `sshOpts` of the deployment instead of replacing them
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    ssh_opts_append: Option<bool>,
+    /// Pass this argument to every ssh command as it is, without splitting or unquoting it like `--ssh-opts`, can be given multiple times (e.g. `--extra-ssh-args -o --extra-ssh-args "ProxyCommand=ssh -W %h:%p bastion"`)
+    #[arg(long, allow_hyphen_values = true)]
+    extra_ssh_args: Vec<String>,
+    /// Known hosts file to check the host keys of the nodes against
+    #[arg(long)]
+    known_hosts_file: Option<PathBuf>,
+    /// ssh config file to use instead of `~/.ssh/config`, for every connection to the nodes
+    #[arg(long)]
+    ssh_config: Option<PathBuf>,
+    /// Show the diagnostics of ssh for every connection to the nodes, give it up to three times for more details (like `ssh -vvv`)
+    #[arg(long, action = clap::ArgAction::Count)]
+    verbose_ssh: u8,
+    /// Deploy to the machine deploy-rs runs on, running the node's commands directly instead of over SSH. Only a single node can be deployed
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    local: Option<bool>,
+    /// Whether ssh should check the host keys of the nodes, by default ssh's own configuration decides
+    #[arg(long, value_enum)]
+    strict_host_key_checking: Option<deploy::StrictHostKeyChecking>,
     /// Override the SSH compression when using `nix copy`
     #[clap(long)]
     compress: Option<bool>,
     /// Override if the connecting to the target node should be considered fast
     #[arg(long)]
     fast_connection: Option<bool>,
+    /// Override if the node should fetch what it can from its own substituters instead of receiving it from `nix copy`
+    #[arg(long)]
+    substitute_on_destination: Option<bool>,
     /// Override if a rollback should be attempted if activation fails
     #[arg(long)]
     auto_rollback: Option<bool>,
-    /// Override hostname used for the node
-    #[arg(long)]
-    hostname: Option<String>,
+    /// Don't attempt a rollback if activation fails, the same as `--auto-rollback false`
+    #[arg(long, conflicts_with = "auto_rollback")]
+    no_auto_rollback: bool,
+    /// Override hostname used for the node, either as `<node>=<host>` for a specific node (can be given multiple times) or as `<host>` when deploying a single node
+    #[arg(long, env = "DEPLOY_HOSTNAME")]
+    hostname: Vec<String>,
     /// Make activation wait for confirmation, or roll back after a period of time
     #[arg(long)]
     magic_rollback: Option<bool>,
-    /// How long activation should wait for confirmation (if using magic-rollback)
-    #[arg(long)]
-    confirm_timeout: Option<u16>,
-    /// How long we should wait for profile activation
+    /// Don't make activation wait for confirmation, the same as `--magic-rollback false`
+    #[arg(long, conflicts_with = "magic_rollback")]
+    no_magic_rollback: bool,
+    /// How long activation should wait for confirmation (if using magic-rollback), either as `<secs>` or as `<node>=<secs>` for a specific node (can be given multiple times)
+    #[arg(long, env = "DEPLOY_CONFIRM_TIMEOUT")]
+    confirm_timeout: Vec<String>,
+    /// Confirm activation by running this local command with the hostname, the profile's store path and the lock file path as arguments, instead of removing the lock file over SSH (if using magic-rollback)
     #[arg(long)]
-    activation_timeout: Option<u16>,
+    confirm_via: Option<String>,
+    /// How often to retry confirming activation when the node can't be reached over SSH
+    #[arg(long, default_value_t = 2)]
+    confirm_retries: u8,
+    /// How often to retry copying profiles to a node when `nix copy` fails because of the network
+    #[arg(long, default_value_t = 2)]
+    copy_retries: u8,
+    /// How many paths `nix copy` may transfer at the same time (passed as the `max-substitution-jobs` setting), Nix's default if not given
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    copy_jobs: Option<u16>,
+    /// Limit the upload of `nix copy` to this many bytes per second, with an optional `k`, `m` or `g` suffix (e.g. `500k`). Needs `pv`, the activation is not limited
+    #[arg(long, value_parser = parse_bwlimit)]
+    copy_bwlimit: Option<String>,
+    /// How long we should wait for profile activation, either as `<secs>` or as `<node>=<secs>` for a specific node (can be given multiple times)
+    #[arg(long, env = "DEPLOY_ACTIVATION_TIMEOUT")]
+    activation_timeout: Vec<String>,
     /// Where to store temporary files (only used by magic-rollback)
-    #[arg(long)]
+    #[arg(long, env = "DEPLOY_TEMP_PATH")]
     temp_path: Option<PathBuf>,
     /// Show what will be activated on the machines
     #[arg(long)]
     dry_activate: bool,
+    /// Log the commands that would build, push and activate the profiles instead of running them, skipping the checks
+    #[arg(long)]
+    dry_run: bool,
+    /// Show the package changes of each profile (`nix store diff-closures`) before activating it
+    #[arg(long, overrides_with = "no_diff")]
+    diff: bool,
+    /// Don't show package changes, even if enabled in the config file
+    #[arg(long, overrides_with = "diff")]
+    no_diff: bool,
     /// Don't activate, but update the boot loader to boot into the new profile
     #[arg(long)]
     boot: bool,
+    /// Activate profiles even if the node already runs them
+    #[arg(long)]
+    force: bool,
     /// Revoke all previously succeeded deploys when deploying multiple profiles
     #[arg(long)]
     rollback_succeeded: Option<bool>,
+    /// Keep deploying the remaining nodes when one fails and report all failures at the end
+    #[arg(long)]
+    keep_going: bool,
+    /// Activate nodes in batches of this many nodes, aborting the remaining batches if one fails
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    batch_size: Option<usize>,
+    /// Seconds to wait between batches
+    #[arg(long, requires = "batch_size")]
+    batch_delay: Option<u64>,
+    /// Prompt for confirmation before each subsequent batch
+    #[arg(long, requires = "batch_size")]
+    batch_confirm: bool,
+    /// Only deploy nodes with this tag, can be given multiple times to deploy nodes with any of the tags
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+    /// Skip this node when deploying all nodes of a flake, can be given multiple times
+    #[arg(long = "exclude-node")]
+    exclude_nodes: Vec<String>,
+    /// Fail instead of warning when a node that is deployed as a whole has no profiles
+    #[arg(long)]
+    error_on_empty_node: bool,
+    /// Only deploy the profile with this name on each selected node, skipping nodes without it
+    #[arg(long)]
+    profile: Option<String>,
+    /// Abort (and roll back) the deployment if it takes longer than this many seconds, including checks and evaluation. 0 disables the timeout
+    #[arg(long)]
+    deploy_timeout: Option<u64>,
+    /// Write the duration of each deployment phase per node as JSON to this file
+    #[arg(long)]
+    timings_json: Option<PathBuf>,
+    /// Write the outcome of every profile as JSON to this file
+    #[arg(long)]
+    report_json: Option<PathBuf>,
+    /// POST a JSON summary of the deployment to this URL once it finished
+    #[cfg(feature = "notify")]
+    #[arg(long)]
+    notify_url: Option<String>,
+    /// Post the result of the deployment to this Slack incoming webhook
+    #[cfg(feature = "slack")]
+    #[arg(long)]
+    slack_webhook: Option<String>,
     /// Which sudo command to use. Must accept at least two arguments: user name to execute commands as and the rest is the command to execute
     #[arg(long)]
     sudo: Option<String>,
+    /// How the sudo command reads the sudo password, by default `doas` is recognized by name
+    #[arg(long, value_enum)]
+    sudo_kind: Option<deploy::data::SudoKind>,
+    /// Use the sudo command exactly as configured, instead of appending `-S -p ""` to make sudo read the password from stdin
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    raw_sudo: Option<bool>,
     /// Prompt for sudo password during activation.
     #[arg(long)]
     interactive_sudo: Option<bool>,
-    /// File for the sudo password with sops integration
+    /// Prompt for the interactive sudo password only once per SSH user and reuse it for every node
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    shared_sudo_password: Option<bool>,
+    /// File for the sudo password with sops integration, either as `<path>` or as `<node>=<path>` for a specific node (can be given multiple times)
+    #[arg(long, env = "DEPLOY_SUDO_FILE")]
+    sudo_file: Vec<String>,
+    /// Key for the sudo password with sops integration, either as `<key>` or as `<node>=<key>` for a specific node (can be given multiple times)
+    #[arg(long, env = "DEPLOY_SUDO_SECRET")]
+    sudo_secret: Vec<String>,
+    /// Output type sops should decrypt the sudo file to before looking up the secret
+    #[arg(long, value_enum, default_value_t)]
+    sudo_secret_format: SecretFormat,
+    /// Identity file used to decrypt the `--age-file`
+    #[arg(long, requires = "age_file")]
+    age_identity: Option<PathBuf>,
+    /// Age encrypted file containing the sudo password, either as plain text or as JSON looked up with `--sudo-secret`
+    #[arg(long, requires = "age_identity")]
+    age_file: Option<PathBuf>,
+    /// Command to run over SSH after activation, confirmation only happens if it exits successfully (if using magic-rollback)
     #[arg(long)]
-    sudo_file: Option<PathBuf>,
-    /// Key for the sudo password with sops integration
+    health_check_cmd: Option<String>,
+    /// How long the health check command may run before it is considered failed
     #[arg(long)]
+    health_check_timeout: Option<u16>,
+    /// Kill the build of a profile if it takes longer than this many seconds, overriding `buildTimeout`
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    build_timeout: Option<u16>,
+
+    /// Read default options from this file instead of looking for a `deploy.toml`
+    #[arg(long, conflicts_with = "no_config")]
+    config: Option<PathBuf>,
+    /// Don't read default options from a `deploy.toml`
+    #[arg(long)]
+    no_config: bool,
+
+    #[command(subcommand)]
+    subcmd: Option<SubCommand>,
+}
+
+/// Defaults for `Opts` read from a `deploy.toml`, options given on the command line take precedence
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    checksigs: Option<bool>,
+    interactive: Option<bool>,
+    interactive_per_node: Option<bool>,
+    diff: Option<bool>,
+    #[serde(default)]
+    extra_build_args: Vec<String>,
+    debug_logs: Option<bool>,
+    log_dir: Option<String>,
+    keep_result: Option<bool>,
+    result_path: Option<String>,
+    skip_checks: Option<bool>,
+    skip_checks_if_unchanged: Option<bool>,
+    #[serde(default)]
+    skip_checks_for: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_systems")]
+    check_systems: Vec<String>,
+    eval_cache: Option<PathBuf>,
+    pure_eval: Option<bool>,
+    remote_build: Option<bool>,
+    via_cache: Option<String>,
+    builders: Option<String>,
+    nom: Option<bool>,
+    print_build_logs: Option<bool>,
+    target_system: Option<String>,
+    ssh_user: Option<String>,
+    profile_user: Option<String>,
+    ssh_opts: Option<String>,
+    ssh_opts_append: Option<bool>,
+    #[serde(default)]
+    extra_ssh_args: Vec<String>,
+    known_hosts_file: Option<PathBuf>,
+    ssh_config: Option<PathBuf>,
+    verbose_ssh: Option<u8>,
+    local: Option<bool>,
+    strict_host_key_checking: Option<deploy::StrictHostKeyChecking>,
+    compress: Option<bool>,
+    fast_connection: Option<bool>,
+    substitute_on_destination: Option<bool>,
+    auto_rollback: Option<bool>,
+    magic_rollback: Option<bool>,
+    confirm_timeout: Option<u16>,
+    activation_timeout: Option<u16>,
+    temp_path: Option<PathBuf>,
+    rollback_succeeded: Option<bool>,
+    deploy_timeout: Option<u64>,
+    notify_url: Option<String>,
+    sudo: Option<String>,
+    sudo_kind: Option<deploy::data::SudoKind>,
+    raw_sudo: Option<bool>,
+    interactive_sudo: Option<bool>,
+    shared_sudo_password: Option<bool>,
+    sudo_file: Option<PathBuf>,
     sudo_secret: Option<String>,
+    health_check_cmd: Option<String>,
+    health_check_timeout: Option<u16>,
+    build_timeout: Option<u16>,
+}
+
+impl ConfigFile {
+    /// Fills in everything `opts` didn't get from the command line
+    fn apply(self, opts: &mut Opts) {
+        opts.checksigs = opts.checksigs.or(self.checksigs);
+        opts.interactive = opts.interactive.or(self.interactive);
+        opts.interactive_per_node = opts.interactive_per_node.or(self.interactive_per_node);
+        opts.diff |= self.diff.unwrap_or(false) && !opts.no_diff;
+        if opts.extra_build_args.is_empty() {
+            opts.extra_build_args = self.extra_build_args;
+        }
+        opts.debug_logs = opts.debug_logs.or(self.debug_logs);
+        opts.log_dir = opts.log_dir.take().or(self.log_dir);
+        opts.keep_result = opts.keep_result.or(self.keep_result);
+        opts.result_path = opts.result_path.take().or(self.result_path);
+        opts.skip_checks = opts.skip_checks.or(self.skip_checks);
+        opts.skip_checks_if_unchanged = opts.skip_checks_if_unchanged.or(self.skip_checks_if_unchanged);
+        if opts.skip_checks_for.is_empty() {
+            opts.skip_checks_for = self.skip_checks_for;
+        }
+        if opts.check_systems.is_empty() {
+            opts.check_systems = self.check_systems;
+        }
+        opts.eval_cache = opts.eval_cache.take().or(self.eval_cache);
+        opts.pure_eval = opts.pure_eval.or(self.pure_eval);
+        opts.remote_build = opts.remote_build.or(self.remote_build);
+        opts.via_cache = opts.via_cache.take().or(self.via_cache);
+        opts.builders = opts.builders.take().or(self.builders);
+        opts.nom = opts.nom.or(self.nom);
+        opts.print_build_logs = opts.print_build_logs.or(self.print_build_logs);
+        opts.target_system = opts.target_system.take().or(self.target_system);
+        opts.ssh_user = opts.ssh_user.take().or(self.ssh_user);
+        opts.profile_user = opts.profile_user.take().or(self.profile_user);
+        opts.ssh_opts = opts.ssh_opts.take().or(self.ssh_opts);
+        opts.ssh_opts_append = opts.ssh_opts_append.or(self.ssh_opts_append);
+        if opts.extra_ssh_args.is_empty() {
+            opts.extra_ssh_args = self.extra_ssh_args;
+        }
+        opts.known_hosts_file = opts.known_hosts_file.take().or(self.known_hosts_file);
+        opts.ssh_config = opts.ssh_config.take().or(self.ssh_config);
+        if opts.verbose_ssh == 0 {
+            opts.verbose_ssh = self.verbose_ssh.unwrap_or(0);
+        }
+        opts.local = opts.local.or(self.local);
+        opts.strict_host_key_checking = opts.strict_host_key_checking.or(self.strict_host_key_checking);
+        opts.compress = opts.compress.or(self.compress);
+        opts.fast_connection = opts.fast_connection.or(self.fast_connection);
+        opts.substitute_on_destination =
+            opts.substitute_on_destination.or(self.substitute_on_destination);
+        opts.auto_rollback = opts.auto_rollback.or(self.auto_rollback);
+        opts.magic_rollback = opts.magic_rollback.or(self.magic_rollback);
+        if opts.confirm_timeout.is_empty() {
+            opts.confirm_timeout = self.confirm_timeout.iter().map(u16::to_string).collect();
+        }
+        if opts.activation_timeout.is_empty() {
+            opts.activation_timeout = self.activation_timeout.iter().map(u16::to_string).collect();
+        }
+        opts.temp_path = opts.temp_path.take().or(self.temp_path);
+        opts.rollback_succeeded = opts.rollback_succeeded.or(self.rollback_succeeded);
+        opts.deploy_timeout = opts.deploy_timeout.or(self.deploy_timeout);
+        #[cfg(feature = "notify")]
+        {
+            opts.notify_url = opts.notify_url.take().or(self.notify_url);
+        }
+        #[cfg(not(feature = "notify"))]
+        if self.notify_url.is_some() {
+            warn!("Ignoring `notify_url` of the config file, deploy-rs was built without the notify feature");
+        }
+        opts.sudo = opts.sudo.take().or(self.sudo);
+        opts.sudo_kind = opts.sudo_kind.or(self.sudo_kind);
+        opts.raw_sudo = opts.raw_sudo.or(self.raw_sudo);
+        opts.interactive_sudo = opts.interactive_sudo.or(self.interactive_sudo);
+        opts.shared_sudo_password = opts.shared_sudo_password.or(self.shared_sudo_password);
+        if opts.sudo_file.is_empty() {
+            opts.sudo_file = self
+                .sudo_file
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect();
+        }
+        if opts.sudo_secret.is_empty() {
+            opts.sudo_secret = self.sudo_secret.into_iter().collect();
+        }
+        opts.health_check_cmd = opts.health_check_cmd.take().or(self.health_check_cmd);
+        opts.health_check_timeout = opts.health_check_timeout.or(self.health_check_timeout);
+        opts.build_timeout = opts.build_timeout.or(self.build_timeout);
+    }
+}
+
+/// Turns the values of `--override-input` back into arguments for nix
+fn override_input_args(override_input: &[String]) -> Vec<String> {
+    override_input
+        .chunks(2)
+        .flat_map(|pair| std::iter::once("--override-input".to_string()).chain(pair.iter().cloned()))
+        .collect()
+}
+
+/// Held by the tests that parse `Opts`, as clap reads the environment variables `test_env_fallbacks` sets
+#[cfg(test)]
+fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+    static ENV: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    ENV.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[test]
+fn test_override_input_args() {
+    let _env = lock_env();
+    let opts = Opts::parse_from([
+        "deploy",
+        "--override-input",
+        "config",
+        "path:./config",
+        "--override-input",
+        "nixpkgs",
+        "github:NixOS/nixpkgs/nixos-unstable",
+    ]);
+
+    assert_eq!(
+        override_input_args(&opts.override_input),
+        vec![
+            "--override-input",
+            "config",
+            "path:./config",
+            "--override-input",
+            "nixpkgs",
+            "github:NixOS/nixpkgs/nixos-unstable",
+        ]
+    );
+}
+
+/// Finds the config file to use: `--config`, otherwise `deploy.toml` in the current directory or
+/// in `$XDG_CONFIG_HOME/deploy-rs/`
+fn find_config(opts: &Opts) -> Option<PathBuf> {
+    if opts.no_config {
+        return None;
+    }
+    if let Some(ref config) = opts.config {
+        return Some(config.clone());
+    }
+
+    let cwd_config = PathBuf::from("deploy.toml");
+    if cwd_config.is_file() {
+        return Some(cwd_config);
+    }
+
+    dirs::config_dir()
+        .map(|dir| dir.join("deploy-rs").join("deploy.toml"))
+        .filter(|path| path.is_file())
+}
+
+fn load_config(path: &Path) -> Result<ConfigFile, RunError> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| RunError::ReadConfig(path.to_path_buf(), e))?;
+
+    toml::from_str(&contents).map_err(|e| RunError::ParseConfig(path.to_path_buf(), e))
+}
+
+#[test]
+fn test_env_fallbacks() {
+    let _env = lock_env();
+    std::env::set_var("DEPLOY_SSH_OPTS", "-p 2222");
+    std::env::set_var("DEPLOY_ACTIVATION_TIMEOUT", "120");
+
+    let opts = Opts::parse_from(["deploy"]);
+    assert_eq!(opts.ssh_opts.as_deref(), Some("-p 2222"));
+    assert_eq!(opts.activation_timeout, vec!["120"]);
+
+    let opts = Opts::parse_from(["deploy", "--ssh-opts", "-p 22", "--activation-timeout", "30"]);
+    assert_eq!(opts.ssh_opts.as_deref(), Some("-p 22"));
+    assert_eq!(opts.activation_timeout, vec!["30"]);
+
+    std::env::remove_var("DEPLOY_SSH_OPTS");
+    std::env::remove_var("DEPLOY_ACTIVATION_TIMEOUT");
+}
+
+#[test]
+fn test_extra_ssh_args() {
+    let _env = lock_env();
+    let opts = Opts::parse_from([
+        "deploy",
+        "--extra-ssh-args",
+        "-o",
+        "--extra-ssh-args",
+        "ProxyCommand=ssh -W %h:%p bastion",
+    ]);
+    assert_eq!(opts.extra_ssh_args, vec!["-o", "ProxyCommand=ssh -W %h:%p bastion"]);
+}
+
+#[test]
+fn test_no_rollback_flags() {
+    let _env = lock_env();
+    let opts = Opts::parse_from(["deploy", "--no-auto-rollback", "--no-magic-rollback"]);
+    assert!(opts.no_auto_rollback && opts.no_magic_rollback);
+    assert_eq!(opts.auto_rollback, None);
+
+    let opts = Opts::parse_from(["deploy", "--auto-rollback", "false", "--magic-rollback", "true"]);
+    assert_eq!(opts.auto_rollback, Some(false));
+    assert_eq!(opts.magic_rollback, Some(true));
+    assert!(!opts.no_auto_rollback && !opts.no_magic_rollback);
+
+    assert!(Opts::try_parse_from(["deploy", "--no-auto-rollback", "--auto-rollback", "true"]).is_err());
+    assert!(
+        Opts::try_parse_from(["deploy", "--no-magic-rollback", "--magic-rollback", "false"]).is_err()
+    );
+}
+
+#[test]
+fn test_copy_jobs() {
+    let _env = lock_env();
+    assert_eq!(Opts::parse_from(["deploy"]).copy_jobs, None);
+    assert_eq!(Opts::parse_from(["deploy", "--copy-jobs", "8"]).copy_jobs, Some(8));
+    assert!(Opts::try_parse_from(["deploy", "--copy-jobs", "0"]).is_err());
+}
+
+/// A rate as `pv -L` takes it, bytes per second with an optional unit suffix
+fn parse_bwlimit(rate: &str) -> Result<String, String> {
+    let digits = rate.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G']);
+    let valid = !digits.is_empty()
+        && rate.len() - digits.len() <= 1
+        && digits.chars().all(|c| c.is_ascii_digit())
+        && digits.chars().any(|c| c != '0');
+
+    match valid {
+        true => Ok(rate.to_string()),
+        false => Err(format!("`{}` is not a rate like `500k` or `2m`", rate)),
+    }
+}
+
+#[test]
+fn test_parse_bwlimit() {
+    assert_eq!(parse_bwlimit("500k").as_deref(), Ok("500k"));
+    assert_eq!(parse_bwlimit("2M").as_deref(), Ok("2M"));
+    assert_eq!(parse_bwlimit("65536").as_deref(), Ok("65536"));
+    assert!(parse_bwlimit("0").is_err());
+    assert!(parse_bwlimit("k").is_err());
+    assert!(parse_bwlimit("5mk").is_err());
+    assert!(parse_bwlimit("1.5m").is_err());
+    assert!(parse_bwlimit("1m; rm -rf /").is_err());
+}
+
+#[test]
+fn test_config_file() {
+    let _env = lock_env();
+    let config: ConfigFile = toml::from_str(
+        r#"
+        ssh_user = "deploy"
+        magic_rollback = false
+        confirm_timeout = 60
+        skip_checks = true
+        skip_checks_if_unchanged = true
+        check_systems = ["x86_64-linux"]
+        temp_path = "/var/tmp"
+        strict_host_key_checking = "accept-new"
+        "#,
+    )
+    .unwrap();
+
+    let mut opts = Opts::parse_from(["deploy", "--ssh-user", "admin"]);
+    config.apply(&mut opts);
+
+    assert_eq!(opts.ssh_user.as_deref(), Some("admin"));
+    assert_eq!(opts.magic_rollback, Some(false));
+    assert_eq!(opts.confirm_timeout, vec!["60"]);
+    assert_eq!(opts.skip_checks, Some(true));
+    assert_eq!(opts.skip_checks_if_unchanged, Some(true));
+    assert_eq!(opts.check_systems, vec!["x86_64-linux"]);
+    assert_eq!(opts.temp_path, Some(PathBuf::from("/var/tmp")));
+    assert_eq!(opts.strict_host_key_checking, Some(deploy::StrictHostKeyChecking::AcceptNew));
+
+    let config: ConfigFile = toml::from_str("skip_checks = true\nraw_sudo = false").unwrap();
+    let mut opts = Opts::parse_from(["deploy", "--skip-checks=false", "--raw-sudo", "-c", ".#node"]);
+    config.apply(&mut opts);
+
+    assert_eq!(opts.skip_checks, Some(false));
+    assert_eq!(opts.raw_sudo, Some(true));
+    assert_eq!(opts.checksigs, Some(true));
+    assert_eq!(opts.target.as_deref(), Some(".#node"));
+
+    let opts = Opts::parse_from(["deploy", "--check-systems", "x86_64-linux,aarch64-linux"]);
+    assert_eq!(opts.check_systems, vec!["x86_64-linux", "aarch64-linux"]);
+    assert!(Opts::try_parse_from(["deploy", "--check-systems", "x86_64-linux,\"x\""]).is_err());
+    assert!(toml::from_str::<ConfigFile>("check_systems = [\"${x}\"]").is_err());
+
+    assert!(toml::from_str::<ConfigFile>("ssh-user = \"deploy\"").is_err());
+}
+
+#[derive(Parser, Debug, Clone)]
+enum SubCommand {
+    Rollback(RollbackOpts),
+    ListGenerations(ListGenerationsOpts),
+    Status(StatusOpts),
+    Unlock(UnlockOpts),
+    Eval(EvalOpts),
+    #[command(hide = true)]
+    GenerateCompletions(GenerateCompletionsOpts),
+}
+
+/// Print a shell completion script to stdout
+#[derive(Parser, Debug, Clone)]
+struct GenerateCompletionsOpts {
+    /// The shell to generate completions for
+    #[arg(value_enum)]
+    shell: clap_complete::Shell,
+}
+
+/// Roll a profile back to a specific generation
+#[derive(Parser, Debug, Clone)]
+struct RollbackOpts {
+    /// The flake containing the node to roll back
+    target: Option<String>,
+    /// The node to roll back
+    #[arg(long)]
+    node: String,
+    /// The profile to roll back
+    #[arg(long)]
+    profile: String,
+    /// The generation to switch the profile to
+    #[arg(long)]
+    to_generation: u32,
+}
+
+/// List the generations of the selected profiles without deploying anything
+#[derive(Parser, Debug, Clone)]
+struct ListGenerationsOpts {
+    /// The flake to list generations for
+    target: Option<String>,
+    /// Print the generations as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Show the current system generation, uptime and leftover magic-rollback lock files of the
+/// selected nodes without deploying anything
+#[derive(Parser, Debug, Clone)]
+struct StatusOpts {
+    /// The flake to show the status of the nodes of
+    target: Option<String>,
+    /// Print the status as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Remove the magic-rollback lock files of the selected profiles, e.g. after a crashed deployment.
+/// This confirms a deployment that is still waiting for its confirmation
+#[derive(Parser, Debug, Clone)]
+struct UnlockOpts {
+    /// The profiles to unlock, e.g. `.#node.profile`
+    target: String,
+}
+
+/// Evaluate the deployment data of the targets and print it as JSON, without building or deploying
+/// anything. The output can be deployed later with `--from-data`
+#[derive(Parser, Debug, Clone)]
+struct EvalOpts {
+    /// The flakes to evaluate, e.g. `.#node`
+    #[arg(default_value = ".")]
+    targets: Vec<String>,
+    /// Write the deployment data to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
 }
 
 /// Returns if the available Nix installation supports flakes
@@ -142,39 +767,260 @@ async fn test_flake_support() -> Result<bool, std::io::Error> {
 pub enum CheckDeploymentError {
     #[error("Failed to execute Nix checking command: {0}")]
     NixCheck(#[from] std::io::Error),
-    #[error("Nix checking command resulted in a bad exit code: {0:?}")]
-    NixCheckExit(Option<i32>),
+    #[error("Checking {0} resulted in a bad exit code: {1:?}{tail}", tail = deploy::deploy::format_stderr_tail(.2))]
+    NixCheckExit(String, Option<i32>, Vec<String>),
+    #[error("Error decoding the names of the checks in {0}: {1}")]
+    DecodeChecks(String, serde_json::Error),
+}
+
+/// Runs a checking command, keeping the end of its output for the error
+async fn run_check(mut command: Command, what: &str) -> Result<(), CheckDeploymentError> {
+    command.stderr(Stdio::piped());
+
+    let mut check_child = command.spawn()?;
+    let check_stderr = deploy::deploy::tee_stderr(&mut check_child, None);
+    let check_status = check_child.wait().await?;
+
+    match check_status.code() {
+        Some(0) => Ok(()),
+        a => {
+            let tail = check_stderr.await.unwrap_or_default();
+            Err(CheckDeploymentError::NixCheckExit(what.to_string(), a, tail))
+        }
+    }
+}
+
+/// A system name for `--check-systems`, which ends up in Nix expressions and attribute paths
+fn parse_system(system: &str) -> Result<String, String> {
+    let valid = !system.is_empty()
+        && system
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    match valid {
+        true => Ok(system.to_string()),
+        false => Err(format!("`{}` is not a system like `x86_64-linux`", system)),
+    }
+}
+
+/// `check_systems` of the config file, checked like `--check-systems`
+fn deserialize_systems<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|system| parse_system(system).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[test]
+fn test_parse_system() {
+    assert_eq!(parse_system("aarch64-linux").as_deref(), Ok("aarch64-linux"));
+    assert_eq!(parse_system("x86_64-darwin").as_deref(), Ok("x86_64-darwin"));
+    assert!(parse_system("").is_err());
+    assert!(parse_system("x86_64-linux\" then throw \"").is_err());
+    assert!(parse_system("${builtins.currentSystem}").is_err());
+}
+
+/// The `--apply` expression on the flake outputs listing the names of the checks of `system`, none
+/// if the flake has no checks or none for it
+fn checks_names_apply(system: &str) -> String {
+    format!(
+        "f: if f ? checks && f.checks ? \"{0}\" then builtins.attrNames f.checks.\"{0}\" else []",
+        system
+    )
+}
+
+#[test]
+fn test_checks_names_apply() {
+    assert_eq!(
+        checks_names_apply("aarch64-linux"),
+        r#"f: if f ? checks && f.checks ? "aarch64-linux" then builtins.attrNames f.checks."aarch64-linux" else []"#
+    );
+}
+
+/// The names of the `checks.<system>` of a flake, evaluated on the flake outputs so that a flake
+/// without any checks has none instead of failing
+async fn flake_check_names(
+    runner: &dyn CommandRunner,
+    repo: &str,
+    system: &str,
+    extra_build_args: &[String],
+) -> Result<Vec<String>, CheckDeploymentError> {
+    let checks_attr = format!("{}#checks.\"{}\"", repo, system);
+
+    let mut names_command = CommandSpec::new("nix");
+    names_command
+        .arg("eval")
+        .arg("--json")
+        .arg(format!("{}#", repo))
+        .arg("--apply")
+        .arg(checks_names_apply(system))
+        .args(extra_build_args)
+        .tee_stderr();
+
+    let names_output = runner.output(&names_command).await?;
+
+    if !names_output.status.success() {
+        let tail = deploy::deploy::stderr_tail(&names_output.stderr);
+        return Err(CheckDeploymentError::NixCheckExit(
+            checks_attr,
+            names_output.status.code(),
+            tail,
+        ));
+    }
+
+    serde_json::from_slice(&names_output.stdout).map_err(|e| CheckDeploymentError::DecodeChecks(checks_attr, e))
+}
+
+#[tokio::test]
+async fn test_flake_check_names_without_checks() {
+    let runner = deploy::command::MockRunner::default();
+    // What the apply expression evaluates to for a flake without a `checks` output
+    runner.push_output(0, "[]\n");
+
+    let names = flake_check_names(&runner, "./flake", "x86_64-linux", &[]).await.unwrap();
+    assert!(names.is_empty());
+
+    let calls = runner.calls();
+    assert_eq!(
+        calls[0].args,
+        vec!["eval", "--json", "./flake#", "--apply", &checks_names_apply("x86_64-linux")]
+    );
+}
+
+/// Builds the `checks.<system>` of a flake for one system only, as `nix flake check` can't be
+/// limited to some systems
+async fn check_flake_system(
+    repo: &str,
+    system: &str,
+    extra_build_args: &[String],
+) -> Result<(), CheckDeploymentError> {
+    let checks_attr = format!("{}#checks.\"{}\"", repo, system);
+
+    let names = flake_check_names(&deploy::command::SYSTEM_RUNNER, repo, system, extra_build_args).await?;
+    if names.is_empty() {
+        info!("No checks for {} in flake in {}", system, repo);
+        return Ok(());
+    }
+
+    let mut build_command = Command::new("nix");
+    build_command.arg("build").arg("--no-link");
+    for name in &names {
+        build_command.arg(format!("{}.\"{}\"", checks_attr, name));
+    }
+    build_command.args(extra_build_args);
+
+    run_check(build_command, &checks_attr).await
 }
 
+/// Runs the checks of `repo`, only those of `systems` if any are given
 async fn check_deployment(
     supports_flakes: bool,
     repo: &str,
     extra_build_args: &[String],
+    systems: &[String],
 ) -> Result<(), CheckDeploymentError> {
     info!("Running checks for flake in {}", repo);
 
-    let mut check_command = match supports_flakes {
-        true => Command::new("nix"),
-        false => Command::new("nix-build"),
-    };
+    if supports_flakes && !systems.is_empty() {
+        for system in systems {
+            check_flake_system(repo, system, extra_build_args).await?;
+        }
+        return Ok(());
+    }
 
     if supports_flakes {
-        check_command.arg("flake").arg("check").arg(repo);
-    } else {
+        let mut check_command = Command::new("nix");
+        check_command.arg("flake").arg("check").arg(repo).args(extra_build_args);
+        return run_check(check_command, repo).await;
+    }
+
+    let system_exprs = match systems {
+        [] => vec!["builtins.currentSystem".to_string()],
+        _ => systems.iter().map(|s| format!("\"{}\"", s)).collect(),
+    };
+
+    for system_expr in system_exprs {
+        let mut check_command = Command::new("nix-build");
         check_command.arg("-E")
                 .arg("--no-out-link")
-                .arg(format!("let r = import {}/.; x = (if builtins.isFunction r then (r {{}}) else r); in if x ? checks then x.checks.${{builtins.currentSystem}} else {{}}", repo));
+                .arg(format!("let r = import {}/.; x = (if builtins.isFunction r then (r {{}}) else r); in if x ? checks then x.checks.${{{}}} else {{}}", repo, system_expr))
+                .args(extra_build_args);
+        run_check(check_command, repo).await?;
     }
 
-    check_command.args(extra_build_args);
+    Ok(())
+}
 
-    let check_status = check_command.status().await?;
+/// Whether `repo` is given with `--skip-checks-for`, either by itself or as part of a target
+fn skips_checks(repo: &str, skip_checks_for: &[String]) -> bool {
+    skip_checks_for
+        .iter()
+        .any(|skipped| skipped.split('#').next() == Some(repo))
+}
 
-    match check_status.code() {
-        Some(0) => (),
-        a => return Err(CheckDeploymentError::NixCheckExit(a)),
+#[test]
+fn test_skips_checks() {
+    let skip_checks_for = vec!["./infra".to_string(), "github:me/dev#web.system".to_string()];
+
+    assert!(skips_checks("./infra", &skip_checks_for));
+    assert!(skips_checks("github:me/dev", &skip_checks_for));
+    assert!(!skips_checks(".", &skip_checks_for));
+    assert!(!skips_checks("./infra/sub", &skip_checks_for));
+}
+
+/// Where the marker of a passed check of `repo` is stored, `None` if the flake can't be locked or
+/// there is no cache directory
+async fn check_marker(
+    repo: &str,
+    extra_build_args: &[String],
+    systems: &[String],
+) -> Option<PathBuf> {
+    let dir = dirs::cache_dir()?.join("deploy-rs").join("checks");
+    // The same checks ran if the same systems were checked, in whichever order they are given
+    let mut systems = systems.to_vec();
+    systems.sort();
+    systems.dedup();
+    let apply = format!("check {}", systems.join(","));
+    let key = eval_cache_key(repo, &apply, extra_build_args).await?;
+
+    Some(dir.join(key))
+}
+
+/// Runs the checks of `repo` unless `skip_if_unchanged` is set and they already passed for the
+/// same locked flake, recording that they passed in that case
+async fn check_deployment_cached(
+    supports_flakes: bool,
+    repo: &str,
+    extra_build_args: &[String],
+    systems: &[String],
+    skip_if_unchanged: bool,
+) -> Result<(), CheckDeploymentError> {
+    let marker = match (supports_flakes, skip_if_unchanged) {
+        (true, true) => check_marker(repo, extra_build_args, systems).await,
+        _ => None,
     };
 
+    if let Some(ref marker) = marker {
+        if marker.is_file() {
+            info!("Skipping checks for flake in {}, they passed before and it is unchanged", repo);
+            return Ok(());
+        }
+    }
+
+    check_deployment(supports_flakes, repo, extra_build_args, systems).await?;
+
+    if let Some(marker) = marker {
+        let written = async {
+            if let Some(dir) = marker.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(&marker, repo).await
+        };
+        if let Err(e) = written.await {
+            warn!("Failed to record the passed checks in {}: {}", marker.display(), e);
+        }
+    }
+
     Ok(())
 }
 
@@ -182,62 +1028,230 @@ async fn check_deployment(
 pub enum GetDeploymentDataError {
     #[error("Failed to execute nix eval command: {0}")]
     NixEval(std::io::Error),
-    #[error("Failed to read output from evaluation: {0}")]
-    NixEvalOut(std::io::Error),
-    #[error("Evaluation resulted in a bad exit code: {0:?}")]
-    NixEvalExit(Option<i32>),
+    #[error("Evaluating {0} resulted in a bad exit code: {1:?}{tail}", tail = deploy::deploy::format_stderr_tail(.2))]
+    NixEvalExit(String, Option<i32>, Vec<String>),
+    #[error("Pure evaluation of {0} resulted in a bad exit code: {1:?}, the deployment might depend on impure values like environment variables{tail}", tail = deploy::deploy::format_stderr_tail(.2))]
+    NixEvalPureExit(String, Option<i32>, Vec<String>),
     #[error("Error converting evaluation output to utf8: {0}")]
     DecodeUtf8(#[from] std::string::FromUtf8Error),
     #[error("Error decoding the JSON from evaluation: {0}")]
     DecodeJson(#[from] serde_json::error::Error),
     #[error("Impossible happened: profile is set but node is not")]
     ProfileNoNode,
+    #[error("Invalid deployment data: {0}")]
+    Validate(#[from] deploy::data::ValidateDataError),
 }
 
-/// Evaluates the Nix in the given `repo` and return the processed Data from it
-async fn get_deployment_data(
-    supports_flakes: bool,
-    flakes: &[deploy::DeployFlake<'_>],
-    extra_build_args: &[String],
-) -> Result<Vec<deploy::data::Data>, GetDeploymentDataError> {
-    futures_util::stream::iter(flakes).then(|flake| async move {
+/// Computes the key under which the evaluation of `repo` is stored in the eval cache, from the
+/// locked source and inputs of the flake and everything else that is passed to `nix eval`.
+/// Returns `None` if the flake can't be locked (e.g. because it doesn't exist), in which case it
+/// is evaluated without the cache. Also keys the markers of passed checks, with `apply` set to
+/// `"check"` and the checked systems
+async fn eval_cache_key(repo: &str, apply: &str, extra_build_args: &[String]) -> Option<String> {
+    let metadata_output = Command::new("nix")
+        .arg("--experimental-features")
+        .arg("nix-command flakes")
+        .arg("flake")
+        .arg("metadata")
+        .arg("--json")
+        .args(override_inputs(extra_build_args))
+        .arg(repo)
+        .output()
+        .await
+        .ok()?;
 
-    info!("Evaluating flake in {}", flake.repo);
+    if !metadata_output.status.success() {
+        debug!("Failed to get the metadata of {}, not using the eval cache", repo);
+        return None;
+    }
 
-    let mut c = if supports_flakes {
-        Command::new("nix")
-    } else {
-        Command::new("nix-instantiate")
-    };
+    let metadata: serde_json::Value = serde_json::from_slice(&metadata_output.stdout).ok()?;
+    let locked = metadata.get("locked")?;
+    if locked.get("rev").is_none() && locked.get("narHash").is_none() {
+        debug!("{} is not locked, not using the eval cache", repo);
+        return None;
+    }
 
-    if supports_flakes {
-        c.arg("eval")
-            .arg("--json")
-            .arg(format!("{}#deploy", flake.repo))
-            // We use --apply instead of --expr so that we don't have to deal with builtins.getFlake
-            .arg("--apply");
-        match (&flake.node, &flake.profile) {
-            (Some(node), Some(profile)) => {
-                // Ignore all nodes and all profiles but the one we're evaluating
-                c.arg(format!(
-                    r#"
-                      deploy:
-                      (deploy // {{
-                        nodes = {{
-                          "{0}" = deploy.nodes."{0}" // {{
-                            profiles = {{
-                              inherit (deploy.nodes."{0}".profiles) "{1}";
-                            }};
+    // `locks` is the content of the flake.lock, so updating any input invalidates the entry
+    let locks = metadata.get("locks").map(|l| l.to_string()).unwrap_or_default();
+
+    Some(hash_cache_key(&locked.to_string(), &locks, apply, extra_build_args))
+}
+
+/// The `--override-input` arguments among `extra_build_args`, which `nix flake metadata` needs to
+/// lock the same inputs as the evaluation
+fn override_inputs(extra_build_args: &[String]) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut iter = extra_build_args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--override-input" {
+            if let (Some(input), Some(flake)) = (iter.next(), iter.next()) {
+                args.extend([arg.as_str(), input.as_str(), flake.as_str()].iter());
+            }
+        }
+    }
+    args
+}
+
+#[test]
+fn test_override_inputs() {
+    let extra_build_args: Vec<String> = vec![
+        "--override-input",
+        "config",
+        "path:./config",
+        "--option",
+        "sandbox",
+        "false",
+        "--override-input",
+        "nixpkgs",
+        "github:NixOS/nixpkgs",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+
+    assert_eq!(
+        override_inputs(&extra_build_args),
+        vec![
+            "--override-input",
+            "config",
+            "path:./config",
+            "--override-input",
+            "nixpkgs",
+            "github:NixOS/nixpkgs",
+        ]
+    );
+    assert!(override_inputs(&["--impure".to_string()]).is_empty());
+}
+
+/// Hashes everything the eval cache key is made of with sha256, so the key stays the same across
+/// runs and builds of deploy-rs. Every part is length prefixed so they can't run into each other
+fn hash_cache_key(locked: &str, locks: &str, apply: &str, extra_build_args: &[String]) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    for part in [locked, locks, apply]
+        .iter()
+        .copied()
+        .chain(extra_build_args.iter().map(String::as_str))
+    {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+#[test]
+fn test_hash_cache_key() {
+    let key = hash_cache_key(r#"{"rev":"abc"}"#, "{}", "check x86_64-linux", &[]);
+
+    assert_eq!(
+        key,
+        "285ed4f75938b51ff1f8ae85e7d9ccdcecf71009fb991301be68acf50b59a51a"
+    );
+    assert_eq!(key, hash_cache_key(r#"{"rev":"abc"}"#, "{}", "check x86_64-linux", &[]));
+    assert_ne!(
+        key,
+        hash_cache_key(r#"{"rev":"abc"}"#, "{}", "check x86_64-linux", &["--impure".to_string()])
+    );
+    assert_ne!(
+        hash_cache_key("ab", "c", "", &[]),
+        hash_cache_key("a", "bc", "", &[])
+    );
+}
+
+/// How much of the deployment data the evaluation of `flake` keeps, see the `--apply` expressions in
+/// `get_deployment_json`
+fn eval_scope(supports_flakes: bool, flake: &deploy::DeployFlake<'_>) -> deploy::data::EvalScope {
+    match (supports_flakes, flake.evaluated()) {
+        (true, (Some(_), Some(_))) => deploy::data::EvalScope::Profile,
+        (true, (Some(_), None)) => deploy::data::EvalScope::Node,
+        _ => deploy::data::EvalScope::All,
+    }
+}
+
+/// How many flakes are evaluated at the same time
+const EVAL_CONCURRENCY: usize = 4;
+
+/// Evaluates the Nix in the given `repo` and return the processed Data from it
+async fn get_deployment_data(
+    runner: &dyn CommandRunner,
+    supports_flakes: bool,
+    flakes: &[deploy::DeployFlake<'_>],
+    extra_build_args: &[String],
+    eval_cache: Option<&Path>,
+    target_system: Option<&str>,
+    pure_eval: bool,
+) -> Result<Vec<deploy::data::Data>, GetDeploymentDataError> {
+    get_deployment_json(
+        runner,
+        supports_flakes,
+        flakes,
+        extra_build_args,
+        eval_cache,
+        target_system,
+        pure_eval,
+    )
+    .await?
+    .into_iter()
+    .map(|data| Ok(serde_json::from_value(data)?))
+    .collect()
+}
+
+/// Evaluates the Nix in the given `repo` and returns the validated JSON of its deployment data
+async fn get_deployment_json(
+    runner: &dyn CommandRunner,
+    supports_flakes: bool,
+    flakes: &[deploy::DeployFlake<'_>],
+    extra_build_args: &[String],
+    eval_cache: Option<&Path>,
+    target_system: Option<&str>,
+    pure_eval: bool,
+) -> Result<Vec<serde_json::Value>, GetDeploymentDataError> {
+    futures_util::stream::iter(flakes).map(|flake| async move {
+
+    let mut c = if supports_flakes {
+        CommandSpec::new("nix")
+    } else {
+        CommandSpec::new("nix-instantiate")
+    };
+
+    let mut cache_file = None;
+
+    // Only the selected node and profile are evaluated, so that is where errors come from
+    let attr_path = match (supports_flakes, flake.evaluated()) {
+        (true, (Some(node), Some(profile))) => {
+            format!("deploy.nodes.\"{}\".profiles.\"{}\"", node, profile)
+        }
+        (true, (Some(node), None)) => format!("deploy.nodes.\"{}\"", node),
+        _ => "deploy".to_string(),
+    };
+    let eval_target = format!("{}#{}", flake.repo, attr_path);
+
+    if supports_flakes {
+        let apply = match flake.evaluated() {
+            (Some(node), Some(profile)) => {
+                // Ignore all nodes and all profiles but the one we're evaluating
+                format!(
+                    r#"
+                      deploy:
+                      (deploy // {{
+                        nodes = {{
+                          "{0}" = deploy.nodes."{0}" // {{
+                            profiles = {{
+                              inherit (deploy.nodes."{0}".profiles) "{1}";
+                            }};
                           }};
                         }};
                       }})
                      "#,
                     node, profile
-                ))
+                )
             }
             (Some(node), None) => {
                 // Ignore all nodes but the one we're evaluating
-                c.arg(format!(
+                format!(
                     r#"
                       deploy:
                       (deploy // {{
@@ -247,13 +1261,45 @@ async fn get_deployment_data(
                       }})
                     "#,
                     node
-                ))
+                )
             }
             (None, None) => {
                 // We need to evaluate all profiles of all nodes anyway, so just do it strictly
-                c.arg("deploy: deploy")
+                "deploy: deploy".to_string()
             }
             (None, Some(_)) => return Err(GetDeploymentDataError::ProfileNoNode),
+        };
+
+        if let Some(eval_cache) = eval_cache {
+            if let Some(key) = eval_cache_key(flake.repo, &apply, extra_build_args).await {
+                let path = eval_cache.join(format!("{}.json", key));
+
+                if let Ok(data_json) = tokio::fs::read_to_string(&path).await {
+                    match serde_json::from_str::<serde_json::Value>(&data_json) {
+                        Ok(data) => {
+                            info!("Using cached evaluation of flake in {}", flake.repo);
+
+                            deploy::data::validate(&data, eval_scope(supports_flakes, flake))?;
+
+                            return Ok(data);
+                        }
+                        Err(e) => warn!("Ignoring broken eval cache entry {}: {}", path.display(), e),
+                    }
+                }
+
+                cache_file = Some(path);
+            }
+        }
+
+        c.arg("eval")
+            .arg("--json")
+            .arg(format!("{}#deploy", flake.repo))
+            // We use --apply instead of --expr so that we don't have to deal with builtins.getFlake
+            .arg("--apply")
+            .arg(apply);
+
+        if pure_eval {
+            c.arg("--pure-eval");
         }
     } else {
         c
@@ -262,30 +1308,162 @@ async fn get_deployment_data(
             .arg("--json")
             .arg("--eval")
             .arg("-E")
-            .arg(format!("let r = import {}/.; in if builtins.isFunction r then (r {{}}).deploy else r.deploy", flake.repo))
-    };
+            .arg(format!("let r = import {}/.; in if builtins.isFunction r then (r {{}}).deploy else r.deploy", flake.repo));
 
-    c.args(extra_build_args);
+        // Makes `builtins.currentSystem` evaluate to the target's system
+        if let Some(system) = target_system {
+            c.arg("--option").arg("system").arg(system);
+        }
+    }
 
-    let build_child = c
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(GetDeploymentDataError::NixEval)?;
+    c.args(extra_build_args).tee_stderr();
 
-    let build_output = build_child
-        .wait_with_output()
-        .await
-        .map_err(GetDeploymentDataError::NixEvalOut)?;
+    info!("Evaluating flake in {}", flake.repo);
+
+    let build_output = runner.output(&c).await.map_err(GetDeploymentDataError::NixEval)?;
+
+    // Warnings are shown as they come in, the last lines of a failure are also kept in the error
+    let tail = deploy::deploy::stderr_tail(&build_output.stderr);
 
     match build_output.status.code() {
         Some(0) => (),
-        a => return Err(GetDeploymentDataError::NixEvalExit(a)),
+        a if pure_eval && supports_flakes => {
+            return Err(GetDeploymentDataError::NixEvalPureExit(eval_target, a, tail))
+        }
+        a => return Err(GetDeploymentDataError::NixEvalExit(eval_target, a, tail)),
     };
 
     let data_json = String::from_utf8(build_output.stdout)?;
+    let data: serde_json::Value = serde_json::from_str(&data_json)?;
+
+    deploy::data::validate(&data, eval_scope(supports_flakes, flake))?;
+
+    if let Some(cache_file) = cache_file {
+        let written = async {
+            if let Some(dir) = cache_file.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(&cache_file, &data_json).await
+        };
+        if let Err(e) = written.await {
+            warn!("Failed to write eval cache entry {}: {}", cache_file.display(), e);
+        }
+    }
+
+    Ok(data)
+})
+    // Evaluations of different flakes are independent, `buffered` keeps the results in order
+    .buffered(EVAL_CONCURRENCY)
+    .try_collect()
+    .await
+}
+
+#[tokio::test]
+async fn test_get_deployment_data() {
+    let runner = deploy::command::MockRunner::default();
+    runner.push_output(
+        0,
+        r#"{"sshUser": "deploy", "nodes": {"web": {"hostname": "web.example.com", "profiles": {}}}}"#,
+    );
+
+    let flakes = [DeployFlake {
+        repo: "./infra",
+        node: None,
+        profile: None,
+    }];
+    let data = get_deployment_data(
+        &runner,
+        false,
+        &flakes,
+        &["--show-trace".to_string()],
+        None,
+        Some("aarch64-linux"),
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(data[0].generic_settings.ssh_user.as_deref(), Some("deploy"));
+    assert!(data[0].nodes.contains_key("web"));
+
+    let calls = runner.calls();
+    assert_eq!(calls[0].program, "nix-instantiate");
+    assert_eq!(
+        calls[0].args,
+        vec![
+            "--strict",
+            "--read-write-mode",
+            "--json",
+            "--eval",
+            "-E",
+            "let r = import ./infra/.; in if builtins.isFunction r then (r {}).deploy else r.deploy",
+            "--option",
+            "system",
+            "aarch64-linux",
+            "--show-trace",
+        ]
+    );
+
+    // Failing evaluations are reported with their exit code and the end of the Nix error
+    runner.push_output_with_stderr(1, "", "error: attribute 'deploy' missing\n");
+    let err = get_deployment_data(&runner, false, &flakes, &[], None, None, false)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, GetDeploymentDataError::NixEvalExit(_, Some(1), _)));
+    assert_eq!(
+        err.to_string(),
+        "Evaluating ./infra#deploy resulted in a bad exit code: Some(1), last lines of output:\n\
+         error: attribute 'deploy' missing"
+    );
+}
+
+#[tokio::test]
+async fn test_get_deployment_data_one_profile() {
+    // The `--apply` of `.#web.system` drops the other profiles, but not the `profilesOrder`
+    let runner = deploy::command::MockRunner::default();
+    runner.push_output(
+        0,
+        r#"{"nodes": {"web": {
+            "hostname": "web.example.com",
+            "profilesOrder": ["system", "home", "backup"],
+            "profiles": {"system": {"path": "/nix/store/abc-system"}}
+        }}}"#,
+    );
+
+    let flakes = [DeployFlake {
+        repo: ".",
+        node: Some("web".to_string()),
+        profile: Some("system".to_string()),
+    }];
+    let data = get_deployment_data(&runner, true, &flakes, &[], None, None, false)
+        .await
+        .unwrap();
+    assert!(data[0].nodes["web"].node_settings.profiles.contains_key("system"));
+}
+
+#[tokio::test]
+async fn test_get_deployment_data_pattern() {
+    // `.#web-*.system` is matched against all nodes, so none of them are left out
+    let runner = deploy::command::MockRunner::default();
+    runner.push_output(
+        0,
+        r#"{"nodes": {
+            "web-1": {"hostname": "web-1.example.com", "profiles": {}},
+            "db": {"hostname": "db.example.com", "profiles": {}}
+        }}"#,
+    );
+
+    let flakes = [DeployFlake {
+        repo: ".",
+        node: Some("web-*".to_string()),
+        profile: Some("system".to_string()),
+    }];
+    let data = get_deployment_data(&runner, true, &flakes, &[], None, None, false)
+        .await
+        .unwrap();
+    assert!(data[0].nodes.contains_key("db"));
 
-    Ok(serde_json::from_str(&data_json)?)
-}).try_collect().await
+    let calls = runner.calls();
+    assert_eq!(calls[0].args[..5], ["eval", "--json", ".#deploy", "--apply", "deploy: deploy"]);
 }
 
 #[derive(Serialize)]
@@ -316,7 +1494,7 @@ fn print_deployment(
                     user: &defs.profile_user,
                     ssh_user: &defs.ssh_user,
                     path: &data.profile.profile_settings.path,
-                    hostname: &data.node.node_settings.hostname,
+                    hostname: data.hostname(),
                     ssh_opts: &data.merged_settings.ssh_opts,
                 },
             );
@@ -338,6 +1516,8 @@ pub enum PromptDeploymentError {
     StdinRead(std::io::Error),
     #[error("User cancelled deployment")]
     Cancelled,
+    #[error("Can't prompt for confirmation because stdin is not a terminal, pass --yes to continue without asking")]
+    NonInteractiveStdin,
 }
 
 fn prompt_deployment(
@@ -346,10 +1526,25 @@ fn prompt_deployment(
         deploy::DeployData,
         deploy::DeployDefs,
     )],
+    assume_yes: bool,
 ) -> Result<(), PromptDeploymentError> {
     print_deployment(parts)?;
 
-    info!("Are you sure you want to deploy these profiles?");
+    prompt_continue("Are you sure you want to deploy these profiles?", assume_yes)
+}
+
+fn prompt_continue(question: &str, assume_yes: bool) -> Result<(), PromptDeploymentError> {
+    if assume_yes {
+        info!("{} Yes (--yes)", question);
+        return Ok(());
+    }
+
+    // Reading from a pipe that nobody writes to would block forever
+    if !stdin().is_terminal() {
+        return Err(PromptDeploymentError::NonInteractiveStdin);
+    }
+
+    info!("{}", question);
     print!("> ");
 
     stdout()
@@ -363,7 +1558,7 @@ fn prompt_deployment(
 
     if !yn::yes(&s) {
         if yn::is_somewhat_yes(&s) {
-            info!("Sounds like you might want to continue, to be more clear please just say \"yes\". Do you want to deploy these profiles?");
+            info!("Sounds like you might want to continue, to be more clear please just say \"yes\". {}", question);
             print!("> ");
 
             stdout()
@@ -392,6 +1587,73 @@ fn prompt_deployment(
     Ok(())
 }
 
+/// What to do with a node, as answered with --interactive-per-node
+#[derive(Debug, PartialEq)]
+enum NodeAnswer {
+    Activate,
+    Skip,
+    Abort,
+}
+
+fn parse_node_answer(s: &str) -> Option<NodeAnswer> {
+    if matches!(s.trim().to_lowercase().as_str(), "abort" | "a" | "quit" | "q") {
+        Some(NodeAnswer::Abort)
+    } else if yn::yes(s) {
+        Some(NodeAnswer::Activate)
+    } else if yn::no(s) {
+        Some(NodeAnswer::Skip)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_parse_node_answer() {
+    assert_eq!(parse_node_answer("yes\n"), Some(NodeAnswer::Activate));
+    assert_eq!(parse_node_answer("y\n"), Some(NodeAnswer::Activate));
+    assert_eq!(parse_node_answer("no\n"), Some(NodeAnswer::Skip));
+    assert_eq!(parse_node_answer("n\n"), Some(NodeAnswer::Skip));
+    assert_eq!(parse_node_answer("abort\n"), Some(NodeAnswer::Abort));
+    assert_eq!(parse_node_answer("Q\n"), Some(NodeAnswer::Abort));
+    assert_eq!(parse_node_answer("maybe\n"), None);
+}
+
+/// Asks whether to activate `node`, skip it, or abort the rest of the deployment
+fn prompt_node(node: &str, assume_yes: bool) -> Result<NodeAnswer, PromptDeploymentError> {
+    let question = format!(
+        "Activate node `{}`? Say \"yes\", \"no\" to skip it or \"abort\" to stop deploying",
+        node
+    );
+
+    if assume_yes {
+        info!("{} Yes (--yes)", question);
+        return Ok(NodeAnswer::Activate);
+    }
+
+    if !stdin().is_terminal() {
+        return Err(PromptDeploymentError::NonInteractiveStdin);
+    }
+
+    loop {
+        info!("{}", question);
+        print!("> ");
+
+        stdout()
+            .flush()
+            .map_err(PromptDeploymentError::StdoutFlush)?;
+
+        let mut s = String::new();
+        stdin()
+            .read_line(&mut s)
+            .map_err(PromptDeploymentError::StdinRead)?;
+
+        match parse_node_answer(&s) {
+            Some(answer) => return Ok(answer),
+            None => info!("That was unclear, please say \"yes\", \"no\" or \"abort\"."),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum RunDeployError {
     #[error("Failed to deploy profile to node {0}: {1}")]
@@ -404,6 +1666,12 @@ pub enum RunDeployError {
     ProfileNotFound(String),
     #[error("No node named `{0}` was found")]
     NodeNotFound(String),
+    #[error("Invalid node pattern `{0}`: {1}")]
+    NodePattern(String, regex::Error),
+    #[error("No node is tagged with any of: {0}")]
+    NoNodeWithTags(String),
+    #[error("Node `{0}` has no profiles")]
+    EmptyNode(String),
     #[error("Profile was provided without a node name")]
     ProfileWithoutNode,
     #[error("Error processing deployment definitions: {0}")]
@@ -414,140 +1682,2273 @@ pub enum RunDeployError {
     PromptDeployment(#[from] PromptDeploymentError),
     #[error("Failed to revoke profile for node {0}: {1}")]
     RevokeProfile(String, deploy::deploy::RevokeProfileError),
+    #[error("Failed to diff closures on node {0}: {1}")]
+    DiffClosures(String, deploy::deploy::DiffClosuresError),
+    #[error("Failed to list generations for node {0}: {1}")]
+    ListGenerations(String, deploy::deploy::ListGenerationsError),
+    #[error("Failed to unlock profile on node {0}: {1}")]
+    UnlockProfile(String, deploy::deploy::UnlockProfileError),
     #[error("Deployment to node {0} failed, rolled back to previous generation")]
     Rollback(String),
+    #[error("Deployment did not finish within {0} seconds")]
+    DeployTimeout(u64),
+    #[error("Deployment was interrupted")]
+    Interrupted,
+    #[error("The dependencies of the nodes form a cycle: {0}")]
+    DependencyCycle(String),
     #[error("Failed to get the password from sops: {0}")]
     Sops(#[from] deploy::cli::SopsError),
+    #[error("Failed to get the password from age: {0}")]
+    Age(#[from] AgeError),
+    #[error("--hostname without a node name can only be used when deploying a single node, use --hostname <node>=<host> instead")]
+    HostnameForMultipleNodes,
+    #[error("--local can only be used when deploying a single node, as it deploys to this machine")]
+    LocalForMultipleNodes,
+    #[error("--{0} was given for node `{1}`, which doesn't exist")]
+    UnknownOverrideNode(&'static str, String),
+    #[error("{}", multiple_failures(.0))]
+    Multiple(Vec<RunDeployError>),
+}
+
+/// Message of `RunDeployError::Multiple`, with one failure per line
+fn multiple_failures(failures: &[RunDeployError]) -> String {
+    let count = match failures.len() {
+        1 => "1 node".to_string(),
+        n => format!("{} nodes", n),
+    };
+    let lines: Vec<String> = failures.iter().map(|e| e.to_string()).collect();
+
+    format!("{} failed to deploy:\n{}", count, lines.join("\n"))
+}
+
+#[test]
+fn test_multiple_failures() {
+    let one = RunDeployError::Multiple(vec![RunDeployError::Rollback("web".to_string())]);
+    assert_eq!(
+        one.to_string(),
+        "1 node failed to deploy:\nDeployment to node web failed, rolled back to previous generation"
+    );
+
+    let two = RunDeployError::Multiple(vec![
+        RunDeployError::NodeNotFound("web".to_string()),
+        RunDeployError::NodeNotFound("db".to_string()),
+    ]);
+    assert_eq!(
+        two.to_string(),
+        "2 nodes failed to deploy:\nNo node named `web` was found\nNo node named `db` was found"
+    );
+}
+
+/// Splits the values of `--hostname` into the one without a node name and those for specific nodes
+fn parse_hostname_overrides(
+    values: Vec<String>,
+) -> Result<(Option<String>, HashMap<String, String>), RunError> {
+    let mut hostname = None;
+    let mut node_hostnames = HashMap::new();
+
+    for value in values {
+        match value.split_once('=') {
+            Some((node, host)) => {
+                node_hostnames.insert(node.to_string(), host.to_string());
+            }
+            None if hostname.is_none() => hostname = Some(value),
+            None => return Err(RunError::MultipleHostnames),
+        }
+    }
+
+    Ok((hostname, node_hostnames))
+}
+
+#[test]
+fn test_parse_hostname_overrides() {
+    let (hostname, node_hostnames) = parse_hostname_overrides(vec![
+        "web1=10.0.0.1".to_string(),
+        "web2=2001:db8::2".to_string(),
+    ])
+    .unwrap();
+    assert_eq!(hostname, None);
+    assert_eq!(node_hostnames.get("web1").map(String::as_str), Some("10.0.0.1"));
+    assert_eq!(node_hostnames.get("web2").map(String::as_str), Some("2001:db8::2"));
+
+    let (hostname, node_hostnames) =
+        parse_hostname_overrides(vec!["example.com".to_string()]).unwrap();
+    assert_eq!(hostname.as_deref(), Some("example.com"));
+    assert!(node_hostnames.is_empty());
+
+    assert!(parse_hostname_overrides(vec!["a.com".to_string(), "b.com".to_string()]).is_err());
+}
+
+/// Splits `--ssh-opts` into the arguments passed to ssh, honouring quotes and backslashes so that
+/// e.g. a `ProxyCommand` with spaces stays a single argument
+fn parse_ssh_opts(ssh_opts: Option<String>) -> Result<Option<Vec<String>>, RunError> {
+    ssh_opts
+        .map(|opts| shlex::split(&opts).ok_or(RunError::InvalidSshOpts(opts)))
+        .transpose()
+}
+
+#[test]
+fn test_parse_ssh_opts() {
+    assert_eq!(parse_ssh_opts(None).unwrap(), None);
+    assert_eq!(
+        parse_ssh_opts(Some("-p 2222  -A".to_string())).unwrap(),
+        Some(vec!["-p".to_string(), "2222".to_string(), "-A".to_string()])
+    );
+    assert_eq!(
+        parse_ssh_opts(Some(
+            "-o 'ProxyCommand=ssh -W %h:%p bastion' -p 22".to_string()
+        ))
+        .unwrap(),
+        Some(vec![
+            "-o".to_string(),
+            "ProxyCommand=ssh -W %h:%p bastion".to_string(),
+            "-p".to_string(),
+            "22".to_string(),
+        ])
+    );
+    assert_eq!(
+        parse_ssh_opts(Some(
+            r#"-o "ProxyCommand=nc -X connect -x proxy:3128 %h %p" -o User=deploy"#.to_string()
+        ))
+        .unwrap(),
+        Some(vec![
+            "-o".to_string(),
+            "ProxyCommand=nc -X connect -x proxy:3128 %h %p".to_string(),
+            "-o".to_string(),
+            "User=deploy".to_string(),
+        ])
+    );
+    assert_eq!(
+        parse_ssh_opts(Some(r"-i /keys/deploy\ key".to_string())).unwrap(),
+        Some(vec!["-i".to_string(), "/keys/deploy key".to_string()])
+    );
+    assert!(matches!(
+        parse_ssh_opts(Some("-o 'ProxyCommand=ssh bastion".to_string())),
+        Err(RunError::InvalidSshOpts(_))
+    ));
+}
+
+/// Splits the values of `--confirm-timeout` or `--activation-timeout` (named by `option`) into
+/// the one without a node name and those for specific nodes
+fn parse_timeout_overrides(
+    option: &'static str,
+    values: Vec<String>,
+) -> Result<(Option<u16>, HashMap<String, u16>), RunError> {
+    let mut timeout = None;
+    let mut node_timeouts = HashMap::new();
+
+    for value in values {
+        let (node, secs) = match value.split_once('=') {
+            Some((node, secs)) => (Some(node), secs),
+            None => (None, value.as_str()),
+        };
+        let secs: u16 = secs
+            .parse()
+            .map_err(|_| RunError::InvalidTimeout(option, value.clone()))?;
+
+        match node {
+            Some(node) => {
+                node_timeouts.insert(node.to_string(), secs);
+            }
+            None if timeout.is_none() => timeout = Some(secs),
+            None => return Err(RunError::MultipleTimeouts(option)),
+        }
+    }
+
+    Ok((timeout, node_timeouts))
+}
+
+#[test]
+fn test_parse_timeout_overrides() {
+    let (timeout, node_timeouts) = parse_timeout_overrides(
+        "confirm-timeout",
+        vec!["60".to_string(), "storage=300".to_string()],
+    )
+    .unwrap();
+    assert_eq!(timeout, Some(60));
+    assert_eq!(node_timeouts.get("storage"), Some(&300));
+    assert_eq!(node_timeouts.len(), 1);
+
+    assert!(matches!(
+        parse_timeout_overrides("confirm-timeout", vec!["60".to_string(), "30".to_string()]),
+        Err(RunError::MultipleTimeouts("confirm-timeout"))
+    ));
+    assert!(matches!(
+        parse_timeout_overrides("activation-timeout", vec!["storage=slow".to_string()]),
+        Err(RunError::InvalidTimeout("activation-timeout", v)) if v == "storage=slow"
+    ));
+}
+
+/// Splits the values of `--sudo-file` or `--sudo-secret` (named by `option`) into the one without
+/// a node name and those for specific nodes
+fn parse_secret_overrides(
+    option: &'static str,
+    values: Vec<String>,
+) -> Result<(Option<String>, HashMap<String, String>), RunError> {
+    let mut secret = None;
+    let mut node_secrets = HashMap::new();
+
+    for value in values {
+        match value.split_once('=') {
+            Some((node, secret)) => {
+                node_secrets.insert(node.to_string(), secret.to_string());
+            }
+            None if secret.is_none() => secret = Some(value),
+            None => return Err(RunError::MultipleSecrets(option)),
+        }
+    }
+
+    Ok((secret, node_secrets))
+}
+
+#[test]
+fn test_parse_secret_overrides() {
+    let (secret, node_secrets) = parse_secret_overrides(
+        "sudo-secret",
+        vec!["passwords/default".to_string(), "db=passwords/db".to_string()],
+    )
+    .unwrap();
+    assert_eq!(secret.as_deref(), Some("passwords/default"));
+    assert_eq!(node_secrets.get("db").map(String::as_str), Some("passwords/db"));
+    assert_eq!(node_secrets.len(), 1);
+
+    assert!(matches!(
+        parse_secret_overrides("sudo-file", vec!["a.yaml".to_string(), "b.yaml".to_string()]),
+        Err(RunError::MultipleSecrets("sudo-file"))
+    ));
+}
+
+/// Per-node overrides for a node that doesn't exist are most likely typos, which would otherwise
+/// silently fall back to the settings for all nodes. Unless `complete`, `data` only holds the nodes
+/// that were evaluated for the targets, so overrides for other nodes are only warned about
+fn check_node_overrides(
+    cmd_overrides: &deploy::CmdOverrides,
+    data: &[deploy::data::Data],
+    complete: bool,
+) -> Result<(), RunDeployError> {
+    let known = |node: &str| data.iter().any(|d| d.nodes.contains_key(node));
+
+    for (option, nodes) in [
+        ("hostname", cmd_overrides.node_hostnames.keys().collect::<Vec<_>>()),
+        ("confirm-timeout", cmd_overrides.node_confirm_timeouts.keys().collect()),
+        ("activation-timeout", cmd_overrides.node_activation_timeouts.keys().collect()),
+        ("sudo-file", cmd_overrides.node_sudo_files.keys().collect()),
+        ("sudo-secret", cmd_overrides.node_sudo_secrets.keys().collect()),
+    ] {
+        for node in nodes.into_iter().filter(|node| !known(node)) {
+            if complete {
+                return Err(RunDeployError::UnknownOverrideNode(option, node.clone()));
+            }
+            warn!("--{} was given for node `{}`, which isn't deployed", option, node);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_node_overrides() {
+    let data: deploy::data::Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "edge": { "hostname": "edge.example.com", "profiles": {} },
+            "storage": { "hostname": "storage.example.com", "profiles": {} },
+        },
+    }))
+    .unwrap();
+    let data = vec![data];
+
+    let cmd_overrides = deploy::CmdOverrides {
+        node_confirm_timeouts: HashMap::from([("storage".to_string(), 300)]),
+        ..Default::default()
+    };
+    assert!(check_node_overrides(&cmd_overrides, &data, true).is_ok());
+
+    let cmd_overrides = deploy::CmdOverrides {
+        node_activation_timeouts: HashMap::from([("stroage".to_string(), 300)]),
+        ..Default::default()
+    };
+    assert!(matches!(
+        check_node_overrides(&cmd_overrides, &data, true),
+        Err(RunDeployError::UnknownOverrideNode("activation-timeout", node)) if node == "stroage"
+    ));
+
+    let cmd_overrides = deploy::CmdOverrides {
+        node_sudo_secrets: HashMap::from([("egde".to_string(), "passwords/edge".to_string())]),
+        ..Default::default()
+    };
+    assert!(matches!(
+        check_node_overrides(&cmd_overrides, &data, true),
+        Err(RunDeployError::UnknownOverrideNode("sudo-secret", node)) if node == "egde"
+    ));
+
+    // Deploying `.#edge` only evaluates `edge`, the overrides may be meant for other deployments
+    let data: deploy::data::Data = serde_json::from_value(serde_json::json!({
+        "nodes": { "edge": { "hostname": "edge.example.com", "profiles": {} } },
+    }))
+    .unwrap();
+    let data = vec![data];
+    let cmd_overrides = deploy::CmdOverrides {
+        node_confirm_timeouts: HashMap::from([("storage".to_string(), 300)]),
+        node_sudo_secrets: HashMap::from([("storage".to_string(), "passwords/storage".to_string())]),
+        ..Default::default()
+    };
+    assert!(check_node_overrides(&cmd_overrides, &data, false).is_ok());
+
+    let cmd_overrides = deploy::CmdOverrides {
+        node_sudo_files: HashMap::from([("stroage".to_string(), PathBuf::from("secrets.yaml"))]),
+        ..Default::default()
+    };
+    assert!(matches!(
+        check_node_overrides(&cmd_overrides, &data, true),
+        Err(RunDeployError::UnknownOverrideNode("sudo-file", node)) if node == "stroage"
+    ));
+    assert!(check_node_overrides(&cmd_overrides, &data, false).is_ok());
+}
+
+/// A `--hostname` without a node name would point every node at the same host, and `--local` at
+/// this machine
+fn check_hostname_override<'b>(
+    cmd_overrides: &deploy::CmdOverrides,
+    node_names: impl IntoIterator<Item = &'b str>,
+) -> Result<(), RunDeployError> {
+    if cmd_overrides.hostname.is_none() && !cmd_overrides.local {
+        return Ok(());
+    }
+
+    let mut nodes: Vec<&str> = node_names.into_iter().collect();
+    nodes.sort_unstable();
+    nodes.dedup();
+
+    if nodes.len() > 1 {
+        return Err(match cmd_overrides.local {
+            true => RunDeployError::LocalForMultipleNodes,
+            false => RunDeployError::HostnameForMultipleNodes,
+        });
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_hostname_override() {
+    let local = deploy::CmdOverrides {
+        local: true,
+        ..Default::default()
+    };
+
+    assert!(check_hostname_override(&deploy::CmdOverrides::default(), ["web", "db"]).is_ok());
+    assert!(check_hostname_override(&local, ["web", "web"]).is_ok());
+    assert!(matches!(
+        check_hostname_override(&local, ["web", "db"]),
+        Err(RunDeployError::LocalForMultipleNodes)
+    ));
+}
+
+/// Looks up a possibly nested key like `a/b/c` in a decrypted secrets file.
+/// Numeric segments index into arrays, e.g. `hosts/0/password`.
+fn lookup_secret(secrets: &serde_json::Value, key: &str) -> Result<String, SopsError> {
+    let mut v = secrets;
+
+    // We support nested keys like a/b/c
+    for i in key.split('/') {
+        v = match v {
+            serde_json::Value::Object(map) => match map.get(i) {
+                Some(v) => v,
+                None => {
+                    return Err(SopsError::SopsKeyNotFound(format!(
+                        "Did not find {} in Map",
+                        i
+                    )));
+                }
+            },
+            serde_json::Value::Array(list) => {
+                let index: usize = i
+                    .parse()
+                    .map_err(|_| SopsError::SopsIndexNotNumeric(i.to_string()))?;
+
+                match list.get(index) {
+                    Some(v) => v,
+                    None => return Err(SopsError::SopsIndexOutOfBounds(index, list.len())),
+                }
+            }
+            _ => {
+                return Err(SopsError::SerdeUnexpectedType(format!(
+                    "Cannot look up {} in a string, bool or number",
+                    i
+                )));
+            }
+        };
+    }
+
+    match v {
+        serde_json::Value::String(s) => Ok(s.into()),
+        serde_json::Value::Bool(b) => Ok(b.to_string()),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        _ => Err(SopsError::SerdeUnexpectedType(
+            "The secret has to be a string, bool or number, not a map, array or null".into(),
+        )),
+    }
+}
+
+#[test]
+fn test_lookup_secret() {
+    let secrets = serde_json::json!({
+        "password": "abc",
+        "nested": { "pin": 1234, "enabled": true },
+        "hosts": [
+            { "name": "a", "password": "first" },
+            { "name": "b", "passwords": ["second", "third"] }
+        ]
+    });
+
+    assert_eq!(lookup_secret(&secrets, "password").unwrap(), "abc");
+    assert_eq!(lookup_secret(&secrets, "nested/pin").unwrap(), "1234");
+    assert_eq!(lookup_secret(&secrets, "nested/enabled").unwrap(), "true");
+    assert_eq!(lookup_secret(&secrets, "hosts/0/password").unwrap(), "first");
+    assert_eq!(lookup_secret(&secrets, "hosts/1/passwords/1").unwrap(), "third");
+
+    assert!(matches!(
+        lookup_secret(&secrets, "missing"),
+        Err(SopsError::SopsKeyNotFound(_))
+    ));
+    assert!(matches!(
+        lookup_secret(&secrets, "hosts/first/password"),
+        Err(SopsError::SopsIndexNotNumeric(_))
+    ));
+    assert!(matches!(
+        lookup_secret(&secrets, "hosts/2/password"),
+        Err(SopsError::SopsIndexOutOfBounds(2, 2))
+    ));
+    assert!(matches!(
+        lookup_secret(&secrets, "hosts/0"),
+        Err(SopsError::SerdeUnexpectedType(_))
+    ));
+    assert!(matches!(
+        lookup_secret(&secrets, "password/more"),
+        Err(SopsError::SerdeUnexpectedType(_))
+    ));
+}
+
+/// Decrypts an age encrypted file (binary or armored) with the identities in `identity_file`
+fn decrypt_age(file: &Path, identity_file: &Path) -> Result<Zeroizing<String>, AgeError> {
+    let identities = age::IdentityFile::from_file(identity_file.to_string_lossy().into())
+        .map_err(|err| AgeError::ReadIdentity(identity_file.to_path_buf(), err))?
+        .into_identities()
+        .map_err(AgeError::Identities)?;
+
+    let encrypted = std::fs::File::open(file)
+        .map_err(|err| AgeError::OpenFile(file.to_path_buf(), err))?;
+
+    let decryptor = age::Decryptor::new(age::armor::ArmoredReader::new(encrypted))
+        .map_err(|err| AgeError::Decrypt(file.to_path_buf(), err))?;
+
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        .map_err(|err| AgeError::Decrypt(file.to_path_buf(), err))?;
+
+    let mut plaintext = Zeroizing::new(String::new());
+    reader
+        .read_to_string(&mut plaintext)
+        .map_err(|err| AgeError::Read(file.to_path_buf(), err))?;
+
+    Ok(plaintext)
+}
+
+#[test]
+fn test_decrypt_age() {
+    use age::secrecy::ExposeSecret;
+
+    let identity = age::x25519::Identity::generate();
+    let dir = std::env::temp_dir().join(format!("deploy-rs-test-age-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let identity_file = dir.join("identity.txt");
+    std::fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+
+    let encrypted = age::encrypt(&identity.to_public(), b"hunter2\n").unwrap();
+    let age_file = dir.join("password.age");
+    std::fs::write(&age_file, encrypted).unwrap();
+
+    assert_eq!(decrypt_age(&age_file, &identity_file).unwrap().as_str(), "hunter2\n");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The format sops should decrypt secret files to
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SecretFormat {
+    #[default]
+    Json,
+    Yaml,
+    Dotenv,
+}
+
+impl SecretFormat {
+    fn sops_output_type(self) -> &'static str {
+        match self {
+            SecretFormat::Json => "json",
+            SecretFormat::Yaml => "yaml",
+            SecretFormat::Dotenv => "dotenv",
+        }
+    }
+
+    /// Parses decrypted secrets into a tree that `lookup_secret` can walk
+    fn parse(self, s: &str) -> Result<serde_json::Value, SopsError> {
+        match self {
+            SecretFormat::Json => {
+                let m: serde_json::Map<String, serde_json::Value> =
+                    serde_json::from_str(s).map_err(SopsError::SerdeDeserialize)?;
+                Ok(serde_json::Value::Object(m))
+            }
+            SecretFormat::Yaml => serde_yaml::from_str(s).map_err(SopsError::YamlDeserialize),
+            SecretFormat::Dotenv => parse_dotenv(s),
+        }
+    }
 }
 
-type ToDeploy<'a> = Vec<(
-    &'a deploy::DeployFlake<'a>,
-    &'a deploy::data::Data,
-    (&'a str, &'a deploy::data::Node),
-    (&'a str, &'a deploy::data::Profile),
-)>;
+/// Parses `KEY=value` lines as written by `sops --output-type dotenv`
+fn parse_dotenv(s: &str) -> Result<serde_json::Value, SopsError> {
+    let mut m = serde_json::Map::new();
+
+    for line in s.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once('=') {
+            Some((key, value)) => {
+                m.insert(
+                    key.trim().to_string(),
+                    serde_json::Value::String(value.to_string()),
+                );
+            }
+            None => return Err(SopsError::DotenvInvalidLine(line.to_string())),
+        }
+    }
+
+    Ok(serde_json::Value::Object(m))
+}
+
+#[test]
+fn test_secret_formats() {
+    let yaml = "password:\n  test: 123\nhosts:\n  - password: abc\n";
+    let secrets = SecretFormat::Yaml.parse(yaml).unwrap();
+    assert_eq!(lookup_secret(&secrets, "password/test").unwrap(), "123");
+    assert_eq!(lookup_secret(&secrets, "hosts/0/password").unwrap(), "abc");
+
+    let dotenv = "# comment\npassword=abc=def\n\nother=1\n";
+    let secrets = SecretFormat::Dotenv.parse(dotenv).unwrap();
+    assert_eq!(lookup_secret(&secrets, "password").unwrap(), "abc=def");
+    assert_eq!(lookup_secret(&secrets, "other").unwrap(), "1");
+    assert!(matches!(
+        SecretFormat::Dotenv.parse("nope"),
+        Err(SopsError::DotenvInvalidLine(_))
+    ));
+
+    let json = r#"{"password": {"test": "123"}}"#;
+    let secrets = SecretFormat::Json.parse(json).unwrap();
+    assert_eq!(lookup_secret(&secrets, "password/test").unwrap(), "123");
+}
+
+/// Decrypts `path` with sops and looks up the (possibly nested) `key` in it
+async fn decrypt_sops(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    key: &str,
+    format: SecretFormat,
+) -> Result<String, SopsError> {
+    if !try_exists(path).await.unwrap() {
+        return Err(SopsError::SopsFileNotFound(format!("{path:?} not found")));
+    }
+
+    let mut sops_command = CommandSpec::new("sops");
+    sops_command
+        .arg("--output-type")
+        .arg(format.sops_output_type())
+        .arg("-d")
+        .arg(path.to_string_lossy())
+        .capture_stderr();
+
+    let out = runner
+        .output(&sops_command)
+        .await
+        .map_err(|err| SopsError::SopsFailedDecryption(path.to_string_lossy().into(), err))?;
+
+    let stdout = Zeroizing::new(out.stdout);
+    let conv_out = std::str::from_utf8(&stdout).map_err(SopsError::SopsCannotConvert)?;
+
+    lookup_secret(&format.parse(conv_out)?, key)
+}
+
+#[tokio::test]
+async fn test_decrypt_sops() {
+    let runner = deploy::command::MockRunner::default();
+    runner.push_output(0, r#"{"hosts": {"web": "hunter2"}}"#);
+
+    // Only has to exist, sops itself is mocked
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    assert_eq!(
+        decrypt_sops(&runner, &path, "hosts/web", SecretFormat::Json).await.unwrap(),
+        "hunter2"
+    );
+
+    let calls = runner.calls();
+    assert_eq!(calls[0].program, "sops");
+    assert_eq!(
+        calls[0].args,
+        vec!["--output-type", "json", "-d", &path.to_string_lossy()]
+    );
+}
+
+type SecretCell = Arc<tokio::sync::OnceCell<SecretString>>;
+
+/// Sudo passwords decrypted with sops during this invocation, so that `sops` runs at most once per
+/// file and key even if many nodes share them
+#[derive(Default)]
+struct SecretCache {
+    secrets: tokio::sync::Mutex<HashMap<(PathBuf, String), SecretCell>>,
+    /// Interactive sudo passwords by SSH user with `--shared-sudo-password`, cleared when the
+    /// deployment is done
+    prompted: tokio::sync::Mutex<HashMap<String, SecretCell>>,
+}
+
+impl SecretCache {
+    async fn get_or_decrypt(
+        &self,
+        runner: &dyn CommandRunner,
+        path: PathBuf,
+        key: String,
+        format: SecretFormat,
+    ) -> Result<SecretString, SopsError> {
+        // Only hold the lock while getting the cell, concurrent lookups of the same secret then
+        // wait on the cell instead of decrypting it again
+        let cell = self
+            .secrets
+            .lock()
+            .await
+            .entry((path.clone(), key.clone()))
+            .or_default()
+            .clone();
+
+        cell.get_or_try_init(|| async {
+            debug!("Decrypting {} from {:?} with sops", key, path);
+            decrypt_sops(runner, &path, &key, format)
+                .await
+                .map(SecretString::from)
+        })
+        .await
+        .cloned()
+    }
+
+    /// The sudo password of `ssh_user`, only prompting for it the first time
+    async fn get_or_prompt<F: std::future::Future<Output = SecretString>>(
+        &self,
+        ssh_user: &str,
+        prompt: impl FnOnce() -> F,
+    ) -> SecretString {
+        // Like `get_or_decrypt`, the lock isn't held while the user is prompted
+        let cell = self
+            .prompted
+            .lock()
+            .await
+            .entry(ssh_user.to_string())
+            .or_default()
+            .clone();
+
+        cell.get_or_init(prompt).await.clone()
+    }
+}
+
+/// Prompts for the sudo password of `hostname` on a blocking thread, as reading it blocks
+async fn prompt_sudo_password(hostname: String) -> SecretString {
+    info!("You will now be prompted for the sudo password for {}.", hostname);
+
+    tokio::task::spawn_blocking(move || {
+        rpassword::prompt_password(format!("(sudo for {}) Password: ", hostname))
+            .map(SecretString::from)
+            .unwrap_or_default()
+    })
+    .await
+    .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+#[tokio::test]
+async fn test_secret_cache_get_or_prompt() {
+    use secrecy::ExposeSecret;
+
+    let secret_cache = SecretCache::default();
+    let mut prompts = 0;
+
+    for _ in 0..3 {
+        let password = secret_cache
+            .get_or_prompt("alice", || {
+                prompts += 1;
+                async { "hunter2".into() }
+            })
+            .await;
+        assert_eq!(password.expose_secret(), "hunter2");
+    }
+    let password = secret_cache.get_or_prompt("bob", || async { "swordfish".into() }).await;
+    assert_eq!(password.expose_secret(), "swordfish");
+    assert_eq!(prompts, 1);
+}
+
+/// Builds the deployment definitions for a profile, including obtaining the sudo password if one is needed
+async fn get_deploy_defs(
+    deploy_data: &deploy::DeployData<'_>,
+    secret_cache: &SecretCache,
+) -> Result<deploy::DeployDefs, RunDeployError> {
+    let mut deploy_defs = deploy_data.defs()?;
+
+    // Nothing runs through sudo, so there is neither a command to rewrite nor a password to get
+    if deploy_data.no_sudo() {
+        return Ok(deploy_defs);
+    }
+
+    let uses_password = deploy_data.merged_settings.interactive_sudo.is_some()
+        || deploy_data.merged_settings.sudo_secret.is_some()
+        || deploy_data.cmd_overrides.age_file.is_some();
+
+    if deploy_data.cmd_overrides.raw_sudo {
+        if uses_password {
+            warn!("With --raw-sudo the sudo command has to read the password from stdin by itself. Deployment may fail if it ignores stdin.");
+        }
+    } else if deploy_defs.sudo_kind == deploy::data::SudoKind::Stdin
+        && deploy_data.merged_settings.sudo_kind.is_none()
+    {
+        warn!("Custom sudo commands should be configured to accept password input from stdin when using the 'interactive sudo' or 'password File' option. Deployment may fail if the custom command ignores stdin.");
+    } else if deploy_defs.sudo_kind == deploy::data::SudoKind::Sudo {
+        // this configures sudo to hide the password prompt and accept input from stdin
+        // at the time of writing, deploy_defs.sudo defaults to 'sudo -u root' when using user=root and sshUser as non-root
+        let original = deploy_defs.sudo.unwrap_or("sudo".to_string());
+        deploy_defs.sudo = Some(format!("{} -S -p \"\"", original));
+    } else if let (Some(sudo), Some(flags)) = (&deploy_defs.sudo, deploy_defs.sudo_kind.flags()) {
+        deploy_defs.sudo = Some(format!("{} {}", sudo, flags));
+    }
+
+    // The password is only ever written to the commands, which a dry run just logs
+    if deploy_data.cmd_overrides.dry_run {
+        return Ok(deploy_defs);
+    }
+
+    if deploy_data
+        .merged_settings
+        .interactive_sudo
+        .unwrap_or(false)
+    {
+        warn!("Interactive sudo is enabled! Using a sudo password is less secure than correctly configured SSH keys.\nPlease use keys in production environments.");
+
+        let hostname = deploy_data.node.node_settings.hostname.clone();
+
+        let sudo_password = if deploy_data.cmd_overrides.shared_sudo_password {
+            secret_cache
+                .get_or_prompt(&deploy_defs.ssh_user, || {
+                    info!(
+                        "The password is reused for every node deployed as `{}`.",
+                        deploy_defs.ssh_user
+                    );
+                    prompt_sudo_password(hostname)
+                })
+                .await
+        } else {
+            prompt_sudo_password(hostname).await
+        };
+
+        deploy_defs.sudo_password = Some(sudo_password);
+    } else if deploy_data.merged_settings.sudo_file.is_some()
+        && deploy_data.merged_settings.sudo_secret.is_some()
+    {
+        // SAFETY: we already checked if it is some
+        let path = deploy_data.merged_settings.sudo_file.clone().unwrap();
+        let key = deploy_data.merged_settings.sudo_secret.clone().unwrap();
+
+        let sudo_password = secret_cache
+            .get_or_decrypt(
+                deploy_data.runner,
+                path,
+                key,
+                deploy_data.cmd_overrides.sudo_secret_format,
+            )
+            .await?;
+
+        deploy_defs.sudo_password = Some(sudo_password);
+    } else if let (Some(age_file), Some(age_identity)) = (
+        &deploy_data.cmd_overrides.age_file,
+        &deploy_data.cmd_overrides.age_identity,
+    ) {
+        let plaintext = decrypt_age(age_file, age_identity)?;
+
+        // JSON content is looked up like a sops file, anything else is the password itself
+        let sudo_password = match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&plaintext) {
+            Ok(m) => match deploy_data.merged_settings.sudo_secret {
+                Some(ref key) => lookup_secret(&serde_json::Value::Object(m), key)
+                    .map_err(AgeError::Lookup)?,
+                None => return Err(RunDeployError::Age(AgeError::NoKey)),
+            },
+            Err(_) => plaintext.trim_end_matches('\n').to_string(),
+        };
+        let sudo_password = SecretString::from(sudo_password);
+
+        deploy_defs.sudo_password = Some(sudo_password);
+    }
+
+    Ok(deploy_defs)
+}
+
+#[tokio::test]
+async fn test_get_deploy_defs_sudo_command() {
+    let node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "alice",
+        "sudo": "please -u",
+        "profiles": {},
+    });
+    let profile = serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+        "user": "root",
+    });
+
+    for (raw_sudo, expected) in [(false, "please -u root -S -p \"\""), (true, "please -u root")] {
+        let cmd_overrides = deploy::CmdOverrides {
+            raw_sudo,
+            ..deploy::CmdOverrides::default()
+        };
+        let deploy_data = deploy::test_deploy_data("example", node.clone(), profile.clone(), cmd_overrides);
+        let deploy_defs = get_deploy_defs(&deploy_data, &SecretCache::default())
+            .await
+            .unwrap();
+        assert_eq!(deploy_defs.sudo.as_deref(), Some(expected));
+    }
+
+    let cmd_overrides = deploy::CmdOverrides {
+        sudo: Some("doas -u".to_string()),
+        ..deploy::CmdOverrides::default()
+    };
+    let deploy_data = deploy::test_deploy_data("example", node, profile.clone(), cmd_overrides);
+    let deploy_defs = get_deploy_defs(&deploy_data, &SecretCache::default())
+        .await
+        .unwrap();
+    assert_eq!(deploy_defs.sudo.as_deref(), Some("doas -u root -n"));
+
+    // A dry run doesn't decrypt the password, it is never sent anywhere
+    let node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "alice",
+        "sudoFile": "secrets.yaml",
+        "sudoSecret": "example",
+        "profiles": {},
+    });
+    let cmd_overrides = deploy::CmdOverrides {
+        dry_run: true,
+        ..deploy::CmdOverrides::default()
+    };
+    let runner = deploy::command::MockRunner::default();
+    let mut deploy_data = deploy::test_deploy_data("example", node, profile, cmd_overrides);
+    deploy_data.runner = &runner;
+    let deploy_defs = get_deploy_defs(&deploy_data, &SecretCache::default())
+        .await
+        .unwrap();
+    assert_eq!(deploy_defs.sudo.as_deref(), Some("sudo -u root -S -p \"\""));
+    assert!(deploy_defs.sudo_password.is_none());
+    assert_eq!(runner.calls(), vec![]);
+}
+
+/// Wall-clock durations (in seconds) of the phases of a single node's deployment
+#[derive(Serialize, Default, Debug)]
+struct NodeTimings {
+    build: f64,
+    push: f64,
+    activate: f64,
+    confirm: Option<f64>,
+}
+
+/// Wall-clock durations (in seconds) of a whole deployment, evaluation and checks are shared by all nodes
+#[derive(Serialize, Default, Debug)]
+struct Timings {
+    eval: f64,
+    check: f64,
+    nodes: BTreeMap<String, NodeTimings>,
+}
+
+impl Timings {
+    fn node(&mut self, node_name: &str) -> &mut NodeTimings {
+        self.nodes.entry(node_name.to_string()).or_default()
+    }
+
+    fn log_summary(&self) {
+        let mut summary = format!(
+            "Deployment timings (seconds), eval: {:.2}, check: {:.2}\n{:<24} {:>9} {:>9} {:>9} {:>9}",
+            self.eval, self.check, "NODE", "BUILD", "PUSH", "ACTIVATE", "CONFIRM"
+        );
+
+        for (node_name, t) in &self.nodes {
+            summary.push_str(&format!(
+                "\n{:<24} {:>9.2} {:>9.2} {:>9.2} {:>9}",
+                node_name,
+                t.build,
+                t.push,
+                t.activate,
+                t.confirm.map_or("-".to_string(), |c| format!("{:.2}", c))
+            ));
+        }
+
+        info!("{}", summary);
+    }
+}
+
+/// Body of the request sent to `--notify-url`
+#[cfg(feature = "notify")]
+#[derive(Serialize)]
+struct NotifyPayload<'a> {
+    success: bool,
+    error: Option<String>,
+    profiles: &'a [deploy::notify::ProfileReport],
+    timings: &'a Timings,
+}
+
+/// Where the outcome of the deployment is posted to
+#[cfg(feature = "notify")]
+struct Notifications {
+    notify_url: Option<String>,
+    #[cfg(feature = "slack")]
+    slack_webhook: Option<String>,
+}
+
+#[cfg(feature = "notify")]
+impl Notifications {
+    fn new(opts: &Opts) -> Self {
+        Notifications {
+            notify_url: opts.notify_url.clone(),
+            #[cfg(feature = "slack")]
+            slack_webhook: opts.slack_webhook.clone(),
+        }
+    }
+}
+
+/// Posts the outcome of the deployment to `--notify-url` and the Slack webhook, if given
+#[cfg(feature = "notify")]
+async fn send_notifications(
+    notifications: &Notifications,
+    result: &Result<(), RunError>,
+    report: &DeployReport,
+    timings: &Timings,
+) {
+    if let Some(ref notify_url) = notifications.notify_url {
+        let payload = NotifyPayload {
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            profiles: &report.profiles,
+            timings,
+        };
+        if let Err(e) = deploy::notify::send_webhook(notify_url, &payload).await {
+            warn!("Failed to send deployment notification to {}: {}", notify_url, e);
+        }
+    }
+
+    #[cfg(feature = "slack")]
+    if let Some(ref slack_webhook) = notifications.slack_webhook {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let message = deploy::notify::slack_message(report, error.as_deref());
+        if let Err(e) = deploy::notify::send_webhook(slack_webhook, &message).await {
+            warn!("Failed to send Slack notification: {}", e);
+        }
+    }
+}
+
+type ToDeploy<'a> = Vec<(
+    &'a deploy::DeployFlake<'a>,
+    &'a deploy::data::Data,
+    (&'a str, &'a deploy::data::Node),
+    (&'a str, &'a deploy::data::Profile),
+)>;
+
+/// Restricts which nodes are deployed when deploying a whole flake
+#[derive(Default)]
+struct NodeFilter<'a> {
+    /// Only nodes with any of these tags, all nodes if empty
+    tags: &'a [String],
+    /// Nodes to leave out
+    exclude: &'a [String],
+    /// Fail instead of warning about selected nodes without profiles
+    error_on_empty_node: bool,
+    /// Only this profile of every node
+    profile: Option<&'a str>,
+}
+
+impl NodeFilter<'_> {
+    fn has_tag(&self, node: &deploy::data::Node) -> bool {
+        self.tags.is_empty() || node.node_settings.tags.iter().any(|t| self.tags.contains(t))
+    }
+
+    fn matches(&self, node_name: &str, node: &deploy::data::Node) -> bool {
+        self.has_tag(node) && !self.exclude.iter().any(|n| n == node_name)
+    }
+}
+
+#[test]
+fn test_node_filter() {
+    let node: deploy::data::Node = serde_json::from_value(serde_json::json!({
+        "hostname": "web1",
+        "tags": ["web", "eu"],
+        "profiles": {}
+    }))
+    .unwrap();
+
+    let tags = ["db".to_string(), "web".to_string()];
+    let exclude = ["web1".to_string()];
+
+    assert!(NodeFilter::default().matches("web1", &node));
+    assert!(NodeFilter { tags: &tags, ..Default::default() }.matches("web1", &node));
+    assert!(!NodeFilter { tags: &tags[..1], ..Default::default() }.matches("web1", &node));
+    assert!(!NodeFilter { exclude: &exclude, ..Default::default() }.matches("web1", &node));
+    assert!(NodeFilter { exclude: &exclude, ..Default::default() }.matches("web2", &node));
+}
+
+/// Turns a node selector into an anchored regular expression, or `None` if it is a plain node name
+fn node_pattern(selector: &str) -> Option<String> {
+    if selector.contains(deploy::REGEX_METACHARACTERS) {
+        return Some(format!("^(?:{})$", selector));
+    }
+    if !selector.contains(deploy::GLOB_METACHARACTERS) {
+        return None;
+    }
+
+    let mut pattern = String::from("^");
+    let mut in_class = false;
+    for c in selector.chars() {
+        match c {
+            '*' if !in_class => pattern.push_str(".*"),
+            '?' if !in_class => pattern.push('.'),
+            '[' if !in_class => {
+                in_class = true;
+                pattern.push('[');
+            }
+            ']' if in_class => {
+                in_class = false;
+                pattern.push(']');
+            }
+            '!' if in_class && pattern.ends_with('[') => pattern.push('^'),
+            c if in_class => pattern.push(c),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+
+    Some(pattern)
+}
+
+#[test]
+fn test_node_pattern() {
+    assert_eq!(node_pattern("web1"), None);
+    assert_eq!(node_pattern("example.com"), None);
+    assert_eq!(node_pattern("web-*").as_deref(), Some("^web\\-.*$"));
+    assert_eq!(node_pattern("web?.[!a-c]").as_deref(), Some("^web.\\.[^a-c]$"));
+    assert_eq!(node_pattern("web-\\d+").as_deref(), Some("^(?:web-\\d+)$"));
+
+    let re = regex::Regex::new(&node_pattern("web-*").unwrap()).unwrap();
+    assert!(re.is_match("web-1"));
+    assert!(!re.is_match("db-web-1"));
+}
+
+/// Looks up the nodes of `data` selected by `selector`, which is either an exact node name or a
+/// glob/regex pattern matched against all node names
+fn select_nodes<'a>(
+    data: &'a deploy::data::Data,
+    selector: &str,
+) -> Result<Vec<(&'a str, &'a deploy::data::Node)>, RunDeployError> {
+    let pattern = match node_pattern(selector) {
+        Some(pattern) => pattern,
+        None => {
+            return match data.nodes.get_key_value(selector) {
+                Some((name, node)) => Ok(vec![(name.as_str(), node)]),
+                None => Err(RunDeployError::NodeNotFound(selector.to_string())),
+            }
+        }
+    };
+
+    let re = regex::Regex::new(&pattern)
+        .map_err(|e| RunDeployError::NodePattern(selector.to_string(), e))?;
+
+    let mut nodes: Vec<(&str, &deploy::data::Node)> = data
+        .nodes
+        .iter()
+        .filter(|(name, _)| re.is_match(name))
+        .map(|(name, node)| (name.as_str(), node))
+        .collect();
+    nodes.sort_by_key(|(name, _)| *name);
+
+    if nodes.is_empty() {
+        return Err(RunDeployError::NodeNotFound(selector.to_string()));
+    }
+
+    Ok(nodes)
+}
+
+/// Restricts the profiles of a node deployed as a whole to the one given with `--profile`, nodes
+/// without it are skipped. A node without any profiles is most likely misconfigured, as nothing
+/// would be deployed to it.
+fn filter_profiles<'a>(
+    node_name: &str,
+    mut profiles: Vec<(&'a str, &'a deploy::data::Profile)>,
+    filter: &NodeFilter,
+) -> Result<Vec<(&'a str, &'a deploy::data::Profile)>, RunDeployError> {
+    if profiles.is_empty() {
+        if filter.error_on_empty_node {
+            return Err(RunDeployError::EmptyNode(node_name.to_string()));
+        }
+
+        warn!("Node `{}` has no profiles, nothing is deployed to it", node_name);
+        return Ok(profiles);
+    }
+
+    if let Some(profile_name) = filter.profile {
+        profiles.retain(|(name, _)| *name == profile_name);
+        if profiles.is_empty() {
+            warn!("Node `{}` has no profile `{}`, skipping it", node_name, profile_name);
+        }
+    }
+
+    Ok(profiles)
+}
+
+/// Resolves the nodes and profiles selected by each flake, honouring `profilesOrder`
+fn resolve_targets<'a>(
+    deploy_flakes: &'a [deploy::DeployFlake<'a>],
+    data: &'a [deploy::data::Data],
+    filter: &NodeFilter,
+) -> Result<ToDeploy<'a>, RunDeployError> {
+    for excluded in filter.exclude {
+        if !data.iter().any(|d| d.nodes.contains_key(excluded)) {
+            warn!("Excluded node `{}` does not exist", excluded);
+        }
+    }
+
+    let to_deploy: ToDeploy = deploy_flakes
+        .iter()
+        .zip(data)
+        .map(|(deploy_flake, data)| {
+            let to_deploys: ToDeploy = match (&deploy_flake.node, &deploy_flake.profile) {
+                (Some(node_name), Some(profile_name)) => {
+                    let mut l = Vec::new();
+
+                    for (node_name, node) in select_nodes(data, node_name)? {
+                        let profile = match node.node_settings.profiles.get(profile_name) {
+                            Some(x) => x,
+                            None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
+                        };
+
+                        l.push((
+                            deploy_flake,
+                            data,
+                            (node_name, node),
+                            (profile_name.as_str(), profile),
+                        ));
+                    }
+
+                    l
+                }
+                (Some(node_name), None) => {
+                    let mut l = Vec::new();
+
+                    for (node_name, node) in select_nodes(data, node_name)? {
+                        if !filter.has_tag(node) {
+                            continue;
+                        }
+
+                        let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
+
+                        for profile_name in [
+                            node.node_settings.profiles_order.iter().collect(),
+                            node.node_settings.profiles.keys().collect::<Vec<&String>>(),
+                        ]
+                        .concat()
+                        {
+                            let profile = match node.node_settings.profiles.get(profile_name) {
+                                Some(x) => x,
+                                None => {
+                                    return Err(RunDeployError::ProfileNotFound(
+                                        profile_name.clone(),
+                                    ))
+                                }
+                            };
+
+                            if !profiles_list.iter().any(|(n, _)| n == profile_name) {
+                                profiles_list.push((profile_name, profile));
+                            }
+                        }
+
+                        l.extend(
+                            filter_profiles(node_name, profiles_list, filter)?
+                                .into_iter()
+                                .map(|x| (deploy_flake, data, (node_name, node), x)),
+                        );
+                    }
+
+                    l
+                }
+                (None, None) => {
+                    let mut l = Vec::new();
+
+                    for (node_name, node) in &data.nodes {
+                        if !filter.matches(node_name, node) {
+                            continue;
+                        }
+
+                        let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
+
+                        for profile_name in [
+                            node.node_settings.profiles_order.iter().collect(),
+                            node.node_settings.profiles.keys().collect::<Vec<&String>>(),
+                        ]
+                        .concat()
+                        {
+                            let profile = match node.node_settings.profiles.get(profile_name) {
+                                Some(x) => x,
+                                None => {
+                                    return Err(RunDeployError::ProfileNotFound(
+                                        profile_name.clone(),
+                                    ))
+                                }
+                            };
+
+                            if !profiles_list.iter().any(|(n, _)| n == profile_name) {
+                                profiles_list.push((profile_name, profile));
+                            }
+                        }
+
+                        let ll: ToDeploy = filter_profiles(node_name, profiles_list, filter)?
+                            .into_iter()
+                            .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
+                            .collect();
+
+                        l.extend(ll);
+                    }
+
+                    l
+                }
+                (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
+            };
+            Ok(to_deploys)
+        })
+        .collect::<Result<Vec<ToDeploy>, RunDeployError>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if !filter.tags.is_empty() && to_deploy.is_empty() {
+        return Err(RunDeployError::NoNodeWithTags(filter.tags.join(", ")));
+    }
+
+    Ok(to_deploy)
+}
+
+#[test]
+fn test_resolve_targets_empty_node() {
+    let data: deploy::data::Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "web": {
+                "hostname": "web.example.com",
+                "profiles": {
+                    "system": { "path": "/nix/store/00000000000000000000000000000000-system" },
+                },
+            },
+            "spare": { "hostname": "spare.example.com", "profiles": {} },
+        },
+    }))
+    .unwrap();
+    let data = vec![data];
+    let deploy_flakes = vec![DeployFlake {
+        repo: ".",
+        node: None,
+        profile: None,
+    }];
+
+    let to_deploy = resolve_targets(&deploy_flakes, &data, &NodeFilter::default()).unwrap();
+    assert_eq!(to_deploy.len(), 1);
+    assert_eq!((to_deploy[0].2).0, "web");
+
+    let filter = NodeFilter {
+        error_on_empty_node: true,
+        ..Default::default()
+    };
+    assert!(matches!(
+        resolve_targets(&deploy_flakes, &data, &filter),
+        Err(RunDeployError::EmptyNode(node)) if node == "spare"
+    ));
+}
+
+#[test]
+fn test_resolve_targets_profile_filter() {
+    let data: deploy::data::Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "web": {
+                "hostname": "web.example.com",
+                "profiles": {
+                    "system": { "path": "/nix/store/00000000000000000000000000000000-system" },
+                    "app": { "path": "/nix/store/11111111111111111111111111111111-app" },
+                },
+            },
+            "db": {
+                "hostname": "db.example.com",
+                "profiles": {
+                    "system": { "path": "/nix/store/22222222222222222222222222222222-system" },
+                },
+            },
+        },
+    }))
+    .unwrap();
+    let data = vec![data];
+    let deploy_flakes = vec![DeployFlake {
+        repo: ".",
+        node: None,
+        profile: None,
+    }];
+    let filter = |profile| NodeFilter {
+        profile: Some(profile),
+        ..Default::default()
+    };
+
+    let to_deploy = resolve_targets(&deploy_flakes, &data, &filter("app")).unwrap();
+    assert_eq!(to_deploy.len(), 1);
+    assert_eq!(((to_deploy[0].2).0, (to_deploy[0].3).0), ("web", "app"));
+
+    let to_deploy = resolve_targets(&deploy_flakes, &data, &filter("system")).unwrap();
+    let mut nodes: Vec<&str> = to_deploy.iter().map(|t| (t.2).0).collect();
+    nodes.sort_unstable();
+    assert_eq!(nodes, vec!["db", "web"]);
+    assert!(to_deploy.iter().all(|t| (t.3).0 == "system"));
+}
+
+/// Orders `nodes` (names with their `dependsOn`) so that every node comes after the nodes it
+/// depends on, otherwise keeping the given order. Dependencies on nodes that aren't in `nodes`,
+/// i.e. aren't being deployed, are ignored.
+fn dependency_order<'a>(nodes: &[(&'a str, &'a [String])]) -> Result<Vec<&'a str>, RunDeployError> {
+    fn visit<'a>(
+        node: &'a str,
+        nodes: &[(&'a str, &'a [String])],
+        path: &mut Vec<&'a str>,
+        order: &mut Vec<&'a str>,
+    ) -> Result<(), RunDeployError> {
+        if order.contains(&node) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|n| *n == node) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(node);
+            return Err(RunDeployError::DependencyCycle(cycle.join(" -> ")));
+        }
+
+        path.push(node);
+        let depends_on = nodes.iter().find(|(n, _)| *n == node).map_or(&[][..], |(_, d)| *d);
+        for dependency in depends_on {
+            if let Some((dependency, _)) = nodes.iter().find(|(n, _)| n == dependency) {
+                visit(dependency, nodes, path, order)?;
+            }
+        }
+        path.pop();
+
+        order.push(node);
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    for (node, _) in nodes {
+        visit(node, nodes, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+#[test]
+fn test_dependency_order() {
+    let db = vec![];
+    let app = vec!["db".to_string(), "cache".to_string()];
+    let cache = vec!["db".to_string()];
+    let nodes: Vec<(&str, &[String])> = vec![("app", &app), ("web", &db), ("cache", &cache), ("db", &db)];
+    assert_eq!(dependency_order(&nodes).unwrap(), vec!["db", "cache", "app", "web"]);
+
+    // `cache` isn't deployed, so it doesn't matter what it depends on
+    let nodes: Vec<(&str, &[String])> = vec![("app", &app), ("db", &db)];
+    assert_eq!(dependency_order(&nodes).unwrap(), vec!["db", "app"]);
+
+    let a = vec!["b".to_string()];
+    let b = vec!["c".to_string()];
+    let c = vec!["a".to_string()];
+    let nodes: Vec<(&str, &[String])> = vec![("a", &a), ("b", &b), ("c", &c)];
+    assert!(matches!(
+        dependency_order(&nodes),
+        Err(RunDeployError::DependencyCycle(cycle)) if cycle == "a -> b -> c -> a"
+    ));
+}
+
+/// Reorders `to_deploy` so the profiles of every node come after those of the nodes in its
+/// `dependsOn`, keeping the profiles of a node together
+fn order_by_dependencies(to_deploy: ToDeploy<'_>) -> Result<ToDeploy<'_>, RunDeployError> {
+    let mut nodes: Vec<(&str, &[String])> = Vec::new();
+    for (_, _, (node_name, node), _) in &to_deploy {
+        if !nodes.iter().any(|(n, _)| n == node_name) {
+            nodes.push((node_name, &node.node_settings.depends_on));
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(to_deploy.len());
+    for node in dependency_order(&nodes)? {
+        ordered.extend(to_deploy.iter().filter(|(_, _, (n, _), _)| *n == node).copied());
+    }
+    Ok(ordered)
+}
+
+/// Whether to keep the build result of a profile and where, `--keep-result` and `--result-path`
+/// take precedence over the settings of the profile
+fn profile_keep_result<'a>(
+    keep_result: Option<bool>,
+    result_path: Option<&'a str>,
+    profile: &'a deploy::data::Profile,
+) -> (bool, Option<&'a str>) {
+    let settings = &profile.profile_settings;
+
+    (
+        keep_result.or(settings.keep_result).unwrap_or(false),
+        result_path.or(settings.result_path.as_deref()),
+    )
+}
+
+#[test]
+fn test_profile_keep_result() {
+    let profile: deploy::data::Profile = serde_json::from_value(serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+        "keepResult": true,
+        "resultPath": "./results",
+    }))
+    .unwrap();
+    let plain: deploy::data::Profile = serde_json::from_value(serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+    }))
+    .unwrap();
+
+    assert_eq!(profile_keep_result(None, None, &profile), (true, Some("./results")));
+    assert_eq!(profile_keep_result(None, Some("./gc"), &profile), (true, Some("./gc")));
+    assert_eq!(profile_keep_result(Some(false), None, &profile), (false, Some("./results")));
+    assert_eq!(profile_keep_result(None, None, &plain), (false, None));
+    assert_eq!(profile_keep_result(Some(true), None, &plain), (true, None));
+}
+
+async fn run_deploy(
+    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
+    data: Vec<deploy::data::Data>,
+    supports_flakes: bool,
+    check_sigs: bool,
+    interactive: bool,
+    interactive_per_node: bool,
+    assume_yes: bool,
+    cmd_overrides: &deploy::CmdOverrides,
+    keep_result: Option<bool>,
+    result_path: Option<&str>,
+    extra_build_args: &[String],
+    debug_logs: bool,
+    dry_activate: bool,
+    boot: bool,
+    log_dir: &Option<String>,
+    rollback_succeeded: bool,
+    batch_size: Option<usize>,
+    batch_delay: Option<u64>,
+    batch_confirm: bool,
+    timings: &mut Timings,
+    report: &mut DeployReport,
+    deadline: Option<Deadline>,
+    filter: &NodeFilter<'_>,
+    diff: bool,
+    keep_going: bool,
+    progress: Option<&MultiProgress>,
+) -> Result<(), RunDeployError> {
+    let complete = deploy_flakes
+        .iter()
+        .all(|flake| eval_scope(supports_flakes, flake) == deploy::data::EvalScope::All);
+    check_node_overrides(cmd_overrides, &data, complete)?;
+    let to_deploy: ToDeploy = resolve_targets(&deploy_flakes, &data, filter)?;
+    check_hostname_override(cmd_overrides, to_deploy.iter().map(|(_, _, (node_name, _), _)| *node_name))?;
+    let to_deploy = order_by_dependencies(to_deploy)?;
+
+    let mut parts: Vec<(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData,
+        deploy::DeployDefs,
+    )> = Vec::new();
+
+    let secret_cache = SecretCache::default();
+
+    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
+        let deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            debug_logs,
+            log_dir.as_deref(),
+        );
+
+        let deploy_defs = get_deploy_defs(&deploy_data, &secret_cache).await?;
+
+        parts.push((deploy_flake, deploy_data, deploy_defs));
+    }
+
+    if batch_size.is_some() {
+        // Batches are made of whole nodes, so keep the profiles of each node together
+        let mut node_order: Vec<&str> = Vec::new();
+        for (_, deploy_data, _) in &parts {
+            if !node_order.contains(&deploy_data.node_name) {
+                node_order.push(deploy_data.node_name);
+            }
+        }
+        parts.sort_by_key(|(_, deploy_data, _)| {
+            node_order.iter().position(|n| *n == deploy_data.node_name)
+        });
+    }
+
+    for (_, deploy_data, _) in &parts {
+        report.add(deploy_data.node_name, deploy_data.profile_name);
+    }
+
+    // With --interactive-per-node every node is asked about before its activation instead
+    if interactive && !interactive_per_node {
+        prompt_deployment(&parts[..], assume_yes)?;
+    } else {
+        print_deployment(&parts[..])?;
+    }
+
+    let progress = DeployProgress::new(progress, parts.iter().map(|(_, d, _)| d.node_name));
+
+    let build_args: Vec<Vec<String>> = parts
+        .iter()
+        .map(|(_, deploy_data, _)| profile_build_args(deploy_data, extra_build_args))
+        .collect();
+
+    let data_iter = || {
+        parts.iter().zip(&build_args).map(|((deploy_flake, deploy_data, deploy_defs), build_args)| {
+            let (keep_result, result_path) =
+                profile_keep_result(keep_result, result_path, deploy_data.profile);
+
+            deploy::push::PushProfileData {
+                supports_flakes,
+                check_sigs,
+                repo: deploy_flake.repo,
+                deploy_data,
+                deploy_defs,
+                keep_result,
+                result_path,
+                extra_build_args: build_args,
+            }
+        })
+    };
+
+    // Profiles activated so far, across all batches, to roll back if the deadline is hit
+    let mut activated: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+    // The profile being activated right now, if the deployment is aborted halfway
+    let mut activating: Option<(&deploy::DeployData, &deploy::DeployDefs)> = None;
+
+    let deployment = async {
+        // With --keep-going, the errors of all failed nodes, whose remaining profiles are left out
+        let mut failures: Vec<RunDeployError> = Vec::new();
+        let mut failed_nodes: Vec<&str> = Vec::new();
+        // Nodes already asked about with --interactive-per-node, and the ones that were skipped
+        let mut prompted_nodes: Vec<&str> = Vec::new();
+        let mut declined_nodes: Vec<&str> = Vec::new();
+
+        for data in data_iter() {
+            let node: &str = data.deploy_data.node_name;
+            if failed_nodes.contains(&node) {
+                continue;
+            }
+
+            let node_name: String = node.to_string();
+            progress.set(&node_name, format!("building `{}`", data.deploy_data.profile_name));
+            let build_start = Instant::now();
+            let profile_name = data.deploy_data.profile_name;
+            if let Err(e) = in_log_context(data.deploy_data, deploy::push::build_profile(data)).await {
+                progress.finish(&node_name, "build failed");
+                report.set_failed(&node_name, profile_name, e.to_string());
+                let e = RunDeployError::BuildProfile(node_name, e);
+                if !keep_going {
+                    return Err(e);
+                }
+                error!("{}", e);
+                failed_nodes.push(node);
+                failures.push(e);
+                continue;
+            }
+            timings.node(&node_name).build += build_start.elapsed().as_secs_f64();
+        }
+
+        // Profiles that go to the same host are copied together, so shared closures are only
+        // copied once
+        let push_groups = deploy::push::group_pushes(
+            data_iter().filter(|data| !failed_nodes.contains(&data.deploy_data.node_name)),
+        );
+
+        // Profiles built on the node or deployed with --local aren't copied
+        let copied_groups: Vec<_> = push_groups
+            .iter()
+            .filter(|group| {
+                let target = deploy::push::push_target(&group[0]);
+                !target.remote_build && !target.local
+            })
+            .collect();
+        let copied_profiles: usize = copied_groups.iter().map(|group| group.len()).sum();
+        let unique_pushes: usize = copied_groups
+            .iter()
+            .map(|group| deploy::push::unique_paths(group).len())
+            .sum();
+        debug!(
+            "Copying {} profiles with {} nix copy invocations, {} duplicate pushes skipped",
+            copied_profiles,
+            copied_groups.len(),
+            copied_profiles - unique_pushes
+        );
+
+        for group in &push_groups {
+            // A failed copy fails every node it was copying to
+            let mut group_nodes: Vec<&str> = Vec::new();
+            for data in group {
+                if !group_nodes.contains(&data.deploy_data.node_name) {
+                    group_nodes.push(data.deploy_data.node_name);
+                }
+            }
+            let node_name: String = group_nodes.join(", ");
+            for node in &group_nodes {
+                progress.set(node, "pushing".to_string());
+            }
+
+            let push_start = Instant::now();
+            if let Err(e) = in_log_context(group[0].deploy_data, deploy::push::push_profiles(group)).await {
+                for node in &group_nodes {
+                    progress.finish(node, "push failed");
+                }
+                for data in group {
+                    report.set_failed(data.deploy_data.node_name, data.deploy_data.profile_name, e.to_string());
+                }
+                let e = RunDeployError::PushProfile(node_name, e);
+                if !keep_going {
+                    return Err(e);
+                }
+                error!("{}", e);
+                failed_nodes.extend(group_nodes);
+                failures.push(e);
+                continue;
+            }
+            let push_time = push_start.elapsed().as_secs_f64();
+
+            for node in group_nodes {
+                progress.set(node, "waiting for activation".to_string());
+                timings.node(node).push += push_time;
+            }
+        }
+
+        if diff && cmd_overrides.dry_run {
+            info!("[dry run] Not showing the changes, the nodes are not contacted");
+        } else if diff {
+            // Group the changes by node, profiles of a node are next to each other in `parts`
+            let mut changes: Vec<(&str, String)> = Vec::new();
+            for (_, deploy_data, deploy_defs) in &parts {
+                if failed_nodes.contains(&deploy_data.node_name) {
+                    continue;
+                }
+                let diff_result =
+                    in_log_context(deploy_data, deploy::deploy::diff_closures(deploy_data, deploy_defs)).await;
+                let diff = match diff_result {
+                    Ok(diff) => diff,
+                    Err(e) => {
+                        progress.finish(deploy_data.node_name, "diff failed");
+                        report.set_failed(deploy_data.node_name, deploy_data.profile_name, e.to_string());
+                        let e = RunDeployError::DiffClosures(deploy_data.node_name.to_string(), e);
+                        if !keep_going {
+                            return Err(e);
+                        }
+                        error!("{}", e);
+                        failed_nodes.push(deploy_data.node_name);
+                        failures.push(e);
+                        continue;
+                    }
+                };
+                let diff = if diff.trim().is_empty() { "no changes\n".to_string() } else { diff };
+
+                if changes.last().map(|(n, _)| *n) != Some(deploy_data.node_name) {
+                    changes.push((deploy_data.node_name, String::new()));
+                }
+                if let Some((_, node_changes)) = changes.last_mut() {
+                    node_changes.push_str(&format!("Profile `{}`:\n{}", deploy_data.profile_name, diff));
+                }
+            }
+
+            // Nodes whose later profiles failed to diff are left out with --keep-going
+            changes.retain(|(node_name, _)| !failed_nodes.contains(node_name));
+            for (node_name, node_changes) in changes {
+                info!("Changes on node `{}`:\n{}", node_name, node_changes.trim_end());
+            }
+
+            if interactive {
+                progress
+                    .suspend_blocking(move || prompt_continue("Do you want to activate these changes?", assume_yes))
+                    .await?;
+            }
+        }
+
+        let batches = match batch_size {
+            Some(batch_size) => split_batches(&parts, |(_, deploy_data, _)| deploy_data.node_name, batch_size),
+            None => vec![&parts[..]],
+        };
+
+        for (i, batch) in batches.iter().enumerate() {
+            if i > 0 {
+                if batch_confirm {
+                    progress.suspend(|| print_deployment(batch))?;
+                    progress
+                        .suspend_blocking(move || {
+                            prompt_continue("Do you want to continue with the next batch?", assume_yes)
+                        })
+                        .await?;
+                }
+
+                if let Some(batch_delay) = batch_delay {
+                    info!("Waiting {} seconds before deploying the next batch", batch_delay);
+                    tokio::time::sleep(std::time::Duration::from_secs(batch_delay)).await;
+                }
+            }
+
+            if batches.len() > 1 {
+                info!("Deploying batch {} of {}", i + 1, batches.len());
+            }
+
+            let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+
+            // Run all deployments
+            // In case of an error rollback any previoulsy made deployment of this batch.
+            // Rollbacks adhere to the global seeting to auto_rollback and secondary
+            // the profile's configuration
+            for (_, deploy_data, deploy_defs) in batch.iter() {
+                if failed_nodes.contains(&deploy_data.node_name) {
+                    continue;
+                }
+                if interactive_per_node && !prompted_nodes.contains(&deploy_data.node_name) {
+                    prompted_nodes.push(deploy_data.node_name);
+                    let node_name = deploy_data.node_name.to_string();
+                    match progress.suspend_blocking(move || prompt_node(&node_name, assume_yes)).await? {
+                        NodeAnswer::Activate => (),
+                        NodeAnswer::Skip => declined_nodes.push(deploy_data.node_name),
+                        // Nodes that were activated already are kept
+                        NodeAnswer::Abort => return Err(PromptDeploymentError::Cancelled.into()),
+                    }
+                }
+                if declined_nodes.contains(&deploy_data.node_name) {
+                    progress.finish(deploy_data.node_name, "skipped");
+                    report.set_status(deploy_data.node_name, deploy_data.profile_name, ProfileStatus::Declined);
+                    continue;
+                }
+                progress.set(deploy_data.node_name, format!("activating `{}`", deploy_data.profile_name));
+                activating = Some((deploy_data, deploy_defs));
+                let deploy_result = in_log_context(
+                    deploy_data,
+                    deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate, boot, || {
+                        progress.set(deploy_data.node_name, format!("confirming `{}`", deploy_data.profile_name))
+                    }),
+                )
+                .await;
+                activating = None;
+                match deploy_result {
+                    Ok(profile_timings) => {
+                        if profile_timings.up_to_date {
+                            progress.set(deploy_data.node_name, format!("`{}` up to date", deploy_data.profile_name));
+                        } else if cmd_overrides.dry_run {
+                            progress.set(deploy_data.node_name, format!("would activate `{}`", deploy_data.profile_name));
+                        } else {
+                            progress.set(deploy_data.node_name, format!("activated `{}`", deploy_data.profile_name));
+                            report.set_status(deploy_data.node_name, deploy_data.profile_name, ProfileStatus::Activated);
+                        }
+                        let node_timings = timings.node(deploy_data.node_name);
+                        node_timings.activate += profile_timings.activate.as_secs_f64();
+                        if let Some(confirm) = profile_timings.confirm {
+                            *node_timings.confirm.get_or_insert(0.0) += confirm.as_secs_f64();
+                        }
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        progress.finish(deploy_data.node_name, "activation failed");
+                        report.set_failed(deploy_data.node_name, deploy_data.profile_name, e.to_string());
+                        if keep_going {
+                            // Only the failed profile is rolled back, by its own activation
+                            failed_nodes.push(deploy_data.node_name);
+                            failures.push(RunDeployError::DeployProfile(deploy_data.node_name.to_string(), e));
+                            continue;
+                        }
+                        if dry_activate {
+                            info!("dry run, not rolling back");
+                        }
+                        if i + 1 < batches.len() {
+                            warn!("Aborting the remaining {} batches", batches.len() - i - 1);
+                        }
+                        if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
+                            info!("Revoking previous deploys");
+                            // revoking all previous deploys
+                            // (adheres to profile configuration if not set explicitely by
+                            //  the command line)
+                            for (deploy_data, deploy_defs) in &succeeded {
+                                if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
+                                    in_log_context(deploy_data, deploy::deploy::revoke(deploy_data, deploy_defs)).await.map_err(|e| {
+                                        RunDeployError::RevokeProfile(deploy_data.node_name.to_string(), e)
+                                    })?;
+                                    progress.finish(deploy_data.node_name, "rolled back");
+                                    report.set_status(deploy_data.node_name, deploy_data.profile_name, ProfileStatus::RolledBack);
+                                }
+                            }
+                            return Err(RunDeployError::Rollback(deploy_data.node_name.to_string()));
+                        }
+                        return Err(RunDeployError::DeployProfile(deploy_data.node_name.to_string(), e))
+                    }
+                }
+                succeeded.push((deploy_data, deploy_defs));
+                activated.push((deploy_data, deploy_defs));
+            }
+        }
+
+        progress.finish_all("done");
+
+        if !declined_nodes.is_empty() {
+            info!("Skipped at the prompt: {}", declined_nodes.join(", "));
+        }
+
+        if !failures.is_empty() {
+            return Err(RunDeployError::Multiple(failures));
+        }
+
+        Ok(())
+    };
+
+    let deadline_reached = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.at).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let error = tokio::select! {
+        result = deployment => return result,
+        _ = deadline_reached => {
+            let secs = deadline.map_or(0, |deadline| deadline.secs);
+            error!("Deployment did not finish within {} seconds", secs);
+            RunDeployError::DeployTimeout(secs)
+        }
+        Ok(()) = interrupted() => {
+            warn!("Interrupted, aborting the deployment (press Ctrl-C again to exit right away)");
+            RunDeployError::Interrupted
+        }
+    };
+
+    if let Some((deploy_data, deploy_defs)) = activating {
+        if deploy_data.merged_settings.magic_rollback.unwrap_or(true) && !dry_activate && !boot {
+            info!(
+                "The activation of profile `{}` on node `{}` was not confirmed, the node rolls it back by itself",
+                deploy_data.profile_name, deploy_data.node_name
+            );
+        } else if !dry_activate {
+            // Rolled back with the profiles activated before it, unless it wasn't switched to yet
+            let current = in_log_context(deploy_data, deploy::deploy::current_profile(deploy_data, deploy_defs)).await;
+            match current {
+                Ok(Some(ref path)) if *path == deploy_data.profile.profile_settings.path => {
+                    activated.push((deploy_data, deploy_defs));
+                }
+                Ok(_) => info!(
+                    "The activation of profile `{}` on node `{}` was aborted before the profile was switched",
+                    deploy_data.profile_name, deploy_data.node_name
+                ),
+                Err(e) => warn!(
+                    "The activation of profile `{}` on node `{}` was aborted halfway, check the state of the node: {}",
+                    deploy_data.profile_name, deploy_data.node_name, e
+                ),
+            }
+        }
+    }
+
+    if dry_activate {
+        info!("dry run, not rolling back");
+    } else if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
+        info!("Revoking previous deploys");
+        for (deploy_data, deploy_defs) in &activated {
+            if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
+                in_log_context(deploy_data, deploy::deploy::revoke(deploy_data, deploy_defs)).await.map_err(|e| {
+                    RunDeployError::RevokeProfile(deploy_data.node_name.to_string(), e)
+                })?;
+                progress.finish(deploy_data.node_name, "rolled back");
+                report.set_status(deploy_data.node_name, deploy_data.profile_name, ProfileStatus::RolledBack);
+            }
+        }
+    }
+    progress.finish_all("aborted");
+    Err(error)
+}
+
+/// Resolves on the first Ctrl-C. Spawned ssh and nix processes are killed when the deployment
+/// is dropped. A second Ctrl-C, or one after the deployment finished, exits right away, e.g. if
+/// a prompt or a rollback is stuck.
+fn interrupted() -> tokio::sync::oneshot::Receiver<()> {
+    let (send, recv) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        if send.send(()).is_err() || tokio::signal::ctrl_c().await.is_ok() {
+            error!("Interrupted, exiting");
+            std::process::exit(130);
+        }
+    });
+
+    recv
+}
+
+/// One progress bar per node for `--progress`, showing which phase of the deployment the node is in.
+/// Does nothing if the progress bars are disabled.
+struct DeployProgress {
+    multi: Option<MultiProgress>,
+    bars: HashMap<String, ProgressBar>,
+}
+
+impl DeployProgress {
+    fn new<'a>(multi: Option<&MultiProgress>, nodes: impl Iterator<Item = &'a str>) -> Self {
+        let mut bars = HashMap::new();
+
+        if let Some(multi) = multi {
+            let style = ProgressStyle::with_template("{spinner} {prefix:.bold} {wide_msg}")
+                .expect("progress bar template is valid");
+
+            for node in nodes {
+                if bars.contains_key(node) {
+                    continue;
+                }
+                let bar = multi.add(ProgressBar::new_spinner());
+                bar.set_style(style.clone());
+                bar.set_prefix(node.to_string());
+                bar.set_message("waiting");
+                bar.enable_steady_tick(Duration::from_millis(100));
+                bars.insert(node.to_string(), bar);
+            }
+        }
+
+        DeployProgress {
+            multi: multi.cloned(),
+            bars,
+        }
+    }
+
+    fn set(&self, node: &str, message: String) {
+        if let Some(bar) = self.bars.get(node) {
+            bar.set_message(message);
+        }
+    }
+
+    fn finish(&self, node: &str, message: &'static str) {
+        if let Some(bar) = self.bars.get(node) {
+            bar.finish_with_message(message);
+        }
+    }
+
+    fn finish_all(&self, message: &'static str) {
+        for bar in self.bars.values() {
+            if !bar.is_finished() {
+                bar.finish_with_message(message);
+            }
+        }
+    }
+
+    /// Hides the progress bars while `f` writes to the terminal, e.g. to prompt the user
+    fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.multi {
+            Some(multi) => multi.suspend(f),
+            None => f(),
+        }
+    }
+
+    /// Like `suspend`, but runs the blocking `f` on a thread of its own, so that reading the answer
+    /// to a prompt doesn't stall the other tasks of the runtime
+    async fn suspend_blocking<R: Send + 'static>(&self, f: impl FnOnce() -> R + Send + 'static) -> R {
+        let multi = self.multi.clone();
+        tokio::task::spawn_blocking(move || match multi {
+            Some(multi) => multi.suspend(f),
+            None => f(),
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+/// Point in time by which the whole deployment has to be finished, see `--deploy-timeout`
+#[derive(Clone, Copy, Debug)]
+struct Deadline {
+    at: tokio::time::Instant,
+    secs: u64,
+}
+
+/// Attributes everything logged while running `f` to the node and profile of `deploy_data`
+async fn in_log_context<F: std::future::Future>(
+    deploy_data: &deploy::DeployData<'_>,
+    f: F,
+) -> F::Output {
+    let context = deploy::LogContext {
+        node: deploy_data.node_name.to_string(),
+        profile: deploy_data.profile_name.to_string(),
+    };
+    deploy::LOG_CONTEXT.scope(context, f).await
+}
+
+/// Splits `parts` into consecutive batches containing at most `batch_size` distinct nodes each,
+/// which is at least 1 (`--batch-size 0` is rejected). Profiles of the same node are expected to be
+/// next to each other.
+fn split_batches<T>(
+    parts: &[T],
+    node_name: impl Fn(&T) -> &str,
+    batch_size: usize,
+) -> Vec<&[T]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut nodes = 0;
+
+    for (i, part) in parts.iter().enumerate() {
+        if i == 0 || node_name(part) != node_name(&parts[i - 1]) {
+            if nodes == batch_size {
+                batches.push(&parts[start..i]);
+                start = i;
+                nodes = 0;
+            }
+            nodes += 1;
+        }
+    }
+
+    if start < parts.len() {
+        batches.push(&parts[start..]);
+    }
+
+    batches
+}
+
+#[test]
+fn test_split_batches() {
+    let _env = lock_env();
+
+    let parts = ["a", "a", "b", "c", "c", "d", "e"];
+
+    assert_eq!(
+        split_batches(&parts, |x| x, 2),
+        vec![&["a", "a", "b"][..], &["c", "c", "d"][..], &["e"][..]]
+    );
+    assert_eq!(split_batches(&parts, |x| x, 10), vec![&parts[..]]);
+    assert_eq!(split_batches(&parts[..0], |x| x, 2), Vec::<&[&str]>::new());
+
+    assert!(Opts::try_parse_from(["deploy", "--batch-size", "0"]).is_err());
+    assert_eq!(Opts::parse_from(["deploy", "--batch-size", "1"]).batch_size, Some(1));
+}
+
+/// The `extraBuildArgs` of the profile, followed by the extra build args of the command line so
+/// that those win
+fn profile_build_args(
+    deploy_data: &deploy::DeployData,
+    extra_build_args: &[String],
+) -> Vec<String> {
+    let mut build_args = deploy_data.merged_settings.extra_build_args.clone();
+    build_args.extend_from_slice(extra_build_args);
+    build_args
+}
+
+#[test]
+fn test_profile_build_args() {
+    let deploy_data = deploy::test_deploy_data(
+        "web",
+        serde_json::json!({
+            "hostname": "web",
+            "extraBuildArgs": ["--keep-going"],
+            "profiles": {},
+        }),
+        serde_json::json!({
+            "path": "/nix/store/abc-system",
+            "extraBuildArgs": ["--option", "sandbox", "relaxed"],
+        }),
+        deploy::CmdOverrides::default(),
+    );
+
+    let cli_args: Vec<String> = vec!["--option".into(), "sandbox".into(), "false".into()];
+    assert_eq!(
+        profile_build_args(&deploy_data, &cli_args),
+        vec!["--keep-going", "--option", "sandbox", "relaxed", "--option", "sandbox", "false"]
+    );
+    assert_eq!(
+        profile_build_args(&deploy_data, &[]),
+        vec!["--keep-going", "--option", "sandbox", "relaxed"]
+    );
+}
+
+async fn run_rollback(
+    rollback_opts: &RollbackOpts,
+    supports_flakes: bool,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    debug_logs: bool,
+    log_dir: &Option<String>,
+) -> Result<(), RunError> {
+    let deploy_flake = DeployFlake {
+        repo: deploy::parse_flake(rollback_opts.target.as_deref().unwrap_or("."))?.repo,
+        node: Some(rollback_opts.node.clone()),
+        profile: Some(rollback_opts.profile.clone()),
+    };
+
+    let data = get_deployment_data(
+        &deploy::command::SYSTEM_RUNNER,
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        extra_build_args,
+        None,
+        cmd_overrides.target_system.as_deref(),
+        cmd_overrides.pure_eval,
+    )
+    .await?;
+    let data = &data[0];
+
+    let node = data
+        .nodes
+        .get(&rollback_opts.node)
+        .ok_or_else(|| RunDeployError::NodeNotFound(rollback_opts.node.clone()))?;
+    let profile = node
+        .node_settings
+        .profiles
+        .get(&rollback_opts.profile)
+        .ok_or_else(|| RunDeployError::ProfileNotFound(rollback_opts.profile.clone()))?;
+
+    let deploy_data = deploy::make_deploy_data(
+        &data.generic_settings,
+        node,
+        &rollback_opts.node,
+        profile,
+        &rollback_opts.profile,
+        cmd_overrides,
+        debug_logs,
+        log_dir.as_deref(),
+    );
+
+    let deploy_defs = get_deploy_defs(&deploy_data, &SecretCache::default()).await?;
+
+    deploy::deploy::rollback(&deploy_data, &deploy_defs, rollback_opts.to_generation)
+        .await
+        .map_err(|e| RunDeployError::RevokeProfile(rollback_opts.node.clone(), e))?;
+
+    info!("Rollback to generation {} succeeded", rollback_opts.to_generation);
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ProfileGenerations<'a> {
+    node: &'a str,
+    profile: &'a str,
+    generations: Vec<deploy::deploy::Generation>,
+}
+
+async fn run_list_generations(
+    list_generations_opts: &ListGenerationsOpts,
+    supports_flakes: bool,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    debug_logs: bool,
+    log_dir: &Option<String>,
+) -> Result<(), RunError> {
+    let deploy_flakes = vec![deploy::parse_flake(
+        list_generations_opts.target.as_deref().unwrap_or("."),
+    )?];
+
+    let data = get_deployment_data(
+        &deploy::command::SYSTEM_RUNNER,
+        supports_flakes,
+        &deploy_flakes,
+        extra_build_args,
+        None,
+        cmd_overrides.target_system.as_deref(),
+        cmd_overrides.pure_eval,
+    )
+    .await?;
+
+    let mut listed: Vec<ProfileGenerations> = Vec::new();
+    let secret_cache = SecretCache::default();
+
+    let targets = resolve_targets(&deploy_flakes, &data, &NodeFilter::default())?;
+    check_hostname_override(cmd_overrides, targets.iter().map(|(_, _, (node_name, _), _)| *node_name))?;
+
+    for (_, data, (node_name, node), (profile_name, profile)) in targets {
+        let deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            debug_logs,
+            log_dir.as_deref(),
+        );
+
+        let deploy_defs = get_deploy_defs(&deploy_data, &secret_cache).await?;
+
+        let generations = deploy::deploy::list_generations(&deploy_data, &deploy_defs)
+            .await
+            .map_err(|e| RunDeployError::ListGenerations(node_name.to_string(), e))?;
+
+        listed.push(ProfileGenerations {
+            node: node_name,
+            profile: profile_name,
+            generations,
+        });
+    }
+
+    if list_generations_opts.json {
+        println!("{}", serde_json::to_string_pretty(&listed).map_err(RunError::JsonOutput)?);
+    } else {
+        for entry in &listed {
+            println!("{}.{}", entry.node, entry.profile);
+            println!("  {:>10}  {:<19}  CURRENT", "GENERATION", "DATE");
+            for generation in &entry.generations {
+                println!(
+                    "  {:>10}  {:<19}  {}",
+                    generation.id,
+                    generation.date,
+                    if generation.current { "*" } else { "" }
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
 
-async fn run_deploy(
-    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
-    data: Vec<deploy::data::Data>,
+async fn run_unlock(
+    unlock_opts: &UnlockOpts,
     supports_flakes: bool,
-    check_sigs: bool,
-    interactive: bool,
     cmd_overrides: &deploy::CmdOverrides,
-    keep_result: bool,
-    result_path: Option<&str>,
     extra_build_args: &[String],
     debug_logs: bool,
-    dry_activate: bool,
-    boot: bool,
     log_dir: &Option<String>,
-    rollback_succeeded: bool,
-) -> Result<(), RunDeployError> {
-    let to_deploy: ToDeploy = deploy_flakes
-        .iter()
-        .zip(&data)
-        .map(|(deploy_flake, data)| {
-            let to_deploys: ToDeploy = match (&deploy_flake.node, &deploy_flake.profile) {
-                (Some(node_name), Some(profile_name)) => {
-                    let node = match data.nodes.get(node_name) {
-                        Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
-                    };
-                    let profile = match node.node_settings.profiles.get(profile_name) {
-                        Some(x) => x,
-                        None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
-                    };
-
-                    vec![(
-                        deploy_flake,
-                        data,
-                        (node_name.as_str(), node),
-                        (profile_name.as_str(), profile),
-                    )]
-                }
-                (Some(node_name), None) => {
-                    let node = match data.nodes.get(node_name) {
-                        Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
-                    };
-
-                    let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
-
-                    for profile_name in [
-                        node.node_settings.profiles_order.iter().collect(),
-                        node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                    ]
-                    .concat()
-                    {
-                        let profile = match node.node_settings.profiles.get(profile_name) {
-                            Some(x) => x,
-                            None => {
-                                return Err(RunDeployError::ProfileNotFound(profile_name.clone()))
-                            }
-                        };
-
-                        if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                            profiles_list.push((profile_name, profile));
-                        }
-                    }
-
-                    profiles_list
-                        .into_iter()
-                        .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
-                        .collect()
-                }
-                (None, None) => {
-                    let mut l = Vec::new();
-
-                    for (node_name, node) in &data.nodes {
-                        let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
-
-                        for profile_name in [
-                            node.node_settings.profiles_order.iter().collect(),
-                            node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                        ]
-                        .concat()
-                        {
-                            let profile = match node.node_settings.profiles.get(profile_name) {
-                                Some(x) => x,
-                                None => {
-                                    return Err(RunDeployError::ProfileNotFound(
-                                        profile_name.clone(),
-                                    ))
-                                }
-                            };
-
-                            if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                                profiles_list.push((profile_name, profile));
-                            }
-                        }
-
-                        let ll: ToDeploy = profiles_list
-                            .into_iter()
-                            .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
-                            .collect();
+) -> Result<(), RunError> {
+    let deploy_flakes = vec![deploy::parse_flake(&unlock_opts.target)?];
 
-                        l.extend(ll);
-                    }
+    let data = get_deployment_data(
+        &deploy::command::SYSTEM_RUNNER,
+        supports_flakes,
+        &deploy_flakes,
+        extra_build_args,
+        None,
+        cmd_overrides.target_system.as_deref(),
+        cmd_overrides.pure_eval,
+    )
+    .await?;
 
-                    l
-                }
-                (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
-            };
-            Ok(to_deploys)
-        })
-        .collect::<Result<Vec<ToDeploy>, RunDeployError>>()?
-        .into_iter()
-        .flatten()
-        .collect();
+    let secret_cache = SecretCache::default();
 
-    let mut parts: Vec<(
-        &deploy::DeployFlake<'_>,
-        deploy::DeployData,
-        deploy::DeployDefs,
-    )> = Vec::new();
+    let targets = resolve_targets(&deploy_flakes, &data, &NodeFilter::default())?;
+    check_hostname_override(cmd_overrides, targets.iter().map(|(_, _, (node_name, _), _)| *node_name))?;
 
-    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
+    for (_, data, (node_name, node), (profile_name, profile)) in targets {
         let deploy_data = deploy::make_deploy_data(
             &data.generic_settings,
             node,
@@ -559,176 +3960,211 @@ async fn run_deploy(
             log_dir.as_deref(),
         );
 
-        let mut deploy_defs = deploy_data.defs()?;
+        let deploy_defs = get_deploy_defs(&deploy_data, &secret_cache).await?;
 
-        if deploy_data.merged_settings.sudo.is_some()
-            && (deploy_data.merged_settings.interactive_sudo.is_some()
-                || deploy_data.merged_settings.sudo_secret.is_some())
-        {
-            warn!("Custom sudo commands should be configured to accept password input from stdin when using the 'interactive sudo' or 'password File' option. Deployment may fail if the custom command ignores stdin.");
+        warn!(
+            "Removing the magic-rollback lock of profile `{}` on node `{}`, a deployment of it that is still waiting for its confirmation is confirmed by this!",
+            profile_name, node_name
+        );
+
+        let removed = in_log_context(&deploy_data, deploy::deploy::unlock_profile(&deploy_data, &deploy_defs))
+            .await
+            .map_err(|e| RunDeployError::UnlockProfile(node_name.to_string(), e))?;
+
+        if removed {
+            warn!("Removed the lock of profile `{}` on node `{}`", profile_name, node_name);
         } else {
-            // this configures sudo to hide the password prompt and accept input from stdin
-            // at the time of writing, deploy_defs.sudo defaults to 'sudo -u root' when using user=root and sshUser as non-root
-            let original = deploy_defs.sudo.unwrap_or("sudo".to_string());
-            deploy_defs.sudo = Some(format!("{} -S -p \"\"", original));
+            info!("Profile `{}` on node `{}` was not locked", profile_name, node_name);
         }
+    }
 
-        if deploy_data
-            .merged_settings
-            .interactive_sudo
-            .unwrap_or(false)
-        {
-            warn!("Interactive sudo is enabled! Using a sudo password is less secure than correctly configured SSH keys.\nPlease use keys in production environments.");
+    Ok(())
+}
 
-            info!(
-                "You will now be prompted for the sudo password for {}.",
-                node.node_settings.hostname
-            );
+#[derive(Serialize)]
+struct NodeStatusReport<'a> {
+    node: &'a str,
+    /// `ok`, `unreachable` or `error`
+    state: &'static str,
+    error: Option<String>,
+    #[serde(flatten)]
+    status: deploy::deploy::NodeStatus,
+}
 
-            let sudo_password = rpassword::prompt_password(format!(
-                "(sudo for {}) Password: ",
-                node.node_settings.hostname
-            ))
-            .unwrap_or("".to_string());
+/// Formats an uptime in seconds like `3d 4h 12m`
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
 
-            deploy_defs.sudo_password = Some(sudo_password);
-        } else if deploy_data.merged_settings.sudo_file.is_some()
-            && deploy_data.merged_settings.sudo_secret.is_some()
-        {
-            // SAFETY: we already checked if it is some
-            let path = deploy_data.merged_settings.sudo_file.clone().unwrap();
-            let key = deploy_data.merged_settings.sudo_secret.clone().unwrap();
-
-            if !try_exists(&path).await.unwrap() {
-                return Err(RunDeployError::Sops(SopsError::SopsFileNotFound(format!(
-                    "{path:?} not found"
-                ))));
-            }
-
-            // We deserialze to json
-            let out = Command::new("sops")
-                .arg("--output-type")
-                .arg("json")
-                .arg("-d")
-                .arg(&path)
-                .output()
-                .await
-                .map_err(|err| {
-                    RunDeployError::Sops(SopsError::SopsFailedDecryption(
-                        path.to_string_lossy().into(),
-                        err,
-                    ))
-                })?;
+#[test]
+fn test_format_uptime() {
+    assert_eq!(format_uptime(59), "0m");
+    assert_eq!(format_uptime(3 * 3600 + 120), "3h 2m");
+    assert_eq!(format_uptime(93784), "1d 2h 3m");
+}
 
-            let conv_out = std::str::from_utf8(&out.stdout)
-                .map_err(|err| RunDeployError::Sops(SopsError::SopsCannotConvert(err)))?;
+/// The deployment data as `--from-data` reads it: the data of a single flake as is, or an array
+/// with the data of each flake
+fn deployment_data_json(mut data: Vec<serde_json::Value>) -> Result<String, serde_json::Error> {
+    match data.len() {
+        1 => serde_json::to_string_pretty(&data.remove(0)),
+        _ => serde_json::to_string_pretty(&data),
+    }
+}
 
-            let mut m: serde_json::Map<String, serde_json::Value> = serde_json::from_str(conv_out)
-                .map_err(|err| RunDeployError::Sops(SopsError::SerdeDeserialize(err)))?;
+#[test]
+fn test_deployment_data_json() {
+    let web = serde_json::json!({ "nodes": { "web": { "hostname": "web", "profiles": {} } } });
+    let db = serde_json::json!({ "nodes": { "db": { "hostname": "db", "profiles": {} } } });
 
-            let mut sudo_password = String::new();
+    let targets = [
+        DeployFlake { repo: ".", node: None, profile: None },
+        DeployFlake { repo: "./db", node: None, profile: None },
+    ];
 
-            // We support nested keys like a/b/c
-            for i in key.split('/') {
-                match m.get(i) {
-                    Some(v) => match v {
-                        serde_json::Value::String(s) => {
-                            sudo_password = s.into();
-                        }
-                        serde_json::Value::Bool(b) => {
-                            sudo_password = b.to_string();
-                        }
-                        serde_json::Value::Number(n) => {
-                            sudo_password = n.to_string();
-                        }
-                        serde_json::Value::Object(map) => {
-                            m = map.clone();
-                        }
-                        _ => {
-                            return Err(RunDeployError::Sops(SopsError::SerdeUnexpectedType(
-                                "We dont handle Arrays, Bools, None, Numbers".into(),
-                            )));
-                        }
-                    },
-                    None => {
-                        return Err(RunDeployError::Sops(SopsError::SopsKeyNotFound(format!(
-                            "Did not find {} in Map",
-                            i
-                        ))));
-                    }
-                }
-            }
-            deploy_defs.sudo_password = Some(sudo_password);
-        }
+    let json = deployment_data_json(vec![web.clone()]).unwrap();
+    let data = parse_deployment_data(Path::new("-"), &json, &targets[..1]).unwrap();
+    assert!(data[0].nodes.contains_key("web"));
 
-        parts.push((deploy_flake, deploy_data, deploy_defs));
-    }
+    let json = deployment_data_json(vec![web, db]).unwrap();
+    let data = parse_deployment_data(Path::new("-"), &json, &targets).unwrap();
+    assert!(data[0].nodes.contains_key("web"));
+    assert!(data[1].nodes.contains_key("db"));
+}
 
-    if interactive {
-        prompt_deployment(&parts[..])?;
-    } else {
-        print_deployment(&parts[..])?;
-    }
+async fn run_eval(
+    eval_opts: &EvalOpts,
+    supports_flakes: bool,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+) -> Result<(), RunError> {
+    let deploy_flakes = eval_opts
+        .targets
+        .iter()
+        .map(|target| deploy::parse_flake(target))
+        .collect::<Result<Vec<_>, _>>()?;
 
-    let data_iter = || {
-        parts.iter().map(
-            |(deploy_flake, deploy_data, deploy_defs)| deploy::push::PushProfileData {
-                supports_flakes,
-                check_sigs,
-                repo: deploy_flake.repo,
-                deploy_data,
-                deploy_defs,
-                keep_result,
-                result_path,
-                extra_build_args,
-            },
-        )
-    };
+    let data = get_deployment_json(
+        &deploy::command::SYSTEM_RUNNER,
+        supports_flakes,
+        &deploy_flakes,
+        extra_build_args,
+        None,
+        cmd_overrides.target_system.as_deref(),
+        cmd_overrides.pure_eval,
+    )
+    .await?;
 
-    for data in data_iter() {
-        let node_name: String = data.deploy_data.node_name.to_string();
-        deploy::push::build_profile(data).await.map_err(|e| {
-            RunDeployError::BuildProfile(node_name, e)
-        })?;
+    let json = deployment_data_json(data).map_err(RunError::JsonOutput)?;
+    match eval_opts.output {
+        Some(ref output) => std::fs::write(output, json + "\n")
+            .map_err(|e| RunError::WriteData(output.clone(), e))?,
+        None => println!("{}", json),
     }
 
-    for data in data_iter() {
-        let node_name: String = data.deploy_data.node_name.to_string();
-        deploy::push::push_profile(data).await.map_err(|e| {
-            RunDeployError::PushProfile(node_name, e)
-        })?;
+    Ok(())
+}
+
+async fn run_status(
+    status_opts: &StatusOpts,
+    supports_flakes: bool,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    debug_logs: bool,
+    log_dir: &Option<String>,
+) -> Result<(), RunError> {
+    let deploy_flakes = vec![deploy::parse_flake(status_opts.target.as_deref().unwrap_or("."))?];
+
+    let data = get_deployment_data(
+        &deploy::command::SYSTEM_RUNNER,
+        supports_flakes,
+        &deploy_flakes,
+        extra_build_args,
+        None,
+        cmd_overrides.target_system.as_deref(),
+        cmd_overrides.pure_eval,
+    )
+    .await?;
+
+    let targets = resolve_targets(&deploy_flakes, &data, &NodeFilter::default())?;
+    check_hostname_override(cmd_overrides, targets.iter().map(|(_, _, (node_name, _), _)| *node_name))?;
+
+    let mut deploy_datas: Vec<deploy::DeployData> = Vec::new();
+    for (_, data, (node_name, node), (profile_name, profile)) in targets {
+        deploy_datas.push(deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            debug_logs,
+            log_dir.as_deref(),
+        ));
     }
 
-    let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+    let mut reports: Vec<NodeStatusReport> = Vec::new();
 
-    // Run all deployments
-    // In case of an error rollback any previoulsy made deployment.
-    // Rollbacks adhere to the global seeting to auto_rollback and secondary
-    // the profile's configuration
-    for (_, deploy_data, deploy_defs) in &parts {
-        if let Err(e) = deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate, boot).await
-        {
-            error!("{}", e);
-            if dry_activate {
-                info!("dry run, not rolling back");
-            }
-            if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
-                info!("Revoking previous deploys");
-                // revoking all previous deploys
-                // (adheres to profile configuration if not set explicitely by
-                //  the command line)
-                for (deploy_data, deploy_defs) in &succeeded {
-                    if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
-                        deploy::deploy::revoke(*deploy_data, *deploy_defs).await.map_err(|e| {
-                            RunDeployError::RevokeProfile(deploy_data.node_name.to_string(), e)
-                        })?;
-                    }
-                }
-                return Err(RunDeployError::Rollback(deploy_data.node_name.to_string()));
+    // The SSH settings of the first profile are used to connect, every profile's temp path is
+    // searched for lock files
+    for deploy_data in &deploy_datas {
+        if reports.iter().any(|r| r.node == deploy_data.node_name) {
+            continue;
+        }
+
+        let mut temp_paths: Vec<&Path> = Vec::new();
+        for d in deploy_datas.iter().filter(|d| d.node_name == deploy_data.node_name) {
+            let temp_path = d.temp_path();
+            if !temp_paths.contains(&temp_path) {
+                temp_paths.push(temp_path);
+            }
+        }
+
+        // Only needs the SSH user, so sudo passwords are never asked for
+        let deploy_defs = deploy_data.defs().map_err(RunDeployError::DeployDataDefs)?;
+
+        let result = in_log_context(
+            deploy_data,
+            deploy::deploy::node_status(deploy_data, &deploy_defs, &temp_paths),
+        )
+        .await;
+
+        let (state, error, status) = match result {
+            Ok(status) => ("ok", None, status),
+            Err(deploy::deploy::NodeStatusError::Unreachable) => ("unreachable", None, Default::default()),
+            Err(e) => {
+                warn!("Failed to get the status of node `{}`: {}", deploy_data.node_name, e);
+                ("error", Some(e.to_string()), Default::default())
             }
-            return Err(RunDeployError::DeployProfile(deploy_data.node_name.to_string(), e))
+        };
+
+        reports.push(NodeStatusReport {
+            node: deploy_data.node_name,
+            state,
+            error,
+            status,
+        });
+    }
+
+    if status_opts.json {
+        println!("{}", serde_json::to_string_pretty(&reports).map_err(RunError::JsonOutput)?);
+    } else {
+        println!("{:<20}  {:<11}  {:>10}  {:<12}  LOCKS", "NODE", "STATE", "GENERATION", "UPTIME");
+        for report in &reports {
+            println!(
+                "{:<20}  {:<11}  {:>10}  {:<12}  {}",
+                report.node,
+                report.state,
+                report.status.generation.map(|g| g.to_string()).unwrap_or_default(),
+                report.status.uptime.map(format_uptime).unwrap_or_default(),
+                report.status.locks.join(" ")
+            );
         }
-        succeeded.push((deploy_data, deploy_defs))
     }
 
     Ok(())
@@ -744,10 +4180,36 @@ pub enum SopsError {
     SopsCannotConvert(Utf8Error),
     #[error("Failed to deserialize: {0}")]
     SerdeDeserialize(serde_json::Error),
+    #[error("Failed to deserialize YAML: {0}")]
+    YamlDeserialize(serde_yaml::Error),
+    #[error("Invalid line in dotenv output, expected `KEY=value`: {0}")]
+    DotenvInvalidLine(String),
     #[error("Error unexpected type: {0}")]
     SerdeUnexpectedType(String),
     #[error("Failed to find key: {0}")]
     SopsKeyNotFound(String),
+    #[error("Expected a numeric index into an array, found `{0}`")]
+    SopsIndexNotNumeric(String),
+    #[error("Index {0} is out of bounds for an array of length {1}")]
+    SopsIndexOutOfBounds(usize, usize),
+}
+
+#[derive(Error, Debug)]
+pub enum AgeError {
+    #[error("Failed to read age identity file {0:?}: {1}")]
+    ReadIdentity(PathBuf, std::io::Error),
+    #[error("Failed to parse age identities: {0}")]
+    Identities(age::DecryptError),
+    #[error("Failed to open age file {0:?}: {1}")]
+    OpenFile(PathBuf, std::io::Error),
+    #[error("Failed to decrypt age file {0:?}: {1}")]
+    Decrypt(PathBuf, age::DecryptError),
+    #[error("Failed to read decrypted age file {0:?} as utf8: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("The decrypted age file is JSON but no sudo secret key was given")]
+    NoKey,
+    #[error("Failed to find the sudo secret in the decrypted age file: {0}")]
+    Lookup(SopsError),
 }
 
 #[derive(Error, Debug)]
@@ -764,36 +4226,195 @@ pub enum RunError {
     GetDeploymentData(#[from] GetDeploymentDataError),
     #[error("Error parsing flake: {0}")]
     ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("--hostname without a node name can only be given once")]
+    MultipleHostnames,
+    #[error("--{0} without a node name can only be given once")]
+    MultipleTimeouts(&'static str),
+    #[error("Invalid value {1:?} for --{0}, expected `<secs>` or `<node>=<secs>`")]
+    InvalidTimeout(&'static str, String),
+    #[error("--{0} without a node name can only be given once")]
+    MultipleSecrets(&'static str),
+    #[error("Invalid value {0:?} for --ssh-opts, check that its quotes are balanced")]
+    InvalidSshOpts(String),
     #[error("Error parsing arguments: {0}")]
     ParseArgs(#[from] clap::Error),
     #[error("Error initiating logger: {0}")]
     Logger(#[from] flexi_logger::FlexiLoggerError),
     #[error("{0}")]
     RunDeploy(#[from] RunDeployError),
+    #[error("Failed to serialize output as JSON: {0}")]
+    JsonOutput(serde_json::Error),
+    #[error("Failed to write timings to {0:?}: {1}")]
+    WriteTimings(PathBuf, std::io::Error),
+    #[error("Failed to write the deployment report to {0:?}: {1}")]
+    WriteReport(PathBuf, std::io::Error),
+    #[error("Failed to read config file {0:?}: {1}")]
+    ReadConfig(PathBuf, std::io::Error),
+    #[error("Failed to parse config file {0:?}: {1}")]
+    ParseConfig(PathBuf, toml::de::Error),
+    #[error("Failed to read targets from {0:?}: {1}")]
+    ReadTargetsFile(PathBuf, std::io::Error),
+    #[error("Failed to parse target on line {0} of the targets file: {1}")]
+    ParseTargetsFile(usize, deploy::ParseFlakeError),
+    #[error("Failed to read deployment data from {0:?}: {1}")]
+    ReadData(PathBuf, std::io::Error),
+    #[error("Failed to parse deployment data from {0:?}: {1}")]
+    ParseData(PathBuf, serde_json::Error),
+    #[error("Invalid deployment data in {0:?}: {1}")]
+    ValidateData(PathBuf, deploy::data::ValidateDataError),
+    #[error("{0:?} holds the deployment data of {1} flakes, but {2} targets are deployed")]
+    DataCount(PathBuf, usize, usize),
+    #[error("Failed to write the deployment data to {0:?}: {1}")]
+    WriteData(PathBuf, std::io::Error),
+}
+
+/// Returns the targets in `contents` together with their (1-based) line numbers, skipping blank
+/// lines and `#` comments
+fn parse_targets(contents: &str) -> Vec<(usize, String)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| (i, line.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_parse_targets() {
+    let contents = "# web servers\n.#web1\n\n  .#web2.system  \n#.#db\n";
+    assert_eq!(
+        parse_targets(contents),
+        vec![(2, ".#web1".to_string()), (4, ".#web2.system".to_string())]
+    );
+}
+
+/// Parses deployment data as `nix eval --json <flake>#deploy` prints it, either one object that is
+/// used for all `targets` or an array with one object per target
+fn parse_deployment_data(
+    path: &Path,
+    contents: &str,
+    targets: &[deploy::DeployFlake<'_>],
+) -> Result<Vec<deploy::data::Data>, RunError> {
+    let value: serde_json::Value =
+        serde_json::from_str(contents).map_err(|e| RunError::ParseData(path.to_path_buf(), e))?;
+
+    let values = match value {
+        serde_json::Value::Array(values) if values.len() != targets.len() => {
+            return Err(RunError::DataCount(path.to_path_buf(), values.len(), targets.len()));
+        }
+        serde_json::Value::Array(values) => values,
+        value => vec![value; targets.len()],
+    };
+
+    values
+        .into_iter()
+        .zip(targets)
+        .map(|(value, target)| {
+            // `deploy eval` of the same targets only keeps what they select
+            deploy::data::validate(&value, eval_scope(true, target))
+                .map_err(|e| RunError::ValidateData(path.to_path_buf(), e))?;
+            serde_json::from_value(value).map_err(|e| RunError::ParseData(path.to_path_buf(), e))
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_deployment_data() {
+    let path = Path::new("deployment.json");
+    let data = r#"{ "sshUser": "deploy", "nodes": { "web": { "hostname": "web.example.com", "profiles": {} } } }"#;
+
+    let target = || DeployFlake { repo: ".", node: None, profile: None };
+    let two = [target(), target()];
+
+    let parsed = parse_deployment_data(path, data, &two).unwrap();
+    assert_eq!(parsed.len(), 2);
+    assert!(parsed[1].nodes.contains_key("web"));
+    assert_eq!(parsed[1].generic_settings.ssh_user.as_deref(), Some("deploy"));
+
+    let both = format!("[{}, {}]", data, data);
+    assert_eq!(parse_deployment_data(path, &both, &two).unwrap().len(), 2);
+    assert!(matches!(
+        parse_deployment_data(path, &both, &[target(), target(), target()]),
+        Err(RunError::DataCount(_, 2, 3))
+    ));
+    assert!(matches!(
+        parse_deployment_data(path, r#"{ "nodes": {}, "sshUsr": "deploy" }"#, &two[..1]),
+        Err(RunError::ValidateData(..))
+    ));
+    assert!(matches!(
+        parse_deployment_data(path, "{", &two[..1]),
+        Err(RunError::ParseData(..))
+    ));
+}
+
+fn read_targets_file(path: &Path) -> Result<Vec<(usize, String)>, RunError> {
+    let mut contents = String::new();
+    if path == Path::new("-") {
+        stdin()
+            .read_to_string(&mut contents)
+            .map_err(|e| RunError::ReadTargetsFile(path.to_path_buf(), e))?;
+    } else {
+        contents = std::fs::read_to_string(path)
+            .map_err(|e| RunError::ReadTargetsFile(path.to_path_buf(), e))?;
+    }
+
+    Ok(parse_targets(&contents))
 }
 
 pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
-    let opts = match args {
+    let mut command = Opts::command();
+    let mut opts = match args {
         Some(o) => <Opts as FromArgMatches>::from_arg_matches(o)?,
-        None => Opts::parse(),
+        None => <Opts as FromArgMatches>::from_arg_matches(&command.clone().get_matches())?,
+    };
+
+    if let Some(SubCommand::GenerateCompletions(ref completions_opts)) = opts.subcmd {
+        clap_complete::generate(completions_opts.shell, &mut command, "deploy", &mut stdout());
+        return Ok(());
+    }
+
+    if let Some(config) = find_config(&opts) {
+        load_config(&config)?.apply(&mut opts);
+    }
+
+    let progress = match opts.progress
+        && opts.log_format == deploy::LogFormat::Human
+        && stdout().is_terminal()
+    {
+        true => Some(MultiProgress::new()),
+        false => None,
     };
 
     deploy::init_logger(
-        opts.debug_logs,
+        opts.debug_logs.unwrap_or(false),
         opts.log_dir.as_deref(),
+        opts.per_node_logs,
         &deploy::LoggerType::Deploy,
+        opts.log_format,
+        opts.color,
+        progress.clone(),
     )?;
 
     if opts.dry_activate && opts.boot {
         error!("Cannot use both --dry-activate & --boot!");
     }
 
-    let deploys = opts
-        .clone()
-        .targets
-        .unwrap_or_else(|| vec![opts.clone().target.unwrap_or_else(|| ".".to_string())]);
+    #[cfg(feature = "notify")]
+    let notifications = Notifications::new(&opts);
+
+    let file_targets = match opts.targets_file {
+        Some(ref path) => read_targets_file(path)?,
+        None => Vec::new(),
+    };
+
+    let deploys = opts.clone().targets.unwrap_or_else(|| match opts.clone().target {
+        Some(target) => vec![target],
+        None if !file_targets.is_empty() => Vec::new(),
+        None => vec![".".to_string()],
+    });
 
-    let deploy_flakes: Vec<DeployFlake> =
+    let mut deploy_flakes: Vec<DeployFlake> =
         if let Some(file) = &opts.file {
             deploys
                 .iter()
@@ -807,27 +4428,89 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
           .collect::<Result<Vec<DeployFlake>, ParseFlakeError>>()?
     };
 
+    for (line, target) in &file_targets {
+        let deploy_flake = match &opts.file {
+            Some(file) => deploy::parse_file(file.as_str(), target.as_str()),
+            None => deploy::parse_flake(target.as_str()),
+        };
+        deploy_flakes.push(deploy_flake.map_err(|e| RunError::ParseTargetsFile(*line, e))?);
+    }
+
+    let ssh_opts = parse_ssh_opts(opts.ssh_opts)?;
+    let (hostname, node_hostnames) = parse_hostname_overrides(opts.hostname)?;
+    let (confirm_timeout, node_confirm_timeouts) =
+        parse_timeout_overrides("confirm-timeout", opts.confirm_timeout)?;
+    let (sudo_file, node_sudo_files) = parse_secret_overrides("sudo-file", opts.sudo_file)?;
+    let (sudo_secret, node_sudo_secrets) =
+        parse_secret_overrides("sudo-secret", opts.sudo_secret)?;
+    let (activation_timeout, node_activation_timeouts) =
+        parse_timeout_overrides("activation-timeout", opts.activation_timeout)?;
+
     let cmd_overrides = deploy::CmdOverrides {
         ssh_user: opts.ssh_user,
         profile_user: opts.profile_user,
-        ssh_opts: opts.ssh_opts,
+        ssh_opts,
+        ssh_opts_append: opts.ssh_opts_append.unwrap_or(false),
+        extra_ssh_args: opts.extra_ssh_args,
         fast_connection: opts.fast_connection,
+        substitute_on_destination: opts.substitute_on_destination,
         compress: opts.compress,
-        auto_rollback: opts.auto_rollback,
-        hostname: opts.hostname,
-        magic_rollback: opts.magic_rollback,
+        auto_rollback: opts.no_auto_rollback.then_some(false).or(opts.auto_rollback),
+        hostname,
+        node_hostnames,
+        known_hosts_file: opts.known_hosts_file,
+        ssh_config: opts.ssh_config,
+        verbose_ssh: opts.verbose_ssh,
+        local: opts.local.unwrap_or(false),
+        strict_host_key_checking: opts.strict_host_key_checking,
+        magic_rollback: opts.no_magic_rollback.then_some(false).or(opts.magic_rollback),
         temp_path: opts.temp_path,
-        confirm_timeout: opts.confirm_timeout,
-        activation_timeout: opts.activation_timeout,
+        confirm_timeout,
+        node_confirm_timeouts,
+        activation_timeout,
+        node_activation_timeouts,
         dry_activate: opts.dry_activate,
-        remote_build: opts.remote_build,
+        remote_build: opts.remote_build.unwrap_or(false),
+        per_node_logs: opts.per_node_logs,
+        prefix_output: opts.prefix_output,
+        confirm_via: opts.confirm_via,
+        confirm_retries: opts.confirm_retries,
+        copy_retries: opts.copy_retries,
+        copy_jobs: opts.copy_jobs,
+        build_timeout: opts.build_timeout,
+        copy_bwlimit: opts.copy_bwlimit,
+        via_cache: opts.via_cache,
+        builders: opts.builders,
+        nom: opts.nom.unwrap_or(false),
+        print_build_logs: opts.print_build_logs.unwrap_or(false),
+        target_system: opts.target_system,
+        pure_eval: opts.pure_eval.unwrap_or(false),
+        force: opts.force,
+        dry_run: opts.dry_run,
         sudo: opts.sudo,
+        sudo_kind: opts.sudo_kind,
         interactive_sudo: opts.interactive_sudo,
-        sudo_file: opts.sudo_file,
-        sudo_secret: opts.sudo_secret,
+        shared_sudo_password: opts.shared_sudo_password.unwrap_or(false),
+        sudo_file: sudo_file.map(PathBuf::from),
+        node_sudo_files: node_sudo_files
+            .into_iter()
+            .map(|(node, path)| (node, PathBuf::from(path)))
+            .collect(),
+        sudo_secret,
+        node_sudo_secrets,
+        sudo_secret_format: opts.sudo_secret_format,
+        raw_sudo: opts.raw_sudo.unwrap_or(false),
+        age_identity: opts.age_identity,
+        age_file: opts.age_file,
+        health_check_cmd: opts.health_check_cmd,
+        health_check_timeout: opts.health_check_timeout,
     };
 
-    let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
+    // Deployment data that was evaluated elsewhere may be deployed where nothing can be evaluated
+    let supports_flakes = match opts.from_data {
+        Some(_) => true,
+        None => test_flake_support().await.map_err(RunError::FlakeTest)?,
+    };
     let do_not_want_flakes = opts.file.is_some();
 
     if !supports_flakes {
@@ -840,35 +4523,217 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
 
     let using_flakes = supports_flakes && !do_not_want_flakes;
 
-    if !opts.skip_checks {
-        let mut set = std::collections::HashSet::new();
-        deploy_flakes.iter().for_each(|item| {
-            set.insert(item.repo);
-        });
+    if !opts.override_input.is_empty() {
+        if using_flakes {
+            // Passed along with the extra build args, so every nix invocation sees the same inputs
+            let mut extra_build_args = override_input_args(&opts.override_input);
+            extra_build_args.append(&mut opts.extra_build_args);
+            opts.extra_build_args = extra_build_args;
+        } else {
+            warn!("--override-input only applies to flakes, ignoring it");
+        }
+    }
 
-        for path in set {
-            check_deployment(using_flakes, path, &opts.extra_build_args).await?;
+    if opts.pure_eval.unwrap_or(false) && !using_flakes {
+        warn!("--pure-eval only applies to flakes, ignoring it");
+    }
+
+    match opts.subcmd {
+        Some(SubCommand::Rollback(ref rollback_opts)) => {
+            return run_rollback(
+                rollback_opts,
+                using_flakes,
+                &cmd_overrides,
+                &opts.extra_build_args,
+                opts.debug_logs.unwrap_or(false),
+                &opts.log_dir,
+            )
+            .await;
+        }
+        Some(SubCommand::ListGenerations(ref list_generations_opts)) => {
+            return run_list_generations(
+                list_generations_opts,
+                using_flakes,
+                &cmd_overrides,
+                &opts.extra_build_args,
+                opts.debug_logs.unwrap_or(false),
+                &opts.log_dir,
+            )
+            .await;
+        }
+        Some(SubCommand::Status(ref status_opts)) => {
+            return run_status(
+                status_opts,
+                using_flakes,
+                &cmd_overrides,
+                &opts.extra_build_args,
+                opts.debug_logs.unwrap_or(false),
+                &opts.log_dir,
+            )
+            .await;
         }
+        Some(SubCommand::Eval(ref eval_opts)) => {
+            return run_eval(
+                eval_opts,
+                using_flakes,
+                &cmd_overrides,
+                &opts.extra_build_args,
+            )
+            .await;
+        }
+        Some(SubCommand::Unlock(ref unlock_opts)) => {
+            return run_unlock(
+                unlock_opts,
+                using_flakes,
+                &cmd_overrides,
+                &opts.extra_build_args,
+                opts.debug_logs.unwrap_or(false),
+                &opts.log_dir,
+            )
+            .await;
+        }
+        Some(SubCommand::GenerateCompletions(_)) | None => (),
     }
+
+    let mut timings = Timings::default();
+    let mut report = DeployReport::default();
+
+    let deadline = match opts.deploy_timeout {
+        None | Some(0) => None,
+        Some(secs) => Some(Deadline {
+            at: tokio::time::Instant::now() + Duration::from_secs(secs),
+            secs,
+        }),
+    };
+
+    // The checks build, which a dry run doesn't do
+    let skip_checks = opts.skip_checks.unwrap_or(false) || opts.dry_run;
+    let skip_checks_if_unchanged = opts.skip_checks_if_unchanged.unwrap_or(false);
+    let check_systems = &opts.check_systems;
+    let skip_checks_for = &opts.skip_checks_for;
+    let extra_build_args = &opts.extra_build_args;
+    let eval_cache = opts.eval_cache.as_deref();
+    let from_data = opts.from_data.as_deref();
+    let prepare = async {
+        if let Some(path) = from_data {
+            info!("Using the deployment data in {}", path.display());
+            let contents = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| RunError::ReadData(path.to_path_buf(), e))?;
+            return parse_deployment_data(path, &contents, &deploy_flakes);
+        }
+
+        if !skip_checks {
+            for skipped in skip_checks_for {
+                let skipped_slice = std::slice::from_ref(skipped);
+                if !deploy_flakes.iter().any(|item| skips_checks(item.repo, skipped_slice)) {
+                    warn!("`--skip-checks-for {}` matches none of the deployed flakes", skipped);
+                }
+            }
+
+            let mut set = std::collections::HashSet::new();
+            deploy_flakes
+                .iter()
+                .filter(|item| !skips_checks(item.repo, skip_checks_for))
+                .for_each(|item| {
+                    set.insert(item.repo);
+                });
+
+            let check_start = Instant::now();
+            for path in set {
+                check_deployment_cached(
+                    using_flakes,
+                    path,
+                    extra_build_args,
+                    check_systems,
+                    skip_checks_if_unchanged,
+                )
+                .await?;
+            }
+            timings.check = check_start.elapsed().as_secs_f64();
+        }
+        let eval_start = Instant::now();
+        let data = get_deployment_data(
+            &deploy::command::SYSTEM_RUNNER,
+            using_flakes,
+            &deploy_flakes,
+            extra_build_args,
+            eval_cache,
+            cmd_overrides.target_system.as_deref(),
+            cmd_overrides.pure_eval,
+        )
+        .await?;
+        timings.eval = eval_start.elapsed().as_secs_f64();
+        Ok::<_, RunError>(data)
+    };
+    // A failed check or evaluation is reported and announced like a failed deployment
+    let data = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.at, prepare)
+            .await
+            .unwrap_or_else(|_| Err(RunDeployError::DeployTimeout(deadline.secs).into())),
+        None => prepare.await,
+    };
     let result_path = opts.result_path.as_deref();
-    let data = get_deployment_data(using_flakes, &deploy_flakes, &opts.extra_build_args).await?;
-    run_deploy(
-        deploy_flakes,
-        data,
-        using_flakes,
-        opts.checksigs,
-        opts.interactive,
-        &cmd_overrides,
-        opts.keep_result,
-        result_path,
-        &opts.extra_build_args,
-        opts.debug_logs,
-        opts.dry_activate,
-        opts.boot,
-        &opts.log_dir,
-        opts.rollback_succeeded.unwrap_or(true),
-    )
-    .await?;
+    let result = match data {
+        Err(e) => Err(e),
+        Ok(data) => run_deploy(
+                deploy_flakes,
+                data,
+                using_flakes,
+                opts.checksigs.unwrap_or(false),
+                opts.interactive.unwrap_or(false),
+                opts.interactive_per_node.unwrap_or(false),
+                opts.yes,
+                &cmd_overrides,
+                opts.keep_result,
+                result_path,
+                &opts.extra_build_args,
+                opts.debug_logs.unwrap_or(false),
+                opts.dry_activate,
+                opts.boot,
+                &opts.log_dir,
+                opts.rollback_succeeded.unwrap_or(true),
+                opts.batch_size,
+                opts.batch_delay,
+                opts.batch_confirm,
+                &mut timings,
+                &mut report,
+                deadline,
+                &NodeFilter {
+                    tags: &opts.tags,
+                    exclude: &opts.exclude_nodes,
+                    error_on_empty_node: opts.error_on_empty_node,
+                    profile: opts.profile.as_deref(),
+                },
+                opts.diff && !opts.no_diff,
+                opts.keep_going,
+                progress.as_ref(),
+            )
+            .await
+            .map_err(RunError::from),
+    };
+
+    timings.log_summary();
+
+    // A dry run changes nothing, so there is nothing to announce
+    #[cfg(feature = "notify")]
+    if !opts.dry_run {
+        send_notifications(&notifications, &result, &report, &timings).await;
+    }
+
+    if let Some(ref report_json) = opts.report_json {
+        let json = serde_json::to_string_pretty(&report.profiles).map_err(RunError::JsonOutput)?;
+        std::fs::write(report_json, json)
+            .map_err(|e| RunError::WriteReport(report_json.clone(), e))?;
+    }
+
+    if let Some(ref timings_json) = opts.timings_json {
+        let json = serde_json::to_string_pretty(&timings).map_err(RunError::JsonOutput)?;
+        std::fs::write(timings_json, json)
+            .map_err(|e| RunError::WriteTimings(timings_json.clone(), e))?;
+    }
+
+    result?;
 
     Ok(())
 }
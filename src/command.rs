@@ -0,0 +1,241 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::future::Future;
+use std::pin::Pin;
+use std::process::{Output, Stdio};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use zeroize::Zeroizing;
+
+/// A command to run, described as data so that tests can check it without spawning anything
+#[derive(Clone, Default, PartialEq)]
+pub struct CommandSpec {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    /// Written to the command's stdin, which is closed once the command exits. Without it stdin
+    /// is `/dev/null`. It may be a sudo password, so it is cleared from memory when dropped.
+    pub stdin: Option<Zeroizing<String>>,
+    /// Capture stderr into the `Output` instead of passing it through to ours
+    pub capture_stderr: bool,
+    /// Stream stderr to ours as it comes in and keep only its last lines in the `Output`
+    pub tee_stderr: bool,
+}
+
+// Written by hand so that the input, which may be a sudo password, is never logged
+impl std::fmt::Debug for CommandSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CommandSpec")
+            .field("program", &self.program)
+            .field("args", &self.args)
+            .field("env", &self.env)
+            .field("stdin", &self.stdin.as_ref().map(|_| "[REDACTED]"))
+            .field("capture_stderr", &self.capture_stderr)
+            .field("tee_stderr", &self.tee_stderr)
+            .finish()
+    }
+}
+
+impl CommandSpec {
+    pub fn new(program: &str) -> Self {
+        CommandSpec {
+            program: program.to_string(),
+            ..CommandSpec::default()
+        }
+    }
+
+    pub fn arg(&mut self, arg: impl Into<String>) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn env(&mut self, key: &str, value: impl Into<String>) -> &mut Self {
+        self.env.push((key.to_string(), value.into()));
+        self
+    }
+
+    pub fn stdin(&mut self, input: impl Into<Zeroizing<String>>) -> &mut Self {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    pub fn capture_stderr(&mut self) -> &mut Self {
+        self.capture_stderr = true;
+        self
+    }
+
+    pub fn tee_stderr(&mut self) -> &mut Self {
+        self.tee_stderr = true;
+        self
+    }
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Runs the commands deploy-rs needs the output of, so that tests can replace the real processes.
+/// Commands that run for long and are streamed or waited on while other things happen (builds,
+/// copies and the activation itself) are still spawned directly
+pub trait CommandRunner: std::fmt::Debug + Send + Sync {
+    /// Runs the command to completion and captures its stdout
+    fn output<'a>(&'a self, command: &'a CommandSpec) -> BoxFuture<'a, std::io::Result<Output>>;
+}
+
+/// Spawns the commands as processes, killing them if the deployment is aborted
+#[derive(Debug)]
+pub struct SystemRunner;
+
+pub static SYSTEM_RUNNER: SystemRunner = SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output<'a>(&'a self, command: &'a CommandSpec) -> BoxFuture<'a, std::io::Result<Output>> {
+        Box::pin(async move {
+            let mut child = Command::new(&command.program)
+                .args(&command.args)
+                .envs(command.env.iter().map(|(k, v)| (k, v)))
+                .stdin(match command.stdin {
+                    Some(_) => Stdio::piped(),
+                    None => Stdio::null(),
+                })
+                .stdout(Stdio::piped())
+                .stderr(if command.capture_stderr || command.tee_stderr {
+                    Stdio::piped()
+                } else {
+                    Stdio::inherit()
+                })
+                .kill_on_drop(true)
+                .spawn()?;
+
+            if let (Some(input), Some(stdin)) = (&command.stdin, child.stdin.as_mut()) {
+                // The command may exit without reading its input, which is for it to report
+                let _ = stdin.write_all(input.as_bytes()).await;
+            }
+
+            let stderr = if command.tee_stderr {
+                Some(crate::deploy::tee_stderr(&mut child, None))
+            } else {
+                None
+            };
+
+            let mut output = child.wait_with_output().await?;
+            if let Some(stderr) = stderr {
+                let tail = stderr.await.unwrap_or_default();
+                output.stderr = tail.join("\n").into_bytes();
+            }
+
+            Ok(output)
+        })
+    }
+}
+
+/// Records the commands it is asked to run and answers them with queued outputs, in order
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockRunner {
+    calls: std::sync::Mutex<Vec<CommandSpec>>,
+    outputs: std::sync::Mutex<std::collections::VecDeque<Output>>,
+}
+
+#[cfg(test)]
+impl MockRunner {
+    /// Queues the exit code and stdout of the next command
+    pub fn push_output(&self, code: i32, stdout: &str) {
+        self.push_output_with_stderr(code, stdout, "");
+    }
+
+    /// Queues the exit code, stdout and captured stderr of the next command
+    pub fn push_output_with_stderr(&self, code: i32, stdout: &str, stderr: &str) {
+        use std::os::unix::process::ExitStatusExt;
+
+        self.outputs.lock().unwrap().push_back(Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        });
+    }
+
+    /// The commands run so far
+    pub fn calls(&self) -> Vec<CommandSpec> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for MockRunner {
+    fn output<'a>(&'a self, command: &'a CommandSpec) -> BoxFuture<'a, std::io::Result<Output>> {
+        self.calls.lock().unwrap().push(command.clone());
+        let output = self.outputs.lock().unwrap().pop_front().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no output queued for `{}`", command.program),
+            )
+        });
+        Box::pin(async move { output })
+    }
+}
+
+#[tokio::test]
+async fn test_system_runner() {
+    let mut command = CommandSpec::new("sh");
+    command
+        .arg("-c")
+        .arg("read line; echo \"$line $GREETING\"; echo oops >&2; exit 3")
+        .env("GREETING", "world")
+        .stdin("hello\n".to_string())
+        .capture_stderr();
+
+    let output = SYSTEM_RUNNER.output(&command).await.unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"hello world\n");
+    assert_eq!(output.stderr, b"oops\n");
+
+    let mut command = CommandSpec::new("sh");
+    command
+        .arg("-c")
+        .arg("echo out; for i in $(seq 1 30); do echo \"line $i\" >&2; done")
+        .tee_stderr();
+
+    let output = SYSTEM_RUNNER.output(&command).await.unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"out\n");
+    let tail: Vec<String> = (11..=30).map(|i| format!("line {}", i)).collect();
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), tail.join("\n"));
+}
+
+#[test]
+fn test_command_spec_debug_redacts_stdin() {
+    let mut command = CommandSpec::new("ssh");
+    command.arg("example.com").stdin("hunter2\n".to_string());
+
+    let debug = format!("{:?}", command);
+    assert!(debug.contains("example.com"));
+    assert!(debug.contains("[REDACTED]"));
+    assert!(!debug.contains("hunter2"));
+}
+
+#[tokio::test]
+async fn test_mock_runner() {
+    let runner = MockRunner::default();
+    runner.push_output(0, "first");
+    runner.push_output(1, "");
+
+    let mut command = CommandSpec::new("nix");
+    command.arg("eval");
+
+    let output = runner.output(&command).await.unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"first");
+    assert_eq!(runner.output(&command).await.unwrap().status.code(), Some(1));
+    assert!(runner.output(&command).await.is_err());
+    assert_eq!(runner.calls(), vec![command.clone(), command.clone(), command]);
+}
@@ -6,12 +6,44 @@ use merge::Merge;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use thiserror::Error;
+
+/// How the `sudo` command reads the sudo password, if it can read one at all
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SudoKind {
+    /// `sudo`, made to read the password from stdin without printing a prompt
+    Sudo,
+    /// `doas`, which only reads passwords from the terminal, made to fail instead of prompting
+    Doas,
+    /// Any other command that reads the password from stdin, used as is
+    Stdin,
+    /// Any other command that never asks for a password, used as is
+    Plain,
+}
+
+impl SudoKind {
+    /// The flags appended to the sudo command
+    pub fn flags(self) -> Option<&'static str> {
+        match self {
+            SudoKind::Sudo => Some("-S -p \"\""),
+            SudoKind::Doas => Some("-n"),
+            SudoKind::Stdin | SudoKind::Plain => None,
+        }
+    }
+
+    pub fn reads_password(self) -> bool {
+        matches!(self, SudoKind::Sudo | SudoKind::Stdin)
+    }
+}
 
 #[derive(Deserialize, Debug, Clone, Merge)]
 pub struct GenericSettings {
     #[serde(rename(deserialize = "sshUser"))]
     pub ssh_user: Option<String>,
     pub user: Option<String>,
+    #[serde(rename(deserialize = "activateUser"))]
+    pub activate_user: Option<String>,
     #[serde(
         skip_serializing_if = "Vec::is_empty",
         default,
@@ -23,6 +55,8 @@ pub struct GenericSettings {
     pub compress: Option<bool>,
     #[serde(rename(deserialize = "fastConnection"))]
     pub fast_connection: Option<bool>,
+    #[serde(rename(deserialize = "substituteOnDestination"))]
+    pub substitute_on_destination: Option<bool>,
     #[serde(rename(deserialize = "autoRollback"))]
     pub auto_rollback: Option<bool>,
     #[serde(rename(deserialize = "confirmTimeout"))]
@@ -35,6 +69,8 @@ pub struct GenericSettings {
     pub magic_rollback: Option<bool>,
     #[serde(rename(deserialize = "sudo"))]
     pub sudo: Option<String>,
+    #[serde(rename(deserialize = "sudoKind"))]
+    pub sudo_kind: Option<SudoKind>,
     #[serde(default,rename(deserialize = "remoteBuild"))]
     pub remote_build: Option<bool>,
     #[serde(rename(deserialize = "interactiveSudo"))]
@@ -44,6 +80,14 @@ pub struct GenericSettings {
     pub sudo_file: Option<PathBuf>,
     #[serde(rename(deserialize = "sudoSecret"))]
     pub sudo_secret: Option<String>,
+    #[serde(rename(deserialize = "healthCheckCmd"))]
+    pub health_check_cmd: Option<String>,
+    #[serde(rename(deserialize = "healthCheckTimeout"))]
+    pub health_check_timeout: Option<u16>,
+    // Later arguments override earlier ones, so the more specific ones are passed last
+    #[serde(default, rename(deserialize = "extraBuildArgs"))]
+    #[merge(strategy = merge::vec::prepend)]
+    pub extra_build_args: Vec<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -56,6 +100,10 @@ pub struct NodeSettings {
         rename(deserialize = "profilesOrder")
     )]
     pub profiles_order: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, rename(deserialize = "dependsOn"))]
+    pub depends_on: Vec<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -63,6 +111,24 @@ pub struct ProfileSettings {
     pub path: String,
     #[serde(rename(deserialize = "profilePath"))]
     pub profile_path: Option<String>,
+    #[serde(rename(deserialize = "preActivate"))]
+    pub pre_activate: Option<String>,
+    #[serde(rename(deserialize = "postActivate"))]
+    pub post_activate: Option<String>,
+    #[serde(rename(deserialize = "activationScript"))]
+    pub activation_script: Option<String>,
+    #[serde(rename(deserialize = "rebootBefore"))]
+    pub reboot_before: Option<bool>,
+    #[serde(rename(deserialize = "rebootAfter"))]
+    pub reboot_after: Option<bool>,
+    #[serde(rename(deserialize = "noSudo"))]
+    pub no_sudo: Option<bool>,
+    #[serde(rename(deserialize = "keepResult"))]
+    pub keep_result: Option<bool>,
+    #[serde(rename(deserialize = "resultPath"))]
+    pub result_path: Option<String>,
+    #[serde(rename(deserialize = "buildTimeout"))]
+    pub build_timeout: Option<u16>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -87,3 +153,247 @@ pub struct Data {
     pub generic_settings: GenericSettings,
     pub nodes: HashMap<String, Node>,
 }
+
+// `deny_unknown_fields` can't be combined with `#[serde(flatten)]`, so unknown fields are
+// rejected by comparing against these lists before deserializing. Keep them in sync with
+// the `rename`s above.
+const GENERIC_SETTINGS_FIELDS: &[&str] = &[
+    "sshUser",
+    "user",
+    "activateUser",
+    "sshOpts",
+    "compress",
+    "fastConnection",
+    "substituteOnDestination",
+    "autoRollback",
+    "confirmTimeout",
+    "activationTimeout",
+    "tempPath",
+    "magicRollback",
+    "sudo",
+    "sudoKind",
+    "remoteBuild",
+    "interactiveSudo",
+    "sudoFile",
+    "sudoSecret",
+    "healthCheckCmd",
+    "healthCheckTimeout",
+    "extraBuildArgs",
+];
+const NODE_SETTINGS_FIELDS: &[&str] =
+    &["hostname", "profiles", "profilesOrder", "tags", "dependsOn"];
+const PROFILE_SETTINGS_FIELDS: &[&str] = &[
+    "path",
+    "profilePath",
+    "preActivate",
+    "postActivate",
+    "activationScript",
+    "rebootBefore",
+    "rebootAfter",
+    "noSudo",
+    "keepResult",
+    "resultPath",
+    "buildTimeout",
+];
+const DATA_FIELDS: &[&str] = &["nodes"];
+
+#[derive(Error, Debug)]
+pub enum ValidateDataError {
+    #[error("Unknown field `{0}` in the top-level deploy settings")]
+    UnknownField(String),
+    #[error("Unknown field `{1}` in node `{0}`")]
+    UnknownNodeField(String, String),
+    #[error("Unknown field `{2}` in profile `{1}` of node `{0}`")]
+    UnknownProfileField(String, String, String),
+    #[error("`profilesOrder` lists profiles that don't exist: {}", format_ordered_profiles(.0))]
+    UnknownOrderedProfiles(Vec<(String, String)>),
+    #[error("Node `{0}` depends on node `{1}`, which doesn't exist")]
+    UnknownDependency(String, String),
+}
+
+fn format_ordered_profiles(missing: &[(String, String)]) -> String {
+    missing
+        .iter()
+        .map(|(node, profile)| format!("`{}` on node `{}`", profile, node))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// How much of the deployment data was evaluated: targets like `.#node` and `.#node.profile` leave
+/// out the other nodes and profiles, so references to those can't be checked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvalScope {
+    All,
+    Node,
+    Profile,
+}
+
+/// Returns the first key of `settings` that is in none of the `known` field lists
+fn find_unknown_field(settings: &serde_json::Value, known: &[&[&str]]) -> Option<String> {
+    settings.as_object()?.keys().find_map(|key| {
+        if known.iter().any(|fields| fields.contains(&key.as_str())) {
+            None
+        } else {
+            Some(key.clone())
+        }
+    })
+}
+
+/// Checks the evaluated `deploy` attribute for fields deploy-rs doesn't know about, e.g. typos like
+/// `magic_rollback` that would otherwise silently fall back to the default
+pub fn validate(data: &serde_json::Value, scope: EvalScope) -> Result<(), ValidateDataError> {
+    if let Some(field) = find_unknown_field(data, &[GENERIC_SETTINGS_FIELDS, DATA_FIELDS]) {
+        return Err(ValidateDataError::UnknownField(field));
+    }
+
+    let nodes = match data.get("nodes").and_then(|n| n.as_object()) {
+        Some(nodes) => nodes,
+        None => return Ok(()),
+    };
+
+    // Collected over all nodes, so every typo is reported at once
+    let mut missing_ordered_profiles = Vec::new();
+
+    for (node_name, node) in nodes {
+        if let Some(field) = find_unknown_field(node, &[GENERIC_SETTINGS_FIELDS, NODE_SETTINGS_FIELDS]) {
+            return Err(ValidateDataError::UnknownNodeField(node_name.clone(), field));
+        }
+
+        let depends_on = node.get("dependsOn").and_then(|d| d.as_array());
+        for dependency in depends_on.into_iter().flatten().filter_map(|d| d.as_str()) {
+            // The other nodes aren't evaluated for a `.#node` target, which still deploys on its own
+            if scope == EvalScope::All && !nodes.contains_key(dependency) {
+                return Err(ValidateDataError::UnknownDependency(
+                    node_name.clone(),
+                    dependency.to_string(),
+                ));
+            }
+        }
+
+        let profiles = match node.get("profiles").and_then(|p| p.as_object()) {
+            Some(profiles) => profiles,
+            None => continue,
+        };
+
+        let profiles_order = node.get("profilesOrder").and_then(|o| o.as_array());
+        for profile_name in profiles_order.into_iter().flatten().filter_map(|p| p.as_str()) {
+            // Only the deployed profile is left of a `.#node.profile` target
+            if scope != EvalScope::Profile && !profiles.contains_key(profile_name) {
+                missing_ordered_profiles.push((node_name.clone(), profile_name.to_string()));
+            }
+        }
+
+        for (profile_name, profile) in profiles {
+            if let Some(field) =
+                find_unknown_field(profile, &[GENERIC_SETTINGS_FIELDS, PROFILE_SETTINGS_FIELDS])
+            {
+                return Err(ValidateDataError::UnknownProfileField(
+                    node_name.clone(),
+                    profile_name.clone(),
+                    field,
+                ));
+            }
+        }
+    }
+
+    if !missing_ordered_profiles.is_empty() {
+        return Err(ValidateDataError::UnknownOrderedProfiles(missing_ordered_profiles));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_validate() {
+    let valid = serde_json::json!({
+        "sshUser": "admin",
+        "nodes": {
+            "foo": {
+                "hostname": "foo.example.com",
+                "magicRollback": false,
+                "profiles": {
+                    "system": { "path": "/nix/store/abc-system", "user": "root" }
+                }
+            }
+        }
+    });
+    validate(&valid, EvalScope::All).unwrap();
+    serde_json::from_value::<Data>(valid).unwrap();
+
+    let typo_in_profile = serde_json::json!({
+        "nodes": {
+            "foo": {
+                "hostname": "foo.example.com",
+                "profiles": {
+                    "system": { "path": "/nix/store/abc-system", "magic_rollback": false }
+                }
+            }
+        }
+    });
+    let err = validate(&typo_in_profile, EvalScope::All).unwrap_err().to_string();
+    assert!(err.contains("`magic_rollback`"));
+    assert!(err.contains("`system`"));
+    assert!(err.contains("`foo`"));
+
+    let typo_in_node = serde_json::json!({
+        "nodes": { "foo": { "hostname": "foo.example.com", "hostnmae": "x", "profiles": {} } }
+    });
+    assert!(matches!(
+        validate(&typo_in_node, EvalScope::All),
+        Err(ValidateDataError::UnknownNodeField(node, field)) if node == "foo" && field == "hostnmae"
+    ));
+
+    let typo_top_level = serde_json::json!({ "ssh_user": "admin", "nodes": {} });
+    assert!(matches!(
+        validate(&typo_top_level, EvalScope::All),
+        Err(ValidateDataError::UnknownField(field)) if field == "ssh_user"
+    ));
+
+    let typo_in_order = serde_json::json!({
+        "nodes": {
+            "foo": {
+                "hostname": "foo.example.com",
+                "profilesOrder": ["system", "hoem"],
+                "profiles": { "system": { "path": "/nix/store/abc-system" } }
+            },
+            "bar": {
+                "hostname": "bar.example.com",
+                "profilesOrder": ["sytem"],
+                "profiles": { "system": { "path": "/nix/store/abc-system" } }
+            }
+        }
+    });
+    let err = validate(&typo_in_order, EvalScope::All).unwrap_err().to_string();
+    assert!(err.contains("`sytem` on node `bar`"));
+    assert!(err.contains("`hoem` on node `foo`"));
+
+    // Deploying `.#foo.system` only evaluates that profile, the others in the order are left out
+    let one_profile = serde_json::json!({
+        "nodes": {
+            "foo": {
+                "hostname": "foo.example.com",
+                "profilesOrder": ["system", "home", "backup"],
+                "profiles": { "system": { "path": "/nix/store/abc-system" } }
+            }
+        }
+    });
+    validate(&one_profile, EvalScope::Profile).unwrap();
+    assert!(matches!(
+        validate(&one_profile, EvalScope::Node),
+        Err(ValidateDataError::UnknownOrderedProfiles(missing)) if missing.len() == 2
+    ));
+
+    let unknown_dependency = serde_json::json!({
+        "nodes": {
+            "app": { "hostname": "app.example.com", "dependsOn": ["db"], "profiles": {} }
+        }
+    });
+    assert!(matches!(
+        validate(&unknown_dependency, EvalScope::All),
+        Err(ValidateDataError::UnknownDependency(node, dependency)) if node == "app" && dependency == "db"
+    ));
+
+    // `.#app` only evaluates `app`, the `db` it depends on is then simply not deployed
+    validate(&unknown_dependency, EvalScope::Node).unwrap();
+    validate(&unknown_dependency, EvalScope::Profile).unwrap();
+}
@@ -4,17 +4,218 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use std::collections::VecDeque;
 use std::path::Path;
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tokio::{io::AsyncWriteExt, process::Command};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+};
 
+use crate::command::CommandSpec;
 use crate::{DeployDataDefsError, DeployDefs, ProfileInfo};
 
+/// Quotes `s` for the remote shell, so that spaces, quotes or `$` in it are taken literally
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// `s` as a single shell word, only quoted if it has to be
+fn shell_word(s: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-+=@%^:,./".contains(c);
+    if !s.is_empty() && s.chars().all(plain) {
+        s.to_string()
+    } else {
+        shell_quote(s)
+    }
+}
+
+/// The command as it could be typed into a shell, with the environment variables it sets
+pub(crate) fn format_command(command: &std::process::Command) -> String {
+    let env = command
+        .get_envs()
+        .filter_map(|(k, v)| v.map(|v| (k, v)))
+        .map(|(k, v)| format!("{}={}", k.to_string_lossy(), shell_word(&v.to_string_lossy())));
+    let program = std::iter::once(command.get_program()).chain(command.get_args());
+
+    env.chain(program.map(|arg| shell_word(&arg.to_string_lossy())))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Logs the command that `--dry-run` doesn't run
+pub(crate) fn log_dry_run(command: &Command) {
+    info!("[dry run] Would run: {}", format_command(command.as_std()));
+}
+
+#[test]
+fn test_format_command() {
+    let mut command = std::process::Command::new("nix");
+    command
+        .arg("copy")
+        .arg("--to")
+        .arg("ssh://root@example.com?compress=true")
+        .arg("/nix/store/abc-system")
+        .env("NIX_SSHOPTS", "-p 2222");
+    assert_eq!(
+        format_command(&command),
+        "NIX_SSHOPTS='-p 2222' nix copy --to 'ssh://root@example.com?compress=true' /nix/store/abc-system"
+    );
+
+    let mut command = std::process::Command::new("ssh");
+    command.arg("root@example.com").arg("echo hi").arg("it's").arg("");
+    assert_eq!(
+        format_command(&command),
+        "ssh root@example.com 'echo hi' 'it'\\''s' ''"
+    );
+}
+
+#[test]
+fn test_shell_quote() {
+    let inputs = [
+        "/nix/store/abc-system",
+        "",
+        "with space",
+        "it's",
+        "'''",
+        "a\"b",
+        "$(touch /tmp/pwned) `id` $HOME",
+        "line\nbreak",
+        "back\\slash",
+    ];
+
+    for input in inputs {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote(input)))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), input);
+    }
+
+    assert_eq!(shell_quote("it's"), "'it'\\''s'");
+}
+
+/// The arguments selecting the profile to act on for `activate-rs`
+fn profile_info_args(profile_info: &ProfileInfo) -> String {
+    match profile_info {
+        ProfileInfo::ProfilePath { profile_path } => {
+            format!("--profile-path {}", shell_quote(profile_path))
+        }
+        ProfileInfo::ProfileUserAndName {
+            profile_user,
+            profile_name,
+        } => format!(
+            "--profile-user {} --profile-name {}",
+            shell_quote(profile_user),
+            shell_quote(profile_name)
+        ),
+    }
+}
+
+/// A shell script setting `$profile` to the path of the profile on the node, found the same way as
+/// `activate-rs` finds it
+fn profile_path_script(profile_info: &ProfileInfo) -> String {
+    let (profile_user, profile_name) = match profile_info {
+        ProfileInfo::ProfilePath { profile_path } => {
+            return format!("profile={}", shell_quote(profile_path))
+        }
+        ProfileInfo::ProfileUserAndName {
+            profile_user,
+            profile_name,
+        } => (profile_user, profile_name),
+    };
+
+    let state_dir = "\"${NIX_STATE_DIR:-/nix/var/nix}\"";
+    match (profile_user.as_str(), profile_name.as_str()) {
+        ("root", "system") => format!("profile={}/profiles/system", state_dir),
+        ("root", _) => format!(
+            "profile={}/profiles/per-user/root/{}",
+            state_dir,
+            shell_quote(profile_name)
+        ),
+        _ => format!(
+            "if [ -e {0}/profiles/per-user/{1} ]; then profile={0}/profiles/per-user/{1}/{2}; \
+             else profile=\"${{XDG_STATE_HOME:-$HOME/.local/state}}\"/nix/profiles/{2}; fi",
+            state_dir,
+            shell_quote(profile_user),
+            shell_quote(profile_name)
+        ),
+    }
+}
+
+#[test]
+fn test_profile_path_script() {
+    let path = |profile_info: &ProfileInfo, home: &str| {
+        let script = format!("{}; printf %s \"$profile\"", profile_path_script(profile_info));
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .env_remove("NIX_STATE_DIR")
+            .env_remove("XDG_STATE_HOME")
+            .env("HOME", home)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let user_and_name = |profile_user: &str, profile_name: &str| ProfileInfo::ProfileUserAndName {
+        profile_user: profile_user.to_string(),
+        profile_name: profile_name.to_string(),
+    };
+
+    assert_eq!(
+        path(&ProfileInfo::ProfilePath { profile_path: "/some profile".to_string() }, "/root"),
+        "/some profile"
+    );
+    assert_eq!(path(&user_and_name("root", "system"), "/root"), "/nix/var/nix/profiles/system");
+    assert_eq!(
+        path(&user_and_name("root", "home"), "/root"),
+        "/nix/var/nix/profiles/per-user/root/home"
+    );
+    assert_eq!(
+        path(&user_and_name("deploy-rs-test-nobody", "it's"), "/home/bob"),
+        "/home/bob/.local/state/nix/profiles/it's"
+    );
+}
+
+/// The path of `activation_script` in `closure`, or without one in the profile as it is deployed
+/// on the node (`$profile`, see `wrap_activate_command`)
+fn activation_script_path(closure: Option<&str>, activation_script: &str) -> String {
+    match closure {
+        Some(closure) => shell_quote(&format!("{}/{}", closure, activation_script)),
+        None => format!("\"$profile\"/{}", shell_quote(activation_script)),
+    }
+}
+
+/// Runs the `activate-rs` `command` with `sudo`. Without a `closure` it runs in a shell that looks
+/// up the deployed profile first, which is where its `activate-rs` comes from.
+fn wrap_activate_command(
+    sudo: &Option<String>,
+    closure: Option<&str>,
+    profile_info: &ProfileInfo,
+    command: String,
+) -> String {
+    let command = match closure {
+        Some(_) => command,
+        None => format!(
+            "sh -c {}",
+            shell_quote(&format!("{}; exec {}", profile_path_script(profile_info), command))
+        ),
+    };
+
+    match sudo {
+        Some(sudo_cmd) => format!("{} {}", sudo_cmd, command),
+        None => command,
+    }
+}
+
 struct ActivateCommandData<'a> {
     sudo: &'a Option<String>,
     profile_info: &'a ProfileInfo,
     closure: &'a str,
+    activation_script: &'a str,
     auto_rollback: bool,
     temp_path: &'a Path,
     confirm_timeout: u16,
@@ -26,32 +227,23 @@ struct ActivateCommandData<'a> {
 }
 
 fn build_activate_command(data: &ActivateCommandData) -> String {
-    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+    let mut self_activate_command =
+        shell_quote(&format!("{}/{}", data.closure, data.activation_script));
 
     if data.debug_logs {
         self_activate_command = format!("{} --debug-logs", self_activate_command);
     }
 
     if let Some(log_dir) = data.log_dir {
-        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, shell_quote(log_dir));
     }
 
     self_activate_command = format!(
-        "{} activate '{}' {} --temp-path '{}'",
+        "{} activate {} {} --temp-path {}",
         self_activate_command,
-        data.closure,
-        match data.profile_info {
-            ProfileInfo::ProfilePath { profile_path } =>
-                format!("--profile-path '{}'", profile_path),
-            ProfileInfo::ProfileUserAndName {
-                profile_user,
-                profile_name,
-            } => format!(
-                "--profile-user {} --profile-name {}",
-                profile_user, profile_name
-            ),
-        },
-        data.temp_path.display()
+        shell_quote(data.closure),
+        profile_info_args(data.profile_info),
+        shell_quote(&data.temp_path.to_string_lossy())
     );
 
     self_activate_command = format!(
@@ -103,6 +295,7 @@ fn test_activation_command_builder() {
             sudo: &sudo,
             profile_info,
             closure,
+            activation_script: "activate-rs",
             auto_rollback,
             temp_path,
             confirm_timeout,
@@ -112,7 +305,34 @@ fn test_activation_command_builder() {
             dry_activate,
             boot,
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback"
+        "sudo -u test '/nix/store/blah/etc/activate-rs' --debug-logs --log-dir '/tmp/something.txt' activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback"
+            .to_string(),
+    );
+}
+
+#[test]
+fn test_activation_command_builder_without_sudo() {
+    let profile_info = &ProfileInfo::ProfileUserAndName {
+        profile_user: "alice".to_string(),
+        profile_name: "home-manager".to_string(),
+    };
+
+    assert_eq!(
+        build_activate_command(&ActivateCommandData {
+            sudo: &None,
+            profile_info,
+            closure: "/nix/store/blah/etc",
+            activation_script: "activate-rs",
+            auto_rollback: true,
+            temp_path: Path::new("/tmp"),
+            confirm_timeout: 30,
+            magic_rollback: true,
+            debug_logs: false,
+            log_dir: None,
+            dry_activate: false,
+            boot: false,
+        }),
+        "'/nix/store/blah/etc/activate-rs' activate '/nix/store/blah/etc' --profile-user 'alice' --profile-name 'home-manager' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback"
             .to_string(),
     );
 }
@@ -120,6 +340,7 @@ fn test_activation_command_builder() {
 struct WaitCommandData<'a> {
     sudo: &'a Option<String>,
     closure: &'a str,
+    activation_script: &'a str,
     temp_path: &'a Path,
     activation_timeout: Option<u16>,
     debug_logs: bool,
@@ -127,21 +348,22 @@ struct WaitCommandData<'a> {
 }
 
 fn build_wait_command(data: &WaitCommandData) -> String {
-    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+    let mut self_activate_command =
+        shell_quote(&format!("{}/{}", data.closure, data.activation_script));
 
     if data.debug_logs {
         self_activate_command = format!("{} --debug-logs", self_activate_command);
     }
 
     if let Some(log_dir) = data.log_dir {
-        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, shell_quote(log_dir));
     }
 
     self_activate_command = format!(
-        "{} wait '{}' --temp-path '{}'",
+        "{} wait {} --temp-path {}",
         self_activate_command,
-        data.closure,
-        data.temp_path.display(),
+        shell_quote(data.closure),
+        shell_quote(&data.temp_path.to_string_lossy()),
     );
     if let Some(activation_timeout) = data.activation_timeout {
         self_activate_command = format!("{} --activation-timeout {}", self_activate_command, activation_timeout);
@@ -167,56 +389,67 @@ fn test_wait_command_builder() {
         build_wait_command(&WaitCommandData {
             sudo: &sudo,
             closure,
+            activation_script: "bin/activate-wrapper",
             temp_path,
             activation_timeout,
             debug_logs,
             log_dir
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt wait '/nix/store/blah/etc' --temp-path '/tmp' --activation-timeout 600"
+        "sudo -u test '/nix/store/blah/etc/bin/activate-wrapper' --debug-logs --log-dir '/tmp/something.txt' wait '/nix/store/blah/etc' --temp-path '/tmp' --activation-timeout 600"
+            .to_string(),
+    );
+}
+
+#[test]
+fn test_wait_command_builder_without_sudo() {
+    assert_eq!(
+        build_wait_command(&WaitCommandData {
+            sudo: &None,
+            closure: "/nix/store/blah/etc",
+            activation_script: "activate-rs",
+            temp_path: Path::new("/tmp"),
+            activation_timeout: None,
+            debug_logs: false,
+            log_dir: None,
+        }),
+        "'/nix/store/blah/etc/activate-rs' wait '/nix/store/blah/etc' --temp-path '/tmp'"
             .to_string(),
     );
 }
 
 struct RevokeCommandData<'a> {
     sudo: &'a Option<String>,
-    closure: &'a str,
+    /// Without one, the `activate-rs` of the profile deployed on the node is used
+    closure: Option<&'a str>,
+    activation_script: &'a str,
     profile_info: ProfileInfo,
+    to_generation: Option<u32>,
     debug_logs: bool,
     log_dir: Option<&'a str>,
 }
 
 fn build_revoke_command(data: &RevokeCommandData) -> String {
-    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+    let mut self_activate_command = activation_script_path(data.closure, data.activation_script);
 
     if data.debug_logs {
         self_activate_command = format!("{} --debug-logs", self_activate_command);
     }
 
     if let Some(log_dir) = data.log_dir {
-        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, shell_quote(log_dir));
     }
 
     self_activate_command = format!(
         "{} revoke {}",
         self_activate_command,
-        match &data.profile_info {
-            ProfileInfo::ProfilePath { profile_path } =>
-                format!("--profile-path '{}'", profile_path),
-            ProfileInfo::ProfileUserAndName {
-                profile_user,
-                profile_name,
-            } => format!(
-                "--profile-user {} --profile-name {}",
-                profile_user, profile_name
-            ),
-        }
+        profile_info_args(&data.profile_info)
     );
 
-    if let Some(sudo_cmd) = &data.sudo {
-        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    if let Some(to_generation) = data.to_generation {
+        self_activate_command = format!("{} --to-generation {}", self_activate_command, to_generation);
     }
 
-    self_activate_command
+    wrap_activate_command(data.sudo, data.closure, &data.profile_info, self_activate_command)
 }
 
 #[test]
@@ -232,184 +465,1242 @@ fn test_revoke_command_builder() {
     assert_eq!(
         build_revoke_command(&RevokeCommandData {
             sudo: &sudo,
-            closure,
+            closure: Some(closure),
+            activation_script: "activate-rs",
             profile_info,
+            to_generation: None,
             debug_logs,
             log_dir
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt revoke --profile-path '/nix/var/nix/per-user/user/profile'"
+        "sudo -u test '/nix/store/blah/etc/activate-rs' --debug-logs --log-dir '/tmp/something.txt' revoke --profile-path '/nix/var/nix/per-user/user/profile'"
             .to_string(),
     );
 }
 
-async fn handle_sudo_stdin(ssh_activate_child: &mut tokio::process::Child, deploy_defs: &DeployDefs) -> Result<(), std::io::Error> {
-    match ssh_activate_child.stdin.as_mut() {
-        Some(stdin) => {
-            let _ = stdin.write_all(format!("{}\n",deploy_defs.sudo_password.clone().unwrap_or("".to_string())).as_bytes()).await;
-            Ok(())
-        }
-        None => {
-            Err(
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to open stdin for sudo command",
-                )
-            )
-        }
-    }
+#[test]
+fn test_revoke_to_generation_command_builder() {
+    let sudo = None;
+    let closure = "/nix/store/blah/etc";
+    let profile_info = ProfileInfo::ProfileUserAndName {
+        profile_user: "root".to_string(),
+        profile_name: "system".to_string(),
+    };
+
+    assert_eq!(
+        build_revoke_command(&RevokeCommandData {
+            sudo: &sudo,
+            closure: Some(closure),
+            activation_script: "activate-rs",
+            profile_info,
+            to_generation: Some(42),
+            debug_logs: false,
+            log_dir: None
+        }),
+        "'/nix/store/blah/etc/activate-rs' revoke --profile-user 'root' --profile-name 'system' --to-generation 42"
+            .to_string(),
+    );
 }
 
-#[derive(Error, Debug)]
-pub enum ConfirmProfileError {
-    #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
-    SSHConfirm(std::io::Error),
-    #[error(
-        "Confirming activation over SSH resulted in a bad exit code (the server should roll back): {0:?}"
-    )]
-    SSHConfirmExit(Option<i32>),
+struct ListGenerationsCommandData<'a> {
+    sudo: &'a Option<String>,
+    /// Without one, the `activate-rs` of the profile deployed on the node is used
+    closure: Option<&'a str>,
+    activation_script: &'a str,
+    profile_info: ProfileInfo,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
 }
 
-pub async fn confirm_profile(
-    deploy_data: &super::DeployData<'_>,
-    deploy_defs: &super::DeployDefs,
-    temp_path: &Path,
-    ssh_addr: &str,
-) -> Result<(), ConfirmProfileError> {
-    let mut ssh_confirm_command = Command::new("ssh");
-    ssh_confirm_command
-        .arg(ssh_addr)
-        .stdin(std::process::Stdio::piped());
+fn build_list_generations_command(data: &ListGenerationsCommandData) -> String {
+    let mut self_activate_command = activation_script_path(data.closure, data.activation_script);
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_confirm_command.arg(ssh_opt);
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
     }
 
-    let lock_path = super::make_lock_path(temp_path, &deploy_data.profile.profile_settings.path);
-
-    let mut confirm_command = format!("rm {}", lock_path.display());
-    if let Some(sudo_cmd) = &deploy_defs.sudo {
-        confirm_command = format!("{} {}", sudo_cmd, confirm_command);
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, shell_quote(log_dir));
     }
 
-    debug!(
-        "Attempting to run command to confirm deployment: {}",
-        confirm_command
+    self_activate_command = format!(
+        "{} list-generations {}",
+        self_activate_command,
+        profile_info_args(&data.profile_info)
     );
 
-    let mut ssh_confirm_child = ssh_confirm_command
-        .arg(confirm_command)
-        .spawn()
-        .map_err(ConfirmProfileError::SSHConfirm)?;
-    
-    if deploy_data
-        .merged_settings
-        .interactive_sudo
-        .unwrap_or(false)
-        || deploy_data.merged_settings.sudo_secret.is_some()
-    {
-        trace!("[confirm] Piping in sudo password");
-        handle_sudo_stdin(&mut ssh_confirm_child, deploy_defs)
-            .await
-            .map_err(ConfirmProfileError::SSHConfirm)?;
-    }
-
-    let ssh_confirm_exit_status = ssh_confirm_child
-        .wait()
-        .await
-        .map_err(ConfirmProfileError::SSHConfirm)?; 
+    wrap_activate_command(data.sudo, data.closure, &data.profile_info, self_activate_command)
+}
 
-    match ssh_confirm_exit_status.code() {
-        Some(0) => (),
-        a => return Err(ConfirmProfileError::SSHConfirmExit(a)),
+#[test]
+fn test_list_generations_command_builder() {
+    let sudo = Some("sudo -u test".to_string());
+    let closure = "/nix/store/blah/etc";
+    let profile_info = ProfileInfo::ProfilePath {
+        profile_path: "/nix/var/nix/per-user/user/profile".to_string(),
     };
 
-    info!("Deployment confirmed.");
+    assert_eq!(
+        build_list_generations_command(&ListGenerationsCommandData {
+            sudo: &sudo,
+            closure: Some(closure),
+            activation_script: "activate-rs",
+            profile_info,
+            debug_logs: false,
+            log_dir: None
+        }),
+        "sudo -u test '/nix/store/blah/etc/activate-rs' list-generations --profile-path '/nix/var/nix/per-user/user/profile'"
+            .to_string(),
+    );
 
-    Ok(())
+    // The `rollback` and `list-generations` subcommands use the `activate-rs` that is deployed
+    let deployed = "profile=\"${NIX_STATE_DIR:-/nix/var/nix}\"/profiles/system; \
+                    exec \"$profile\"/'activate-rs' list-generations \
+                    --profile-user 'root' --profile-name 'system'";
+    assert_eq!(
+        build_list_generations_command(&ListGenerationsCommandData {
+            sudo: &sudo,
+            closure: None,
+            activation_script: "activate-rs",
+            profile_info: ProfileInfo::ProfileUserAndName {
+                profile_user: "root".to_string(),
+                profile_name: "system".to_string(),
+            },
+            debug_logs: false,
+            log_dir: None
+        }),
+        format!("sudo -u test sh -c {}", shell_quote(deployed)),
+    );
 }
 
-#[derive(Error, Debug)]
-pub enum DeployProfileError {
-    #[error("Failed to spawn activation command over SSH: {0}")]
-    SSHSpawnActivate(std::io::Error),
+struct DiffClosuresCommandData<'a> {
+    sudo: &'a Option<String>,
+    closure: &'a str,
+    activation_script: &'a str,
+    profile_info: ProfileInfo,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+}
 
-    #[error("Failed to run activation command over SSH: {0}")]
-    SSHActivate(std::io::Error),
-    #[error("Activating over SSH resulted in a bad exit code: {0:?}")]
-    SSHActivateExit(Option<i32>),
-    #[error("Activating over SSH resulted in a bad exit code: {0:?}")]
-    SSHActivateTimeout(tokio::sync::oneshot::error::RecvError),
+fn build_diff_closures_command(data: &DiffClosuresCommandData) -> String {
+    let mut self_activate_command =
+        shell_quote(&format!("{}/{}", data.closure, data.activation_script));
 
-    #[error("Failed to run wait command over SSH: {0}")]
-    SSHWait(std::io::Error),
-    #[error("Waiting over SSH resulted in a bad exit code: {0:?}")]
-    SSHWaitExit(Option<i32>),
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
 
-    #[error("Failed to pipe to child stdin: {0}")]
-    SSHActivatePipe(std::io::Error),
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, shell_quote(log_dir));
+    }
 
-    #[error("Error confirming deployment: {0}")]
-    Confirm(#[from] ConfirmProfileError),
-    #[error("Deployment data invalid: {0}")]
-    InvalidDeployDataDefs(#[from] DeployDataDefsError),
-}
+    self_activate_command = format!(
+        "{} diff-closures {} {}",
+        self_activate_command,
+        shell_quote(data.closure),
+        profile_info_args(&data.profile_info)
+    );
 
-pub async fn deploy_profile(
-    deploy_data: &super::DeployData<'_>,
-    deploy_defs: &super::DeployDefs,
-    dry_activate: bool,
-    boot: bool,
-) -> Result<(), DeployProfileError> {
-    if !dry_activate {
-        info!(
-            "Activating profile `{}` for node `{}`",
-            deploy_data.profile_name, deploy_data.node_name
-        );
+    if let Some(sudo_cmd) = &data.sudo {
+        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
     }
 
-    let temp_path: &Path = match &deploy_data.merged_settings.temp_path {
-        Some(x) => x,
-        None => Path::new("/tmp"),
-    };
+    self_activate_command
+}
 
-    let confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
+#[test]
+fn test_diff_closures_command_builder() {
+    let closure = "/nix/store/blah/etc";
+    let profile_info = ProfileInfo::ProfileUserAndName {
+        profile_user: "root".to_string(),
+        profile_name: "system".to_string(),
+    };
 
-    let activation_timeout = deploy_data.merged_settings.activation_timeout;
+    assert_eq!(
+        build_diff_closures_command(&DiffClosuresCommandData {
+            sudo: &None,
+            closure,
+            activation_script: "activate-rs",
+            profile_info,
+            debug_logs: true,
+            log_dir: None
+        }),
+        "'/nix/store/blah/etc/activate-rs' --debug-logs diff-closures '/nix/store/blah/etc' --profile-user 'root' --profile-name 'system'"
+            .to_string(),
+    );
+}
 
-    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+struct CurrentProfileCommandData<'a> {
+    sudo: &'a Option<String>,
+    closure: &'a str,
+    activation_script: &'a str,
+    profile_info: ProfileInfo,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+}
 
-    let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
+fn build_current_profile_command(data: &CurrentProfileCommandData) -> String {
+    let mut self_activate_command =
+        shell_quote(&format!("{}/{}", data.closure, data.activation_script));
 
-    let self_activate_command = build_activate_command(&ActivateCommandData {
-        sudo: &deploy_defs.sudo,
-        profile_info: &deploy_data.get_profile_info()?,
-        closure: &deploy_data.profile.profile_settings.path,
-        auto_rollback,
-        temp_path: temp_path,
-        confirm_timeout,
-        magic_rollback,
-        debug_logs: deploy_data.debug_logs,
-        log_dir: deploy_data.log_dir,
-        dry_activate,
-        boot,
-    });
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
 
-    debug!("Constructed activation command: {}", self_activate_command);
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, shell_quote(log_dir));
+    }
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
+    self_activate_command = format!(
+        "{} current-profile {}",
+        self_activate_command,
+        profile_info_args(&data.profile_info)
+    );
+
+    if let Some(sudo_cmd) = &data.sudo {
+        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_current_profile_command_builder() {
+    let closure = "/nix/store/blah/etc";
+    let profile_info = ProfileInfo::ProfilePath {
+        profile_path: "/home/bob/.local/state/nix/profiles/test".to_string(),
+    };
+
+    assert_eq!(
+        build_current_profile_command(&CurrentProfileCommandData {
+            sudo: &Some("sudo -u test".to_string()),
+            closure,
+            activation_script: "activate-rs",
+            profile_info,
+            debug_logs: false,
+            log_dir: None
+        }),
+        "sudo -u test '/nix/store/blah/etc/activate-rs' current-profile --profile-path '/home/bob/.local/state/nix/profiles/test'"
+            .to_string(),
+    );
+}
+
+/// A single entry of `nix-env --list-generations`
+#[derive(Debug, PartialEq, Clone, serde::Serialize)]
+pub struct Generation {
+    pub id: u32,
+    pub date: String,
+    pub current: bool,
+}
+
+/// Parses the output of `nix-env --list-generations`, skipping lines that don't look like generations
+pub fn parse_generations(output: &str) -> Vec<Generation> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let id = fields.next()?.parse().ok()?;
+            let date = [fields.next()?, fields.next()?].join(" ");
+            let current = fields.next() == Some("(current)");
+
+            Some(Generation { id, date, current })
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_generations() {
+    let output = "   1   2024-01-02 10:00:00   \n   2   2024-01-03 11:30:15   \n   3   2024-01-04 09:12:01   (current)\n";
+
+    assert_eq!(
+        parse_generations(output),
+        vec![
+            Generation {
+                id: 1,
+                date: "2024-01-02 10:00:00".to_string(),
+                current: false,
+            },
+            Generation {
+                id: 2,
+                date: "2024-01-03 11:30:15".to_string(),
+                current: false,
+            },
+            Generation {
+                id: 3,
+                date: "2024-01-04 09:12:01".to_string(),
+                current: true,
+            },
+        ]
+    );
+
+    assert_eq!(parse_generations(""), vec![]);
+}
+
+async fn handle_sudo_stdin(ssh_activate_child: &mut tokio::process::Child, deploy_defs: &DeployDefs) -> Result<(), std::io::Error> {
+    // Commands like `doas` only read passwords from a terminal, so there is nothing to send them
+    let sudo_stdin = match deploy_defs.sudo_stdin() {
+        Some(sudo_stdin) => sudo_stdin,
+        None => return Ok(()),
+    };
+
+    match ssh_activate_child.stdin.as_mut() {
+        Some(stdin) => {
+            let _ = stdin.write_all(sudo_stdin.as_bytes()).await;
+            Ok(())
+        }
+        None => {
+            Err(
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Failed to open stdin for sudo command",
+                )
+            )
+        }
+    }
+}
+
+/// How many lines of the activation's output are kept to explain why it failed
+const STDERR_TAIL_LINES: usize = 20;
+
+/// What to prefix the output of the commands run for `deploy_data` with, if `--prefix-output` is
+/// given
+pub(crate) fn output_prefix(deploy_data: &crate::DeployData) -> Option<String> {
+    if deploy_data.cmd_overrides.prefix_output {
+        Some(format!("{}/{}", deploy_data.node_name, deploy_data.profile_name))
+    } else {
+        None
+    }
+}
+
+/// Logs the lines of `output` as they come in, resolving to the last of them once it closes. With a
+/// `prefix` the lines start with `[<prefix>]`, otherwise they are printed as they are.
+fn tee_lines<R>(output: Option<R>, prefix: Option<String>) -> tokio::task::JoinHandle<Vec<String>>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    let tee = async move {
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+
+        if let Some(output) = output {
+            let mut lines = BufReader::new(output).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let logged = colored_line(&line, yansi::Paint::is_enabled());
+                match prefix {
+                    Some(ref prefix) => info!("[{}] {}", prefix, logged),
+                    None => info!(target: crate::OUTPUT_LOG_TARGET, "{}", logged),
+                }
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                // activate-rs colors its log lines, which the errors and reports shouldn't contain
+                tail.push_back(strip_ansi_escapes::strip_str(&line));
+            }
+        }
+
+        tail.into_iter().collect()
+    };
+
+    spawn_in_log_context(tee)
+}
+
+/// Spawns `future` in the `LOG_CONTEXT` of the caller, which a spawned task doesn't inherit, so that
+/// what it logs is still attributed to the node being deployed, e.g. for `--per-node-logs`
+fn spawn_in_log_context<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match crate::LOG_CONTEXT.try_with(|c| c.clone()) {
+        Ok(context) => tokio::spawn(crate::LOG_CONTEXT.scope(context, future)),
+        Err(_) => tokio::spawn(future),
+    }
+}
+
+#[tokio::test]
+async fn test_spawn_in_log_context() {
+    let node = || crate::LOG_CONTEXT.try_with(|c| c.node.clone()).ok();
+    let context = crate::LogContext {
+        node: "web".to_string(),
+        profile: "system".to_string(),
+    };
+
+    let spawned = crate::LOG_CONTEXT.scope(context, async { spawn_in_log_context(async move { node() }).await });
+    assert_eq!(spawned.await.unwrap().as_deref(), Some("web"));
+    assert_eq!(spawn_in_log_context(async move { node() }).await.unwrap(), None);
+}
+
+/// activate-rs colors its log lines even though it runs without a terminal, the colors are only
+/// passed on if our own logs are colored
+fn colored_line(line: &str, colored: bool) -> std::borrow::Cow<'_, str> {
+    if colored {
+        line.into()
+    } else {
+        strip_ansi_escapes::strip_str(line).into()
+    }
+}
+
+#[test]
+fn test_colored_line() {
+    let line = "\x1b[1;32mINFO\x1b[0m Activating profile";
+
+    assert_eq!(colored_line(line, true), line);
+    assert_eq!(colored_line(line, false), "INFO Activating profile");
+}
+
+/// Streams the child's stderr like `tee_lines`, resolving to its last lines once it closes
+pub(crate) fn tee_stderr(
+    child: &mut tokio::process::Child,
+    prefix: Option<String>,
+) -> tokio::task::JoinHandle<Vec<String>> {
+    tee_lines(child.stderr.take(), prefix)
+}
+
+/// Streams the child's stdout like `tee_lines`
+pub(crate) fn tee_stdout(
+    child: &mut tokio::process::Child,
+    prefix: Option<String>,
+) -> tokio::task::JoinHandle<Vec<String>> {
+    tee_lines(child.stdout.take(), prefix)
+}
+
+/// The last lines of stderr that was captured as a whole
+pub(crate) fn stderr_tail(stderr: &[u8]) -> Vec<String> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..]
+        .iter()
+        .map(strip_ansi_escapes::strip_str)
+        .collect()
+}
+
+pub(crate) fn format_stderr_tail(tail: &[String]) -> String {
+    if tail.is_empty() {
+        String::new()
+    } else {
+        format!(", last lines of output:\n{}", tail.join("\n"))
+    }
+}
+
+#[tokio::test]
+async fn test_tee_stderr() {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg("for i in $(seq 1 30); do echo \"line $i\" >&2; done")
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let tail = tee_stderr(&mut child, None).await.unwrap();
+    child.wait().await.unwrap();
+
+    let expected: Vec<String> = (11..=30).map(|i| format!("line {}", i)).collect();
+    assert_eq!(tail, expected);
+    assert_eq!(
+        format_stderr_tail(&tail[18..]),
+        ", last lines of output:\nline 29\nline 30"
+    );
+    assert_eq!(format_stderr_tail(&[]), "");
+
+    let stderr: String = (1..=30).map(|i| format!("line {}\n", i)).collect();
+    assert_eq!(stderr_tail(stderr.as_bytes()), expected);
+    assert!(stderr_tail(b"").is_empty());
+
+    // Prefixed lines are logged instead, but still kept
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg("echo building; echo done")
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let tail = tee_stdout(&mut child, Some("web/system".to_string())).await.unwrap();
+    child.wait().await.unwrap();
+    assert_eq!(tail, vec!["building", "done"]);
+
+    // The colors of activate-rs are left out of the kept lines
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg("printf '\\033[31mERROR\\033[0m activation failed\\n' >&2")
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let tail = tee_stderr(&mut child, None).await.unwrap();
+    child.wait().await.unwrap();
+    assert_eq!(tail, vec!["ERROR activation failed"]);
+    assert_eq!(
+        stderr_tail(b"\x1b[1;31mERROR\x1b[0m activation failed\n"),
+        vec!["ERROR activation failed"]
+    );
+}
+
+#[test]
+fn test_output_prefix() {
+    let prefix = |cmd_overrides: crate::CmdOverrides| {
+        let deploy_data = crate::test_deploy_data(
+            "web",
+            serde_json::json!({ "hostname": "web.example.com", "profiles": {} }),
+            serde_json::json!({ "path": "/nix/store/00000000000000000000000000000000-system" }),
+            cmd_overrides,
+        );
+        output_prefix(&deploy_data)
+    };
+
+    assert_eq!(prefix(crate::CmdOverrides::default()), None);
+    let cmd_overrides = crate::CmdOverrides {
+        prefix_output: true,
+        ..crate::CmdOverrides::default()
+    };
+    assert_eq!(prefix(cmd_overrides).as_deref(), Some("web/system"));
+}
+
+#[derive(Error, Debug)]
+pub enum ConfirmProfileError {
+    #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
+    SSHConfirm(std::io::Error),
+    #[error(
+        "Confirming activation over SSH resulted in a bad exit code (the server should roll back): {0:?}"
+    )]
+    SSHConfirmExit(Option<i32>),
+    #[error("Failed to run the external confirmation command (the server should roll back): {0}")]
+    ExternalConfirm(std::io::Error),
+    #[error(
+        "External confirmation command resulted in a bad exit code (the server should roll back): {0:?}"
+    )]
+    ExternalConfirmExit(Option<i32>),
+}
+
+impl ConfirmProfileError {
+    /// Whether the confirmation failed because SSH couldn't reach the node (ssh exits with 255 in
+    /// that case) rather than because removing the lock file failed
+    fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            ConfirmProfileError::SSHConfirm(_) | ConfirmProfileError::SSHConfirmExit(Some(255))
+        )
+    }
+}
+
+#[test]
+fn test_confirm_error_is_connection_error() {
+    assert!(ConfirmProfileError::SSHConfirmExit(Some(255)).is_connection_error());
+    assert!(ConfirmProfileError::SSHConfirm(std::io::Error::other("spawn failed")).is_connection_error());
+    assert!(!ConfirmProfileError::SSHConfirmExit(Some(1)).is_connection_error());
+    assert!(!ConfirmProfileError::ExternalConfirmExit(Some(255)).is_connection_error());
+}
+
+/// How long to wait before retrying a confirmation that failed to reach the node
+const CONFIRM_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Below this many seconds, a confirm timeout is likely to expire before the deployment is
+/// confirmed
+const MIN_CONFIRM_TIMEOUT: u16 = 10;
+
+/// Advice for a `confirmTimeout` the confirmation might not make it in, given how long an SSH
+/// round trip to the node took before the activation. Confirming needs a new connection.
+fn confirm_timeout_warning(confirm_timeout: u16, round_trip: Option<Duration>) -> Option<String> {
+    if confirm_timeout < MIN_CONFIRM_TIMEOUT {
+        return Some(format!(
+            "confirmTimeout is only {}s, the node may roll back before the deployment is confirmed, \
+             consider raising it (e.g. with --confirm-timeout)",
+            confirm_timeout
+        ));
+    }
+
+    match round_trip {
+        Some(round_trip) if round_trip.as_secs_f64() * 2.0 > confirm_timeout as f64 => {
+            Some(format!(
+                "reaching the node took {:.1}s, more than half of confirmTimeout ({}s), the node may \
+                 roll back before the deployment is confirmed, consider raising it (e.g. with \
+                 --confirm-timeout)",
+                round_trip.as_secs_f64(),
+                confirm_timeout
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn test_confirm_timeout_warning() {
+    assert!(confirm_timeout_warning(30, None).is_none());
+    assert!(confirm_timeout_warning(30, Some(Duration::from_secs(2))).is_none());
+    assert!(confirm_timeout_warning(5, None).unwrap().contains("only 5s"));
+    assert!(confirm_timeout_warning(30, Some(Duration::from_secs(20)))
+        .unwrap()
+        .contains("took 20.0s"));
+}
+
+/// Confirms the deployment by running `confirm_via` locally (through `sh -c`) with the node's
+/// hostname, the profile's closure and the lock file to remove as arguments
+async fn confirm_profile_external(
+    deploy_data: &super::DeployData<'_>,
+    confirm_via: &str,
+    lock_path: &Path,
+) -> Result<(), ConfirmProfileError> {
+    let hostname = super::ssh_host(deploy_data.hostname());
+
+    debug!(
+        "Attempting to run external command to confirm deployment: {}",
+        confirm_via
+    );
+
+    let confirm_exit_status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", confirm_via))
+        .arg("sh")
+        .arg(hostname)
+        .arg(&deploy_data.profile.profile_settings.path)
+        .arg(lock_path)
+        .kill_on_drop(true)
+        .status()
+        .await
+        .map_err(ConfirmProfileError::ExternalConfirm)?;
+
+    match confirm_exit_status.code() {
+        Some(0) => (),
+        a => return Err(ConfirmProfileError::ExternalConfirmExit(a)),
+    };
+
+    info!("Deployment confirmed.");
+
+    Ok(())
+}
+
+pub async fn confirm_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &Path,
+    ssh_addr: &str,
+) -> Result<(), ConfirmProfileError> {
+    let lock_path = super::make_lock_path(temp_path, &deploy_data.profile.profile_settings.path);
+
+    if let Some(confirm_via) = &deploy_data.cmd_overrides.confirm_via {
+        return confirm_profile_external(deploy_data, confirm_via, &lock_path).await;
+    }
+
+    let retries = deploy_data.cmd_overrides.confirm_retries;
+    let mut attempt = 0;
+    loop {
+        match confirm_profile_ssh(deploy_data, deploy_defs, &lock_path, ssh_addr).await {
+            Err(e) if e.is_connection_error() && attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "Failed to reach the node to confirm the deployment ({}), retrying ({}/{})",
+                    e, attempt, retries
+                );
+                tokio::time::sleep(CONFIRM_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Removing the lock file is what confirms a deployment to the waiting activation
+fn build_remove_lock_command(sudo: &Option<String>, lock_path: &Path) -> String {
+    let remove_lock_command = format!("rm {}", shell_quote(&lock_path.to_string_lossy()));
+    match sudo {
+        Some(sudo_cmd) => format!("{} {}", sudo_cmd, remove_lock_command),
+        None => remove_lock_command,
+    }
+}
+
+#[test]
+fn test_remove_lock_command_builder() {
+    assert_eq!(
+        build_remove_lock_command(
+            &Some("sudo -u root".to_string()),
+            Path::new("/tmp/deploy-rs-canary-abc")
+        ),
+        "sudo -u root rm '/tmp/deploy-rs-canary-abc'".to_string()
+    );
+}
+
+async fn confirm_profile_ssh(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    lock_path: &Path,
+    ssh_addr: &str,
+) -> Result<(), ConfirmProfileError> {
+    let mut ssh_confirm_command = node_command(deploy_data, ssh_addr);
+    ssh_confirm_command
+        .stdin(std::process::Stdio::piped())
+        .kill_on_drop(true);
+
+    let confirm_command = build_remove_lock_command(&deploy_defs.sudo, lock_path);
+
+    debug!(
+        "Attempting to run command to confirm deployment: {}",
+        confirm_command
+    );
+
+    let mut ssh_confirm_child = ssh_confirm_command
+        .arg(confirm_command)
+        .spawn()
+        .map_err(ConfirmProfileError::SSHConfirm)?;
+    
+    if deploy_defs.sudo_password.is_some() {
+        trace!("[confirm] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_confirm_child, deploy_defs)
+            .await
+            .map_err(ConfirmProfileError::SSHConfirm)?;
+    }
+
+    let ssh_confirm_exit_status = ssh_confirm_child
+        .wait()
+        .await
+        .map_err(ConfirmProfileError::SSHConfirm)?; 
+
+    match ssh_confirm_exit_status.code() {
+        Some(0) => (),
+        a => return Err(ConfirmProfileError::SSHConfirmExit(a)),
     };
 
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+    info!("Deployment confirmed.");
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum UnlockProfileError {
+    #[error("Failed to spawn the command removing the lock file over SSH: {0}")]
+    SSHSpawnUnlock(std::io::Error),
+    #[error("Failed to remove the lock file over SSH: {0}")]
+    SSHUnlock(std::io::Error),
+    #[error("Removing the lock file over SSH resulted in a bad exit code: {0:?}")]
+    SSHUnlockExit(Option<i32>),
+}
+
+/// Exit code of the unlock command if there is no lock file to remove
+const NO_LOCK_EXIT_CODE: i32 = 3;
+
+/// Removes the magic-rollback lock file of the profile's closure from the node, like a
+/// confirmation does. Returns whether there was a lock file.
+pub async fn unlock_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<bool, UnlockProfileError> {
+    let temp_path = deploy_data.temp_path();
+    let lock_path = super::make_lock_path(temp_path, &deploy_data.profile.profile_settings.path);
+
+    let unlock_command = format!(
+        "[ -e {} ] || exit {}; {}",
+        shell_quote(&lock_path.to_string_lossy()),
+        NO_LOCK_EXIT_CODE,
+        build_remove_lock_command(&deploy_defs.sudo, &lock_path)
+    );
+
+    debug!("Constructed unlock command: {}", unlock_command);
+
+    let mut ssh_unlock_command =
+        node_command(deploy_data, &deploy_data.ssh_addr(&deploy_defs.ssh_user));
+    ssh_unlock_command
+        .stdin(std::process::Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut ssh_unlock_child = ssh_unlock_command
+        .arg(unlock_command)
+        .spawn()
+        .map_err(UnlockProfileError::SSHSpawnUnlock)?;
+
+    if deploy_defs.sudo_password.is_some() {
+        trace!("[unlock] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_unlock_child, deploy_defs)
+            .await
+            .map_err(UnlockProfileError::SSHUnlock)?;
+    }
+
+    let ssh_unlock_exit_status = ssh_unlock_child
+        .wait()
+        .await
+        .map_err(UnlockProfileError::SSHUnlock)?;
+
+    match ssh_unlock_exit_status.code() {
+        Some(0) => Ok(true),
+        Some(NO_LOCK_EXIT_CODE) => Ok(false),
+        a => Err(UnlockProfileError::SSHUnlockExit(a)),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HealthCheckError {
+    #[error("Failed to run health check command over SSH: {0}")]
+    SSHHealthCheck(std::io::Error),
+    #[error("Health check over SSH resulted in a bad exit code: {0:?}")]
+    SSHHealthCheckExit(Option<i32>),
+    #[error("Health check did not finish within {0} seconds")]
+    Timeout(u16),
+}
+
+pub async fn health_check(
+    deploy_data: &super::DeployData<'_>,
+    health_check_cmd: &str,
+    health_check_timeout: u16,
+    ssh_addr: &str,
+) -> Result<(), HealthCheckError> {
+    info!("Running health check for profile `{}` of node `{}`", deploy_data.profile_name, deploy_data.node_name);
+
+    let mut ssh_health_check_command = node_command(deploy_data, ssh_addr);
+    ssh_health_check_command
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+
+    debug!("Attempting to run health check command: {}", health_check_cmd);
+
+    let ssh_health_check_exit_status = match tokio::time::timeout(
+        Duration::from_secs(health_check_timeout as u64),
+        ssh_health_check_command.arg(health_check_cmd).status(),
+    )
+    .await
+    {
+        Ok(x) => x.map_err(HealthCheckError::SSHHealthCheck)?,
+        Err(_) => return Err(HealthCheckError::Timeout(health_check_timeout)),
+    };
+
+    match ssh_health_check_exit_status.code() {
+        Some(0) => (),
+        a => return Err(HealthCheckError::SSHHealthCheckExit(a)),
+    };
+
+    info!("Health check passed.");
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum HookError {
+    #[error("Failed to run {0} hook over SSH: {1}")]
+    SSHHook(&'static str, std::io::Error),
+    #[error("{0} hook over SSH resulted in a bad exit code: {1:?}")]
+    SSHHookExit(&'static str, Option<i32>),
+}
+
+fn build_hook_command(sudo: &Option<String>, hook_cmd: &str) -> String {
+    match sudo {
+        Some(sudo_cmd) => format!("{} sh -c {}", sudo_cmd, shell_quote(hook_cmd)),
+        None => hook_cmd.to_string(),
+    }
+}
+
+#[test]
+fn test_hook_command_builder() {
+    assert_eq!(
+        build_hook_command(&None, "systemctl stop app"),
+        "systemctl stop app".to_string()
+    );
+    assert_eq!(
+        build_hook_command(&Some("sudo -u root".to_string()), "echo 'draining' && lb drain"),
+        "sudo -u root sh -c 'echo '\\''draining'\\'' && lb drain'".to_string()
+    );
+}
+
+/// Runs a profile's `hook` command (e.g. `preActivate`) on the node with the profile's SSH and sudo settings
+async fn run_hook(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    hook: &'static str,
+    hook_cmd: &str,
+    ssh_addr: &str,
+) -> Result<(), HookError> {
+    info!(
+        "Running {} hook for profile `{}` of node `{}`",
+        hook, deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let mut ssh_hook_command = node_command(deploy_data, ssh_addr);
+    ssh_hook_command
+        .stdin(std::process::Stdio::piped())
+        .kill_on_drop(true);
+
+    let hook_command = build_hook_command(&deploy_defs.sudo, hook_cmd);
+
+    debug!("Attempting to run {} hook command: {}", hook, hook_command);
+
+    let mut ssh_hook_child = ssh_hook_command
+        .arg(hook_command)
+        .spawn()
+        .map_err(|e| HookError::SSHHook(hook, e))?;
+
+    if deploy_defs.sudo_password.is_some() {
+        trace!("[{}] Piping in sudo password", hook);
+        handle_sudo_stdin(&mut ssh_hook_child, deploy_defs)
+            .await
+            .map_err(|e| HookError::SSHHook(hook, e))?;
+    }
+
+    let ssh_hook_exit_status = ssh_hook_child
+        .wait()
+        .await
+        .map_err(|e| HookError::SSHHook(hook, e))?;
+
+    match ssh_hook_exit_status.code() {
+        Some(0) => (),
+        a => return Err(HookError::SSHHookExit(hook, a)),
+    };
+
+    Ok(())
+}
+
+/// How long a rebooting node has to go down and answer SSH again
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(600);
+/// How long to wait between two probes of a rebooting node
+const REBOOT_PROBE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Error, Debug)]
+pub enum RebootError {
+    #[error("Failed to run reboot command over SSH: {0}")]
+    SSHReboot(std::io::Error),
+    #[error("Reboot command over SSH resulted in a bad exit code: {0:?}")]
+    SSHRebootExit(Option<i32>),
+    #[error("Failed to run probe command over SSH: {0}")]
+    SSHProbe(std::io::Error),
+    #[error("Node did not come back within {0} seconds of rebooting")]
+    Timeout(u64),
+    #[error("Can't reboot the machine deploy-rs runs on when deploying with --local")]
+    Local,
+}
+
+/// Probes the node with `ssh true` until it answers, or until it stops answering if `reachable`
+/// is false
+async fn wait_for_reachable(
+    deploy_data: &super::DeployData<'_>,
+    ssh_addr: &str,
+    reachable: bool,
+    deadline: Instant,
+) -> Result<(), RebootError> {
+    loop {
+        let mut ssh_probe_command = Command::new("ssh");
+        ssh_probe_command
+            .arg(ssh_addr)
+            .args(&deploy_data.merged_settings.ssh_opts)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true);
+
+        let probe = ssh_probe_command
+            .args(["-o", "BatchMode=yes", "-o", "ConnectTimeout=5", "true"])
+            .status();
+
+        // A probe that hangs counts as the node being unreachable
+        let answered = match tokio::time::timeout_at(deadline.into(), probe).await {
+            Ok(status) => status.map_err(RebootError::SSHProbe)?.success(),
+            Err(_) => false,
+        };
+
+        if answered == reachable {
+            return Ok(());
+        }
+
+        if Instant::now() + REBOOT_PROBE_INTERVAL >= deadline {
+            return Err(RebootError::Timeout(REBOOT_TIMEOUT.as_secs()));
+        }
+
+        tokio::time::sleep(REBOOT_PROBE_INTERVAL).await;
+    }
+}
+
+/// Reboots the node with the profile's SSH and sudo settings and waits until it answers SSH again
+async fn reboot_node(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    ssh_addr: &str,
+) -> Result<(), RebootError> {
+    if deploy_data.cmd_overrides.local {
+        return Err(RebootError::Local);
+    }
+
+    info!("Rebooting node `{}`", deploy_data.node_name);
+
+    let mut ssh_reboot_command = node_command(deploy_data, ssh_addr);
+    ssh_reboot_command
+        .stdin(std::process::Stdio::piped())
+        .kill_on_drop(true);
+
+    let reboot_command = build_hook_command(&deploy_defs.sudo, "reboot");
+
+    debug!("Attempting to run reboot command: {}", reboot_command);
+
+    let mut ssh_reboot_child = ssh_reboot_command
+        .arg(reboot_command)
+        .spawn()
+        .map_err(RebootError::SSHReboot)?;
+
+    if deploy_defs.sudo_password.is_some() {
+        trace!("[reboot] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_reboot_child, deploy_defs)
+            .await
+            .map_err(RebootError::SSHReboot)?;
+    }
+
+    let ssh_reboot_exit_status = ssh_reboot_child
+        .wait()
+        .await
+        .map_err(RebootError::SSHReboot)?;
+
+    match ssh_reboot_exit_status.code() {
+        // The node may close the connection before `reboot` returns
+        Some(0) | Some(255) => (),
+        a => return Err(RebootError::SSHRebootExit(a)),
+    };
+
+    // Waiting for the node to go down first, so it isn't mistaken as back before it even shut down
+    let deadline = Instant::now() + REBOOT_TIMEOUT;
+    wait_for_reachable(deploy_data, ssh_addr, false, deadline).await?;
+    info!("Node `{}` went down, waiting for it to come back", deploy_data.node_name);
+    wait_for_reachable(deploy_data, ssh_addr, true, deadline).await?;
+
+    info!("Node `{}` is back after rebooting", deploy_data.node_name);
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum DeployProfileError {
+    #[error("Failed to spawn activation command over SSH: {0}")]
+    SSHSpawnActivate(std::io::Error),
+    #[error("Profile `{0}` for node `{1}` has no activation binary `{2}`.\n\
+             Did you forget to use deploy-rs#lib.<...>.activate.<...> on your profile path?")]
+    MissingActivationScript(String, String, String),
+
+    #[error("Failed to run activation command over SSH: {0}")]
+    SSHActivate(std::io::Error),
+    #[error("The SSH connection running the activation failed with exit code {0:?}{tail}", tail = format_stderr_tail(.1))]
+    SSHActivateExit(Option<i32>, Vec<String>),
+    #[error("The activation failed on the node with exit code {0:?}{tail}", tail = format_stderr_tail(.1))]
+    ActivationScriptFailed(Option<i32>, Vec<String>),
+    #[error("The activation command ended without reporting its result: {0}")]
+    SSHActivateTimeout(tokio::sync::oneshot::error::RecvError),
+
+    #[error("Failed to run wait command over SSH: {0}")]
+    SSHWait(std::io::Error),
+    #[error("Waiting for the activation over SSH failed with exit code {0:?}")]
+    SSHWaitExit(Option<i32>),
+    #[error("The activation did not finish on the node within {0} seconds (see `activationTimeout`)")]
+    WaitTimedOut(u16),
+
+    #[error("Failed to pipe to child stdin: {0}")]
+    SSHActivatePipe(std::io::Error),
+
+    #[error("Pre-activation hook failed, not activating: {0}")]
+    PreActivate(HookError),
+
+    #[error("Rebooting before activation failed, not activating: {0}")]
+    RebootBefore(RebootError),
+    #[error("Rebooting after activation failed: {0}")]
+    RebootAfter(RebootError),
+
+    #[error("Health check failed, not confirming deployment (the server should roll back): {0}")]
+    HealthCheck(#[from] HealthCheckError),
+
+    #[error("Error confirming deployment: {0}")]
+    Confirm(#[from] ConfirmProfileError),
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+}
+
+/// ssh exits with 255 if the connection failed, any other exit code comes from `activate-rs`
+fn activate_exit_error(code: Option<i32>, tail: Vec<String>) -> DeployProfileError {
+    match code {
+        Some(255) => DeployProfileError::SSHActivateExit(code, tail),
+        _ => DeployProfileError::ActivationScriptFailed(code, tail),
+    }
+}
+
+#[test]
+fn test_activate_exit_error() {
+    assert!(matches!(
+        activate_exit_error(Some(255), Vec::new()),
+        DeployProfileError::SSHActivateExit(Some(255), _)
+    ));
+    assert!(matches!(
+        activate_exit_error(Some(1), Vec::new()),
+        DeployProfileError::ActivationScriptFailed(Some(1), _)
+    ));
+    assert!(matches!(
+        activate_exit_error(None, Vec::new()),
+        DeployProfileError::ActivationScriptFailed(None, _)
+    ));
+}
+
+/// Fails early if the profile lacks the activation binary, instead of with a "no such file" from
+/// the node. Profiles that are not in the local store (e.g. built on the node) aren't checked.
+fn check_activation_script(deploy_data: &super::DeployData<'_>) -> Result<(), DeployProfileError> {
+    let closure = Path::new(&deploy_data.profile.profile_settings.path);
+    if !closure.exists() || closure.join(deploy_data.activation_script()).exists() {
+        return Ok(());
+    }
+
+    Err(DeployProfileError::MissingActivationScript(
+        deploy_data.profile_name.to_string(),
+        deploy_data.node_name.to_string(),
+        deploy_data.activation_script().to_string(),
+    ))
+}
+
+#[test]
+fn test_check_activation_script() {
+    let closure = std::env::temp_dir().join(format!("deploy-rs-closure-{}", std::process::id()));
+    std::fs::create_dir_all(&closure).unwrap();
+
+    let check = |profile: serde_json::Value| {
+        let node = serde_json::json!({ "hostname": "example.com", "profiles": {} });
+        let deploy_data = crate::test_deploy_data("example", node, profile, crate::CmdOverrides::default());
+        check_activation_script(&deploy_data)
+    };
+
+    let missing = check(serde_json::json!({ "path": closure }));
+    std::fs::write(closure.join("activate-rs"), "").unwrap();
+    let present = check(serde_json::json!({ "path": closure }));
+    let custom = check(serde_json::json!({ "path": closure, "activationScript": "bin/activate" }));
+    let not_local = check(serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+    }));
+    std::fs::remove_dir_all(&closure).unwrap();
+
+    assert_eq!(
+        missing.unwrap_err().to_string().lines().next(),
+        Some("Profile `system` for node `example` has no activation binary `activate-rs`.")
+    );
+    assert!(present.is_ok());
+    assert!(matches!(
+        custom,
+        Err(DeployProfileError::MissingActivationScript(_, _, script)) if script == "bin/activate"
+    ));
+    assert!(not_local.is_ok());
+}
+
+/// Wall-clock durations of the phases of `deploy_profile`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DeployProfileTimings {
+    pub activate: Duration,
+    pub confirm: Option<Duration>,
+    /// The profile was already active on the node, so nothing was activated
+    pub up_to_date: bool,
+}
+
+/// Activates the profile on its node. `on_confirm` is called once the activation succeeded and
+/// deploy-rs starts confirming it, which only happens with magic rollback.
+pub async fn deploy_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    dry_activate: bool,
+    boot: bool,
+    on_confirm: impl FnOnce(),
+) -> Result<DeployProfileTimings, DeployProfileError> {
+    let activate_start = Instant::now();
+    let mut timings = DeployProfileTimings::default();
+
+    if !dry_activate {
+        info!(
+            "Activating profile `{}` for node `{}`",
+            deploy_data.profile_name, deploy_data.node_name
+        );
+    }
+
+    check_activation_script(deploy_data)?;
+
+    let temp_path = deploy_data.temp_path();
+
+    let confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
+
+    let activation_timeout = deploy_data.merged_settings.activation_timeout;
+
+    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+
+    let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
+
+    let self_activate_command = build_activate_command(&ActivateCommandData {
+        sudo: &deploy_defs.sudo,
+        profile_info: &deploy_data.get_profile_info()?,
+        closure: &deploy_data.profile.profile_settings.path,
+        activation_script: deploy_data.activation_script(),
+        auto_rollback,
+        temp_path: temp_path,
+        confirm_timeout,
+        magic_rollback,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+        dry_activate,
+        boot,
+    });
+
+    // The sudo password is only ever written to stdin, never part of a command line
+    debug!("Constructed activation command: {}", self_activate_command);
+
+    let ssh_addr = deploy_data.ssh_addr(&deploy_defs.ssh_user);
 
-    let mut ssh_activate_command = Command::new("ssh");
+    // Killed when the deployment is aborted, e.g. on Ctrl-C
+    let mut ssh_activate_command = node_command(deploy_data, &ssh_addr);
     ssh_activate_command
-        .arg(&ssh_addr)
-        .stdin(std::process::Stdio::piped());
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    if deploy_data.cmd_overrides.prefix_output {
+        ssh_activate_command.stdout(std::process::Stdio::piped());
+    }
+
+    let self_wait_command = build_wait_command(&WaitCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        activation_script: deploy_data.activation_script(),
+        temp_path: temp_path,
+        activation_timeout: activation_timeout,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+    });
+
+    if deploy_data.cmd_overrides.dry_run {
+        ssh_activate_command.arg(&self_activate_command);
+        log_dry_run(&ssh_activate_command);
+
+        if magic_rollback && !dry_activate && !boot {
+            let mut ssh_wait_command = node_command(deploy_data, &ssh_addr);
+            ssh_wait_command.arg(&self_wait_command);
+            log_dry_run(&ssh_wait_command);
+        }
+
+        return Ok(timings);
+    }
+
+    // The boot entry might still differ from the current profile, so `--boot` always proceeds
+    let mut round_trip = None;
+    if !dry_activate && !boot && !deploy_data.cmd_overrides.force {
+        let probe_start = Instant::now();
+        match current_profile(deploy_data, deploy_defs).await {
+            Ok(Some(current)) if current == deploy_data.profile.profile_settings.path => {
+                info!(
+                    "Profile `{}` for node `{}` is already up to date, skipping activation",
+                    deploy_data.profile_name, deploy_data.node_name
+                );
+                timings.up_to_date = true;
+                return Ok(timings);
+            }
+            Ok(_) => round_trip = Some(probe_start.elapsed()),
+            Err(e) => warn!("Failed to check if the profile is already up to date: {}", e),
+        }
+    }
+
+    if magic_rollback && !dry_activate {
+        // The round trip only tells how long confirming over SSH takes
+        let round_trip = round_trip.filter(|_| deploy_data.cmd_overrides.confirm_via.is_none());
+        if let Some(warning) = confirm_timeout_warning(confirm_timeout, round_trip) {
+            warn!(
+                "Profile `{}` for node `{}`: {}",
+                deploy_data.profile_name, deploy_data.node_name, warning
+            );
+        }
+    }
+
+    if !dry_activate {
+        if let Some(pre_activate) = &deploy_data.profile.profile_settings.pre_activate {
+            run_hook(deploy_data, deploy_defs, "preActivate", pre_activate, &ssh_addr)
+                .await
+                .map_err(DeployProfileError::PreActivate)?;
+        }
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
+        if deploy_data.profile.profile_settings.reboot_before.unwrap_or(false) {
+            reboot_node(deploy_data, deploy_defs, &ssh_addr)
+                .await
+                .map_err(DeployProfileError::RebootBefore)?;
+        }
     }
 
     if !magic_rollback || dry_activate || boot {
@@ -417,13 +1708,10 @@ pub async fn deploy_profile(
             .arg(self_activate_command)
             .spawn()
             .map_err(DeployProfileError::SSHSpawnActivate)?;
+        let activate_stderr = tee_stderr(&mut ssh_activate_child, output_prefix(deploy_data));
+        let activate_stdout = tee_stdout(&mut ssh_activate_child, output_prefix(deploy_data));
 
-        if deploy_data
-            .merged_settings
-            .interactive_sudo
-            .unwrap_or(false)
-            || deploy_data.merged_settings.sudo_secret.is_some()
-        {
+        if deploy_defs.sudo_password.is_some() {
             trace!("[activate] Piping in sudo password");
             handle_sudo_stdin(&mut ssh_activate_child, deploy_defs)
                 .await
@@ -435,9 +1723,15 @@ pub async fn deploy_profile(
             .await
             .map_err(DeployProfileError::SSHActivate)?;
 
+        // Show all of the activation's output before saying how it went
+        let _ = activate_stdout.await;
+
         match ssh_activate_exit_status.code() {
             Some(0) => (),
-            a => return Err(DeployProfileError::SSHActivateExit(a)),
+            a => {
+                let tail = activate_stderr.await.unwrap_or_default();
+                return Err(activate_exit_error(a, tail));
+            }
         };
 
         if dry_activate {
@@ -447,29 +1741,19 @@ pub async fn deploy_profile(
         } else {
             info!("Success activating, done!");
         }
-    } else {
-        let self_wait_command = build_wait_command(&WaitCommandData {
-            sudo: &deploy_defs.sudo,
-            closure: &deploy_data.profile.profile_settings.path,
-            temp_path: temp_path,
-            activation_timeout: activation_timeout,
-            debug_logs: deploy_data.debug_logs,
-            log_dir: deploy_data.log_dir,
-        });
 
+        timings.activate = activate_start.elapsed();
+    } else {
         debug!("Constructed wait command: {}", self_wait_command);
 
         let mut ssh_activate_child = ssh_activate_command
             .arg(self_activate_command)
             .spawn()
             .map_err(DeployProfileError::SSHSpawnActivate)?;
+        let activate_stderr = tee_stderr(&mut ssh_activate_child, output_prefix(deploy_data));
+        let activate_stdout = tee_stdout(&mut ssh_activate_child, output_prefix(deploy_data));
 
-        if deploy_data
-            .merged_settings
-            .interactive_sudo
-            .unwrap_or(false)
-            || deploy_data.merged_settings.sudo_secret.is_some()
-        {
+        if deploy_defs.sudo_password.is_some() {
             trace!("[activate] Piping in sudo password");
             handle_sudo_stdin(&mut ssh_activate_child, deploy_defs)
                 .await
@@ -478,26 +1762,26 @@ pub async fn deploy_profile(
 
         info!("Creating activation waiter");
 
-        let mut ssh_wait_command = Command::new("ssh");
+        let mut ssh_wait_command = node_command(deploy_data, &ssh_addr);
         ssh_wait_command
-            .arg(&ssh_addr)
-            .stdin(std::process::Stdio::piped());
-        
-        for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-            ssh_wait_command.arg(ssh_opt);
-        }
+            .stdin(std::process::Stdio::piped())
+            .kill_on_drop(true);
 
-        let (send_activate, recv_activate) = tokio::sync::oneshot::channel();
+        let (send_activate, mut recv_activate) = tokio::sync::oneshot::channel();
         let (send_activated, recv_activated) = tokio::sync::oneshot::channel();
 
-        let thread = tokio::spawn(async move {
-            let o = ssh_activate_child.wait_with_output().await;
+        let thread = spawn_in_log_context(async move {
+            let o = ssh_activate_child.wait().await;
+            let _ = activate_stdout.await;
 
             let maybe_err = match o {
                 Err(x) => Some(DeployProfileError::SSHActivate(x)),
-                Ok(ref x) => match x.status.code() {
+                Ok(x) => match x.code() {
                     Some(0) => None,
-                    a => Some(DeployProfileError::SSHActivateExit(a)),
+                    a => {
+                        let tail = activate_stderr.await.unwrap_or_default();
+                        Some(activate_exit_error(a, tail))
+                    }
                 },
             };
 
@@ -513,12 +1797,7 @@ pub async fn deploy_profile(
             .spawn()
             .map_err(DeployProfileError::SSHWait)?;
 
-        if deploy_data
-            .merged_settings
-            .interactive_sudo
-            .unwrap_or(false)
-            || deploy_data.merged_settings.sudo_secret.is_some()
-        {
+        if deploy_defs.sudo_password.is_some() {
             trace!("[wait] Piping in sudo password");
             handle_sudo_stdin(&mut ssh_wait_child, deploy_defs)
                 .await
@@ -530,16 +1809,40 @@ pub async fn deploy_profile(
                 debug!("Wait command ended");
                 match x.map_err(DeployProfileError::SSHWait)?.code() {
                     Some(0) => (),
+                    Some(super::WAIT_TIMED_OUT_EXIT_CODE) => {
+                        return Err(DeployProfileError::WaitTimedOut(activation_timeout.unwrap_or(240)))
+                    }
                     a => return Err(DeployProfileError::SSHWaitExit(a)),
                 };
             },
-            x = recv_activate => {
+            x = &mut recv_activate => {
                 debug!("Activate command exited with an error");
                 return Err(x.unwrap());
             },
         }
 
+        if let Some(health_check_cmd) = &deploy_data.merged_settings.health_check_cmd {
+            let health_check_timeout = deploy_data
+                .merged_settings
+                .health_check_timeout
+                .unwrap_or(confirm_timeout);
+
+            tokio::select! {
+                x = health_check(deploy_data, health_check_cmd, health_check_timeout, &ssh_addr) => {
+                    x?;
+                },
+                x = &mut recv_activate => {
+                    debug!("Activate command exited with an error");
+                    return Err(x.unwrap());
+                },
+            }
+        }
+
         info!("Success activating, attempting to confirm activation");
+        on_confirm();
+
+        timings.activate = activate_start.elapsed();
+        let confirm_start = Instant::now();
 
         let c = confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr).await;
         recv_activated.await.map_err(|x| DeployProfileError::SSHActivateTimeout(x))?;
@@ -548,9 +1851,459 @@ pub async fn deploy_profile(
         thread
             .await
             .map_err(|x| DeployProfileError::SSHActivate(x.into()))?;
+
+        timings.confirm = Some(confirm_start.elapsed());
     }
 
-    Ok(())
+    // With `--boot` this is what makes the node run the new profile
+    if !dry_activate && deploy_data.profile.profile_settings.reboot_after.unwrap_or(false) {
+        reboot_node(deploy_data, deploy_defs, &ssh_addr)
+            .await
+            .map_err(DeployProfileError::RebootAfter)?;
+    }
+
+    if !dry_activate && !boot {
+        if let Some(post_activate) = &deploy_data.profile.profile_settings.post_activate {
+            // The new profile is already live at this point, so a failing hook must not roll it back
+            if let Err(e) =
+                run_hook(deploy_data, deploy_defs, "postActivate", post_activate, &ssh_addr).await
+            {
+                warn!(
+                    "Post-activation hook for profile `{}` of node `{}` failed: {}",
+                    deploy_data.profile_name, deploy_data.node_name, e
+                );
+            }
+        }
+    }
+
+    Ok(timings)
+}
+
+/// The start of a command running a shell command on the node, which is given as its last
+/// argument: `ssh` with the profile's SSH options, or `sh -c` on this machine with `--local`
+fn node_command_spec(deploy_data: &super::DeployData<'_>, ssh_addr: &str) -> CommandSpec {
+    if deploy_data.cmd_overrides.local {
+        let mut command = CommandSpec::new("sh");
+        command.arg("-c");
+        return command;
+    }
+
+    let mut command = CommandSpec::new("ssh");
+    command
+        .arg(ssh_addr)
+        .args(deploy_data.merged_settings.ssh_opts.iter().cloned());
+    command
+}
+
+/// Like `node_command_spec`, for commands that are spawned directly
+fn node_command(deploy_data: &super::DeployData<'_>, ssh_addr: &str) -> Command {
+    let spec = node_command_spec(deploy_data, ssh_addr);
+    let mut command = Command::new(&spec.program);
+    command.args(&spec.args);
+    command
+}
+
+/// The command running `remote_command` on the node over SSH with the profile's SSH options, with
+/// the sudo password (if there is one) on its stdin
+fn ssh_command_spec(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    remote_command: String,
+) -> CommandSpec {
+    let mut command = node_command_spec(deploy_data, &deploy_data.ssh_addr(&deploy_defs.ssh_user));
+    command.arg(remote_command);
+
+    if let Some(sudo_stdin) = deploy_defs.sudo_stdin() {
+        trace!("Piping in sudo password");
+        command.stdin(sudo_stdin);
+    }
+
+    command
+}
+
+#[derive(Error, Debug)]
+pub enum ListGenerationsError {
+    #[error("Failed to list generations over SSH: {0}")]
+    SSHListGenerations(std::io::Error),
+    #[error("Listing generations over SSH resulted in a bad exit code: {0:?}")]
+    SSHListGenerationsExit(Option<i32>),
+    #[error("Error converting generation list output to utf8: {0}")]
+    DecodeUtf8(#[from] std::string::FromUtf8Error),
+
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+}
+
+pub async fn list_generations(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<Vec<Generation>, ListGenerationsError> {
+    // The evaluated closure may have never been pushed, the deployed one knows its generations
+    let self_list_generations_command = build_list_generations_command(&ListGenerationsCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: None,
+        activation_script: deploy_data.activation_script(),
+        profile_info: deploy_data.get_profile_info()?,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+    });
+
+    debug!("Constructed list-generations command: {}", self_list_generations_command);
+
+    let output = deploy_data
+        .runner
+        .output(&ssh_command_spec(deploy_data, deploy_defs, self_list_generations_command))
+        .await
+        .map_err(ListGenerationsError::SSHListGenerations)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(ListGenerationsError::SSHListGenerationsExit(a)),
+    };
+
+    Ok(parse_generations(&String::from_utf8(output.stdout)?))
+}
+
+#[derive(Error, Debug)]
+pub enum DiffClosuresError {
+    #[error("Failed to diff closures over SSH: {0}")]
+    SSHDiffClosures(std::io::Error),
+    #[error("Diffing closures over SSH resulted in a bad exit code: {0:?}")]
+    SSHDiffClosuresExit(Option<i32>),
+    #[error("Error converting closure diff output to utf8: {0}")]
+    DecodeUtf8(#[from] std::string::FromUtf8Error),
+
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+}
+
+/// Returns the output of `nix store diff-closures` between the profile currently active on the
+/// node and the (already pushed) new closure
+pub async fn diff_closures(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<String, DiffClosuresError> {
+    let self_diff_closures_command = build_diff_closures_command(&DiffClosuresCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        activation_script: deploy_data.activation_script(),
+        profile_info: deploy_data.get_profile_info()?,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+    });
+
+    debug!("Constructed diff-closures command: {}", self_diff_closures_command);
+
+    let output = deploy_data
+        .runner
+        .output(&ssh_command_spec(deploy_data, deploy_defs, self_diff_closures_command))
+        .await
+        .map_err(DiffClosuresError::SSHDiffClosures)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(DiffClosuresError::SSHDiffClosuresExit(a)),
+    };
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[derive(Error, Debug)]
+pub enum CurrentProfileError {
+    #[error("Failed to read the current profile over SSH: {0}")]
+    SSHCurrentProfile(std::io::Error),
+    #[error("Reading the current profile over SSH resulted in a bad exit code: {0:?}")]
+    SSHCurrentProfileExit(Option<i32>),
+    #[error("Error converting current profile output to utf8: {0}")]
+    DecodeUtf8(#[from] std::string::FromUtf8Error),
+
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+}
+
+/// Returns the store path the profile currently points to on the node, or `None` if the profile
+/// doesn't exist yet
+pub async fn current_profile(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<Option<String>, CurrentProfileError> {
+    let self_current_profile_command = build_current_profile_command(&CurrentProfileCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        activation_script: deploy_data.activation_script(),
+        profile_info: deploy_data.get_profile_info()?,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir.as_deref(),
+    });
+
+    debug!("Constructed current-profile command: {}", self_current_profile_command);
+
+    let output = deploy_data
+        .runner
+        .output(&ssh_command_spec(deploy_data, deploy_defs, self_current_profile_command))
+        .await
+        .map_err(CurrentProfileError::SSHCurrentProfile)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(CurrentProfileError::SSHCurrentProfileExit(a)),
+    };
+
+    let current = String::from_utf8(output.stdout)?.trim().to_string();
+
+    Ok(if current.is_empty() { None } else { Some(current) })
+}
+
+#[tokio::test]
+async fn test_current_profile() {
+    let node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "deploy",
+        "sshOpts": ["-p", "2222"],
+        "profiles": {},
+    });
+    let profile = serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+        "user": "root",
+    });
+    let cmd_overrides = crate::CmdOverrides::default();
+
+    let runner = crate::command::MockRunner::default();
+    runner.push_output(0, "/nix/store/11111111111111111111111111111111-system\n");
+    runner.push_output(0, "");
+
+    let mut deploy_data = crate::test_deploy_data("example", node, profile, cmd_overrides);
+    deploy_data.runner = &runner;
+    let mut deploy_defs = deploy_data.defs().unwrap();
+    deploy_defs.sudo_password = Some("hunter2".into());
+
+    assert_eq!(
+        current_profile(&deploy_data, &deploy_defs).await.unwrap().as_deref(),
+        Some("/nix/store/11111111111111111111111111111111-system")
+    );
+    // A profile that doesn't exist yet has no current closure
+    assert_eq!(current_profile(&deploy_data, &deploy_defs).await.unwrap(), None);
+
+    let calls = runner.calls();
+    assert_eq!(calls[0].program, "ssh");
+    assert_eq!(
+        calls[0].args,
+        vec![
+            "deploy@example.com",
+            "-p",
+            "2222",
+            "sudo -u root '/nix/store/00000000000000000000000000000000-system/activate-rs' \
+             current-profile --profile-user 'root' --profile-name 'system'",
+        ]
+    );
+    assert_eq!(calls[0].stdin.as_deref().map(String::as_str), Some("hunter2\n"));
+    assert!(!format!("{:?} {:?}", calls[0], deploy_defs).contains("hunter2"));
+}
+
+#[tokio::test]
+async fn test_deploy_profile_dry_run() {
+    let node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "deploy",
+        "profiles": {},
+    });
+    let profile = serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+        "user": "root",
+    });
+    let cmd_overrides = crate::CmdOverrides {
+        dry_run: true,
+        ..crate::CmdOverrides::default()
+    };
+
+    // Nothing is queued, so checking whether the profile is up to date would fail
+    let runner = crate::command::MockRunner::default();
+    let mut deploy_data = crate::test_deploy_data("example", node, profile, cmd_overrides);
+    deploy_data.runner = &runner;
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let mut confirmed = false;
+    let timings = deploy_profile(&deploy_data, &deploy_defs, false, false, || confirmed = true)
+        .await
+        .unwrap();
+    assert!(!timings.up_to_date);
+    assert!(!confirmed);
+    assert_eq!(runner.calls(), vec![]);
+}
+
+#[tokio::test]
+async fn test_current_profile_local() {
+    let node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "deploy",
+        "sshOpts": ["-p", "2222"],
+        "profiles": {},
+    });
+    let profile = serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+        "user": "root",
+    });
+    let cmd_overrides = crate::CmdOverrides {
+        local: true,
+        ..crate::CmdOverrides::default()
+    };
+
+    let runner = crate::command::MockRunner::default();
+    runner.push_output(0, "");
+
+    let mut deploy_data = crate::test_deploy_data("example", node, profile, cmd_overrides);
+    deploy_data.runner = &runner;
+    let mut deploy_defs = deploy_data.defs().unwrap();
+    // Whether sudo is needed depends on who runs the tests, as with any --local deployment
+    deploy_defs.sudo = Some("sudo -u root".to_string());
+    deploy_defs.sudo_password = Some("hunter2".into());
+
+    assert_eq!(current_profile(&deploy_data, &deploy_defs).await.unwrap(), None);
+
+    // The same command runs through a local shell, sudo still reads the password from stdin
+    let calls = runner.calls();
+    assert_eq!(calls[0].program, "sh");
+    assert_eq!(
+        calls[0].args,
+        vec![
+            "-c",
+            "sudo -u root '/nix/store/00000000000000000000000000000000-system/activate-rs' \
+             current-profile --profile-user 'root' --profile-name 'system'",
+        ]
+    );
+    assert_eq!(calls[0].stdin.as_deref().map(String::as_str), Some("hunter2\n"));
+}
+
+/// What `deploy status` reports about a node
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct NodeStatus {
+    /// The current generation of the NixOS system profile, `None` if the node isn't NixOS
+    pub generation: Option<u32>,
+    /// Seconds since the node booted
+    pub uptime: Option<u64>,
+    /// Magic-rollback lock files on the node, left behind by a deployment that is still waiting
+    /// for its confirmation or that was aborted
+    pub locks: Vec<String>,
+}
+
+/// Builds a read-only shell command printing the status of a node as `<key> <value>` lines,
+/// looking for lock files in each of `temp_paths`
+fn build_status_command(temp_paths: &[&Path]) -> String {
+    let mut status_command = [
+        "echo \"generation $(readlink /nix/var/nix/profiles/system)\"",
+        "echo \"uptime $(cut -d ' ' -f 1 /proc/uptime)\"",
+    ]
+    .join("; ");
+
+    for temp_path in temp_paths {
+        // `*` instead of a store hash matches the lock file of any closure, it is left unquoted
+        // so the shell expands it
+        let lock_glob = super::make_lock_path(temp_path, "/nix/store/*");
+        let lock_name = lock_glob.file_name().unwrap_or_default().to_string_lossy();
+        status_command = format!(
+            "{}; for f in {}/{}; do [ -e \"$f\" ] && echo \"lock $f\"; done",
+            status_command,
+            shell_quote(&temp_path.to_string_lossy()),
+            lock_name
+        );
+    }
+
+    // The last `[ -e ]` fails if there is no lock file
+    format!("{}; true", status_command)
+}
+
+#[test]
+fn test_status_command_builder() {
+    assert_eq!(
+        build_status_command(&[Path::new("/tmp"), Path::new("/var/tmp")]),
+        "echo \"generation $(readlink /nix/var/nix/profiles/system)\"; \
+         echo \"uptime $(cut -d ' ' -f 1 /proc/uptime)\"; \
+         for f in '/tmp'/deploy-rs-canary-*; do [ -e \"$f\" ] && echo \"lock $f\"; done; \
+         for f in '/var/tmp'/deploy-rs-canary-*; do [ -e \"$f\" ] && echo \"lock $f\"; done; true"
+            .to_string()
+    );
+}
+
+fn parse_status(output: &str) -> NodeStatus {
+    let mut status = NodeStatus::default();
+
+    for line in output.lines() {
+        match line.split_once(' ') {
+            Some(("generation", link)) => {
+                status.generation = link
+                    .strip_prefix("system-")
+                    .and_then(|l| l.strip_suffix("-link"))
+                    .and_then(|id| id.parse().ok());
+            }
+            Some(("uptime", secs)) => {
+                status.uptime = secs.parse::<f64>().ok().map(|secs| secs as u64);
+            }
+            Some(("lock", path)) => status.locks.push(path.to_string()),
+            _ => (),
+        }
+    }
+
+    status
+}
+
+#[test]
+fn test_parse_status() {
+    assert_eq!(
+        parse_status("generation system-42-link\nuptime 93784.51\nlock /tmp/deploy-rs-canary-abc\n"),
+        NodeStatus {
+            generation: Some(42),
+            uptime: Some(93784),
+            locks: vec!["/tmp/deploy-rs-canary-abc".to_string()],
+        }
+    );
+    assert_eq!(parse_status("generation \nuptime \n"), NodeStatus::default());
+}
+
+#[derive(Error, Debug)]
+pub enum NodeStatusError {
+    #[error("Failed to query the status over SSH: {0}")]
+    SSHStatus(std::io::Error),
+    #[error("Node is unreachable")]
+    Unreachable,
+    #[error("Querying the status over SSH resulted in a bad exit code: {0:?}")]
+    SSHStatusExit(Option<i32>),
+    #[error("Error converting the status output to utf8: {0}")]
+    DecodeUtf8(#[from] std::string::FromUtf8Error),
+}
+
+/// Queries the status of the node of `deploy_data` over SSH without changing anything on it
+pub async fn node_status(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    temp_paths: &[&Path],
+) -> Result<NodeStatus, NodeStatusError> {
+    let status_command = build_status_command(temp_paths);
+
+    debug!("Constructed status command: {}", status_command);
+
+    let mut ssh_status_command =
+        node_command_spec(deploy_data, &deploy_data.ssh_addr(&deploy_defs.ssh_user));
+    ssh_status_command
+        .arg(status_command)
+        .capture_stderr();
+
+    let output = deploy_data
+        .runner
+        .output(&ssh_status_command)
+        .await
+        .map_err(NodeStatusError::SSHStatus)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        // ssh exits with 255 if it can't connect
+        Some(255) => {
+            debug!("ssh: {}", String::from_utf8_lossy(&output.stderr).trim());
+            return Err(NodeStatusError::Unreachable);
+        }
+        a => return Err(NodeStatusError::SSHStatusExit(a)),
+    };
+
+    Ok(parse_status(&String::from_utf8(output.stdout)?))
 }
 
 #[derive(Error, Debug)]
@@ -563,50 +2316,75 @@ pub enum RevokeProfileError {
     #[error("Revoking over SSH resulted in a bad exit code: {0:?}")]
     SSHRevokeExit(Option<i32>),
 
+    #[error("Failed to list generations: {0}")]
+    ListGenerations(#[from] ListGenerationsError),
+    #[error("Generation {0} does not exist on the node")]
+    GenerationNotFound(u32),
+
     #[error("Deployment data invalid: {0}")]
     InvalidDeployDataDefs(#[from] DeployDataDefsError),
 }
 pub async fn revoke(
     deploy_data: &crate::DeployData<'_>,
     deploy_defs: &crate::DeployDefs,
+) -> Result<(), RevokeProfileError> {
+    let closure = &deploy_data.profile.profile_settings.path;
+    revoke_to_generation(deploy_data, deploy_defs, Some(closure), None).await
+}
+
+/// Rolls the profile back to the given generation, after checking that it exists on the node
+pub async fn rollback(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+    to_generation: u32,
+) -> Result<(), RevokeProfileError> {
+    let generations = list_generations(deploy_data, deploy_defs).await?;
+
+    if !generations.iter().any(|g| g.id == to_generation) {
+        return Err(RevokeProfileError::GenerationNotFound(to_generation));
+    }
+
+    info!(
+        "Rolling back profile `{}` for node `{}` to generation {}",
+        deploy_data.profile_name, deploy_data.node_name, to_generation
+    );
+
+    // Like the generations, the rollback doesn't need the evaluated closure on the node
+    revoke_to_generation(deploy_data, deploy_defs, None, Some(to_generation)).await
+}
+
+/// Revokes the profile with the `activate-rs` of `closure`, or of the profile deployed on the node
+async fn revoke_to_generation(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+    closure: Option<&str>,
+    to_generation: Option<u32>,
 ) -> Result<(), RevokeProfileError> {
     let self_revoke_command = build_revoke_command(&RevokeCommandData {
         sudo: &deploy_defs.sudo,
-        closure: &deploy_data.profile.profile_settings.path,
+        closure,
+        activation_script: deploy_data.activation_script(),
         profile_info: deploy_data.get_profile_info()?,
+        to_generation,
         debug_logs: deploy_data.debug_logs,
-        log_dir: deploy_data.log_dir,
+        log_dir: deploy_data.log_dir.as_deref(),
     });
 
     debug!("Constructed revoke command: {}", self_revoke_command);
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
-
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+    let ssh_addr = deploy_data.ssh_addr(&deploy_defs.ssh_user);
 
-    let mut ssh_activate_command = Command::new("ssh");
+    let mut ssh_activate_command = node_command(deploy_data, &ssh_addr);
     ssh_activate_command
-        .arg(&ssh_addr)
-        .stdin(std::process::Stdio::piped());
-
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
-    }
+        .stdin(std::process::Stdio::piped())
+        .kill_on_drop(true);
 
     let mut ssh_revoke_child = ssh_activate_command
         .arg(self_revoke_command)
         .spawn()
         .map_err(RevokeProfileError::SSHSpawnRevoke)?;
 
-    if deploy_data
-        .merged_settings
-        .interactive_sudo
-        .unwrap_or(false)
-        || deploy_data.merged_settings.sudo_secret.is_some()
-    {
+    if deploy_defs.sudo_password.is_some() {
         trace!("[revoke] Piping in sudo password");
         handle_sudo_stdin(&mut ssh_revoke_child, deploy_defs)
             .await
@@ -11,9 +11,17 @@ use merge::Merge;
 use thiserror::Error;
 
 use flexi_logger::*;
+use indicatif::MultiProgress;
+use indicatif_log_bridge::LogWrapper;
 
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
 
+/// The exit code of `activate-rs wait` when the activation didn't finish within its timeout
+pub const WAIT_TIMED_OUT_EXIT_CODE: i32 = 124;
+
 pub fn make_lock_path(temp_path: &Path, closure: &str) -> PathBuf {
     let lock_hash =
         &closure["/nix/store/".len()..closure.find('-').unwrap_or_else(|| closure.len())];
@@ -78,11 +86,66 @@ pub fn logger_formatter_revoke(
     )
 }
 
+pub fn logger_formatter_list_generations(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let level = record.level();
+
+    write!(
+        w,
+        "📜 {} [list-generations] [{}] {}",
+        make_emoji(level),
+        style(level, level.to_string()),
+        record.args()
+    )
+}
+
+pub fn logger_formatter_diff_closures(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let level = record.level();
+
+    write!(
+        w,
+        "🔍 {} [diff-closures] [{}] {}",
+        make_emoji(level),
+        style(level, level.to_string()),
+        record.args()
+    )
+}
+
+pub fn logger_formatter_current_profile(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let level = record.level();
+
+    write!(
+        w,
+        "🔗 {} [current-profile] [{}] {}",
+        make_emoji(level),
+        style(level, level.to_string()),
+        record.args()
+    )
+}
+
+/// The target of the output of commands that is logged as is, without `--prefix-output`
+pub(crate) const OUTPUT_LOG_TARGET: &str = "deploy_rs::output";
+
 pub fn logger_formatter_deploy(
     w: &mut dyn std::io::Write,
     _now: &mut DeferredNow,
     record: &Record,
 ) -> Result<(), std::io::Error> {
+    if record.target() == OUTPUT_LOG_TARGET {
+        return write!(w, "{}", record.args());
+    }
+
     let level = record.level();
 
     write!(
@@ -94,30 +157,175 @@ pub fn logger_formatter_deploy(
     )
 }
 
+/// Node and profile that log lines emitted within `LOG_CONTEXT.scope` belong to
+#[derive(Clone, Debug)]
+pub struct LogContext {
+    pub node: String,
+    pub profile: String,
+}
+
+tokio::task_local! {
+    pub static LOG_CONTEXT: LogContext;
+}
+
+pub fn logger_formatter_json(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let context = LOG_CONTEXT.try_with(|c| c.clone()).ok();
+
+    let line = serde_json::json!({
+        "timestamp": now.now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "module": record.module_path(),
+        "node": context.as_ref().map(|c| &c.node),
+        "profile": context.as_ref().map(|c| &c.profile),
+        "message": record.args().to_string(),
+    });
+
+    write!(w, "{}", line)
+}
+
+/// Colors of the log levels as ANSI color numbers: error (red), warn (yellow), info (green), debug
+/// and trace
+const LOG_PALETTE: &str = "196;226;46;7;8";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorChoice {
+    /// Color the output if stderr is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::io::stderr().is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
 pub enum LoggerType {
     Deploy,
     Activate,
     Wait,
     Revoke,
+    ListGenerations,
+    DiffClosures,
+    CurrentProfile,
+}
+
+/// Mirrors log lines that belong to a node (see `LOG_CONTEXT`) into `<log_dir>/<node>/deploy.log`
+struct PerNodeLogWriter {
+    log_dir: PathBuf,
+    format: FormatFunction,
+    files: std::sync::Mutex<HashMap<String, std::fs::File>>,
+}
+
+impl PerNodeLogWriter {
+    fn new(log_dir: &str) -> Self {
+        PerNodeLogWriter {
+            log_dir: PathBuf::from(log_dir),
+            format: default_format,
+            files: Default::default(),
+        }
+    }
+}
+
+impl writers::LogWriter for PerNodeLogWriter {
+    fn write(&self, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+        let node = match LOG_CONTEXT.try_with(|c| c.node.clone()) {
+            Ok(node) => node,
+            Err(_) => return Ok(()),
+        };
+
+        let mut files = self.files.lock().unwrap();
+        let file = match files.entry(node) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let dir = self.log_dir.join(e.key());
+                std::fs::create_dir_all(&dir)?;
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(dir.join("deploy.log"))?;
+                e.insert(file)
+            }
+        };
+
+        (self.format)(file, now, record)?;
+        writeln!(file)
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        for file in self.files.lock().unwrap().values_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn max_log_level(&self) -> log::LevelFilter {
+        log::LevelFilter::Trace
+    }
+
+    fn format(&mut self, format: FormatFunction) {
+        self.format = format;
+    }
 }
 
 pub fn init_logger(
     debug_logs: bool,
     log_dir: Option<&str>,
+    per_node_logs: bool,
     logger_type: &LoggerType,
+    log_format: LogFormat,
+    color: ColorChoice,
+    progress: Option<MultiProgress>,
 ) -> Result<(), FlexiLoggerError> {
-    let logger_formatter = match &logger_type {
-        LoggerType::Deploy => logger_formatter_deploy,
-        LoggerType::Activate => logger_formatter_activate,
-        LoggerType::Wait => logger_formatter_wait,
-        LoggerType::Revoke => logger_formatter_revoke,
+    // The formatters color the log levels through flexi_logger's `style`, which is backed by yansi
+    if log_format == LogFormat::Json || !color.enabled() {
+        yansi::Paint::disable();
+    }
+
+    let logger_formatter = match (log_format, &logger_type) {
+        (LogFormat::Json, _) => logger_formatter_json,
+        (LogFormat::Human, LoggerType::Deploy) => logger_formatter_deploy,
+        (LogFormat::Human, LoggerType::Activate) => logger_formatter_activate,
+        (LogFormat::Human, LoggerType::Wait) => logger_formatter_wait,
+        (LogFormat::Human, LoggerType::Revoke) => logger_formatter_revoke,
+        (LogFormat::Human, LoggerType::ListGenerations) => logger_formatter_list_generations,
+        (LogFormat::Human, LoggerType::DiffClosures) => logger_formatter_diff_closures,
+        (LogFormat::Human, LoggerType::CurrentProfile) => logger_formatter_current_profile,
     };
 
     if let Some(log_dir) = log_dir {
+        let file_formatter = match log_format {
+            LogFormat::Json => logger_formatter_json,
+            LogFormat::Human => default_format,
+        };
+
         let mut logger = Logger::with_env_or_str("debug")
             .log_to_file()
             .format_for_stderr(logger_formatter)
-            .set_palette("196;208;51;7;8".to_string())
+            .format_for_files(file_formatter)
+            .format_for_writer(file_formatter)
+            .set_palette(LOG_PALETTE.to_string())
             .directory(log_dir)
             .duplicate_to_stderr(match debug_logs {
                 true => Duplicate::Debug,
@@ -129,48 +337,118 @@ pub fn init_logger(
             LoggerType::Activate => logger = logger.discriminant("activate"),
             LoggerType::Wait => logger = logger.discriminant("wait"),
             LoggerType::Revoke => logger = logger.discriminant("revoke"),
+            LoggerType::ListGenerations => logger = logger.discriminant("list-generations"),
+            LoggerType::DiffClosures => logger = logger.discriminant("diff-closures"),
+            LoggerType::CurrentProfile => logger = logger.discriminant("current-profile"),
             LoggerType::Deploy => (),
         }
 
-        logger.start()?;
+        if per_node_logs {
+            logger = logger.log_target(LogTarget::FileAndWriter(Box::new(PerNodeLogWriter::new(
+                log_dir,
+            ))));
+        }
+
+        start_logger(logger, progress)?;
     } else {
-        Logger::with_env_or_str(match debug_logs {
+        let logger = Logger::with_env_or_str(match debug_logs {
             true => "debug",
             false => "info",
         })
         .log_target(LogTarget::StdErr)
         .format(logger_formatter)
-        .set_palette("196;208;51;7;8".to_string())
-        .start()?;
+        .set_palette(LOG_PALETTE.to_string());
+
+        start_logger(logger, progress)?;
+    }
+
+    Ok(())
+}
+
+/// Installs `logger` globally, routing it through `progress` so log lines are printed above the
+/// progress bars instead of through them
+fn start_logger(logger: Logger, progress: Option<MultiProgress>) -> Result<(), FlexiLoggerError> {
+    match progress {
+        None => {
+            logger.start()?;
+        }
+        Some(progress) => {
+            let (logger, _handle) = logger.build()?;
+            LogWrapper::new(progress, logger).try_init()?;
+        }
     }
 
     Ok(())
 }
 
 pub mod cli;
+pub mod command;
 pub mod data;
 pub mod deploy;
+pub mod notify;
 pub mod push;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CmdOverrides {
     pub ssh_user: Option<String>,
     pub profile_user: Option<String>,
-    pub ssh_opts: Option<String>,
+    pub ssh_opts: Option<Vec<String>>,
+    /// Pass `ssh_opts` in addition to the ones of the deployment instead of replacing them
+    pub ssh_opts_append: bool,
+    /// Arguments passed to ssh as they are, before all other ssh options
+    pub extra_ssh_args: Vec<String>,
     pub compress: Option<bool>,
     pub fast_connection: Option<bool>,
+    pub substitute_on_destination: Option<bool>,
     pub auto_rollback: Option<bool>,
     pub hostname: Option<String>,
+    pub known_hosts_file: Option<PathBuf>,
+    pub ssh_config: Option<PathBuf>,
+    pub verbose_ssh: u8,
+    /// Run the commands of the node on this machine instead of over SSH
+    pub local: bool,
+    pub strict_host_key_checking: Option<StrictHostKeyChecking>,
+    pub node_hostnames: HashMap<String, String>,
     pub magic_rollback: Option<bool>,
     pub temp_path: Option<PathBuf>,
     pub confirm_timeout: Option<u16>,
+    pub node_confirm_timeouts: HashMap<String, u16>,
     pub activation_timeout: Option<u16>,
+    pub node_activation_timeouts: HashMap<String, u16>,
     pub sudo: Option<String>,
+    pub sudo_kind: Option<data::SudoKind>,
     pub interactive_sudo: Option<bool>,
+    pub shared_sudo_password: bool,
     pub sudo_file: Option<PathBuf>,
+    pub node_sudo_files: HashMap<String, PathBuf>,
     pub sudo_secret: Option<String>,
+    pub node_sudo_secrets: HashMap<String, String>,
+    pub sudo_secret_format: cli::SecretFormat,
+    pub raw_sudo: bool,
+    pub age_identity: Option<PathBuf>,
+    pub age_file: Option<PathBuf>,
+    pub health_check_cmd: Option<String>,
+    pub health_check_timeout: Option<u16>,
     pub dry_activate: bool,
     pub remote_build: bool,
+    pub per_node_logs: bool,
+    /// Log the output of commands run for a profile prefixed with its node and profile
+    pub prefix_output: bool,
+    pub confirm_via: Option<String>,
+    pub confirm_retries: u8,
+    pub copy_retries: u8,
+    pub copy_jobs: Option<u16>,
+    pub build_timeout: Option<u16>,
+    pub copy_bwlimit: Option<String>,
+    pub via_cache: Option<String>,
+    pub builders: Option<String>,
+    pub nom: bool,
+    pub print_build_logs: bool,
+    pub target_system: Option<String>,
+    pub pure_eval: bool,
+    pub force: bool,
+    /// Only log the commands that would build, push and activate the profiles
+    pub dry_run: bool,
 }
 
 #[derive(PartialEq, Debug)]
@@ -188,10 +466,114 @@ pub enum ParseFlakeError {
     Unrecognized,
 }
 
+/// Characters that only make sense in a regular expression, a node name containing any of them is
+/// matched as one
+pub const REGEX_METACHARACTERS: &[char] = &['\\', '+', '^', '$', '(', ')', '|', '{', '}'];
+/// Characters that make a node name a glob pattern, unless it is already a regular expression
+pub const GLOB_METACHARACTERS: &[char] = &['*', '?', '['];
+
+/// Whether the node name of a target is a glob or regex pattern matched against all node names
+pub fn is_node_pattern(node: &str) -> bool {
+    node.contains(REGEX_METACHARACTERS) || node.contains(GLOB_METACHARACTERS)
+}
+
+impl DeployFlake<'_> {
+    /// The node and profile the evaluation can be limited to. A node pattern is matched against
+    /// all nodes, which all have to be evaluated for that
+    pub fn evaluated(&self) -> (Option<&str>, Option<&str>) {
+        match self.node.as_deref() {
+            Some(node) if is_node_pattern(node) => (None, None),
+            node => (node, self.profile.as_deref()),
+        }
+    }
+}
+
+/// Splits a fragment containing a node pattern like `web-*.system` or `"web-\d+"` by hand, as
+/// patterns aren't valid Nix. Like in Nix, `.` separates the node from the profile outside of
+/// double quotes.
+fn split_pattern_fragment(
+    fragment: &str,
+) -> Result<(Option<String>, Option<String>), ParseFlakeError> {
+    let mut parts: Vec<String> = vec![String::new()];
+    let mut quoted = false;
+    for c in fragment.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+
+    if quoted || parts.iter().any(|part| part.is_empty()) {
+        return Err(ParseFlakeError::Unrecognized);
+    }
+
+    let mut parts = parts.into_iter();
+    let (node, profile) = (parts.next(), parts.next());
+    if parts.next().is_some() {
+        return Err(ParseFlakeError::PathTooLong);
+    }
+
+    Ok((node, profile))
+}
+
+#[test]
+fn test_split_pattern_fragment() {
+    let split = |fragment| split_pattern_fragment(fragment).unwrap();
+
+    assert_eq!(split("web-*"), (Some("web-*".to_string()), None));
+    assert_eq!(
+        split("web-*.system"),
+        (Some("web-*".to_string()), Some("system".to_string()))
+    );
+    assert_eq!(split("web-\\d+"), (Some("web-\\d+".to_string()), None));
+    assert_eq!(
+        split("\"web-\\d+\\.example\\.com\".\"home.nix\""),
+        (
+            Some("web-\\d+\\.example\\.com".to_string()),
+            Some("home.nix".to_string())
+        )
+    );
+    assert!(matches!(
+        split_pattern_fragment("web-*.system.extra"),
+        Err(ParseFlakeError::PathTooLong)
+    ));
+    assert!(matches!(
+        split_pattern_fragment("\"web-*"),
+        Err(ParseFlakeError::Unrecognized)
+    ));
+    assert!(matches!(
+        split_pattern_fragment("web-*."),
+        Err(ParseFlakeError::Unrecognized)
+    ));
+}
+
+#[test]
+fn test_deploy_flake_evaluated() {
+    let flake = |node: Option<&str>, profile: Option<&str>| DeployFlake {
+        repo: ".",
+        node: node.map(str::to_string),
+        profile: profile.map(str::to_string),
+    };
+
+    assert_eq!(flake(None, None).evaluated(), (None, None));
+    assert_eq!(flake(Some("web"), None).evaluated(), (Some("web"), None));
+    assert_eq!(
+        flake(Some("web"), Some("system")).evaluated(),
+        (Some("web"), Some("system"))
+    );
+    assert_eq!(flake(Some("web-*"), Some("system")).evaluated(), (None, None));
+    assert_eq!(flake(Some("web-\\d+"), None).evaluated(), (None, None));
+}
+
 fn parse_fragment(fragment: &str) -> Result<(Option<String>, Option<String>), ParseFlakeError> {
     let mut node: Option<String> = None;
     let mut profile: Option<String> = None;
 
+    if is_node_pattern(fragment) {
+        return split_pattern_fragment(fragment);
+    }
+
     let ast = rnix::parse(fragment);
 
     let first_child = match ast.root().node().first_child() {
@@ -312,6 +694,15 @@ fn test_parse_flake() {
         }
     );
 
+    assert_eq!(
+        parse_flake("../deploy/examples/system#web-*.system").unwrap(),
+        DeployFlake {
+            repo: "../deploy/examples/system",
+            node: Some("web-*".to_string()),
+            profile: Some("system".to_string())
+        }
+    );
+
     assert_eq!(
         parse_flake("../deploy/examples/system").unwrap(),
         DeployFlake {
@@ -346,16 +737,44 @@ pub struct DeployData<'a> {
 
     pub merged_settings: data::GenericSettings,
 
+    /// Runs the commands whose output deploy-rs needs, replaced by tests. Builds, copies and the
+    /// activation are spawned directly
+    pub runner: &'a dyn command::CommandRunner,
+
     pub debug_logs: bool,
-    pub log_dir: Option<&'a str>,
+    pub log_dir: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct DeployDefs {
     pub ssh_user: String,
+    pub activate_user: String,
     pub profile_user: String,
     pub sudo: Option<String>,
-    pub sudo_password: Option<String>,
+    pub sudo_kind: data::SudoKind,
+    /// Cleared from memory when dropped. It must never end up in logs or errors, which is why
+    /// it is only ever exposed through `sudo_stdin` and even debug formatting redacts it.
+    pub sudo_password: Option<secrecy::SecretString>,
+}
+
+impl DeployDefs {
+    /// What to write to the stdin of a command running through sudo, if the sudo command reads
+    /// a password from there
+    pub fn sudo_stdin(&self) -> Option<zeroize::Zeroizing<String>> {
+        use secrecy::ExposeSecret;
+
+        match self.sudo_password {
+            Some(ref password) if self.sudo_kind.reads_password() => {
+                let mut stdin = zeroize::Zeroizing::new(String::with_capacity(
+                    password.expose_secret().len() + 1,
+                ));
+                stdin.push_str(password.expose_secret());
+                stdin.push('\n');
+                Some(stdin)
+            }
+            _ => None,
+        }
+    }
 }
 enum ProfileInfo {
     ProfilePath {
@@ -377,10 +796,178 @@ pub enum DeployDataDefsError {
     NoSopsSecret(String, String),
     #[error("Interactive Sudo set but sudo secret set as well for profile {0} of node {1}")]
     SopsButInteractive(String, String),
+    #[error("Age file set but sudo file set as well for profile {0} of node {1}")]
+    AgeButSops(String, String),
+    #[error("Age file set but interactive sudo set as well for profile {0} of node {1}")]
+    AgeButInteractive(String, String),
+    #[error("The temp path {2} of profile {0} of node {1} is not absolute")]
+    RelativeTempPath(String, String, PathBuf),
+    #[error("noSudo is set for profile {0} of node {1}, but it is activated as a different user than `sshUser`")]
+    NoSudoButOtherUser(String, String),
+    #[error("noSudo is set for profile {0} of node {1}, but a sudo password is configured as well")]
+    NoSudoButPassword(String, String),
+    #[error("The sudo command of profile {0} of node {1} can't read a password (sudoKind {2:?}), but one is configured")]
+    PasswordNotRead(String, String, data::SudoKind),
+}
+
+/// Values of ssh's `StrictHostKeyChecking` option
+#[derive(serde::Deserialize, clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrictHostKeyChecking {
+    Yes,
+    No,
+    AcceptNew,
+}
+
+/// The ssh options for `--known-hosts-file` and `--strict-host-key-checking`
+fn host_key_ssh_opts(
+    known_hosts_file: Option<&Path>,
+    strict_host_key_checking: Option<StrictHostKeyChecking>,
+) -> Vec<String> {
+    let mut ssh_opts = Vec::new();
+
+    if let Some(known_hosts_file) = known_hosts_file {
+        ssh_opts.push("-o".to_string());
+        ssh_opts.push(format!("UserKnownHostsFile={}", known_hosts_file.display()));
+    }
+
+    if let Some(strict_host_key_checking) = strict_host_key_checking {
+        ssh_opts.push("-o".to_string());
+        ssh_opts.push(format!(
+            "StrictHostKeyChecking={}",
+            match strict_host_key_checking {
+                StrictHostKeyChecking::Yes => "yes",
+                StrictHostKeyChecking::No => "no",
+                StrictHostKeyChecking::AcceptNew => "accept-new",
+            }
+        ));
+    }
+
+    ssh_opts
+}
+
+#[test]
+fn test_host_key_ssh_opts() {
+    assert!(host_key_ssh_opts(None, None).is_empty());
+    assert_eq!(
+        host_key_ssh_opts(
+            Some(Path::new("/etc/deploy/known_hosts")),
+            Some(StrictHostKeyChecking::AcceptNew)
+        ),
+        vec![
+            "-o",
+            "UserKnownHostsFile=/etc/deploy/known_hosts",
+            "-o",
+            "StrictHostKeyChecking=accept-new"
+        ]
+    );
+}
+
+/// Strips the brackets from an IPv6 address, as `ssh` doesn't accept them
+pub fn ssh_host(hostname: &str) -> &str {
+    hostname
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(hostname)
+}
+
+/// Puts IPv6 addresses in brackets, as needed in URLs like the ones of Nix stores
+pub fn url_host(hostname: &str) -> String {
+    let host = ssh_host(hostname);
+    // Neither hostnames nor IPv4 addresses can contain colons
+    if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    }
+}
+
+#[test]
+fn test_ssh_and_url_host() {
+    assert_eq!(ssh_host("example.com"), "example.com");
+    assert_eq!(ssh_host("192.168.0.1"), "192.168.0.1");
+    assert_eq!(ssh_host("2001:db8::1"), "2001:db8::1");
+    assert_eq!(ssh_host("[2001:db8::1]"), "2001:db8::1");
+
+    assert_eq!(url_host("example.com"), "example.com");
+    assert_eq!(url_host("192.168.0.1"), "192.168.0.1");
+    assert_eq!(url_host("2001:db8::1"), "[2001:db8::1]");
+    assert_eq!(url_host("[::1]"), "[::1]");
 }
 
 impl<'a> DeployData<'a> {
+    /// The hostname of the node, `--hostname` takes precedence over the node's settings
+    pub fn hostname(&self) -> &'a str {
+        match self.cmd_overrides.node_hostnames.get(self.node_name) {
+            Some(x) => x,
+            None => match self.cmd_overrides.hostname {
+                Some(ref x) => x,
+                None => &self.node.node_settings.hostname,
+            },
+        }
+    }
+
+    /// The `user@host` address to connect to the node with `ssh`
+    pub fn ssh_addr(&self, ssh_user: &str) -> String {
+        format!("{}@{}", ssh_user, ssh_host(self.hostname()))
+    }
+
+    /// The path of the activation binary within the profile, `activate-rs` unless the profile
+    /// sets `activationScript`
+    pub fn activation_script(&self) -> &'a str {
+        self.profile
+            .profile_settings
+            .activation_script
+            .as_deref()
+            .unwrap_or("activate-rs")
+    }
+
+    fn uses_sudo_password(&self) -> bool {
+        self.merged_settings.interactive_sudo.unwrap_or(false)
+            || self.merged_settings.sudo_secret.is_some()
+            || self.cmd_overrides.age_file.is_some()
+    }
+
+    /// How the sudo command reads the password, unless `sudoKind` says so `doas` is recognized by
+    /// name and other custom commands are expected to read a password from stdin if one is used
+    pub fn sudo_kind(&self) -> data::SudoKind {
+        if let Some(sudo_kind) = self.merged_settings.sudo_kind {
+            return sudo_kind;
+        }
+
+        match self.merged_settings.sudo {
+            Some(ref sudo) if sudo.split_whitespace().next() == Some("doas") => data::SudoKind::Doas,
+            Some(_) if self.uses_sudo_password() => data::SudoKind::Stdin,
+            _ => data::SudoKind::Sudo,
+        }
+    }
+
+    /// Whether the profile is deployed as the SSH user without sudo, like home-manager profiles
+    pub fn no_sudo(&self) -> bool {
+        self.profile.profile_settings.no_sudo.unwrap_or(false)
+    }
+
+    /// The directory for the magic-rollback lock files, `/tmp` unless `tempPath` is set
+    pub fn temp_path(&self) -> &Path {
+        match self.merged_settings.temp_path {
+            Some(ref x) => x,
+            None => Path::new("/tmp"),
+        }
+    }
+
     pub fn defs(&'a self) -> Result<DeployDefs, DeployDataDefsError> {
+        // Lock paths are derived from the temp path on both ends, a relative one would resolve
+        // against whatever the working directory of `activate-rs` happens to be
+        if let Some(ref temp_path) = self.merged_settings.temp_path {
+            if !temp_path.is_absolute() {
+                return Err(DeployDataDefsError::RelativeTempPath(
+                    self.profile_name.to_owned(),
+                    self.node_name.to_owned(),
+                    temp_path.to_owned(),
+                ));
+            }
+        }
+
         let ssh_user = match self.merged_settings.ssh_user {
             Some(ref u) => u.clone(),
             None => whoami::username(),
@@ -388,11 +975,50 @@ impl<'a> DeployData<'a> {
 
         let profile_user = self.get_profile_user()?;
 
-        let sudo: Option<String> = match self.merged_settings.user {
-            Some(ref user) if user != &ssh_user => Some(format!("{} {}", self.get_sudo(), user)),
-            _ => None,
+        // Activating as the profile's owner unless told otherwise, which needs no sudo without either
+        let activate_user = match self.merged_settings.activate_user {
+            Some(ref x) => x.clone(),
+            None => match self.merged_settings.user {
+                Some(ref x) => x.clone(),
+                None => ssh_user.clone(),
+            },
         };
 
+        // With --local the commands are run as whoever runs deploy-rs, not as the `sshUser`, so
+        // that is who needs sudo to become the activating user
+        let ssh_user = if self.cmd_overrides.local {
+            whoami::username()
+        } else {
+            ssh_user
+        };
+
+        if self.no_sudo() {
+            if activate_user != ssh_user {
+                return Err(DeployDataDefsError::NoSudoButOtherUser(
+                    self.profile_name.to_owned(),
+                    self.node_name.to_owned(),
+                ));
+            }
+
+            if self.uses_sudo_password() {
+                return Err(DeployDataDefsError::NoSudoButPassword(
+                    self.profile_name.to_owned(),
+                    self.node_name.to_owned(),
+                ));
+            }
+        }
+
+        let sudo = sudo_command(&self.get_sudo(), &ssh_user, &activate_user);
+
+        let sudo_kind = self.sudo_kind();
+        if !self.no_sudo() && self.uses_sudo_password() && !sudo_kind.reads_password() {
+            return Err(DeployDataDefsError::PasswordNotRead(
+                self.profile_name.to_owned(),
+                self.node_name.to_owned(),
+                sudo_kind,
+            ));
+        }
+
         // Check if one of sudo_file or sudo_secret is missing
         if self.merged_settings.sudo_file.is_some() && self.merged_settings.sudo_secret.is_none() {
             return Err(DeployDataDefsError::NoSopsSecret(
@@ -401,7 +1027,10 @@ impl<'a> DeployData<'a> {
             ));
         }
 
-        if self.merged_settings.sudo_file.is_none() && self.merged_settings.sudo_secret.is_some() {
+        if self.merged_settings.sudo_file.is_none()
+            && self.merged_settings.sudo_secret.is_some()
+            && self.cmd_overrides.age_file.is_none()
+        {
             return Err(DeployDataDefsError::NoSopsFile(
                 self.profile_name.to_owned(),
                 self.node_name.to_owned(),
@@ -418,10 +1047,29 @@ impl<'a> DeployData<'a> {
             ));
         }
 
+        // The age file replaces the sops file and interactive sudo, so only one of them may be used
+        if self.cmd_overrides.age_file.is_some() {
+            if self.merged_settings.sudo_file.is_some() {
+                return Err(DeployDataDefsError::AgeButSops(
+                    self.profile_name.to_owned(),
+                    self.node_name.to_owned(),
+                ));
+            }
+
+            if self.merged_settings.interactive_sudo.unwrap_or(false) {
+                return Err(DeployDataDefsError::AgeButInteractive(
+                    self.profile_name.to_owned(),
+                    self.node_name.to_owned(),
+                ));
+            }
+        }
+
         Ok(DeployDefs {
             ssh_user,
+            activate_user,
             profile_user,
             sudo,
+            sudo_kind,
             sudo_password: None,
         })
     }
@@ -465,23 +1113,56 @@ impl<'a> DeployData<'a> {
     }
 }
 
-pub fn make_deploy_data<'a, 's>(
-    top_settings: &'s data::GenericSettings,
-    node: &'a data::Node,
-    node_name: &'a str,
-    profile: &'a data::Profile,
-    profile_name: &'a str,
-    cmd_overrides: &'a CmdOverrides,
-    debug_logs: bool,
-    log_dir: Option<&'a str>,
-) -> DeployData<'a> {
-    let mut merged_settings = profile.generic_settings.clone();
-    merged_settings.merge(node.generic_settings.clone());
+/// The command prefix that runs commands as `activate_user`, none if that is the SSH user anyway
+fn sudo_command(sudo: &str, ssh_user: &str, activate_user: &str) -> Option<String> {
+    if activate_user == ssh_user {
+        None
+    } else {
+        Some(format!("{} {}", sudo, activate_user))
+    }
+}
+
+#[test]
+fn test_sudo_command() {
+    assert_eq!(sudo_command("sudo -u", "deploy", "root"), Some("sudo -u root".to_string()));
+    assert_eq!(sudo_command("doas -u", "deploy", "svc"), Some("doas -u svc".to_string()));
+    assert_eq!(sudo_command("sudo -u", "root", "root"), None);
+}
+
+/// Resolves the settings a profile is deployed with. Each setting is taken from the first of
+/// these that sets it:
+///
+/// 1. the command line, where an override given for this node (e.g. `--node-confirm-timeout`)
+///    wins over the one given for all nodes. `--remote-build` is the exception, it only applies
+///    to the nodes and profiles that don't set `remoteBuild` themselves,
+/// 2. the profile,
+/// 3. the node,
+/// 4. the top level of the deployment.
+///
+/// `ssh_opts` are the exception: those of the command line, profile, node and top level are all
+/// passed, in that order, as ssh uses the first value it gets for an option. `--ssh-opts` replaces
+/// the ones of the deployment though, unless `--ssh-opts-append` is given. `--extra-ssh-args` come
+/// before all of them and are never split like `--ssh-opts` is. `--ssh-config`,
+/// `--verbose-ssh`, `--known-hosts-file` and `--strict-host-key-checking` always add to them.
+/// `extra_build_args` are all passed too, the other way around: later build arguments win, so
+/// those of the top level come first and the profile's last, before the ones of the command line.
+pub fn merge_settings(
+    top_settings: &data::GenericSettings,
+    node_settings: &data::GenericSettings,
+    profile_settings: &data::GenericSettings,
+    node_name: &str,
+    cmd_overrides: &CmdOverrides,
+) -> data::GenericSettings {
+    let mut merged_settings = profile_settings.clone();
+    merged_settings.merge(node_settings.clone());
     merged_settings.merge(top_settings.clone());
 
-    // build all machines remotely when the command line flag is set
-    if cmd_overrides.remote_build {
-        merged_settings.remote_build = Some(cmd_overrides.remote_build);
+    if cmd_overrides.local {
+        // The profile is built into the store it is activated from anyway
+        merged_settings.remote_build = Some(false);
+    } else if cmd_overrides.remote_build && merged_settings.remote_build.is_none() {
+        // A mixed fleet can build some nodes locally even with --remote-build
+        merged_settings.remote_build = Some(true);
     }
     if cmd_overrides.ssh_user.is_some() {
         merged_settings.ssh_user = cmd_overrides.ssh_user.clone();
@@ -490,11 +1171,36 @@ pub fn make_deploy_data<'a, 's>(
         merged_settings.user = cmd_overrides.profile_user.clone();
     }
     if let Some(ref ssh_opts) = cmd_overrides.ssh_opts {
-        merged_settings.ssh_opts = ssh_opts.split(' ').map(|x| x.to_owned()).collect();
+        let mut cmd_ssh_opts = ssh_opts.clone();
+        if cmd_overrides.ssh_opts_append {
+            cmd_ssh_opts.append(&mut merged_settings.ssh_opts);
+        }
+        merged_settings.ssh_opts = cmd_ssh_opts;
+    }
+    if !cmd_overrides.extra_ssh_args.is_empty() {
+        let mut ssh_opts = cmd_overrides.extra_ssh_args.clone();
+        ssh_opts.append(&mut merged_settings.ssh_opts);
+        merged_settings.ssh_opts = ssh_opts;
     }
+    // Every ssh invocation, including the ones of `nix copy`, gets its options from here
+    if let Some(ref ssh_config) = cmd_overrides.ssh_config {
+        merged_settings.ssh_opts.push("-F".to_string());
+        merged_settings.ssh_opts.push(ssh_config.display().to_string());
+    }
+    if cmd_overrides.verbose_ssh > 0 {
+        let verbosity = "v".repeat(cmd_overrides.verbose_ssh.min(3).into());
+        merged_settings.ssh_opts.push(format!("-{}", verbosity));
+    }
+    merged_settings.ssh_opts.extend(host_key_ssh_opts(
+        cmd_overrides.known_hosts_file.as_deref(),
+        cmd_overrides.strict_host_key_checking,
+    ));
     if let Some(fast_connection) = cmd_overrides.fast_connection {
         merged_settings.fast_connection = Some(fast_connection);
     }
+    if let Some(substitute_on_destination) = cmd_overrides.substitute_on_destination {
+        merged_settings.substitute_on_destination = Some(substitute_on_destination);
+    }
     if let Some(compress) = cmd_overrides.compress {
         merged_settings.compress = Some(compress);
     }
@@ -504,21 +1210,292 @@ pub fn make_deploy_data<'a, 's>(
     if let Some(magic_rollback) = cmd_overrides.magic_rollback {
         merged_settings.magic_rollback = Some(magic_rollback);
     }
-    if let Some(confirm_timeout) = cmd_overrides.confirm_timeout {
+    if let Some(ref temp_path) = cmd_overrides.temp_path {
+        merged_settings.temp_path = Some(temp_path.to_owned());
+    }
+    // A timeout given for this node takes precedence over one given for all nodes
+    if let Some(confirm_timeout) = cmd_overrides
+        .node_confirm_timeouts
+        .get(node_name)
+        .copied()
+        .or(cmd_overrides.confirm_timeout)
+    {
         merged_settings.confirm_timeout = Some(confirm_timeout);
     }
-    if let Some(activation_timeout) = cmd_overrides.activation_timeout {
+    if let Some(activation_timeout) = cmd_overrides
+        .node_activation_timeouts
+        .get(node_name)
+        .copied()
+        .or(cmd_overrides.activation_timeout)
+    {
         merged_settings.activation_timeout = Some(activation_timeout);
     }
+    if let Some(ref sudo) = cmd_overrides.sudo {
+        merged_settings.sudo = Some(sudo.to_owned());
+    }
+    if let Some(sudo_kind) = cmd_overrides.sudo_kind {
+        merged_settings.sudo_kind = Some(sudo_kind);
+    }
     if let Some(interactive_sudo) = cmd_overrides.interactive_sudo {
         merged_settings.interactive_sudo = Some(interactive_sudo);
     }
-    if let Some(ref sudo_file) = cmd_overrides.sudo_file {
+    if let Some(sudo_file) = cmd_overrides
+        .node_sudo_files
+        .get(node_name)
+        .or(cmd_overrides.sudo_file.as_ref())
+    {
         merged_settings.sudo_file = Some(sudo_file.to_owned());
     }
-    if let Some(ref sudo_secret) = cmd_overrides.sudo_secret {
+    if let Some(sudo_secret) = cmd_overrides
+        .node_sudo_secrets
+        .get(node_name)
+        .or(cmd_overrides.sudo_secret.as_ref())
+    {
         merged_settings.sudo_secret = Some(sudo_secret.to_owned());
     }
+    if let Some(ref health_check_cmd) = cmd_overrides.health_check_cmd {
+        merged_settings.health_check_cmd = Some(health_check_cmd.to_owned());
+    }
+    if let Some(health_check_timeout) = cmd_overrides.health_check_timeout {
+        merged_settings.health_check_timeout = Some(health_check_timeout);
+    }
+
+    merged_settings
+}
+
+#[cfg(test)]
+fn layer_settings(layer: &str, timeout: u16, flag: bool) -> data::GenericSettings {
+    serde_json::from_value(serde_json::json!({
+        "sshUser": format!("{}-ssh", layer),
+        "user": format!("{}-user", layer),
+        "activateUser": format!("{}-activate", layer),
+        "sshOpts": [format!("-{}", layer)],
+        "compress": flag,
+        "fastConnection": flag,
+        "substituteOnDestination": flag,
+        "autoRollback": flag,
+        "confirmTimeout": timeout,
+        "activationTimeout": timeout,
+        "tempPath": format!("/tmp/{}", layer),
+        "magicRollback": flag,
+        "sudo": format!("{}-sudo", layer),
+        "sudoKind": "plain",
+        "remoteBuild": flag,
+        "interactiveSudo": flag,
+        "sudoFile": format!("/secrets/{}", layer),
+        "sudoSecret": format!("{}-secret", layer),
+        "healthCheckCmd": format!("{}-check", layer),
+        "healthCheckTimeout": timeout,
+        "extraBuildArgs": [format!("--{}", layer)],
+    }))
+    .unwrap()
+}
+
+#[cfg(test)]
+fn assert_layer(settings: &data::GenericSettings, layer: &str, timeout: u16, flag: bool) {
+    let expected = layer_settings(layer, timeout, flag);
+    assert_eq!(settings.ssh_user, expected.ssh_user);
+    assert_eq!(settings.user, expected.user);
+    assert_eq!(settings.activate_user, expected.activate_user);
+    assert_eq!(settings.compress, expected.compress);
+    assert_eq!(settings.fast_connection, expected.fast_connection);
+    assert_eq!(settings.substitute_on_destination, expected.substitute_on_destination);
+    assert_eq!(settings.auto_rollback, expected.auto_rollback);
+    assert_eq!(settings.confirm_timeout, expected.confirm_timeout);
+    assert_eq!(settings.activation_timeout, expected.activation_timeout);
+    assert_eq!(settings.temp_path, expected.temp_path);
+    assert_eq!(settings.magic_rollback, expected.magic_rollback);
+    assert_eq!(settings.sudo, expected.sudo);
+    assert_eq!(settings.remote_build, expected.remote_build);
+    assert_eq!(settings.interactive_sudo, expected.interactive_sudo);
+    assert_eq!(settings.sudo_file, expected.sudo_file);
+    assert_eq!(settings.sudo_secret, expected.sudo_secret);
+    assert_eq!(settings.health_check_cmd, expected.health_check_cmd);
+    assert_eq!(settings.health_check_timeout, expected.health_check_timeout);
+}
+
+#[test]
+fn test_merge_settings_precedence() {
+    let unset: data::GenericSettings = serde_json::from_value(serde_json::json!({})).unwrap();
+    let top = layer_settings("top", 11, false);
+    let node = layer_settings("node", 20, true);
+    let profile = layer_settings("profile", 31, false);
+    let cmd_overrides = CmdOverrides::default();
+
+    // The profile wins over the node, which wins over the top level
+    let merged = merge_settings(&top, &node, &profile, "web", &cmd_overrides);
+    assert_layer(&merged, "profile", 31, false);
+    let merged = merge_settings(&top, &node, &unset, "web", &cmd_overrides);
+    assert_layer(&merged, "node", 20, true);
+    let merged = merge_settings(&top, &unset, &unset, "web", &cmd_overrides);
+    assert_layer(&merged, "top", 11, false);
+
+    // Settings missing from a layer are taken from the next one
+    let merged = merge_settings(&top, &unset, &profile, "web", &cmd_overrides);
+    assert_layer(&merged, "profile", 31, false);
+    let magic_rollback_only: data::GenericSettings =
+        serde_json::from_value(serde_json::json!({ "magicRollback": true })).unwrap();
+    let merged = merge_settings(&top, &node, &magic_rollback_only, "web", &cmd_overrides);
+    assert_eq!(merged.magic_rollback, Some(true));
+    assert_eq!(merged.auto_rollback, Some(true));
+    assert_eq!(merged.confirm_timeout, Some(20));
+    assert_eq!(merged.temp_path, Some(PathBuf::from("/tmp/node")));
+
+    // ssh_opts of all layers are passed, the profile's first
+    let merged = merge_settings(&top, &node, &profile, "web", &cmd_overrides);
+    assert_eq!(merged.ssh_opts, vec!["-profile", "-node", "-top"]);
+    // extra_build_args of all layers are passed too, the profile's last
+    assert_eq!(merged.extra_build_args, vec!["--top", "--node", "--profile"]);
+}
+
+#[test]
+fn test_merge_settings_cmd_overrides() {
+    let top = layer_settings("top", 11, false);
+    let node = layer_settings("node", 20, true);
+    let profile = layer_settings("profile", 31, false);
+
+    let cmd_overrides = CmdOverrides {
+        ssh_user: Some("cli-ssh".to_string()),
+        profile_user: Some("cli-user".to_string()),
+        ssh_opts: Some(vec!["-p".to_string(), "2222".to_string()]),
+        ssh_config: Some(PathBuf::from("/etc/ssh/deploy_config")),
+        verbose_ssh: 5,
+        strict_host_key_checking: Some(StrictHostKeyChecking::AcceptNew),
+        fast_connection: Some(true),
+        substitute_on_destination: Some(true),
+        compress: Some(true),
+        auto_rollback: Some(true),
+        magic_rollback: Some(true),
+        temp_path: Some(PathBuf::from("/tmp/cli")),
+        confirm_timeout: Some(40),
+        node_confirm_timeouts: vec![("web".to_string(), 50)].into_iter().collect(),
+        activation_timeout: Some(40),
+        sudo: Some("cli-sudo".to_string()),
+        sudo_kind: Some(data::SudoKind::Sudo),
+        interactive_sudo: Some(true),
+        sudo_file: Some(PathBuf::from("/secrets/cli")),
+        node_sudo_secrets: vec![("db".to_string(), "db-secret".to_string())]
+            .into_iter()
+            .collect(),
+        sudo_secret: Some("cli-secret".to_string()),
+        health_check_cmd: Some("cli-check".to_string()),
+        health_check_timeout: Some(40),
+        remote_build: true,
+        ..CmdOverrides::default()
+    };
+
+    let merged = merge_settings(&top, &node, &profile, "web", &cmd_overrides);
+    assert_eq!(merged.ssh_user.as_deref(), Some("cli-ssh"));
+    assert_eq!(merged.user.as_deref(), Some("cli-user"));
+    // Not overridable from the command line
+    assert_eq!(merged.activate_user.as_deref(), Some("profile-activate"));
+    assert_eq!(merged.fast_connection, Some(true));
+    assert_eq!(merged.substitute_on_destination, Some(true));
+    assert_eq!(merged.compress, Some(true));
+    assert_eq!(merged.auto_rollback, Some(true));
+    assert_eq!(merged.magic_rollback, Some(true));
+    assert_eq!(merged.temp_path, Some(PathBuf::from("/tmp/cli")));
+    assert_eq!(merged.confirm_timeout, Some(50));
+    assert_eq!(merged.activation_timeout, Some(40));
+    assert_eq!(merged.sudo.as_deref(), Some("cli-sudo"));
+    assert_eq!(merged.sudo_kind, Some(data::SudoKind::Sudo));
+    assert_eq!(merged.interactive_sudo, Some(true));
+    assert_eq!(merged.sudo_file, Some(PathBuf::from("/secrets/cli")));
+    assert_eq!(merged.sudo_secret.as_deref(), Some("cli-secret"));
+    assert_eq!(merged.health_check_cmd.as_deref(), Some("cli-check"));
+    assert_eq!(merged.health_check_timeout, Some(40));
+    // --remote-build does not override the remoteBuild of the deployment
+    assert_eq!(merged.remote_build, Some(false));
+    let unset: data::GenericSettings = serde_json::from_value(serde_json::json!({})).unwrap();
+    let merged = merge_settings(&unset, &unset, &unset, "web", &cmd_overrides);
+    assert_eq!(merged.remote_build, Some(true));
+    // --ssh-opts replaces the options of all layers, the other ssh flags add to it
+    assert_eq!(
+        merged.ssh_opts,
+        vec![
+            "-p",
+            "2222",
+            "-F",
+            "/etc/ssh/deploy_config",
+            "-vvv",
+            "-o",
+            "StrictHostKeyChecking=accept-new",
+        ]
+    );
+
+    // Overrides for other nodes do not apply
+    let merged = merge_settings(&top, &node, &profile, "db", &cmd_overrides);
+    assert_eq!(merged.confirm_timeout, Some(40));
+    assert_eq!(merged.sudo_secret.as_deref(), Some("db-secret"));
+
+    // Boolean flags that are off do not override the deployment's settings
+    let merged = merge_settings(&top, &node, &profile, "web", &CmdOverrides::default());
+    assert_eq!(merged.remote_build, Some(false));
+
+    // --extra-ssh-args are passed first and as they are, even with spaces
+    let cmd_overrides = CmdOverrides {
+        ssh_opts: Some(vec!["-p".to_string(), "2222".to_string()]),
+        extra_ssh_args: vec!["-o".to_string(), "ProxyCommand=ssh -W %h:%p bastion".to_string()],
+        ..CmdOverrides::default()
+    };
+    let merged = merge_settings(&top, &node, &profile, "web", &cmd_overrides);
+    assert_eq!(
+        merged.ssh_opts,
+        vec!["-o", "ProxyCommand=ssh -W %h:%p bastion", "-p", "2222"]
+    );
+
+    // With --ssh-opts-append they come before the options of all layers instead
+    let cmd_overrides = CmdOverrides {
+        ssh_opts: Some(vec!["-p".to_string(), "2222".to_string()]),
+        ssh_opts_append: true,
+        verbose_ssh: 1,
+        ..CmdOverrides::default()
+    };
+    let merged = merge_settings(&top, &node, &profile, "web", &cmd_overrides);
+    assert_eq!(
+        merged.ssh_opts,
+        vec!["-p", "2222", "-profile", "-node", "-top", "-v"]
+    );
+    let cmd_overrides = CmdOverrides {
+        ssh_opts_append: true,
+        ..CmdOverrides::default()
+    };
+    let merged = merge_settings(&top, &node, &profile, "web", &cmd_overrides);
+    assert_eq!(merged.ssh_opts, vec!["-profile", "-node", "-top"]);
+
+    // --local never builds remotely
+    let cmd_overrides = CmdOverrides {
+        local: true,
+        remote_build: true,
+        ..CmdOverrides::default()
+    };
+    let merged = merge_settings(&top, &node, &layer_settings("profile", 30, true), "web", &cmd_overrides);
+    assert_eq!(merged.remote_build, Some(false));
+}
+
+pub fn make_deploy_data<'a, 's>(
+    top_settings: &'s data::GenericSettings,
+    node: &'a data::Node,
+    node_name: &'a str,
+    profile: &'a data::Profile,
+    profile_name: &'a str,
+    cmd_overrides: &'a CmdOverrides,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+) -> DeployData<'a> {
+    let merged_settings = merge_settings(
+        top_settings,
+        &node.generic_settings,
+        &profile.generic_settings,
+        node_name,
+        cmd_overrides,
+    );
+
+    // activate-rs creates the log directory itself if it does not exist yet
+    let log_dir = match log_dir {
+        Some(log_dir) if cmd_overrides.per_node_logs => Some(format!("{}/{}", log_dir, node_name)),
+        log_dir => log_dir.map(str::to_string),
+    };
 
     DeployData {
         node_name,
@@ -527,7 +1504,283 @@ pub fn make_deploy_data<'a, 's>(
         profile,
         cmd_overrides,
         merged_settings,
+        runner: &command::SYSTEM_RUNNER,
         debug_logs,
         log_dir,
     }
 }
+
+/// The `DeployData` of the profile `system` of `node_name` for tests, from the JSON of the node and
+/// the profile. What it borrows is leaked, which doesn't matter in a test
+#[cfg(test)]
+pub(crate) fn test_deploy_data(
+    node_name: &'static str,
+    node: serde_json::Value,
+    profile: serde_json::Value,
+    cmd_overrides: CmdOverrides,
+) -> DeployData<'static> {
+    let top_settings: data::GenericSettings = serde_json::from_value(serde_json::json!({})).unwrap();
+    let node: data::Node = serde_json::from_value(node).unwrap();
+    let profile: data::Profile = serde_json::from_value(profile).unwrap();
+
+    make_deploy_data(
+        &top_settings,
+        Box::leak(Box::new(node)),
+        node_name,
+        Box::leak(Box::new(profile)),
+        "system",
+        Box::leak(Box::new(cmd_overrides)),
+        false,
+        None,
+    )
+}
+
+#[test]
+fn test_relative_temp_path() {
+    let node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "deploy",
+        "tempPath": "/var/tmp",
+        "profiles": {},
+    });
+    let profile = serde_json::json!({ "path": "/nix/store/00000000000000000000000000000000-system" });
+
+    let deploy_data = test_deploy_data("example", node.clone(), profile.clone(), CmdOverrides::default());
+    assert_eq!(deploy_data.temp_path(), Path::new("/var/tmp"));
+    assert!(deploy_data.defs().is_ok());
+
+    let cmd_overrides = CmdOverrides {
+        temp_path: Some(PathBuf::from("deploy-rs")),
+        ..CmdOverrides::default()
+    };
+    let deploy_data = test_deploy_data("example", node, profile, cmd_overrides);
+    assert!(matches!(
+        deploy_data.defs(),
+        Err(DeployDataDefsError::RelativeTempPath(_, _, p)) if p == Path::new("deploy-rs")
+    ));
+}
+
+#[test]
+fn test_no_sudo() {
+    let node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "alice",
+        "profiles": {},
+    });
+    let profile = serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-home-manager",
+        "noSudo": true,
+    });
+
+    let deploy_data = test_deploy_data("example", node.clone(), profile.clone(), CmdOverrides::default());
+    let deploy_defs = deploy_data.defs().unwrap();
+    assert_eq!(deploy_defs.sudo, None);
+    assert_eq!(deploy_defs.profile_user, "alice");
+
+    let cmd_overrides = CmdOverrides {
+        profile_user: Some("root".to_string()),
+        ..CmdOverrides::default()
+    };
+    let deploy_data = test_deploy_data("example", node, profile, cmd_overrides);
+    assert!(matches!(
+        deploy_data.defs(),
+        Err(DeployDataDefsError::NoSudoButOtherUser(..))
+    ));
+}
+
+#[test]
+fn test_ssh_config() {
+    let cmd_overrides = CmdOverrides {
+        ssh_config: Some(PathBuf::from("/etc/deploy/ssh_config")),
+        verbose_ssh: 5,
+        strict_host_key_checking: Some(StrictHostKeyChecking::Yes),
+        ..CmdOverrides::default()
+    };
+
+    let deploy_data = test_deploy_data(
+        "example",
+        serde_json::json!({
+            "hostname": "example.com",
+            "sshOpts": ["-p", "2222"],
+            "profiles": {},
+        }),
+        serde_json::json!({ "path": "/nix/store/00000000000000000000000000000000-system" }),
+        cmd_overrides,
+    );
+    assert_eq!(
+        deploy_data.merged_settings.ssh_opts,
+        vec![
+            "-p",
+            "2222",
+            "-F",
+            "/etc/deploy/ssh_config",
+            "-vvv",
+            "-o",
+            "StrictHostKeyChecking=yes"
+        ]
+    );
+}
+
+#[test]
+fn test_node_sudo_secret() {
+    for (node_name, expected) in [("db", "passwords/db"), ("web", "passwords/default")] {
+        let cmd_overrides = CmdOverrides {
+            sudo_file: Some(PathBuf::from("secrets.yaml")),
+            sudo_secret: Some("passwords/default".to_string()),
+            node_sudo_secrets: HashMap::from([("db".to_string(), "passwords/db".to_string())]),
+            ..CmdOverrides::default()
+        };
+        let deploy_data = test_deploy_data(
+            node_name,
+            serde_json::json!({
+                "hostname": "example.com",
+                "sudoSecret": "passwords/flake",
+                "profiles": {},
+            }),
+            serde_json::json!({ "path": "/nix/store/00000000000000000000000000000000-system" }),
+            cmd_overrides,
+        );
+        assert_eq!(deploy_data.merged_settings.sudo_secret.as_deref(), Some(expected));
+        assert_eq!(
+            deploy_data.merged_settings.sudo_file.as_deref(),
+            Some(Path::new("secrets.yaml"))
+        );
+    }
+}
+
+#[test]
+fn test_deploy_defs_debug_redacts_password() {
+    let deploy_defs = DeployDefs {
+        ssh_user: "alice".to_string(),
+        activate_user: "root".to_string(),
+        profile_user: "root".to_string(),
+        sudo: Some("sudo -u root -S -p \"\"".to_string()),
+        sudo_kind: data::SudoKind::Sudo,
+        sudo_password: Some("hunter2".into()),
+    };
+
+    let debug = format!("{:?}", deploy_defs);
+    assert!(debug.contains("alice"));
+    assert!(!debug.contains("hunter2"));
+}
+
+#[test]
+fn test_sudo_kind() {
+    let defs = |node: serde_json::Value| {
+        let profile = serde_json::json!({
+            "path": "/nix/store/00000000000000000000000000000000-system",
+            "user": "root",
+        });
+        test_deploy_data("example", node, profile, CmdOverrides::default()).defs()
+    };
+
+    let deploy_defs = defs(serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "alice",
+        "profiles": {},
+    }))
+    .unwrap();
+    assert_eq!(deploy_defs.sudo_kind, data::SudoKind::Sudo);
+
+    let mut deploy_defs = defs(serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "alice",
+        "sudo": "doas -u",
+        "profiles": {},
+    }))
+    .unwrap();
+    assert_eq!(deploy_defs.sudo_kind, data::SudoKind::Doas);
+    deploy_defs.sudo_password = Some("hunter2".into());
+    assert_eq!(deploy_defs.sudo_stdin(), None);
+
+    let deploy_defs = defs(serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "alice",
+        "sudo": "please -u",
+        "interactiveSudo": true,
+        "profiles": {},
+    }))
+    .unwrap();
+    assert_eq!(deploy_defs.sudo_kind, data::SudoKind::Stdin);
+
+    assert!(matches!(
+        defs(serde_json::json!({
+            "hostname": "example.com",
+            "sshUser": "alice",
+            "sudo": "doas -u",
+            "interactiveSudo": true,
+            "profiles": {},
+        })),
+        Err(DeployDataDefsError::PasswordNotRead(_, _, data::SudoKind::Doas))
+    ));
+}
+
+#[test]
+fn test_activate_user() {
+    let defs = |profile: serde_json::Value| {
+        let node = serde_json::json!({
+            "hostname": "example.com",
+            "sshUser": "deploy",
+            "profiles": {},
+        });
+        test_deploy_data("example", node, profile, CmdOverrides::default())
+            .defs()
+            .unwrap()
+    };
+
+    // Without `activateUser` the profile is activated as its owner
+    let deploy_defs = defs(serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-app",
+        "user": "svc",
+    }));
+    assert_eq!(deploy_defs.activate_user, "svc");
+    assert_eq!(deploy_defs.sudo.as_deref(), Some("sudo -u svc"));
+
+    let deploy_defs = defs(serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-app",
+        "user": "svc",
+        "activateUser": "root",
+    }));
+    assert_eq!(deploy_defs.ssh_user, "deploy");
+    assert_eq!(deploy_defs.activate_user, "root");
+    assert_eq!(deploy_defs.profile_user, "svc");
+    assert_eq!(deploy_defs.sudo.as_deref(), Some("sudo -u root"));
+}
+
+#[test]
+fn test_local_sudo() {
+    let local_user = whoami::username();
+
+    // The `sshUser` is irrelevant without ssh, sudo depends on who runs deploy-rs
+    let defs = |ssh_user: &str| {
+        let node = serde_json::json!({
+            "hostname": "localhost",
+            "sshUser": ssh_user,
+            "profiles": {},
+        });
+        let profile = serde_json::json!({
+            "path": "/nix/store/00000000000000000000000000000000-system",
+            "user": "root",
+        });
+        let cmd_overrides = CmdOverrides {
+            local: true,
+            ..CmdOverrides::default()
+        };
+        test_deploy_data("localhost", node, profile, cmd_overrides)
+            .defs()
+            .unwrap()
+    };
+
+    let deploy_defs = defs("root");
+    assert_eq!(deploy_defs.ssh_user, local_user);
+    assert_eq!(deploy_defs.activate_user, "root");
+    if local_user == "root" {
+        assert_eq!(deploy_defs.sudo, None);
+    } else {
+        assert_eq!(deploy_defs.sudo.as_deref(), Some("sudo -u root"));
+    }
+
+    let deploy_defs = defs("someone-else");
+    assert_eq!(deploy_defs.ssh_user, local_user);
+    assert_eq!(deploy_defs.sudo.is_none(), local_user == "root");
+}
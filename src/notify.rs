@@ -0,0 +1,206 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::Serialize;
+#[cfg(feature = "notify")]
+use std::time::Duration;
+#[cfg(feature = "notify")]
+use thiserror::Error;
+
+/// How long to wait for a notification endpoint before giving up
+#[cfg(feature = "notify")]
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileStatus {
+    /// The profile was not activated, either because it was already up to date or because the
+    /// deployment was aborted before reaching it
+    Skipped,
+    Activated,
+    Failed,
+    RolledBack,
+    /// Skipped at the `--interactive-per-node` prompt
+    Declined,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProfileReport {
+    pub node: String,
+    pub profile: String,
+    pub status: ProfileStatus,
+    pub error: Option<String>,
+}
+
+/// The outcome of every profile that was part of a deployment
+#[derive(Serialize, Debug, Default)]
+pub struct DeployReport {
+    pub profiles: Vec<ProfileReport>,
+}
+
+impl DeployReport {
+    pub fn add(&mut self, node: &str, profile: &str) {
+        self.profiles.push(ProfileReport {
+            node: node.to_string(),
+            profile: profile.to_string(),
+            status: ProfileStatus::Skipped,
+            error: None,
+        });
+    }
+
+    pub fn set_status(&mut self, node: &str, profile: &str, status: ProfileStatus) {
+        if let Some(p) = self
+            .profiles
+            .iter_mut()
+            .find(|p| p.node == node && p.profile == profile)
+        {
+            p.status = status;
+        }
+    }
+
+    pub fn set_failed(&mut self, node: &str, profile: &str, error: String) {
+        if let Some(p) = self
+            .profiles
+            .iter_mut()
+            .find(|p| p.node == node && p.profile == profile)
+        {
+            p.status = ProfileStatus::Failed;
+            p.error = Some(error);
+        }
+    }
+}
+
+#[cfg(feature = "notify")]
+#[derive(Error, Debug)]
+pub enum NotifyError {
+    #[error("Failed to build the HTTP client: {0}")]
+    Client(reqwest::Error),
+    #[error("Failed to send the notification: {0}")]
+    Send(reqwest::Error),
+    #[error("Notification endpoint responded with status {0}")]
+    Status(reqwest::StatusCode),
+}
+
+/// POSTs `payload` as JSON to `url`
+#[cfg(feature = "notify")]
+pub async fn send_webhook(url: &str, payload: &impl Serialize) -> Result<(), NotifyError> {
+    let client = reqwest::Client::builder()
+        .timeout(NOTIFY_TIMEOUT)
+        .build()
+        .map_err(NotifyError::Client)?;
+
+    let response = client
+        .post(url)
+        .json(payload)
+        .send()
+        .await
+        .map_err(NotifyError::Send)?;
+
+    if !response.status().is_success() {
+        return Err(NotifyError::Status(response.status()));
+    }
+
+    Ok(())
+}
+
+/// Formats a deployment as a Slack message, with one attachment per node
+#[cfg(feature = "slack")]
+pub fn slack_message(report: &DeployReport, error: Option<&str>) -> serde_json::Value {
+    let mut nodes: Vec<&str> = Vec::new();
+    for p in &report.profiles {
+        if !nodes.contains(&p.node.as_str()) {
+            nodes.push(&p.node);
+        }
+    }
+
+    let attachments: Vec<serde_json::Value> = nodes
+        .into_iter()
+        .map(|node| {
+            let profiles: Vec<&ProfileReport> =
+                report.profiles.iter().filter(|p| p.node == node).collect();
+            let color = if profiles.iter().all(|p| p.status == ProfileStatus::Activated) {
+                "good"
+            } else if profiles.iter().any(|p| {
+                p.status == ProfileStatus::Failed || p.status == ProfileStatus::RolledBack
+            }) {
+                "danger"
+            } else {
+                "warning"
+            };
+            let text = profiles
+                .iter()
+                .map(|p| {
+                    let status = match p.status {
+                        ProfileStatus::Skipped => "not deployed",
+                        ProfileStatus::Activated => "activated",
+                        ProfileStatus::Failed => "failed",
+                        ProfileStatus::RolledBack => "rolled back",
+                        ProfileStatus::Declined => "skipped",
+                    };
+                    format!("`{}`: {}", p.profile, status)
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            serde_json::json!({
+                "color": color,
+                "title": node,
+                "text": text,
+                "mrkdwn_in": ["text"],
+            })
+        })
+        .collect();
+
+    let text = match error {
+        None => "Deployment succeeded".to_string(),
+        Some(e) => format!("Deployment failed: {}", e),
+    };
+
+    serde_json::json!({
+        "text": text,
+        "attachments": attachments,
+    })
+}
+
+#[cfg(feature = "slack")]
+#[test]
+fn test_slack_message() {
+    let mut report = DeployReport::default();
+    report.add("a", "system");
+    report.add("a", "user");
+    report.add("b", "system");
+    report.set_status("a", "system", ProfileStatus::Activated);
+    report.set_status("a", "user", ProfileStatus::Activated);
+    report.set_status("b", "system", ProfileStatus::RolledBack);
+
+    let message = slack_message(&report, Some("boom"));
+    assert_eq!(message["text"], "Deployment failed: boom");
+    assert_eq!(message["attachments"][0]["color"], "good");
+    assert_eq!(message["attachments"][0]["text"], "`system`: activated\n`user`: activated");
+    assert_eq!(message["attachments"][1]["color"], "danger");
+    assert_eq!(message["attachments"][1]["title"], "b");
+}
+
+#[test]
+fn test_deploy_report() {
+    let mut report = DeployReport::default();
+    report.add("a", "system");
+    report.add("b", "system");
+    report.add("c", "system");
+    report.set_failed("b", "system", "boom".to_string());
+    report.set_status("c", "system", ProfileStatus::RolledBack);
+    report.add("d", "system");
+    report.set_status("d", "system", ProfileStatus::Declined);
+
+    assert_eq!(report.profiles[0].status, ProfileStatus::Skipped);
+    assert_eq!(report.profiles[1].status, ProfileStatus::Failed);
+
+    let json = serde_json::to_value(&report.profiles).unwrap();
+    assert_eq!(json[0]["status"], "skipped");
+    assert_eq!(json[0]["error"], serde_json::Value::Null);
+    assert_eq!(json[1]["status"], "failed");
+    assert_eq!(json[1]["error"], "boom");
+    assert_eq!(json[2]["status"], "rolled_back");
+    assert_eq!(json[3]["status"], "declined");
+}
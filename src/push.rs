@@ -3,11 +3,18 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use log::{debug, info, warn};
-use std::path::Path;
-use std::process::Stdio;
+use std::convert::TryInto;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::process::Command;
 
+use crate::command::CommandSpec;
+use crate::deploy::{format_stderr_tail, log_dry_run, output_prefix, shell_quote, tee_stderr};
+
 #[derive(Error, Debug)]
 pub enum PushProfileError {
     #[error("Failed to run Nix show-derivation command: {0}")]
@@ -26,6 +33,10 @@ pub enum PushProfileError {
     Build(std::io::Error),
     #[error("Nix build command resulted in a bad exit code: {0:?}")]
     BuildExit(Option<i32>),
+    #[error("Nix build command did not finish within {0} seconds and was killed")]
+    BuildTimeout(u16),
+    #[error("Failed to run nix-output-monitor: {0}")]
+    Nom(std::io::Error),
     #[error(
         "Activation script deploy-rs-activate does not exist in profile.\n\
              Did you forget to use deploy-rs#lib.<...>.activate.<...> on your profile path?"
@@ -40,11 +51,26 @@ pub enum PushProfileError {
     SignExit(Option<i32>),
     #[error("Failed to run Nix copy command: {0}")]
     Copy(std::io::Error),
-    #[error("Nix copy command resulted in a bad exit code: {0:?}")]
-    CopyExit(Option<i32>),
+    #[error("Nix copy command resulted in a bad exit code: {0:?}{tail}", tail = format_stderr_tail(.1))]
+    CopyExit(Option<i32>, Vec<String>),
 
     #[error("Failed to run Nix path-info command: {0}")]
     PathInfo(std::io::Error),
+
+    #[error("Failed to run Nix store ping command: {0}")]
+    CachePing(std::io::Error),
+    #[error("Binary cache `{0}` is unreachable")]
+    CacheUnreachable(String),
+    #[error("Failed to substitute profile on the node over SSH: {0}")]
+    Realise(std::io::Error),
+    #[error("Substituting profile on the node resulted in a bad exit code: {0:?}, is `{1}` configured as a substituter on the node?")]
+    RealiseExit(Option<i32>, String),
+    #[error("--copy-bwlimit needs `{0}` in PATH")]
+    BwLimitMissing(&'static str),
+    #[error("Failed to create the ssh wrapper limiting the bandwidth of nix copy: {0}")]
+    BwLimitWrapper(std::io::Error),
+    #[error("Unknown placeholder `{{{1}}}` in result path `{0}`, only `{{node}}` and `{{profile}}` are supported")]
+    ResultPathPlaceholder(String, String),
 }
 
 pub struct PushProfileData<'a> {
@@ -58,6 +84,321 @@ pub struct PushProfileData<'a> {
     pub extra_build_args: &'a [String],
 }
 
+pub struct BuildCommandData<'a> {
+    pub supports_flakes: bool,
+    pub derivation_name: &'a str,
+    pub out_link: Option<&'a str>,
+    pub builders: Option<&'a str>,
+    pub system: Option<&'a str>,
+    pub print_build_logs: bool,
+    pub extra_build_args: &'a [String],
+}
+
+pub fn build_build_command(data: &BuildCommandData) -> Command {
+    let mut build_command = if data.supports_flakes {
+        Command::new("nix")
+    } else {
+        Command::new("nix-build")
+    };
+
+    if data.supports_flakes {
+        build_command.arg("build").arg(data.derivation_name)
+    } else {
+        build_command.arg(data.derivation_name)
+    };
+
+    match (data.out_link, data.supports_flakes) {
+        (Some(out_link), _) => build_command.arg("--out-link").arg(out_link),
+        (None, false) => build_command.arg("--no-out-link"),
+        (None, true) => build_command.arg("--no-link"),
+    };
+
+    if let Some(builders) = data.builders {
+        build_command.arg("--builders").arg(builders);
+    }
+
+    if let Some(system) = data.system {
+        build_command.arg("--option").arg("system").arg(system);
+    }
+
+    // `nix-build` always prints the full build logs
+    if data.print_build_logs && data.supports_flakes {
+        build_command.arg("--print-build-logs");
+    }
+
+    // Given last, so that extra build args can still override anything set above
+    build_command.args(data.extra_build_args);
+
+    build_command
+}
+
+#[test]
+fn test_build_command_builder() {
+    let extra_build_args = vec!["--max-jobs".to_string(), "0".to_string()];
+    let build_command = build_build_command(&BuildCommandData {
+        supports_flakes: true,
+        derivation_name: "/nix/store/blah.drv^out",
+        out_link: None,
+        builders: Some("ssh://builder x86_64-linux"),
+        system: None,
+        print_build_logs: true,
+        extra_build_args: &extra_build_args,
+    });
+
+    assert_eq!(build_command.as_std().get_program(), "nix");
+    assert_eq!(
+        build_command.as_std().get_args().collect::<Vec<_>>(),
+        vec![
+            "build",
+            "/nix/store/blah.drv^out",
+            "--no-link",
+            "--builders",
+            "ssh://builder x86_64-linux",
+            "--print-build-logs",
+            "--max-jobs",
+            "0"
+        ]
+    );
+
+    let build_command = build_build_command(&BuildCommandData {
+        supports_flakes: false,
+        derivation_name: "/nix/store/blah.drv",
+        out_link: Some("./.deploy-gc/node/system"),
+        builders: None,
+        system: Some("aarch64-linux"),
+        print_build_logs: true,
+        extra_build_args: &[],
+    });
+
+    assert_eq!(build_command.as_std().get_program(), "nix-build");
+    assert_eq!(
+        build_command.as_std().get_args().collect::<Vec<_>>(),
+        vec![
+            "/nix/store/blah.drv",
+            "--out-link",
+            "./.deploy-gc/node/system",
+            "--option",
+            "system",
+            "aarch64-linux"
+        ]
+    );
+}
+
+/// Set once the user has been told that `--nom` has no effect because `nom` is missing
+static NOM_MISSING_WARNED: AtomicBool = AtomicBool::new(false);
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|path| path.is_file())
+    })
+}
+
+fn nom_available() -> bool {
+    find_in_path("nom").is_some()
+}
+
+/// How long the build of a profile may take, `--build-timeout` taking precedence over `buildTimeout`
+fn build_timeout(deploy_data: &super::DeployData) -> Option<u16> {
+    deploy_data
+        .cmd_overrides
+        .build_timeout
+        .or(deploy_data.profile.profile_settings.build_timeout)
+}
+
+/// Runs a build command, showing its logs through nix-output-monitor if `nom` is set and available,
+/// and kills it once `timeout` seconds have passed
+async fn run_build_command(
+    build_command: &mut Command,
+    nom: bool,
+    timeout: Option<u16>,
+    prefix: Option<String>,
+) -> Result<ExitStatus, PushProfileError> {
+    let build = wait_build_command(build_command, nom, prefix);
+
+    match timeout {
+        // Dropping the build kills it, as its processes are spawned with kill_on_drop
+        Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout.into()), build)
+            .await
+            .map_err(|_| PushProfileError::BuildTimeout(timeout))?,
+        None => build.await,
+    }
+}
+
+/// Runs a build command to completion, see `run_build_command`
+async fn wait_build_command(
+    build_command: &mut Command,
+    nom: bool,
+    prefix: Option<String>,
+) -> Result<ExitStatus, PushProfileError> {
+    // Logging should be in stderr, this just stops the store path from printing for no reason
+    build_command.stdout(Stdio::null()).kill_on_drop(true);
+
+    let use_nom = nom && nom_available();
+
+    if nom && !use_nom && !NOM_MISSING_WARNED.swap(true, Ordering::Relaxed) {
+        warn!("`nom` was not found in PATH, showing the raw build output instead");
+    }
+
+    // nix-output-monitor draws the progress of the build itself, which can't be prefixed
+    if !use_nom && prefix.is_some() {
+        let mut build_child = build_command
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(PushProfileError::Build)?;
+        let build_stderr = tee_stderr(&mut build_child, prefix);
+
+        let build_exit_status = build_child.wait().await.map_err(PushProfileError::Build)?;
+        let _ = build_stderr.await;
+
+        return Ok(build_exit_status);
+    }
+
+    if !use_nom {
+        return build_command.status().await.map_err(PushProfileError::Build);
+    }
+
+    let mut build_child = build_command
+        .arg("--log-format")
+        .arg("internal-json")
+        .arg("-v")
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(PushProfileError::Build)?;
+
+    let build_stderr: Stdio = build_child
+        .stderr
+        .take()
+        .expect("stderr of the build command is piped")
+        .try_into()
+        .map_err(PushProfileError::Build)?;
+
+    let mut nom_child = Command::new("nom")
+        .kill_on_drop(true)
+        .arg("--json")
+        .stdin(build_stderr)
+        .spawn()
+        .map_err(PushProfileError::Nom)?;
+
+    let build_exit_status = build_child.wait().await.map_err(PushProfileError::Build)?;
+    nom_child.wait().await.map_err(PushProfileError::Nom)?;
+
+    Ok(build_exit_status)
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_build_command_killed_on_drop() {
+    let pid_file = std::env::temp_dir().join(format!("deploy-rs-test-kill-{}", std::process::id()));
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg("echo $$ > \"$0\"; exec sleep 60")
+        .arg(&pid_file);
+
+    // Abandons the build halfway, like an aborted deployment does
+    let build = run_build_command(&mut command, false, None, None);
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(500), build).await.is_err());
+
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    std::fs::remove_file(&pid_file).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // Either reaped already or a zombie waiting to be
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+    let state = stat.rsplit(") ").next().and_then(|rest| rest.chars().next());
+    assert!(stat.is_empty() || state == Some('Z'), "build still running: {}", stat);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_build_timeout() {
+    let pid_file =
+        std::env::temp_dir().join(format!("deploy-rs-test-timeout-{}", std::process::id()));
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg("echo $$ > \"$0\"; exec sleep 60")
+        .arg(&pid_file);
+
+    let result = run_build_command(&mut command, false, Some(1), None).await;
+    assert!(matches!(result, Err(PushProfileError::BuildTimeout(1))));
+
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    std::fs::remove_file(&pid_file).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+    let state = stat.rsplit(") ").next().and_then(|rest| rest.chars().next());
+    assert!(stat.is_empty() || state == Some('Z'), "build still running: {}", stat);
+
+    let mut command = Command::new("true");
+    let status = run_build_command(&mut command, false, Some(1), None).await.unwrap();
+    assert!(status.success());
+}
+
+/// Where the result of a profile is linked to. A result path with placeholders is the link itself,
+/// without any it is the directory holding `<node>/<profile>` links.
+pub fn expand_result_path(
+    result_path: &str,
+    node_name: &str,
+    profile_name: &str,
+) -> Result<String, PushProfileError> {
+    if !result_path.contains('{') {
+        return Ok(format!("{}/{}/{}", result_path, node_name, profile_name));
+    }
+
+    let mut expanded = String::new();
+    let mut rest = result_path;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+
+        let end = rest[start..].find('}').map(|end| start + end);
+        let placeholder = &rest[start + 1..end.unwrap_or(rest.len())];
+        match (placeholder, end) {
+            ("node", Some(_)) => expanded.push_str(node_name),
+            ("profile", Some(_)) => expanded.push_str(profile_name),
+            _ => {
+                return Err(PushProfileError::ResultPathPlaceholder(
+                    result_path.to_string(),
+                    placeholder.to_string(),
+                ))
+            }
+        }
+
+        rest = &rest[end.unwrap_or(rest.len()) + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[test]
+fn test_expand_result_path() {
+    assert_eq!(
+        expand_result_path("./.deploy-gc", "web", "system").unwrap(),
+        "./.deploy-gc/web/system"
+    );
+    assert_eq!(
+        expand_result_path("/var/lib/gc/{node}-{profile}", "web", "system").unwrap(),
+        "/var/lib/gc/web-system"
+    );
+    assert_eq!(
+        expand_result_path("./{profile}/{node}", "web", "system").unwrap(),
+        "./system/web"
+    );
+
+    assert!(matches!(
+        expand_result_path("./{host}", "web", "system"),
+        Err(PushProfileError::ResultPathPlaceholder(_, placeholder)) if placeholder == "host"
+    ));
+    assert!(matches!(
+        expand_result_path("./{node", "web", "system"),
+        Err(PushProfileError::ResultPathPlaceholder(_, placeholder)) if placeholder == "node"
+    ));
+}
+
 pub async fn build_profile_locally(
     data: &PushProfileData<'_>,
     derivation_name: &str,
@@ -67,18 +408,28 @@ pub async fn build_profile_locally(
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
-    let mut build_command = if data.supports_flakes {
-        Command::new("nix")
-    } else {
-        Command::new("nix-build")
-    };
+    let out_link = if data.keep_result {
+        let result_path = data.result_path.unwrap_or("./.deploy-gc");
 
-    if data.supports_flakes {
-        build_command.arg("build").arg(derivation_name)
+        Some(expand_result_path(
+            result_path,
+            data.deploy_data.node_name,
+            data.deploy_data.profile_name,
+        )?)
     } else {
-        build_command.arg(derivation_name)
+        None
     };
 
+    let mut build_command = build_build_command(&BuildCommandData {
+        supports_flakes: data.supports_flakes,
+        derivation_name,
+        out_link: out_link.as_deref(),
+        builders: data.deploy_data.cmd_overrides.builders.as_deref(),
+        system: data.deploy_data.cmd_overrides.target_system.as_deref(),
+        print_build_logs: data.deploy_data.cmd_overrides.print_build_logs,
+        extra_build_args: data.extra_build_args,
+    });
+
     match std::env::var("TMPDIR") {
         Ok(build_dir) => {
             info!("Detected TMPDIR is set for build to {build_dir}");
@@ -86,27 +437,19 @@ pub async fn build_profile_locally(
         }
         Err(_) => {}
     }
-    match (data.keep_result, data.supports_flakes) {
-        (true, _) => {
-            let result_path = data.result_path.unwrap_or("./.deploy-gc");
-
-            build_command.arg("--out-link").arg(format!(
-                "{}/{}/{}",
-                result_path, data.deploy_data.node_name, data.deploy_data.profile_name
-            ))
-        }
-        (false, false) => build_command.arg("--no-out-link"),
-        (false, true) => build_command.arg("--no-link"),
-    };
 
-    build_command.args(data.extra_build_args);
+    if data.deploy_data.cmd_overrides.dry_run {
+        log_dry_run(&build_command);
+        return Ok(());
+    }
 
-    let build_exit_status = build_command
-        // Logging should be in stderr, this just stops the store path from printing for no reason
-        .stdout(Stdio::null())
-        .status()
-        .await
-        .map_err(PushProfileError::Build)?;
+    let build_exit_status = run_build_command(
+        &mut build_command,
+        data.deploy_data.cmd_overrides.nom,
+        build_timeout(data.deploy_data),
+        output_prefix(data.deploy_data),
+    )
+    .await?;
 
     match build_exit_status.code() {
         Some(0) => (),
@@ -144,6 +487,7 @@ pub async fn build_profile_locally(
         );
 
         let sign_exit_status = Command::new("nix")
+            .kill_on_drop(true)
             .arg("sign-paths")
             .arg("-r")
             .arg("-k")
@@ -161,6 +505,190 @@ pub async fn build_profile_locally(
     Ok(())
 }
 
+/// The `NIX_SSHOPTS` passing `ssh_opts` to the ssh of Nix, which splits it like a shell would.
+/// Only the options that need it are quoted, e.g. a `ProxyCommand` with spaces, as older versions
+/// of Nix just split on whitespace.
+fn nix_sshopts(ssh_opts: &[String]) -> String {
+    // Only a nul byte can't be quoted, and that can't be passed in the environment anyway
+    shlex::try_join(ssh_opts.iter().map(String::as_str)).unwrap_or_else(|_| ssh_opts.join(" "))
+}
+
+#[test]
+fn test_nix_sshopts() {
+    let ssh_opts = vec![
+        "-p".to_string(),
+        "2222".to_string(),
+        "-o".to_string(),
+        "ProxyCommand=ssh -W %h:%p bastion".to_string(),
+    ];
+    assert_eq!(nix_sshopts(&ssh_opts[..2]), "-p 2222");
+    assert_eq!(nix_sshopts(&ssh_opts), "-p 2222 -o 'ProxyCommand=ssh -W %h:%p bastion'");
+    assert_eq!(shlex::split(&nix_sshopts(&ssh_opts)).unwrap(), ssh_opts);
+}
+
+/// The `nix build` that builds the copied derivation in the store of the node
+fn remote_build_command(
+    data: &PushProfileData<'_>,
+    derivation_name: &str,
+    store_address: &str,
+) -> Command {
+    let mut build_command = Command::new("nix");
+    build_command
+        .arg("--experimental-features")
+        .arg("nix-command")
+        .arg("build")
+        .arg(derivation_name)
+        .arg("--eval-store")
+        .arg("auto")
+        .arg("--store")
+        .arg(store_address);
+
+    if data.deploy_data.cmd_overrides.print_build_logs {
+        build_command.arg("--print-build-logs");
+    }
+
+    build_command
+        .args(data.extra_build_args)
+        .env("NIX_SSHOPTS", nix_sshopts(&data.deploy_data.merged_settings.ssh_opts))
+        .kill_on_drop(true);
+
+    build_command
+}
+
+#[test]
+fn test_remote_build_per_node() {
+    let profile = || serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+        "user": "root",
+    });
+    let storage = || serde_json::json!({
+        "hostname": "storage.example.com",
+        "remoteBuild": true,
+        "profiles": {},
+    });
+    let small = || serde_json::json!({
+        "hostname": "small.example.com",
+        "remoteBuild": false,
+        "profiles": {},
+    });
+    let unset = || serde_json::json!({
+        "hostname": "unset.example.com",
+        "profiles": {},
+    });
+
+    let remote_build = |node: serde_json::Value, remote_build: bool| {
+        let cmd_overrides = super::CmdOverrides {
+            remote_build,
+            ..super::CmdOverrides::default()
+        };
+        let deploy_data = super::test_deploy_data("example", node, profile(), cmd_overrides);
+        let deploy_defs = deploy_data.defs().unwrap();
+        push_target(&PushProfileData {
+            supports_flakes: true,
+            check_sigs: false,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        })
+        .remote_build
+    };
+
+    // The setting of the node wins over --remote-build, which only applies to the other nodes
+    assert!(remote_build(storage(), true));
+    assert!(!remote_build(small(), true));
+    assert!(remote_build(unset(), true));
+
+    assert!(remote_build(storage(), false));
+    assert!(!remote_build(small(), false));
+    assert!(!remote_build(unset(), false));
+
+    let cmd_overrides = super::CmdOverrides::default();
+    let deploy_data = super::test_deploy_data("storage", storage(), profile(), cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+    let extra_build_args = vec!["--max-jobs".to_string(), "4".to_string()];
+    let build_command = remote_build_command(
+        &PushProfileData {
+            supports_flakes: true,
+            check_sigs: false,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &extra_build_args,
+        },
+        "/nix/store/blah.drv^out",
+        "ssh-ng://root@storage.example.com?compress=false",
+    );
+
+    assert_eq!(build_command.as_std().get_program(), "nix");
+    assert_eq!(
+        build_command.as_std().get_args().collect::<Vec<_>>(),
+        vec![
+            "--experimental-features",
+            "nix-command",
+            "build",
+            "/nix/store/blah.drv^out",
+            "--eval-store",
+            "auto",
+            "--store",
+            "ssh-ng://root@storage.example.com?compress=false",
+            "--max-jobs",
+            "4"
+        ]
+    );
+}
+
+#[test]
+fn test_ssh_opts_reach_nix() {
+    let node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "deploy",
+        "profiles": {},
+    });
+    let profile = serde_json::json!({
+        "path": "/nix/store/00000000000000000000000000000000-system",
+        "user": "root",
+    });
+    // As `run` splits `--ssh-opts "-o 'ProxyCommand=ssh -W %h:%p bastion' -p 22"`
+    let cmd_overrides = super::CmdOverrides {
+        ssh_opts: shlex::split("-o 'ProxyCommand=ssh -W %h:%p bastion' -p 22"),
+        ..super::CmdOverrides::default()
+    };
+
+    let deploy_data = super::test_deploy_data("example", node, profile, cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+    let data = PushProfileData {
+        supports_flakes: true,
+        check_sigs: false,
+        repo: ".",
+        deploy_data: &deploy_data,
+        deploy_defs: &deploy_defs,
+        keep_result: false,
+        result_path: None,
+        extra_build_args: &[],
+    };
+
+    let nix_sshopts = |command: &Command| {
+        command
+            .as_std()
+            .get_envs()
+            .find(|(k, _)| *k == "NIX_SSHOPTS")
+            .and_then(|(_, v)| v)
+            .map(|v| v.to_string_lossy().into_owned())
+    };
+    let expected = Some("-o 'ProxyCommand=ssh -W %h:%p bastion' -p 22".to_string());
+
+    let copy = copy_command(&push_target(&data), &["/nix/store/abc-system"], None);
+    assert_eq!(nix_sshopts(&copy), expected);
+
+    let build = remote_build_command(&data, "/nix/store/abc.drv^out", "ssh-ng://deploy@example.com");
+    assert_eq!(nix_sshopts(&build), expected);
+}
+
 pub async fn build_profile_remotely(
     data: &PushProfileData<'_>,
     derivation_name: &str,
@@ -170,12 +698,6 @@ pub async fn build_profile_remotely(
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
-    // TODO: this should probably be handled more nicely during 'data' construction
-    let hostname = match data.deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &data.deploy_data.node.node_settings.hostname,
-    };
-
     let compress = data
         .deploy_data
         .merged_settings
@@ -184,13 +706,17 @@ pub async fn build_profile_remotely(
 
     let store_address = format!(
         "ssh-ng://{}@{}?compress={}",
-        data.deploy_defs.ssh_user, hostname, compress
+        data.deploy_defs.ssh_user,
+        super::url_host(data.deploy_data.hostname()),
+        compress
     );
 
-    let ssh_opts_str = data.deploy_data.merged_settings.ssh_opts.join(" ");
+    let ssh_opts_str = nix_sshopts(&data.deploy_data.merged_settings.ssh_opts);
 
     // copy the derivation to remote host so it can be built there
-    let copy_command_status = Command::new("nix")
+    let mut copy_command = Command::new("nix");
+    copy_command
+        .kill_on_drop(true)
         .arg("--experimental-features")
         .arg("nix-command")
         .arg("copy")
@@ -200,37 +726,27 @@ pub async fn build_profile_remotely(
         .arg("--derivation")
         .arg(derivation_name)
         .env("NIX_SSHOPTS", ssh_opts_str.clone())
-        .stdout(Stdio::null())
-        .status()
-        .await
-        .map_err(PushProfileError::Copy)?;
+        .stdout(Stdio::null());
 
-    match copy_command_status.code() {
-        Some(0) => (),
-        a => return Err(PushProfileError::CopyExit(a)),
-    };
+    let mut build_command = remote_build_command(data, derivation_name, &store_address);
 
-    let mut build_command = Command::new("nix");
-    build_command
-        .arg("--experimental-features")
-        .arg("nix-command")
-        .arg("build")
-        .arg(derivation_name)
-        .arg("--eval-store")
-        .arg("auto")
-        .arg("--store")
-        .arg(&store_address)
-        .args(data.extra_build_args)
-        .env("NIX_SSHOPTS", ssh_opts_str.clone());
+    if data.deploy_data.cmd_overrides.dry_run {
+        log_dry_run(&copy_command);
+        log_dry_run(&build_command);
+        return Ok(());
+    }
+
+    run_copy(copy_command, output_prefix(data.deploy_data)).await?;
 
     debug!("build command: {:?}", build_command);
 
-    let build_exit_status = build_command
-        // Logging should be in stderr, this just stops the store path from printing for no reason
-        .stdout(Stdio::null())
-        .status()
-        .await
-        .map_err(PushProfileError::Build)?;
+    let build_exit_status = run_build_command(
+        &mut build_command,
+        data.deploy_data.cmd_overrides.nom,
+        build_timeout(data.deploy_data),
+        output_prefix(data.deploy_data),
+    )
+    .await?;
 
     match build_exit_status.code() {
         Some(0) => (),
@@ -240,19 +756,26 @@ pub async fn build_profile_remotely(
     Ok(())
 }
 
-pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+/// Finds the derivation to build the profile from, as `nix build` takes it
+async fn find_deriver(data: &PushProfileData<'_>) -> Result<String, PushProfileError> {
     debug!(
         "Finding the deriver of store path for {}",
         &data.deploy_data.profile.profile_settings.path
     );
 
     // `nix-store --query --deriver` doesn't work on invalid paths, so we parse output of show-derivation :(
-    let show_derivation_output = Command::new("nix")
+    let mut show_derivation_command = CommandSpec::new("nix");
+    show_derivation_command
         .arg("--experimental-features")
         .arg("nix-command")
         .arg("show-derivation")
         .arg(&data.deploy_data.profile.profile_settings.path)
-        .output()
+        .capture_stderr();
+
+    let show_derivation_output = data
+        .deploy_data
+        .runner
+        .output(&show_derivation_command)
         .await
         .map_err(PushProfileError::ShowDerivation)?;
 
@@ -301,12 +824,18 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
         deriver.clone()
     };
 
-    let path_info_output = Command::new("nix")
+    let mut path_info_command = CommandSpec::new("nix");
+    path_info_command
         .arg("--experimental-features")
         .arg("nix-command")
         .arg("path-info")
         .arg(&deriver)
-        .output()
+        .capture_stderr();
+
+    let path_info_output = data
+        .deploy_data
+        .runner
+        .output(&path_info_command)
         .await
         .map_err(PushProfileError::PathInfo)?;
 
@@ -327,16 +856,40 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
         // 'error: path '...' is not valid'.
         deriver
     };
-    if data
+
+    Ok(deriver)
+}
+
+pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+    let remote_build = data
         .deploy_data
         .merged_settings
         .remote_build
-        .unwrap_or(false)
-    {
+        .unwrap_or(false);
+
+    let deriver = if data.deploy_data.cmd_overrides.dry_run {
+        // Looking the deriver up already runs nix, so a dry run can only name it
+        format!(
+            "<deriver of {}>{}",
+            data.deploy_data.profile.profile_settings.path,
+            if data.supports_flakes || remote_build { "^out" } else { "" }
+        )
+    } else {
+        find_deriver(&data).await?
+    };
+
+    if remote_build {
         if !data.supports_flakes {
             warn!("remote builds using non-flake nix are experimental");
         }
 
+        if data.deploy_data.cmd_overrides.builders.is_some() {
+            warn!(
+                "Profile `{}` for node `{}` is built on the node itself, ignoring --builders",
+                data.deploy_data.profile_name, data.deploy_data.node_name
+            );
+        }
+
         build_profile_remotely(&data, &deriver).await?;
     } else {
         build_profile_locally(&data, &deriver).await?;
@@ -345,69 +898,699 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
     Ok(())
 }
 
+#[tokio::test]
+async fn test_dry_run_builds_and_pushes_nothing() {
+    // Nothing is queued, so any command run through it fails
+    let runner = crate::command::MockRunner::default();
+
+    for &remote_build in &[false, true] {
+        let node = serde_json::json!({
+            "hostname": "example.com",
+            "remoteBuild": remote_build,
+            "profiles": {},
+        });
+        let profile = serde_json::json!({
+            "path": "/nix/store/00000000000000000000000000000000-system",
+            "user": "root",
+        });
+        let cmd_overrides = super::CmdOverrides {
+            dry_run: true,
+            // Doesn't need `pv` without copying
+            copy_bwlimit: Some("1m".to_string()),
+            ..super::CmdOverrides::default()
+        };
+        let mut deploy_data = super::test_deploy_data("example", node, profile, cmd_overrides);
+        deploy_data.runner = &runner;
+        let deploy_defs = deploy_data.defs().unwrap();
+        let data = || PushProfileData {
+            supports_flakes: true,
+            check_sigs: false,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        };
+
+        build_profile(data()).await.unwrap();
+        push_profiles(&[data()]).await.unwrap();
+    }
+
+    assert_eq!(runner.calls(), vec![]);
+}
+
+/// Runs a `nix copy` command, keeping the end of its output to tell why it failed
+async fn run_copy(
+    mut copy_command: Command,
+    prefix: Option<String>,
+) -> Result<(), PushProfileError> {
+    let mut copy_child = copy_command
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(PushProfileError::Copy)?;
+    let copy_stderr = tee_stderr(&mut copy_child, prefix);
+
+    let copy_exit_status = copy_child.wait().await.map_err(PushProfileError::Copy)?;
+
+    match copy_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(PushProfileError::CopyExit(
+            a,
+            copy_stderr.await.unwrap_or_default(),
+        )),
+    }
+}
+
+/// How long to wait before the first retry of a `nix copy`, doubled for every further one
+const COPY_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Output of `nix copy` (or of the ssh it runs) that points to the connection to the node
+/// failing, rather than to e.g. a missing signature or a rejected key
+const TRANSIENT_COPY_ERRORS: &[&str] = &[
+    "Connection reset",
+    "Connection timed out",
+    "Connection closed",
+    "Connection refused",
+    "Broken pipe",
+    "Network is unreachable",
+    "No route to host",
+    "Could not resolve hostname",
+    "unexpected end-of-file",
+    "failed to start SSH connection",
+];
+
+impl PushProfileError {
+    /// Whether the copy failed because of the network, so trying again might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PushProfileError::CopyExit(_, tail) => tail
+                .iter()
+                .any(|line| TRANSIENT_COPY_ERRORS.iter().any(|e| line.contains(e))),
+            _ => false,
+        }
+    }
+}
+
+/// Runs `copy` until it succeeds, retrying failures caused by the network up to `retries` times
+/// with exponential backoff starting at `delay`
+async fn retry_copy<F, Fut>(retries: u8, delay: Duration, mut copy: F) -> Result<(), PushProfileError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), PushProfileError>>,
+{
+    let mut attempt = 0;
+    let mut delay = delay;
+    loop {
+        match copy().await {
+            Err(e) if e.is_transient() && attempt < retries => {
+                attempt += 1;
+                debug!(
+                    "nix copy failed because of the network ({}), retrying in {:?} ({}/{})",
+                    e, delay, attempt, retries
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_retry_copy() {
+    let network_error = || {
+        PushProfileError::CopyExit(
+            Some(1),
+            vec!["error: cannot connect: Connection reset by peer".to_string()],
+        )
+    };
+
+    // Fails twice because of the network, then succeeds
+    let mut attempts = 0;
+    let result = retry_copy(2, Duration::ZERO, || {
+        attempts += 1;
+        let result = if attempts < 3 { Err(network_error()) } else { Ok(()) };
+        async move { result }
+    })
+    .await;
+    assert!(result.is_ok());
+    assert_eq!(attempts, 3);
+
+    // Gives up once the retries are used up
+    let mut attempts = 0;
+    let result = retry_copy(1, Duration::ZERO, || {
+        attempts += 1;
+        async { Err(network_error()) }
+    })
+    .await;
+    assert!(matches!(result, Err(PushProfileError::CopyExit(..))));
+    assert_eq!(attempts, 2);
+
+    // A missing signature won't go away by trying again
+    let mut attempts = 0;
+    let result = retry_copy(2, Duration::ZERO, || {
+        attempts += 1;
+        async {
+            Err(PushProfileError::CopyExit(
+                Some(1),
+                vec![
+                    "error: cannot add path '/nix/store/abc-system' because it lacks a signature \
+                     by a trusted key"
+                        .to_string(),
+                ],
+            ))
+        }
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+}
+
+/// Where and how a profile is copied to, profiles with the same target are copied with a single
+/// `nix copy`
+#[derive(PartialEq, Debug)]
+pub struct PushTarget<'a> {
+    pub ssh_user: &'a str,
+    pub hostname: &'a str,
+    pub ssh_opts: &'a [String],
+    pub compress: bool,
+    pub substitute_on_destination: bool,
+    pub check_sigs: bool,
+    pub remote_build: bool,
+    pub via_cache: Option<&'a str>,
+    pub local: bool,
+    pub copy_jobs: Option<u16>,
+    pub copy_bwlimit: Option<&'a str>,
+}
+
+/// Whether `nix copy` compresses the SSH connection and lets the node substitute, as `(compress,
+/// substitute_on_destination)`. An explicit `compress` or `substituteOnDestination` (from the
+/// flake or the command line) wins. Otherwise only a slow connection (`fastConnection = false`) is
+/// compressed, and the node substitutes unless the connection is fast (`fastConnection = true`).
+fn copy_options(merged_settings: &super::data::GenericSettings) -> (bool, bool) {
+    let fast_connection = merged_settings.fast_connection;
+
+    (
+        merged_settings.compress.unwrap_or(fast_connection == Some(false)),
+        merged_settings
+            .substitute_on_destination
+            .unwrap_or(fast_connection != Some(true)),
+    )
+}
+
+#[test]
+fn test_copy_options() {
+    let options = |settings: serde_json::Value| {
+        copy_options(&serde_json::from_value(settings).unwrap())
+    };
+
+    assert_eq!(options(serde_json::json!({})), (false, true));
+    assert_eq!(options(serde_json::json!({ "fastConnection": true })), (false, false));
+    assert_eq!(options(serde_json::json!({ "fastConnection": false })), (true, true));
+    assert_eq!(
+        options(serde_json::json!({ "fastConnection": false, "compress": false })),
+        (false, true)
+    );
+    assert_eq!(
+        options(serde_json::json!({ "fastConnection": true, "compress": true })),
+        (true, false)
+    );
+    assert_eq!(
+        options(serde_json::json!({ "fastConnection": true, "substituteOnDestination": true })),
+        (false, true)
+    );
+}
+
+pub fn push_target<'a>(data: &PushProfileData<'a>) -> PushTarget<'a> {
+    let merged_settings = &data.deploy_data.merged_settings;
+    let (compress, substitute_on_destination) = copy_options(merged_settings);
+
+    PushTarget {
+        ssh_user: &data.deploy_defs.ssh_user,
+        hostname: data.deploy_data.hostname(),
+        ssh_opts: &merged_settings.ssh_opts,
+        compress,
+        substitute_on_destination,
+        check_sigs: data.check_sigs,
+        remote_build: merged_settings.remote_build.unwrap_or(false),
+        via_cache: data.deploy_data.cmd_overrides.via_cache.as_deref(),
+        local: data.deploy_data.cmd_overrides.local,
+        copy_jobs: data.deploy_data.cmd_overrides.copy_jobs,
+        copy_bwlimit: data.deploy_data.cmd_overrides.copy_bwlimit.as_deref(),
+    }
+}
+
+/// The `ssh` wrapper limiting what is sent to the node to `rate` (in bytes per second, with an
+/// optional `k`, `m` or `g` suffix) using `pv`
+fn bwlimit_ssh_wrapper(rate: &str, ssh: &Path) -> String {
+    format!(
+        "#!/bin/sh\npv -q -L {} | exec {} \"$@\"\n",
+        shell_quote(rate),
+        shell_quote(&ssh.to_string_lossy())
+    )
+}
+
+#[test]
+fn test_bwlimit_ssh_wrapper() {
+    assert_eq!(
+        bwlimit_ssh_wrapper("500k", Path::new("/usr/bin/ssh")),
+        "#!/bin/sh\npv -q -L '500k' | exec '/usr/bin/ssh' \"$@\"\n"
+    );
+}
+
+/// Writes the `ssh` wrapper for `rate` into a new directory only we can access, so that nobody
+/// else can swap it for an `ssh` of their own. The directory is removed when it is dropped.
+fn write_bwlimit_ssh_dir(rate: &str, ssh: &Path) -> std::io::Result<tempfile::TempDir> {
+    let dir = tempfile::Builder::new()
+        .prefix("deploy-rs-bwlimit-")
+        .permissions(std::os::unix::fs::PermissionsExt::from_mode(0o700))
+        .tempdir()?;
+
+    let wrapper = dir.path().join("ssh");
+    std::fs::write(&wrapper, bwlimit_ssh_wrapper(rate, ssh))?;
+    let mut permissions = std::fs::metadata(&wrapper)?.permissions();
+    std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o700);
+    std::fs::set_permissions(&wrapper, permissions)?;
+
+    Ok(dir)
+}
+
+#[test]
+fn test_write_bwlimit_ssh_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = write_bwlimit_ssh_dir("1m", Path::new("/usr/bin/ssh")).unwrap();
+    let path = dir.path().to_path_buf();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o700);
+    assert_eq!(
+        std::fs::read_to_string(path.join("ssh")).unwrap(),
+        bwlimit_ssh_wrapper("1m", Path::new("/usr/bin/ssh"))
+    );
+
+    let other = write_bwlimit_ssh_dir("1m", Path::new("/usr/bin/ssh")).unwrap();
+    assert_ne!(other.path(), path);
+
+    drop(dir);
+    assert!(!path.exists());
+}
+
+/// Creates a directory holding an `ssh` wrapper throttling the upload to `rate`, which is put
+/// first in the `PATH` of `nix copy` so that only the copy is throttled and not the activation.
+/// It has to be kept until the copy is done.
+fn bwlimit_ssh_dir(rate: &str) -> Result<tempfile::TempDir, PushProfileError> {
+    let ssh = find_in_path("ssh").ok_or(PushProfileError::BwLimitMissing("ssh"))?;
+    find_in_path("pv").ok_or(PushProfileError::BwLimitMissing("pv"))?;
+
+    write_bwlimit_ssh_dir(rate, &ssh).map_err(PushProfileError::BwLimitWrapper)
+}
+
+/// Limits how many paths `nix copy` transfers at the same time
+fn copy_jobs_args(copy_command: &mut Command, copy_jobs: Option<u16>) {
+    if let Some(jobs) = copy_jobs {
+        copy_command
+            .arg("--option")
+            .arg("max-substitution-jobs")
+            .arg(jobs.to_string());
+    }
+}
+
+#[test]
+fn test_push_target_substitute_on_destination() {
+    let substitute_on_destination = |node: serde_json::Value| {
+        let profile = serde_json::json!({
+            "path": "/nix/store/00000000000000000000000000000000-system",
+            "user": "root",
+        });
+        let deploy_data = super::test_deploy_data("example", node, profile, super::CmdOverrides::default());
+        let deploy_defs = deploy_data.defs().unwrap();
+        push_target(&PushProfileData {
+            supports_flakes: true,
+            check_sigs: false,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        })
+        .substitute_on_destination
+    };
+
+    assert!(substitute_on_destination(serde_json::json!({
+        "hostname": "example.com",
+        "profiles": {},
+    })));
+    assert!(!substitute_on_destination(serde_json::json!({
+        "hostname": "example.com",
+        "fastConnection": true,
+        "profiles": {},
+    })));
+    assert!(substitute_on_destination(serde_json::json!({
+        "hostname": "example.com",
+        "fastConnection": true,
+        "substituteOnDestination": true,
+        "profiles": {},
+    })));
+    assert!(substitute_on_destination(serde_json::json!({
+        "hostname": "example.com",
+        "fastConnection": false,
+        "profiles": {},
+    })));
+    assert!(!substitute_on_destination(serde_json::json!({
+        "hostname": "example.com",
+        "fastConnection": false,
+        "substituteOnDestination": false,
+        "profiles": {},
+    })));
+}
+
+/// The distinct store paths of the given profiles
+pub fn unique_paths<'a>(datas: &[PushProfileData<'a>]) -> Vec<&'a str> {
+    let mut paths: Vec<&str> = Vec::new();
+    for data in datas {
+        let path = data.deploy_data.profile.profile_settings.path.as_str();
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Groups the profiles by their `push_target`, keeping the order in which the targets first appear
+pub fn group_pushes<'a>(
+    datas: impl IntoIterator<Item = PushProfileData<'a>>,
+) -> Vec<Vec<PushProfileData<'a>>> {
+    let mut groups: Vec<Vec<PushProfileData>> = Vec::new();
+    for data in datas {
+        let target = push_target(&data);
+        match groups.iter_mut().find(|group| push_target(&group[0]) == target) {
+            Some(group) => group.push(data),
+            None => groups.push(vec![data]),
+        }
+    }
+    groups
+}
+
+#[test]
+fn test_group_pushes() {
+    let deploy_data = |node_name: &'static str, hostname: &str, path: &str| {
+        super::test_deploy_data(
+            node_name,
+            serde_json::json!({ "hostname": hostname, "sshUser": "deploy", "profiles": {} }),
+            serde_json::json!({ "path": path }),
+            super::CmdOverrides::default(),
+        )
+    };
+    let system = "/nix/store/00000000000000000000000000000000-system";
+    let app = "/nix/store/11111111111111111111111111111111-app";
+
+    let deploy_datas = [
+        deploy_data("db", "db.example.com", system),
+        deploy_data("web", "web.example.com", system),
+        deploy_data("db", "db.example.com", app),
+        // Another node name for the same host, copied to together with `db`
+        deploy_data("db-replica", "db.example.com", app),
+    ];
+    let deploy_defs: Vec<super::DeployDefs> =
+        deploy_datas.iter().map(|d| d.defs().unwrap()).collect();
+
+    let groups = group_pushes(deploy_datas.iter().zip(&deploy_defs).map(|(deploy_data, deploy_defs)| {
+        PushProfileData {
+            supports_flakes: true,
+            check_sigs: false,
+            repo: ".",
+            deploy_data,
+            deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        }
+    }));
+
+    let names: Vec<Vec<(&str, &str)>> = groups
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .map(|data| {
+                    let path = data.deploy_data.profile.profile_settings.path.as_str();
+                    (data.deploy_data.node_name, path)
+                })
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        names,
+        vec![vec![("db", system), ("db", app), ("db-replica", app)], vec![("web", system)]]
+    );
+    assert_eq!(unique_paths(&groups[0]).len(), 2);
+}
+
+/// The prefix of the output of a copy to all of `nodes`, each of them listed once
+fn copy_prefix<'a>(nodes: impl IntoIterator<Item = &'a str>) -> String {
+    let mut unique: Vec<&str> = Vec::new();
+    for node in nodes {
+        if !unique.contains(&node) {
+            unique.push(node);
+        }
+    }
+    unique.join(",")
+}
+
+#[test]
+fn test_copy_prefix() {
+    assert_eq!(copy_prefix(vec!["web"]), "web");
+    assert_eq!(copy_prefix(vec!["web-1", "web-1", "web-2"]), "web-1,web-2");
+}
+
+/// Copies the profiles to a binary cache and has the node substitute them from there, instead of
+/// copying them to the node directly
+async fn push_profiles_via_cache(
+    target: &PushTarget<'_>,
+    paths: &[&str],
+    cache: &str,
+    prefix: Option<String>,
+    dry_run: bool,
+) -> Result<(), PushProfileError> {
+    let mut copy_command = Command::new("nix");
+    copy_command
+        .kill_on_drop(true)
+        .arg("--experimental-features")
+        .arg("nix-command")
+        .arg("copy")
+        .arg("--to")
+        .arg(cache)
+        .args(paths);
+    copy_jobs_args(&mut copy_command, target.copy_jobs);
+
+    let mut realise_command = Command::new("ssh");
+    realise_command
+        .kill_on_drop(true)
+        .arg(format!("{}@{}", target.ssh_user, super::ssh_host(target.hostname)))
+        .args(target.ssh_opts)
+        .arg("nix-store")
+        .arg("--realise")
+        .args(paths)
+        .stdout(Stdio::null());
+
+    if dry_run {
+        log_dry_run(&copy_command);
+        log_dry_run(&realise_command);
+        return Ok(());
+    }
+
+    let ping_exit_status = Command::new("nix")
+        .kill_on_drop(true)
+        .arg("--experimental-features")
+        .arg("nix-command")
+        .arg("store")
+        .arg("ping")
+        .arg("--store")
+        .arg(cache)
+        .stdout(Stdio::null())
+        .status()
+        .await
+        .map_err(PushProfileError::CachePing)?;
+
+    if ping_exit_status.code() != Some(0) {
+        return Err(PushProfileError::CacheUnreachable(cache.to_string()));
+    }
+
+    info!("Copying to binary cache `{}`", cache);
+
+    run_copy(copy_command, prefix).await?;
+
+    info!("Substituting on `{}`", target.hostname);
+
+    let realise_exit_status = realise_command
+        .status()
+        .await
+        .map_err(PushProfileError::Realise)?;
+
+    match realise_exit_status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::RealiseExit(a, cache.to_string())),
+    };
+
+    Ok(())
+}
+
+/// The `nix copy` of `paths` to `target`, run with `path` as its `PATH` if given
+fn copy_command(target: &PushTarget<'_>, paths: &[&str], path: Option<&std::ffi::OsStr>) -> Command {
+    let mut copy_command = Command::new("nix");
+    copy_command.kill_on_drop(true).arg("copy");
+
+    if target.substitute_on_destination {
+        copy_command.arg("--substitute-on-destination");
+    }
+
+    if !target.check_sigs {
+        copy_command.arg("--no-check-sigs");
+    }
+
+    copy_jobs_args(&mut copy_command, target.copy_jobs);
+
+    copy_command
+        .arg("--to")
+        .arg(format!(
+            "ssh://{}@{}?compress={}",
+            target.ssh_user,
+            super::url_host(target.hostname),
+            target.compress
+        ))
+        .args(paths)
+        .env("NIX_SSHOPTS", nix_sshopts(target.ssh_opts));
+
+    if let Some(path) = path {
+        copy_command.env("PATH", path);
+    }
+
+    copy_command
+}
+
+#[test]
+fn test_copy_command() {
+    let ssh_opts = vec![
+        "-o".to_string(),
+        "ProxyCommand=ssh -W %h:%p bastion".to_string(),
+        "-p".to_string(),
+        "22".to_string(),
+    ];
+    let target = PushTarget {
+        ssh_user: "deploy",
+        hostname: "example.com",
+        ssh_opts: &ssh_opts,
+        compress: false,
+        substitute_on_destination: true,
+        check_sigs: true,
+        remote_build: false,
+        via_cache: None,
+        local: false,
+        copy_jobs: None,
+        copy_bwlimit: None,
+    };
+
+    let command = copy_command(&target, &["/nix/store/abc-system"], None);
+    let command = command.as_std();
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        vec![
+            "copy",
+            "--substitute-on-destination",
+            "--to",
+            "ssh://deploy@example.com?compress=false",
+            "/nix/store/abc-system",
+        ]
+    );
+
+    // Nix splits the options again, the ProxyCommand has to stay a single argument
+    let nix_sshopts = command
+        .get_envs()
+        .find(|(k, _)| *k == "NIX_SSHOPTS")
+        .and_then(|(_, v)| v)
+        .unwrap();
+    assert_eq!(nix_sshopts, "-o 'ProxyCommand=ssh -W %h:%p bastion' -p 22");
+    assert_eq!(shlex::split(nix_sshopts.to_str().unwrap()).unwrap(), ssh_opts);
+}
+
+/// Copies a single profile to its node, see `push_profiles`
+#[deprecated(note = "use `push_profiles`, which copies profiles going to the same host together")]
 pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
-    let ssh_opts_str = data
-        .deploy_data
-        .merged_settings
-        .ssh_opts
-        // This should provide some extra safety, but it also breaks for some reason, oh well
-        // .iter()
-        // .map(|x| format!("'{}'", x))
-        // .collect::<Vec<String>>()
-        .join(" ");
+    push_profiles(&[data]).await
+}
+
+/// Copies profiles that share the same `PushTarget` to their node, with a single `nix copy`
+pub async fn push_profiles(datas: &[PushProfileData<'_>]) -> Result<(), PushProfileError> {
+    let target = match datas.first() {
+        Some(data) => push_target(data),
+        None => return Ok(()),
+    };
 
     // remote building guarantees that the resulting derivation is stored on the target system
     // no need to copy after building
-    if !data
-        .deploy_data
-        .merged_settings
-        .remote_build
-        .unwrap_or(false)
-    {
+    if target.remote_build {
+        return Ok(());
+    }
+
+    // With --local the profile is activated from the store it was built into
+    if target.local {
+        return Ok(());
+    }
+
+    for data in datas {
         info!(
             "Copying profile `{}` to node `{}`",
             data.deploy_data.profile_name, data.deploy_data.node_name
         );
+    }
 
-        let mut copy_command = Command::new("nix");
-        copy_command.arg("copy");
+    let paths = unique_paths(datas);
+    let dry_run = datas[0].deploy_data.cmd_overrides.dry_run;
 
-        if data.deploy_data.merged_settings.fast_connection != Some(true) {
-            copy_command.arg("--substitute-on-destination");
-        }
+    // The profiles are copied together, so only the nodes can be told
+    let prefix = if datas[0].deploy_data.cmd_overrides.prefix_output {
+        Some(copy_prefix(datas.iter().map(|data| data.deploy_data.node_name)))
+    } else {
+        None
+    };
 
-        if !data.check_sigs {
-            copy_command.arg("--no-check-sigs");
-        }
+    if let Some(cache) = target.via_cache {
+        return push_profiles_via_cache(&target, &paths, cache, prefix, dry_run).await;
+    }
 
-        let hostname = match data.deploy_data.cmd_overrides.hostname {
-            Some(ref x) => x,
-            None => &data.deploy_data.node.node_settings.hostname,
-        };
+    // Nothing is copied, so there is neither a need for `pv` nor for the wrapper around ssh
+    if dry_run {
+        log_dry_run(&copy_command(&target, &paths, None));
+        return Ok(());
+    }
 
-        let compress = data
-            .deploy_data
-            .merged_settings
-            .compress
-            .unwrap_or_else(|| false);
-
-        let copy_exit_status = copy_command
-            .arg("--to")
-            .arg(format!(
-                "ssh://{}@{}?compress={}",
-                data.deploy_defs.ssh_user, hostname, compress
-            ))
-            .arg(&data.deploy_data.profile.profile_settings.path)
-            .env("NIX_SSHOPTS", ssh_opts_str)
-            .status()
-            .await
-            .map_err(PushProfileError::Copy)?;
+    let copy_retries = datas[0].deploy_data.cmd_overrides.copy_retries;
 
-        match copy_exit_status.code() {
-            Some(0) => (),
-            a => return Err(PushProfileError::CopyExit(a)),
-        };
-    }
+    // Removed once the copy, with all its retries, is done
+    let bwlimit_dir = target.copy_bwlimit.map(bwlimit_ssh_dir).transpose()?;
+    let copy_path = match bwlimit_dir {
+        Some(ref dir) => {
+            let paths = std::env::var_os("PATH").unwrap_or_default();
+            let paths =
+                std::iter::once(dir.path().to_path_buf()).chain(std::env::split_paths(&paths));
+            Some(std::env::join_paths(paths).map_err(|e| {
+                PushProfileError::BwLimitWrapper(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    e,
+                ))
+            })?)
+        }
+        None => None,
+    };
+
+    // `run_copy` takes ownership of the command, so every attempt builds a fresh one
+    let copy_command = || copy_command(&target, &paths, copy_path.as_deref());
+
+    retry_copy(copy_retries, COPY_RETRY_DELAY, || {
+        run_copy(copy_command(), prefix.clone())
+    })
+    .await?;
 
     Ok(())
 }
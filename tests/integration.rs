@@ -0,0 +1,187 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! End-to-end deployments against a real SSH server, run with
+//! `cargo test --features integration-tests`.
+//!
+//! The node is `localhost` unless `DEPLOY_RS_TEST_HOSTNAME` is set, and is reached as the current
+//! user with the options in `DEPLOY_RS_TEST_SSH_OPTS`. It has to share the Nix store with the
+//! machine running the tests (a throwaway container with `/nix` mounted works too), since the
+//! profiles are added to the local store and never copied.
+
+#![cfg(feature = "integration-tests")]
+
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use deploy::{data, CmdOverrides};
+
+/// A scratch directory for one test, removed when dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "deploy-rs-integration-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Adds a trivial profile to the store, whose activation writes `generation` to `marker`
+fn build_profile(scratch: &Path, generation: &str, marker: &Path) -> String {
+    let dir = scratch.join(format!("deploy-rs-test-{}", generation));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy(env!("CARGO_BIN_EXE_activate"), dir.join("activate-rs")).unwrap();
+
+    let script = dir.join("deploy-rs-activate");
+    std::fs::write(
+        &script,
+        format!("#!/bin/sh\necho {} > {}\n", generation, marker.display()),
+    )
+    .unwrap();
+    let mut permissions = std::fs::metadata(&script).unwrap().permissions();
+    std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o755);
+    std::fs::set_permissions(&script, permissions).unwrap();
+
+    let output = Command::new("nix-store")
+        .arg("--add")
+        .arg(&dir)
+        .output()
+        .expect("nix-store is needed to run the integration tests");
+    assert!(output.status.success(), "nix-store --add failed");
+
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+fn node_json() -> serde_json::Value {
+    let ssh_opts: Vec<String> = std::env::var("DEPLOY_RS_TEST_SSH_OPTS")
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+
+    let hostname =
+        std::env::var("DEPLOY_RS_TEST_HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+
+    serde_json::json!({
+        "hostname": hostname,
+        "sshUser": whoami::username(),
+        "sshOpts": ssh_opts,
+        "profiles": {},
+    })
+}
+
+/// Deploys `closure` as the profile at `profile_path`, returning whether the confirmation found
+/// the lock file where `make_lock_path` says it is
+async fn deploy(
+    closure: &str,
+    profile_path: &Path,
+    temp_path: &Path,
+    cmd_overrides: &CmdOverrides,
+) -> bool {
+    let top_settings: data::GenericSettings =
+        serde_json::from_value(serde_json::json!({})).unwrap();
+    let node: data::Node = serde_json::from_value(node_json()).unwrap();
+    let profile: data::Profile = serde_json::from_value(serde_json::json!({
+        "path": closure,
+        "profilePath": profile_path,
+        "tempPath": temp_path,
+        "noSudo": true,
+    }))
+    .unwrap();
+
+    let deploy_data = deploy::make_deploy_data(
+        &top_settings,
+        &node,
+        "localhost",
+        &profile,
+        "integration",
+        cmd_overrides,
+        false,
+        None,
+    );
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let lock_path = deploy::make_lock_path(temp_path, closure);
+    let lock_found = Cell::new(false);
+    deploy::deploy::deploy_profile(&deploy_data, &deploy_defs, false, false, || {
+        lock_found.set(lock_path.exists())
+    })
+    .await
+    .unwrap();
+
+    lock_found.get()
+}
+
+fn current_closure(profile_path: &Path) -> PathBuf {
+    std::fs::canonicalize(profile_path).unwrap()
+}
+
+#[tokio::test]
+async fn test_deploy_and_confirm() {
+    let scratch = Scratch::new("confirm");
+    let marker = scratch.0.join("marker");
+    let profile_path = scratch.0.join("profile");
+    let temp_path = scratch.0.join("locks");
+
+    let closure = build_profile(&scratch.0, "first", &marker);
+    let lock_found = deploy(
+        &closure,
+        &profile_path,
+        &temp_path,
+        &CmdOverrides::default(),
+    )
+    .await;
+
+    assert!(lock_found, "no lock file at the path from make_lock_path");
+    assert!(!deploy::make_lock_path(&temp_path, &closure).exists());
+    assert_eq!(current_closure(&profile_path), Path::new(&closure));
+    assert_eq!(std::fs::read_to_string(&marker).unwrap(), "first\n");
+}
+
+#[tokio::test]
+async fn test_magic_rollback_timeout() {
+    let scratch = Scratch::new("rollback");
+    let marker = scratch.0.join("marker");
+    let profile_path = scratch.0.join("profile");
+    let temp_path = scratch.0.join("locks");
+
+    let first = build_profile(&scratch.0, "first", &marker);
+    deploy(&first, &profile_path, &temp_path, &CmdOverrides::default()).await;
+
+    // Claim the confirmation succeeded without removing the lock file, like a confirmation that
+    // never reached the node
+    let second = build_profile(&scratch.0, "second", &marker);
+    let cmd_overrides = CmdOverrides {
+        confirm_via: Some("true".to_string()),
+        confirm_timeout: Some(3),
+        ..CmdOverrides::default()
+    };
+    deploy(&second, &profile_path, &temp_path, &cmd_overrides).await;
+    assert_eq!(current_closure(&profile_path), Path::new(&second));
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    // The marker is only rewritten once the rolled back profile has been activated again
+    while std::fs::read_to_string(&marker).unwrap() != "first\n" {
+        assert!(
+            Instant::now() < deadline,
+            "the profile was not rolled back after the confirm timeout"
+        );
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    assert_eq!(current_closure(&profile_path), Path::new(&first));
+}